@@ -1,18 +1,39 @@
-use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use crate::crypto::secp;
+use secp256k1::rand::Rng;
+use secp256k1::{PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(from = "SerializedAddress")]
 pub struct Address {
     pub value: String,
     #[serde(skip)]
     pub raw_bytes: Option<Vec<u8>>,
 }
 
+/// Serialized form of an `Address`, only the hex string is stored
+#[derive(Deserialize)]
+struct SerializedAddress {
+    value: String,
+}
+
+impl From<SerializedAddress> for Address {
+    /// Rebuild the raw bytes so a deserialized address compares like a constructed one
+    fn from(serialized: SerializedAddress) -> Self {
+        let mut address = Address { value: serialized.value, raw_bytes: None };
+        address.normalize();
+        address
+    }
+}
+
 impl Address {
     pub fn generate() -> (Self, SecretKey, PublicKey) {
-        let secp = Secp256k1::new();
+        Self::generate_with_rng(&mut secp256k1::rand::rng())
+    }
 
-        let (secret_key, public_key) = secp.generate_keypair(&mut secp256k1::rand::rng());
+    /// Generate a key pair from `rng`, a seeded generator gives the same address on every run
+    pub fn generate_with_rng<R: Rng + ?Sized>(rng: &mut R) -> (Self, SecretKey, PublicKey) {
+        let (secret_key, public_key) = secp().generate_keypair(rng);
         let pub_key_bytes = public_key.serialize_uncompressed();
 
         let address = Self::from_public_key(&pub_key_bytes);
@@ -38,6 +59,30 @@ impl Address {
         }
     }
 
+    /// 33-byte compressed form of `public_key`, the compact encoding wallets store
+    pub fn compress_pubkey(public_key: &PublicKey) -> [u8; 33] {
+        public_key.serialize()
+    }
+
+    /// Address of a public key stored by `compress_pubkey`, `None` when the bytes are not a valid key.
+    /// The address is always derived from the uncompressed key, so both forms give the same address.
+    pub fn from_compressed_public_key(compressed: &[u8; 33]) -> Option<Self> {
+        let public_key = PublicKey::from_slice(compressed).ok()?;
+        Some(Self::from_public_key(&public_key.serialize_uncompressed()))
+    }
+
+    /// The all-zero address, reserved as the sender of coinbase transactions
+    pub fn zero() -> Self {
+        Self {
+            value: format!("0x{}", "0".repeat(40)),
+            raw_bytes: Some(vec![0; 20]),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::zero()
+    }
+
     pub fn is_valid(&self) -> bool {
         if !self.value.starts_with("0x") || self.value.len() != 42 {
             return false;
@@ -56,6 +101,17 @@ impl Address {
         }
     }
 
+    /// Set `raw_bytes` to the bytes `value` spells, `None` when it is not a valid address
+    pub fn normalize(&mut self) {
+        self.raw_bytes = self.as_bytes();
+    }
+
+    /// Whether `raw_bytes` are missing or the bytes `value` spells. Equality trusts the bytes over the string,
+    /// so an address whose bytes disagree with it compares like some other address.
+    pub fn has_consistent_bytes(&self) -> bool {
+        self.raw_bytes.is_none() || self.raw_bytes == self.as_bytes()
+    }
+
     fn keccak256(data: &[u8]) -> Vec<u8> {
         use sha3::{Digest, Keccak256};
         let mut hasher = Keccak256::new();
@@ -97,6 +153,21 @@ mod tests {
         assert_eq!(public_key.serialize_uncompressed().len(), 65);
     }
 
+    #[test]
+    fn test_seeded_generation_is_reproducible() {
+        use secp256k1::rand::rngs::StdRng;
+        use secp256k1::rand::SeedableRng;
+
+        let (first, first_key, _) = Address::generate_with_rng(&mut StdRng::seed_from_u64(7));
+        let (second, second_key, _) = Address::generate_with_rng(&mut StdRng::seed_from_u64(7));
+        assert_eq!(first, second);
+        assert_eq!(first_key, second_key);
+        assert!(first.is_valid());
+
+        let (other, _, _) = Address::generate_with_rng(&mut StdRng::seed_from_u64(8));
+        assert_ne!(first, other);
+    }
+
     #[test]
     fn test_from_public_key() {
         // Test with uncompressed public key (65 bytes, starts with 0x04)
@@ -109,6 +180,19 @@ mod tests {
         assert!(address.raw_bytes.is_some());
     }
 
+    #[test]
+    fn test_compressed_public_key_round_trip() {
+        let (address, _, public_key) = Address::generate();
+        let compressed = Address::compress_pubkey(&public_key);
+        assert!(compressed[0] == 0x02 || compressed[0] == 0x03);
+
+        let derived = Address::from_compressed_public_key(&compressed).unwrap();
+        assert_eq!(derived, address);
+        assert_eq!(derived.value, address.value);
+
+        assert!(Address::from_compressed_public_key(&[0x05; 33]).is_none());
+    }
+
     #[test]
     fn test_from_public_key_without_prefix() {
         // Test with 64-byte public key (without 0x04 prefix)
@@ -209,6 +293,29 @@ mod tests {
         assert_eq!(addr1, addr4);
     }
 
+    #[test]
+    fn test_json_round_trip_restores_raw_bytes() {
+        let (address, _, _) = Address::generate();
+        let json = serde_json::to_string(&address).unwrap();
+        let restored: Address = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.raw_bytes, address.raw_bytes);
+        assert_eq!(restored, address);
+
+        // Byte comparison ignores the case of the hex string
+        let checksummed = format!("{{\"value\":\"0x{}\"}}", address.value[2..].to_uppercase());
+        let restored: Address = serde_json::from_str(&checksummed).unwrap();
+        assert_eq!(restored, address);
+    }
+
+    #[test]
+    fn test_zero_address() {
+        let zero = Address::zero();
+        assert!(zero.is_valid());
+        assert!(zero.is_zero());
+        assert!(!Address::generate().0.is_zero());
+    }
+
     #[test]
     fn test_keccak256_deterministic() {
         let data = b"test data";
@@ -1,10 +1,29 @@
+use crate::address::Address;
+use crate::clock::Clock;
 use crate::hash::Hash;
+use crate::merkle::{self, ProofStep};
+use crate::network::{GenesisConfig, NetworkConfig};
+use crate::store::StoreError;
 use crate::transaction::Transaction;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Newest block version this node builds and understands, raised whenever validation rules change
+pub(crate) const BLOCK_VERSION: u32 = 1;
+
+/// Lowest difficulty a block may be mined or accepted at, difficulty 0 would accept any hash
+pub(crate) const MIN_DIFFICULTY: u32 = 1;
+
+/// Blocks with fewer transactions are verified on the calling thread, spawning would cost more than it saves
+const PARALLEL_VERIFICATION_THRESHOLD: usize = 32;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct Block {
+    /// Set of validation rules the block follows, see `BLOCK_VERSION`
+    pub version: u32,
     pub index: u64,
     pub timestamp: DateTime<Utc>,
     pub transactions: Vec<Transaction>,
@@ -14,37 +33,104 @@ pub(crate) struct Block {
     pub data: Vec<u8>,
     pub nonce: u64,
     pub difficulty: u32,
+    /// Fee burned for every transaction in this block, only the excess goes to the miner
+    pub base_fee: u64,
+    /// Transactions serialized for the block hash, with the ids and signatures they were serialized from
+    #[serde(skip)]
+    serialized_transactions: OnceLock<(Vec<TransactionKey>, Vec<u8>)>,
+}
+
+/// Id and signature of a transaction, together they cover every serialized field
+type TransactionKey = (String, Option<String>);
+
+#[cfg(test)]
+thread_local! {
+    /// Times this thread serialized a block's transactions for its hash
+    static TRANSACTION_SERIALIZATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Identity of a block, announced to peers before they fetch the full body
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BlockHeader {
+    pub index: u64,
+    pub hash: Hash,
+    /// Root of the transaction tree, what inclusion proofs are checked against
+    pub merkle_root: Hash,
+}
+
+/// What a block list shows of a block, without its transactions, see `Block::summary`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct BlockSummary {
+    pub index: u64,
+    pub hash: Hash,
+    pub previous_hash: Option<Hash>,
+    pub timestamp: DateTime<Utc>,
+    pub transaction_count: usize,
+    /// Everything paid to recipients, coinbase included
+    pub total_amount: u64,
+    /// Fees paid by the block's transactions
+    pub total_fees: u64,
+    /// Part of the fees removed from supply by the base fee
+    pub burned_fees: u64,
+    pub difficulty: u32,
+    /// Length of the block encoded as JSON
+    pub size: usize,
 }
 
 impl Block {
+    #[cfg(test)]
     pub(crate) fn genesis() -> Self {
+        Self::genesis_from(&GenesisConfig::default())
+    }
+
+    /// Genesis block built from `config`, its network id, version and `extra_data` end up in the block data
+    pub(crate) fn genesis_from(config: &GenesisConfig) -> Self {
         let mut genesis_block = Self {
+            version: BLOCK_VERSION,
             index: 0,
-            timestamp: Utc::now(),
+            timestamp: config.timestamp,
             previous_block_hash: None,
             current_block_hash: None,
             merkle_root: Hash::genesis(),
-            data: Vec::new(),
+            data: config.block_data(),
             nonce: 0,
             transactions: Vec::new(),
-            difficulty: 4,
+            difficulty: config.difficulty,
+            base_fee: 0,
+            serialized_transactions: OnceLock::new(),
         };
 
         genesis_block.current_block_hash = Some(genesis_block.compute_hash());
         genesis_block
     }
 
-    pub(crate) fn new(index: u64, transactions: Vec<Transaction>, previous_block_hash: Hash) -> Self {
+    /// Block to be mined at `difficulty`, usually `Chain::next_difficulty` of the chain it extends
+    #[cfg(test)]
+    pub(crate) fn new(index: u64, transactions: Vec<Transaction>, previous_block_hash: Hash, difficulty: u32) -> Self {
+        Self::new_with_clock(index, transactions, previous_block_hash, difficulty, &crate::clock::SystemClock)
+    }
+
+    /// Build a block timestamped by `clock`
+    pub(crate) fn new_with_clock(
+        index: u64,
+        transactions: Vec<Transaction>,
+        previous_block_hash: Hash,
+        difficulty: u32,
+        clock: &dyn Clock,
+    ) -> Self {
         let mut new_block = Self {
+            version: BLOCK_VERSION,
             index,
-            timestamp: Utc::now(),
+            timestamp: clock.now(),
             transactions: transactions.clone(),
             previous_block_hash: Some(previous_block_hash),
             current_block_hash: None, // Not computed yet
             merkle_root: Self::calculate_merkle_root(&transactions),
             data: Vec::new(),
             nonce: 0,
-            difficulty: 4,
+            difficulty,
+            base_fee: 0,
+            serialized_transactions: OnceLock::new(),
         };
 
         // Calculate the actual hash for the new block
@@ -52,28 +138,107 @@ impl Block {
         new_block
     }
 
-    fn calculate_merkle_root(transactions: &[Transaction]) -> Hash {
-        if transactions.is_empty() {
-            return Hash::new(&[]);
+    /// Set the base fee, recomputing the block hash
+    pub fn with_base_fee(mut self, base_fee: u64) -> Self {
+        self.base_fee = base_fee;
+        self.current_block_hash = Some(self.compute_hash());
+        self
+    }
+
+    /// The block as JSON, in the same form it is stored and sent to peers
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Index and hash announcing the block, the hash is computed when the block was never mined
+    pub(crate) fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            hash: self.current_block_hash.clone().unwrap_or_else(|| self.compute_hash()),
+            merkle_root: self.merkle_root.clone(),
         }
+    }
+
+    /// Length of the block encoded as JSON, the form it is stored and sent in
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map_or(0, |bytes| bytes.len())
+    }
 
-        // Simple merkle root calculation (concatenate all transaction IDs)
-        let mut merkle_input = Vec::new();
-        for tx in transactions {
-            merkle_input.extend_from_slice(tx.id.as_bytes());
+    /// The block without its transactions, for listing blocks. Totals saturate rather than overflow.
+    pub(crate) fn summary(&self) -> BlockSummary {
+        BlockSummary {
+            index: self.index,
+            hash: self.header().hash,
+            previous_hash: self.previous_block_hash.clone(),
+            timestamp: self.timestamp,
+            transaction_count: self.transactions.len(),
+            total_amount: self
+                .transactions
+                .iter()
+                .fold(0, |total, tx| total.saturating_add(tx.total_amount().unwrap_or(u64::MAX))),
+            total_fees: self.transactions.iter().fold(0, |total: u64, tx| total.saturating_add(tx.fee)),
+            burned_fees: self.burned_fees().unwrap_or(u64::MAX),
+            difficulty: self.difficulty,
+            size: self.size(),
         }
+    }
+
+    /// Summed weight of the transactions, see `Transaction::weight`
+    pub fn weight(&self) -> u64 {
+        self.transactions.iter().map(Transaction::weight).sum()
+    }
 
-        Hash::new(&merkle_input)
+    /// Total amount removed from supply by this block's base fee
+    pub fn burned_fees(&self) -> Option<u64> {
+        let paying = self.transactions.iter().filter(|tx| !tx.is_coinbase()).count() as u64;
+        self.base_fee.checked_mul(paying)
+    }
+
+    /// Transactions in this block sent from or to the given address
+    pub fn transactions_for_address(&self, addr: &Address) -> Vec<&Transaction> {
+        self.transactions
+            .iter()
+            .filter(|tx| &tx.from == addr || tx.pays_to(addr))
+            .collect()
+    }
+
+    /// Whether the stored merkle root commits to the transactions in their current order.
+    /// Genesis blocks carry the all-zero root instead.
+    pub fn has_valid_merkle_root(&self) -> bool {
+        let expected = if self.index == 0 && self.transactions.is_empty() {
+            Hash::genesis()
+        } else {
+            Self::calculate_merkle_root(&self.transactions)
+        };
+        self.merkle_root == expected
+    }
+
+    fn calculate_merkle_root(transactions: &[Transaction]) -> Hash {
+        merkle::root(&Self::merkle_leaves(transactions))
+    }
+
+    fn merkle_leaves(transactions: &[Transaction]) -> Vec<Hash> {
+        transactions.iter().map(|tx| merkle::leaf(tx.id.as_bytes())).collect()
+    }
+
+    /// Proof that transaction `tx_id` is in this block, checked against the merkle root with
+    /// `Chain::verify_block_inclusion`. `None` if the block does not hold it.
+    pub(crate) fn merkle_proof_for(&self, tx_id: &str) -> Option<Vec<ProofStep>> {
+        let position = self.transactions.iter().position(|tx| tx.id == tx_id)?;
+        merkle::proof(&Self::merkle_leaves(&self.transactions), position)
     }
 
     fn compute_hash(&self) -> Hash {
         let mut hash_input = Vec::new();
 
         // Add block metadata
+        hash_input.extend_from_slice(&self.version.to_le_bytes());
         hash_input.extend_from_slice(&self.index.to_le_bytes());
         hash_input.extend_from_slice(&self.timestamp.timestamp().to_le_bytes());
+        hash_input.extend_from_slice(&self.timestamp.timestamp_subsec_nanos().to_le_bytes());
         hash_input.extend_from_slice(&self.nonce.to_le_bytes());
         hash_input.extend_from_slice(&self.difficulty.to_le_bytes());
+        hash_input.extend_from_slice(&self.base_fee.to_le_bytes());
 
         // Add previous block hash if it exists
         if let Some(prev_hash) = &self.previous_block_hash {
@@ -84,11 +249,7 @@ impl Block {
         hash_input.extend_from_slice(self.merkle_root.value.as_bytes());
 
         // Add transaction data
-        for transaction in &self.transactions {
-            if let Ok(tx_bytes) = serde_json::to_vec(transaction) {
-                hash_input.extend_from_slice(&tx_bytes);
-            }
-        }
+        hash_input.extend_from_slice(&self.transaction_bytes());
 
         // Add additional data
         hash_input.extend_from_slice(&self.data);
@@ -96,33 +257,304 @@ impl Block {
         Hash::new(&hash_input)
     }
 
-    pub fn mine_block(&mut self, target_difficulty: u32) {
-        let target = "0".repeat(target_difficulty as usize);
+    /// Transaction data entering the block hash. Serializing is the expensive part of hashing, so it is done
+    /// once and reused for every nonce and every validation while the transaction ids and signatures stay the
+    /// same. Ids commit to every other field, `validate` rejects any transaction whose id does not match it.
+    fn transaction_bytes(&self) -> Cow<'_, [u8]> {
+        let keys_match = |keys: &[TransactionKey]| {
+            keys.len() == self.transactions.len()
+                && keys.iter().zip(&self.transactions).all(|((id, signature), tx)| {
+                    *id == tx.id && *signature == tx.signature
+                })
+        };
+        match self.serialized_transactions.get() {
+            Some((keys, bytes)) if keys_match(keys) => Cow::Borrowed(bytes),
+            // The transactions were replaced after caching, do not trust the cache
+            Some(_) => Cow::Owned(Self::serialize_transactions(&self.transactions)),
+            None => {
+                let (_, bytes) = self.serialized_transactions.get_or_init(|| {
+                    let keys = self.transactions.iter().map(|tx| (tx.id.clone(), tx.signature.clone())).collect();
+                    (keys, Self::serialize_transactions(&self.transactions))
+                });
+                Cow::Borrowed(bytes)
+            }
+        }
+    }
+
+    fn serialize_transactions(transactions: &[Transaction]) -> Vec<u8> {
+        #[cfg(test)]
+        TRANSACTION_SERIALIZATIONS.with(|count| count.set(count.get() + 1));
+
+        let mut bytes = Vec::new();
+        for transaction in transactions {
+            if let Ok(tx_bytes) = serde_json::to_vec(transaction) {
+                bytes.extend_from_slice(&tx_bytes);
+            }
+        }
+        bytes
+    }
+
+    /// Whether the stored hash matches the block contents
+    pub fn has_valid_hash(&self) -> bool {
+        self.current_block_hash.as_ref() == Some(&self.compute_hash())
+    }
+
+    /// Whether the stored hash satisfies the block's own difficulty
+    pub fn has_valid_proof_of_work(&self) -> bool {
+        self.current_block_hash
+            .as_ref()
+            .is_some_and(|hash| hash.meets_difficulty(self.difficulty))
+    }
+
+    /// First transaction, in block order, that is not signed by its sender
+    pub fn first_unsigned_transaction(&self, chain_id: u64) -> Option<&Transaction> {
+        self.transactions.iter().find(|tx| !tx.is_signed_by_sender(chain_id))
+    }
+
+    /// Same result as `first_unsigned_transaction`, with the signatures checked on several threads
+    pub fn first_unsigned_transaction_parallel(&self, chain_id: u64) -> Option<&Transaction> {
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        self.first_unsigned_transaction_on(chain_id, threads)
+    }
+
+    fn first_unsigned_transaction_on(&self, chain_id: u64, threads: usize) -> Option<&Transaction> {
+        if threads < 2 || self.transactions.len() < PARALLEL_VERIFICATION_THRESHOLD {
+            return self.first_unsigned_transaction(chain_id);
+        }
+
+        let chunk_size = self.transactions.len().div_ceil(threads);
+        let first_failure = std::thread::scope(|scope| {
+            let workers: Vec<_> = self
+                .transactions
+                .chunks(chunk_size)
+                .enumerate()
+                .map(|(chunk_index, chunk)| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .position(|tx| !tx.is_signed_by_sender(chain_id))
+                            .map(|position| chunk_index * chunk_size + position)
+                    })
+                })
+                .collect();
 
-        loop {
+            // Chunks are in block order, so the first chunk reporting a failure holds the earliest one
+            workers.into_iter().filter_map(|worker| worker.join().unwrap()).next()
+        });
+
+        first_failure.map(|index| &self.transactions[index])
+    }
+
+    /// Check the block is internally consistent: its hash matches its contents, every transaction is signed
+    /// by its sender, pays for its gas within the network limit and covers the base fee and, if it carries
+    /// a coinbase, that coinbase is first, unique and pays the subsidy plus the fees left after burning the base fee
+    pub fn validate(&self, network: &NetworkConfig) -> Result<(), StoreError> {
+        // Every known version shares the rules below, later versions add theirs by matching on `self.version`
+        if !(1..=BLOCK_VERSION).contains(&self.version) {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} has version {} but this node only understands versions up to {}",
+                self.index, self.version, BLOCK_VERSION
+            )));
+        }
+
+        if self.difficulty < MIN_DIFFICULTY {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} difficulty {} is below the minimum {}", self.index, self.difficulty, MIN_DIFFICULTY
+            )));
+        }
+
+        if !self.has_valid_hash() {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} hash does not match its contents", self.index
+            )));
+        }
+
+        if !self.has_valid_merkle_root() {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} merkle root does not match its transactions", self.index
+            )));
+        }
+
+        // Cached transaction bytes are keyed by id, so an id must commit to its transaction's contents
+        if let Some(tx) = self.transactions.iter().find(|tx| !tx.has_valid_id()) {
+            return Err(StoreError::ValidationError(format!(
+                "Transaction {} id does not match its contents", tx.id
+            )));
+        }
+
+        // Cheap checks before the signatures
+        for tx in self.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            tx.validate_structure().map_err(|rejection| {
+                StoreError::ValidationError(format!("Transaction {} is invalid: {}", tx.id, rejection))
+            })?;
+        }
+
+        let unsigned = if network.parallel_signature_verification {
+            self.first_unsigned_transaction_parallel(network.chain_id)
+        } else {
+            self.first_unsigned_transaction(network.chain_id)
+        };
+        if let Some(tx) = unsigned {
+            return Err(StoreError::ValidationError(format!(
+                "Transaction {} is not signed by its sender", tx.id
+            )));
+        }
+
+        if let Some(tx) = self.transactions.iter().find(|tx| !tx.has_valid_gas()) {
+            return Err(StoreError::ValidationError(format!(
+                "Transaction {} fee {} does not match its gas {} at price {}", tx.id, tx.fee, tx.gas_limit, tx.gas_price
+            )));
+        }
+
+        for tx in self.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            network.check_gas_limit(tx.gas_limit).map_err(StoreError::ValidationError)?;
+        }
+
+        let weight = self.weight();
+        if weight > network.max_block_weight {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} weight {} is above the maximum of {}", self.index, weight, network.max_block_weight
+            )));
+        }
+
+        if let Some(tx) = self.transactions.iter().find(|tx| !tx.is_coinbase() && tx.fee < self.base_fee) {
+            return Err(StoreError::ValidationError(format!(
+                "Transaction {} fee {} is below the block base fee {}", tx.id, tx.fee, self.base_fee
+            )));
+        }
+
+        self.check_transaction_times(network)?;
+
+        let coinbase_count = self.transactions.iter().filter(|tx| tx.is_coinbase()).count();
+        if coinbase_count > 1 {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} contains {} coinbase transactions", self.index, coinbase_count
+            )));
+        }
+
+        if coinbase_count == 1 {
+            if !self.transactions[0].is_coinbase() {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} coinbase must be the first transaction", self.index
+                )));
+            }
+
+            // Maturity and supply accounting only follow the coinbase's main output
+            if !self.transactions[0].additional_outputs.is_empty() {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} coinbase pays more than one recipient", self.index
+                )));
+            }
+
+            let tips = self.transactions[1..]
+                .iter()
+                .try_fold(0u64, |total, tx| total.checked_add(tx.fee - self.base_fee))
+                .ok_or_else(|| StoreError::ValidationError(format!(
+                    "Block {} fees overflow", self.index
+                )))?;
+            let expected = network.block_subsidy.checked_add(tips).ok_or_else(|| {
+                StoreError::ValidationError(format!("Block {} reward overflows", self.index))
+            })?;
+
+            let paid = self.transactions[0].total_amount();
+            if paid != Some(expected) {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} coinbase pays {} but expected {}",
+                    self.index,
+                    paid.map_or_else(|| "more than the supply".to_string(), |paid| paid.to_string()),
+                    expected
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reject transactions timestamped after this block, beyond the clock tolerance, or older than
+    /// `max_transaction_age_secs` before it
+    fn check_transaction_times(&self, network: &NetworkConfig) -> Result<(), StoreError> {
+        let block_time = self.timestamp.timestamp();
+        let latest = block_time.saturating_add_unsigned(network.transaction_time_tolerance_secs);
+        let earliest = block_time.saturating_sub_unsigned(network.max_transaction_age_secs);
+
+        for tx in &self.transactions {
+            let tx_time = i64::try_from(tx.timestamp).unwrap_or(i64::MAX);
+            if tx_time > latest {
+                return Err(StoreError::ValidationError(format!(
+                    "Transaction {} is timestamped {}s after block {}", tx.id, tx_time - block_time, self.index
+                )));
+            }
+            if tx_time < earliest {
+                return Err(StoreError::ValidationError(format!(
+                    "Transaction {} is timestamped {}s before block {}, older than the {}s allowed",
+                    tx.id, block_time - tx_time, self.index, network.max_transaction_age_secs
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Mine in one go, the block builder mines with `mine_block_throttled` to keep an eye on the clock
+    #[cfg(test)]
+    pub fn mine_block(&mut self, target_difficulty: u32) -> Result<(), StoreError> {
+        while !self.mine_block_throttled(target_difficulty, u64::MAX)? {}
+        Ok(())
+    }
+
+    /// Try at most `max_hashes_per_call` nonces, returning whether one met `target_difficulty`.
+    /// The nonce is left at the next untried value, so calling again resumes where this call stopped
+    /// and the caller can yield the CPU in between.
+    pub fn mine_block_throttled(&mut self, target_difficulty: u32, max_hashes_per_call: u64) -> Result<bool, StoreError> {
+        if target_difficulty < MIN_DIFFICULTY {
+            return Err(StoreError::ValidationError(format!(
+                "Cannot mine block {} at difficulty {}, the minimum is {}", self.index, target_difficulty, MIN_DIFFICULTY
+            )));
+        }
+
+        for _ in 0..max_hashes_per_call {
             let hash = self.compute_hash();
-            if hash.value.starts_with(&target) {
+            if hash.meets_difficulty(target_difficulty) {
                 self.current_block_hash = Some(hash);
-                break;
+                return Ok(true);
             }
             self.nonce += 1;
         }
+        Ok(false)
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.current_block_hash {
+            Some(hash) => write!(
+                f,
+                "Block #{} {} ({} txs, difficulty {}, {} leading zero bits)",
+                self.index,
+                hash.value,
+                self.transactions.len(),
+                self.difficulty,
+                hash.leading_zero_bits()
+            ),
+            None => write!(f, "Block #{} <unhashed> ({} txs)", self.index, self.transactions.len()),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::address::Address;
+    use crate::clock::MockClock;
+    use crate::network::{DEFAULT_CHAIN_ID, DEFAULT_DIFFICULTY};
+    use crate::transaction::{OUTPUT_WEIGHT, TRANSFER_GAS};
 
     #[test]
     fn test_genesis_block() {
         let genesis = Block::genesis();
         assert_eq!(genesis.index, 0);
         assert!(genesis.previous_block_hash.is_none());
-        assert!(genesis.data.is_empty());
+        assert_eq!(genesis.data, GenesisConfig::default().block_data());
         assert!(genesis.transactions.is_empty());
-        assert_eq!(genesis.difficulty, 4);
+        assert_eq!(genesis.difficulty, DEFAULT_DIFFICULTY);
     }
 
     #[test]
@@ -138,7 +570,7 @@ mod tests {
             )
         ];
 
-        let block = Block::new(1, transactions.clone(), previous_hash.clone());
+        let block = Block::new(1, transactions.clone(), previous_hash.clone(), 4);
 
         assert_eq!(block.index, 1);
         assert_eq!(block.previous_block_hash.unwrap().value, previous_hash.value);
@@ -147,6 +579,29 @@ mod tests {
         assert!(block.timestamp.timestamp() > 0);
     }
 
+    #[test]
+    fn test_transaction_timestamp_checked_against_block_time() {
+        let (from, secret_key, _) = Address::generate();
+        let block_time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let network = NetworkConfig::default();
+        let block_with_tx_at = |offset_secs: i64| {
+            let clock = MockClock::new(block_time + chrono::Duration::seconds(offset_secs));
+            let mut tx = Transaction::new_with_clock(from.clone(), Address::generate().0, 10, &clock)
+                .with_gas(TRANSFER_GAS, 1);
+            tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+            let mut block = Block::new_with_clock(1, vec![tx], Hash::genesis(), 4, &MockClock::new(block_time));
+            block.mine_block(1).unwrap();
+            block.validate(&network)
+        };
+
+        assert!(block_with_tx_at(-3600).is_ok());
+        assert!(block_with_tx_at(network.transaction_time_tolerance_secs as i64).is_ok());
+        let future = block_with_tx_at(network.transaction_time_tolerance_secs as i64 + 1).unwrap_err();
+        assert!(future.to_string().contains("after block 1"));
+        let stale = block_with_tx_at(-(network.max_transaction_age_secs as i64) - 1).unwrap_err();
+        assert!(stale.to_string().contains("older than"));
+    }
+
     #[test]
     fn test_block_hash_calculation() {
         let genesis = Block::genesis();
@@ -156,6 +611,85 @@ mod tests {
         assert_eq!(genesis.current_block_hash.unwrap().value, calculated_hash.value);
     }
 
+    #[test]
+    fn test_transactions_serialized_once_per_block() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        let serializations = || TRANSACTION_SERIALIZATIONS.with(|count| count.get());
+
+        let before = serializations();
+        let mut block = Block::new(1, vec![tx], Hash::genesis(), 1);
+        block.mine_block(1).unwrap();
+        block.validate(&NetworkConfig::default()).unwrap();
+        assert!(block.has_valid_hash());
+        assert_eq!(serializations(), before + 1);
+
+        // Replacing a signature keeps the id, but still bypasses the stale cache
+        let mut resigned = block.clone();
+        let (_, other_key, _) = Address::generate();
+        resigned.transactions[0].sign(&other_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(resigned.transactions[0].id, block.transactions[0].id);
+        assert!(!resigned.has_valid_hash());
+        assert_eq!(serializations(), before + 2);
+
+        // So does replacing the transactions
+        block.transactions.clear();
+        assert!(!block.has_valid_hash());
+        assert_eq!(serializations(), before + 3);
+    }
+
+    #[test]
+    fn test_block_mined_at_its_own_difficulty() {
+        let block = Block::new(1, vec![], Hash::genesis(), 8);
+        assert_eq!(block.difficulty, 8);
+
+        let mut mined = block.clone();
+        mined.mine_block(mined.difficulty).unwrap();
+        assert!(mined.current_block_hash.as_ref().unwrap().value.starts_with("00"));
+        assert!(mined.has_valid_hash());
+        assert_eq!(mined.difficulty, 8);
+    }
+
+    #[test]
+    fn test_difficulty_zero_rejected() {
+        let mut block = Block::new(1, vec![], Hash::genesis(), 0);
+        assert!(block.mine_block(0).is_err());
+        assert!(block.mine_block_throttled(0, 1).is_err());
+        assert_eq!(block.nonce, 0);
+
+        // Any hash meets difficulty 0, validation must not take it as proof of work
+        assert!(block.has_valid_proof_of_work());
+        let error = block.validate(&NetworkConfig::default()).unwrap_err();
+        assert!(error.to_string().contains("difficulty 0"));
+    }
+
+    #[test]
+    fn test_mine_block_throttled_respects_budget() {
+        let mut block = Block::new(1, vec![], Hash::genesis(), 4);
+        let unmined_hash = block.current_block_hash.clone();
+
+        // 64 leading zero bits, a 1 in 2^64 chance per hash, will not be met within 500 hashes
+        assert!(!block.mine_block_throttled(64, 500).unwrap());
+        assert_eq!(block.nonce, 500);
+        assert_eq!(block.current_block_hash, unmined_hash);
+
+        assert!(block.mine_block_throttled(1, 10_000).unwrap());
+        assert!(block.has_valid_hash());
+        assert!(block.current_block_hash.unwrap().meets_difficulty(1));
+    }
+
+    #[test]
+    fn test_sub_second_timestamp_changes_hash() {
+        let mut first = Block::new(1, vec![], Hash::genesis(), 4);
+        first.timestamp = DateTime::from_timestamp(1_700_000_000, 1_000_000).unwrap();
+        let mut second = first.clone();
+        second.timestamp = DateTime::from_timestamp(1_700_000_000, 2_000_000).unwrap();
+
+        assert_eq!(first.timestamp.timestamp(), second.timestamp.timestamp());
+        assert_ne!(first.compute_hash(), second.compute_hash());
+    }
+
     #[test]
     fn test_merkle_root_calculation() {
         let address1 = Address::generate().0;
@@ -182,4 +716,313 @@ mod tests {
         let empty_merkle = Block::calculate_merkle_root(&[]);
         assert_eq!(empty_merkle.value, Hash::new(&[]).value);
     }
+
+    #[test]
+    fn test_block_version_is_checked() {
+        let network = NetworkConfig::default();
+        let mut block = Block::new(1, vec![signed_transaction(10, 1)], Hash::genesis(), 4);
+        assert_eq!(block.version, BLOCK_VERSION);
+        assert!(block.validate(&network).is_ok());
+
+        let original_hash = block.current_block_hash.clone();
+        block.version = BLOCK_VERSION + 1;
+        block.current_block_hash = Some(block.compute_hash());
+        assert_ne!(block.current_block_hash, original_hash);
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains(&format!("version {}", BLOCK_VERSION + 1)));
+    }
+
+    #[test]
+    fn test_reordered_transactions_invalidate_merkle_root() {
+        let network = NetworkConfig::default();
+        let mut block = Block::new(1, vec![signed_transaction(10, 1), signed_transaction(20, 1)], Hash::genesis(), 4);
+        assert!(block.has_valid_merkle_root());
+        assert!(block.validate(&network).is_ok());
+
+        // Rehash so only the merkle root gives the reordering away
+        block.transactions.swap(0, 1);
+        block.current_block_hash = Some(block.compute_hash());
+        assert!(!block.has_valid_merkle_root());
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains("merkle root"));
+    }
+
+    #[test]
+    fn test_transactions_for_address() {
+        let alice = Address::generate().0;
+        let bob = Address::generate().0;
+        let carol = Address::generate().0;
+
+        let transactions = vec![
+            Transaction::new(alice.clone(), bob.clone(), 10),
+            Transaction::new(bob.clone(), carol.clone(), 20),
+            Transaction::new(carol.clone(), alice.clone(), 30),
+        ];
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+
+        let alice_txs = block.transactions_for_address(&alice);
+        assert_eq!(alice_txs.len(), 2);
+        assert_eq!(alice_txs[0].amount, 10);
+        assert_eq!(alice_txs[1].amount, 30);
+
+        let stranger = Address::generate().0;
+        assert!(block.transactions_for_address(&stranger).is_empty());
+    }
+
+    fn signed_transaction(amount: u64, fee: u64) -> Transaction {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, amount).with_gas(TRANSFER_GAS, fee);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_block_over_weight_limit_rejected() {
+        let transactions: Vec<Transaction> = (0..3).map(|_| signed_transaction(10, 1)).collect();
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        assert!(block.validate(&NetworkConfig::default()).is_ok());
+
+        // A few hundred bytes, but more signatures than the limit allows
+        let network = NetworkConfig { max_block_weight: block.weight() - 1, ..NetworkConfig::default() };
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains("weight"));
+    }
+
+    #[test]
+    fn test_many_output_transaction_over_weight_limit_rejected() {
+        let (from, secret_key, _) = Address::generate();
+        let mut fan_out = Transaction::new(from, Address::generate().0, 1).with_gas(TRANSFER_GAS, 1);
+        for _ in 0..50 {
+            fan_out = fan_out.with_output(Address::generate().0, 1);
+        }
+        fan_out.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(fan_out.weight(), signed_transaction(10, 1).weight() + 50 * OUTPUT_WEIGHT);
+
+        // Room for several single-recipient transfers, but not for one paying fifty more recipients
+        let network = NetworkConfig { max_block_weight: 2 * fan_out.weight() / 3, ..NetworkConfig::default() };
+        let transfers = Block::new(1, vec![signed_transaction(10, 1), signed_transaction(10, 1)], Hash::genesis(), 4);
+        assert!(transfers.validate(&network).is_ok());
+        let error = Block::new(1, vec![fan_out], Hash::genesis(), 4).validate(&network).unwrap_err();
+        assert!(error.to_string().contains("weight"));
+    }
+
+    #[test]
+    fn test_summary_matches_block() {
+        let transfers = vec![signed_transaction(10, 2), signed_transaction(30, 5)];
+        let mut transactions = vec![Transaction::coinbase(Address::generate().0, 57)];
+        transactions.extend(transfers);
+        let mut block = Block::new(1, transactions, Hash::genesis(), 1).with_base_fee(2);
+        block.mine_block(1).unwrap();
+
+        let summary = block.summary();
+        assert_eq!(summary.index, 1);
+        assert_eq!(Some(&summary.hash), block.current_block_hash.as_ref());
+        assert_eq!(summary.previous_hash, Some(Hash::genesis()));
+        assert_eq!(summary.transaction_count, 3);
+        assert_eq!(summary.total_fees, 7);
+        assert_eq!(summary.burned_fees, 4);
+        assert_eq!(summary.total_amount, 97);
+        assert_eq!(summary.size, serde_json::to_vec(&block).unwrap().len());
+        assert!(serde_json::to_vec(&summary).unwrap().len() < summary.size);
+    }
+
+    #[test]
+    fn test_validate_coinbase_block() {
+        let network = NetworkConfig::default();
+        let miner = Address::generate().0;
+        let transactions = vec![
+            Transaction::coinbase(miner, network.block_subsidy + 3),
+            signed_transaction(10, 1),
+            signed_transaction(20, 2),
+        ];
+
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        assert!(block.validate(&network).is_ok());
+    }
+
+    #[test]
+    fn test_parallel_verification_reports_first_failure() {
+        let mut transactions: Vec<Transaction> = (0..100).map(|_| signed_transaction(10, 0)).collect();
+        let (_, other_key, _) = Address::generate();
+        for index in [37, 80] {
+            transactions[index].sign(&other_key, DEFAULT_CHAIN_ID).unwrap();
+        }
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+
+        let sequential = block.first_unsigned_transaction(DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(sequential.id, block.transactions[37].id);
+        for threads in [2, 3, 4, 8] {
+            let parallel = block.first_unsigned_transaction_on(DEFAULT_CHAIN_ID, threads).unwrap();
+            assert_eq!(parallel.id, sequential.id);
+        }
+
+        let network = NetworkConfig::default();
+        assert!(network.parallel_signature_verification);
+        assert!(matches!(block.validate(&network), Err(StoreError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_parallel_verification_agrees_on_large_block() {
+        let mut transactions: Vec<Transaction> = (0..500).map(|_| signed_transaction(10, 0)).collect();
+        let block = Block::new(1, transactions.clone(), Hash::genesis(), 4);
+        assert!(block.first_unsigned_transaction(DEFAULT_CHAIN_ID).is_none());
+        assert!(block.first_unsigned_transaction_on(DEFAULT_CHAIN_ID, 4).is_none());
+
+        transactions[377].signature = transactions[3].signature.clone();
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        let expected = Some(&block.transactions[377].id);
+        for threads in [1, 4, 7] {
+            assert_eq!(block.first_unsigned_transaction_on(DEFAULT_CHAIN_ID, threads).map(|tx| &tx.id), expected);
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_two_coinbases() {
+        let network = NetworkConfig::default();
+        let transactions = vec![
+            Transaction::coinbase(Address::generate().0, network.block_subsidy),
+            Transaction::coinbase(Address::generate().0, network.block_subsidy),
+        ];
+
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        assert!(matches!(block.validate(&network), Err(StoreError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_validate_rejects_misplaced_coinbase() {
+        let network = NetworkConfig::default();
+        let transactions = vec![
+            signed_transaction(10, 0),
+            Transaction::coinbase(Address::generate().0, network.block_subsidy),
+        ];
+
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        assert!(block.validate(&network).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_wrong_coinbase_amount() {
+        let network = NetworkConfig::default();
+        let transactions = vec![
+            Transaction::coinbase(Address::generate().0, network.block_subsidy + 100),
+            signed_transaction(10, 1),
+        ];
+
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        assert!(block.validate(&network).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_coinbase_claiming_too_little() {
+        let network = NetworkConfig::default();
+        let transactions = vec![
+            Transaction::coinbase(Address::generate().0, network.block_subsidy + 2),
+            signed_transaction(10, 3),
+        ];
+
+        let block = Block::new(1, transactions, Hash::genesis(), 4);
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains("coinbase pays 52 but expected 53"));
+    }
+
+    #[test]
+    fn test_validate_rejects_coinbase_with_several_outputs() {
+        let network = NetworkConfig::default();
+        // Paying the expected reward in total, split across two recipients
+        let coinbase = Transaction::coinbase(Address::generate().0, network.block_subsidy + 2)
+            .with_output(Address::generate().0, 1);
+        let block = Block::new(1, vec![coinbase, signed_transaction(10, 3)], Hash::genesis(), 4);
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains("more than one recipient"));
+    }
+
+    #[test]
+    fn test_mined_block_display_shows_work() {
+        let mut block = Block::new(1, vec![], Hash::genesis(), 8);
+        block.mine_block(8).unwrap();
+
+        let hash = block.current_block_hash.clone().unwrap();
+        assert!(hash.leading_zero_bits() >= 8);
+        assert!(block.has_valid_proof_of_work());
+
+        let display = block.to_string();
+        assert!(display.starts_with("Block #1 "));
+        assert!(display.contains(&hash.value));
+        assert!(display.contains(&format!("{} leading zero bits", hash.leading_zero_bits())));
+    }
+
+    #[test]
+    fn test_burned_base_fee_excluded_from_coinbase() {
+        let network = NetworkConfig::default();
+        let miner = Address::generate().0;
+        let transactions = vec![
+            Transaction::coinbase(miner.clone(), network.block_subsidy + 3 + 1),
+            signed_transaction(10, 8),
+            signed_transaction(20, 6),
+        ];
+
+        let block = Block::new(1, transactions, Hash::genesis(), 4).with_base_fee(5);
+        assert_eq!(block.burned_fees(), Some(10));
+        assert!(block.validate(&network).is_ok());
+
+        let transactions = vec![
+            Transaction::coinbase(miner, network.block_subsidy + 14),
+            signed_transaction(10, 8),
+            signed_transaction(20, 6),
+        ];
+        let block = Block::new(1, transactions, Hash::genesis(), 4).with_base_fee(5);
+        assert!(block.validate(&network).is_err());
+    }
+
+    #[test]
+    fn test_transaction_below_base_fee_rejected() {
+        let network = NetworkConfig::default();
+        let block = Block::new(1, vec![signed_transaction(10, 4)], Hash::genesis(), 4).with_base_fee(5);
+        assert!(matches!(block.validate(&network), Err(StoreError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_validate_rejects_forged_signature() {
+        let network = NetworkConfig::default();
+        let (victim, _, _) = Address::generate();
+        let (_, forger_key, _) = Address::generate();
+        let mut forged = Transaction::new(victim, Address::generate().0, 10);
+        forged.sign(&forger_key, network.chain_id).unwrap();
+
+        let block = Block::new(1, vec![forged], Hash::genesis(), 4);
+        assert!(matches!(block.validate(&network), Err(StoreError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_validate_rejects_transaction_with_stale_id() {
+        let network = NetworkConfig::default();
+        let mut tampered = signed_transaction(10, 3);
+        tampered.amount = 1_000;
+
+        let block = Block::new(1, vec![tampered], Hash::genesis(), 4);
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains("id does not match its contents"));
+    }
+
+    #[test]
+    fn test_validate_rejects_malformed_transaction() {
+        let network = NetworkConfig::default();
+        let (from, secret_key, _) = Address::generate();
+        let mut to_self = Transaction::new(from.clone(), from, 10).with_gas(TRANSFER_GAS, 3);
+        to_self.sign(&secret_key, network.chain_id).unwrap();
+
+        let block = Block::new(1, vec![to_self], Hash::genesis(), 4);
+        let error = block.validate(&network).unwrap_err();
+        assert!(error.to_string().contains("pays its own sender"));
+    }
+
+    #[test]
+    fn test_new_block_uses_clock() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = crate::clock::MockClock::new(start);
+
+        let block = Block::new_with_clock(1, vec![], Hash::genesis(), 4, &clock);
+        assert_eq!(block.timestamp, start);
+        assert!(block.has_valid_hash());
+    }
 }
@@ -1,76 +1,652 @@
+use crate::address::Address;
 use crate::block::Block;
 use crate::chain::Chain;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{BlockSchedule, PoolLimits};
 use crate::hash::Hash;
+use crate::state::AccountState;
 use crate::store::StoreError;
 use crate::transaction::Transaction;
 use crate::transaction_pool::TransactionPool;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Longest wait between two blocks, however few transactions are pending
+pub(crate) const DEFAULT_BLOCK_TIME_LIMIT_SECS: u64 = 600;
+/// How far behind the current time a block being mined may fall before its timestamp is refreshed
+pub(crate) const DEFAULT_MAX_TIMESTAMP_DRIFT_SECS: u64 = 30;
+/// Pending transactions that make a block worth building before the block time limit
+pub(crate) const DEFAULT_MIN_TRANSACTIONS: usize = 100;
+/// Pending bytes that make a block worth building before the block time limit, a quarter of the default block
+pub(crate) const DEFAULT_MIN_PENDING_BYTES: usize = 256 * 1024;
+/// Nonces tried between two looks at the clock while mining
+const HASHES_PER_CLOCK_CHECK: u64 = 10_000;
 
 #[derive(Clone)]
 pub struct BlockBuilder {
-    transaction_pool: TransactionPool,
+    /// Shared with the node so RPC and peers see the transactions waiting to be mined
+    transaction_pool: Arc<Mutex<TransactionPool>>,
     current_block: Option<Block>,
     blockchain: Chain,
     block_time_limit: u64,
     min_transactions: usize,
+    min_pending_bytes: usize,
     last_block_time: u64,
+    miner_address: Option<Address>,
+    clock: Arc<dyn Clock>,
+    max_timestamp_drift: u64,
+    /// Account state at the tip of the shared chain, kept by the node, the pool is revalidated against it
+    state: Option<Arc<RwLock<AccountState>>>,
 }
 
 impl BlockBuilder {
 
     pub fn new(chain: Chain) -> Self {
         Self {
-            transaction_pool: TransactionPool::new(1000, 1024*1024), // 1000 txs, 1MB max
+            transaction_pool: Arc::new(Mutex::new(Self::pool_for(&chain, &PoolLimits::default()))),
             current_block: None,
             blockchain: chain,
-            block_time_limit: 600, // 10 minutes
-            min_transactions: 1,
+            block_time_limit: DEFAULT_BLOCK_TIME_LIMIT_SECS,
+            min_transactions: DEFAULT_MIN_TRANSACTIONS,
+            min_pending_bytes: DEFAULT_MIN_PENDING_BYTES,
             last_block_time: 0,
+            miner_address: None,
+            clock: Arc::new(SystemClock),
+            max_timestamp_drift: DEFAULT_MAX_TIMESTAMP_DRIFT_SECS,
+            state: None,
         }
     }
 
+    /// Take transactions from `pool`, which other threads keep adding to
+    pub fn with_transaction_pool(mut self, pool: Arc<Mutex<TransactionPool>>) -> Self {
+        self.transaction_pool = pool;
+        self
+    }
+
+    pub(crate) fn pool_for(chain: &Chain, limits: &PoolLimits) -> TransactionPool {
+        TransactionPool::new(limits.max_transactions, limits.max_size, limits.max_per_sender)
+            .with_max_orphans(limits.max_orphans)
+            .with_max_pool_bytes(limits.max_bytes)
+            .with_high_priority_reserve(limits.high_priority_reserve)
+            .with_min_fee(chain.next_base_fee())
+            .with_network(chain.network().clone())
+            .with_events(chain.events().clone())
+    }
+
+    /// Read time from `clock` instead of the system clock
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Refresh the timestamp of a block being mined once it is more than `seconds` behind the clock
+    pub fn with_max_timestamp_drift(mut self, seconds: u64) -> Self {
+        self.max_timestamp_drift = seconds;
+        self
+    }
+
+    /// Build a block at least every `seconds`, however few transactions are pending
+    pub fn with_block_time_limit(mut self, seconds: u64) -> Self {
+        self.block_time_limit = seconds;
+        self
+    }
+
+    /// Build a block before the block time limit once `min_transactions` or `min_pending_bytes` are pending
+    pub fn with_batch_target(mut self, min_transactions: usize, min_pending_bytes: usize) -> Self {
+        self.min_transactions = min_transactions;
+        self.min_pending_bytes = min_pending_bytes;
+        self
+    }
+
+    /// Build and mine blocks as `schedule` says
+    pub(crate) fn with_schedule(self, schedule: &BlockSchedule) -> Self {
+        self.with_block_time_limit(schedule.block_time_limit)
+            .with_batch_target(schedule.min_transactions, schedule.min_pending_bytes)
+            .with_max_timestamp_drift(schedule.max_timestamp_drift)
+    }
+
+    /// Revalidate the pool against `state`, the live account state the node keeps at the tip, instead of the
+    /// state of the builder's own copy of the chain
+    pub(crate) fn with_state(mut self, state: Arc<RwLock<AccountState>>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Pay the block subsidy and transaction tips of every built block to `address`
+    pub fn with_miner_address(mut self, address: Address) -> Self {
+        self.miner_address = Some(address);
+        self
+    }
+
+    /// Build on `chain` from now on, typically after a competing block or a reorg moved the tip
+    pub fn update_chain(&mut self, chain: Chain) {
+        self.blockchain = chain;
+        self.reset();
+        self.revalidate_pool();
+    }
+
+    /// Forget the block in progress and restart the block time window
+    pub fn reset(&mut self) {
+        self.current_block = None;
+        self.last_block_time = 0;
+    }
+
+    #[cfg(test)]
     pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
-        self.transaction_pool.add_transaction(transaction)
+        self.transaction_pool.lock().unwrap().add_transaction(transaction)
     }
 
+    /// Whether a block is due: the block time limit has passed since the last one, the pool holds a full
+    /// batch by count or by size
     pub fn should_create_block(&self) -> bool {
-        let now = chrono::Utc::now().timestamp() as u64;
-        let time_elapsed = now - self.last_block_time;
+        let now = self.clock.now().timestamp() as u64;
+        let time_elapsed = now.saturating_sub(self.last_block_time);
+        if time_elapsed >= self.block_time_limit {
+            return true;
+        }
 
-        time_elapsed >= self.block_time_limit ||
-            self.transaction_pool.pending_count() >= self.min_transactions
+        let pool = self.transaction_pool.lock().unwrap();
+        pool.pending_count() >= self.min_transactions || pool.pending_bytes() >= self.min_pending_bytes
     }
 
-    pub fn create_block(&mut self) -> Option<Block> {
+    /// Build the next block from pending transactions. `Ok(None)` when there is nothing to mine yet,
+    /// an error when the chain has no usable tip, which only a broken load produces.
+    pub fn create_block(&mut self) -> Result<Option<Block>, StoreError> {
         if !self.should_create_block() {
-            return None;
+            return Ok(None);
         }
 
-        let transactions = self.transaction_pool.pull_transactions_for_block();
+        // Check the tip before pulling, so a broken chain does not swallow pending transactions
+        let Some(previous_block) = self.blockchain.blocks.last() else {
+            return Err(StoreError::ValidationError("Chain has no tip to build on".to_string()));
+        };
+        let new_index = previous_block.index + 1;
+        let Some(previous_hash) = previous_block.current_block_hash.clone() else {
+            return Err(StoreError::ValidationError(format!("Tip block {} has no hash", previous_block.index)));
+        };
+        let base_fee = self.blockchain.next_base_fee();
+
+        // The pool stays locked until the block is built, so a failed build puts back exactly what it pulled
+        let mut pool = self.transaction_pool.lock().unwrap();
+        let checkpoint = pool.checkpoint();
+        let mut transactions = pool.pull_transactions_for_block();
         if transactions.is_empty() {
-            return None;
+            return Ok(None);
         }
 
-        let previous_block = self.blockchain.blocks.last()?;
-        let new_index = previous_block.index + 1;
-        let previous_hash = previous_block.current_block_hash.clone()?;
+        if let Some(miner_address) = &self.miner_address {
+            let reward = transactions
+                .iter()
+                .try_fold(0u64, |total, tx| total.checked_add(tx.fee.saturating_sub(base_fee)))
+                .and_then(|tips| self.blockchain.network().block_subsidy.checked_add(tips));
+            let Some(reward) = reward else {
+                pool.restore(checkpoint);
+                return Err(StoreError::ValidationError(format!("Block {} reward overflows", new_index)));
+            };
+            transactions.insert(0, Transaction::coinbase(miner_address.clone(), reward));
+        }
+        drop(pool);
 
-        let block = Block::new(new_index, transactions, previous_hash);
-        self.last_block_time = chrono::Utc::now().timestamp() as u64;
+        let difficulty = self.blockchain.next_difficulty();
+        let block = Block::new_with_clock(new_index, transactions, previous_hash, difficulty, self.clock.as_ref())
+            .with_base_fee(base_fee);
+        self.last_block_time = self.clock.now().timestamp() as u64;
 
-        Some(block)
+        Ok(Some(block))
     }
 
-    pub fn mine_and_add_block(&mut self) -> Result<Hash, StoreError> {
-        if let Some(mut block) = self.create_block() {
-            block.mine_block(block.difficulty);
-            self.blockchain.add_block(block)
-        } else {
-            Err(StoreError::NoBlockToCreate())
+    /// Build and mine a block on the tip of `shared`, the chain other threads extend too. The chain is only
+    /// read while building, and written by `commit` once the block is mined, see `commit_mined`.
+    pub(crate) fn mine_onto(
+        &mut self,
+        shared: &RwLock<Chain>,
+        commit: impl FnOnce(&mut Chain, Block) -> Result<Hash, StoreError>,
+    ) -> Result<Hash, StoreError> {
+        // Blocks accepted from peers move the tip, stop building on the old one
+        let moved = {
+            let current = shared.read().unwrap();
+            (current.tip_header() != self.blockchain.tip_header()).then(|| current.detached())
+        };
+        if let Some(current) = moved {
+            self.update_chain(current);
         }
+
+        let Some(mut block) = self.create_block()? else {
+            return Err(StoreError::NoBlockToCreate());
+        };
+        self.mine(&mut block)?;
+        self.commit_mined(shared, block, commit)
     }
 
+    /// Add the mined `block` to `shared` with `commit`, under the write lock, if its parent is still the tip.
+    /// Otherwise a block from a peer won the race: the mined block is abandoned, its transactions go back to
+    /// the pool and the builder moves to the new tip. Its transactions also go back when `commit` fails.
+    pub(crate) fn commit_mined(
+        &mut self,
+        shared: &RwLock<Chain>,
+        block: Block,
+        commit: impl FnOnce(&mut Chain, Block) -> Result<Hash, StoreError>,
+    ) -> Result<Hash, StoreError> {
+        let mut chain = shared.write().unwrap();
+        let tip_hash = chain.blocks.last().and_then(|tip| tip.current_block_hash.clone());
+        if tip_hash != block.previous_block_hash {
+            let current = chain.detached();
+            drop(chain);
+            self.return_to_pool(&block.transactions);
+            self.update_chain(current);
+            return Err(StoreError::StaleBlock(format!(
+                "Block {} was mined on a tip that has since moved",
+                block.index
+            )));
+        }
+
+        let transactions = block.transactions.clone();
+        let hash = match commit(&mut chain, block) {
+            Ok(hash) => hash,
+            Err(e) => {
+                drop(chain);
+                self.return_to_pool(&transactions);
+                return Err(e);
+            }
+        };
+        self.blockchain = chain.detached();
+        drop(chain);
+        if let Some(tip) = self.blockchain.blocks.last() {
+            self.transaction_pool.lock().unwrap().confirm_block(tip);
+        }
+        self.revalidate_pool();
+        Ok(hash)
+    }
+
+    /// Put the non-coinbase `transactions` of a block that did not make it onto the chain back in the pool
+    fn return_to_pool(&self, transactions: &[Transaction]) {
+        let mut pool = self.transaction_pool.lock().unwrap();
+        for tx in transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            // Refused when the new tip already includes it, which revalidation would drop anyway
+            let _ = pool.add_transaction(tx.clone());
+        }
+    }
+
+    /// Mine `block`, moving its timestamp to the current time whenever it falls more than `max_timestamp_drift`
+    /// seconds behind, so a block that took long to mine carries its completion time rather than its start time
+    pub(crate) fn mine(&self, block: &mut Block) -> Result<(), StoreError> {
+        loop {
+            let now = self.clock.now();
+            if (now - block.timestamp).num_seconds() > self.max_timestamp_drift as i64 {
+                block.timestamp = now;
+            }
+            if block.mine_block_throttled(block.difficulty, HASHES_PER_CLOCK_CHECK)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Drop pending transactions the current chain state no longer allows or that pay less than the next
+    /// base fee
+    fn revalidate_pool(&mut self) {
+        let state = match &self.state {
+            Some(state) => Ok(state.read().unwrap().clone()),
+            None => self.blockchain.state_at_tip(),
+        };
+        match state {
+            Ok(state) => {
+                let mut pool = self.transaction_pool.lock().unwrap();
+                let mut dropped = pool.revalidate_against_state(&state);
+                dropped.extend(pool.reprioritize(self.blockchain.next_base_fee()));
+                if !dropped.is_empty() {
+                    println!("Dropped {} pending transactions invalidated by the new chain state", dropped.len());
+                }
+            }
+            Err(e) => eprintln!("Failed to rebuild the chain state : {}", e),
+        }
+    }
+
+    pub fn chain(&self) -> &Chain {
+        &self.blockchain
+    }
+
+    #[cfg(test)]
     pub fn get_pending_transaction_count(&self) -> usize {
-        self.transaction_pool.pending_count()
+        self.transaction_pool.lock().unwrap().pending_count()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::network::GenesisConfig;
+    use crate::transaction::TRANSFER_GAS;
+    use crate::transaction_pool::TxOutcome;
+    use chrono::{DateTime, Duration, Utc};
+
+    fn signed_transaction(fee: u64, chain_id: u64) -> Transaction {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, fee);
+        tx.sign(&secret_key, chain_id).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_create_block_pays_coinbase_to_miner() {
+        let chain = Chain::new_easy();
+        let subsidy = chain.network().block_subsidy;
+        let chain_id = chain.network().chain_id;
+        let miner = Address::generate().0;
+        let mut builder = BlockBuilder::new(chain).with_miner_address(miner.clone());
+        builder.add_transaction(signed_transaction(3, chain_id)).unwrap();
+
+        let block = builder.create_block().unwrap().unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        assert!(block.transactions[0].is_coinbase());
+        assert_eq!(block.transactions[0].to, miner);
+        assert_eq!(block.transactions[0].amount, subsidy + 3);
+        assert!(block.validate(builder.chain().network()).is_ok());
+    }
+
+    #[test]
+    fn test_create_block_uses_chain_difficulty() {
+        let chain = Chain::from_genesis(&GenesisConfig { difficulty: 8, ..GenesisConfig::default() });
+        assert_eq!(chain.next_difficulty(), 8);
+        let chain_id = chain.network().chain_id;
+        let mut builder = BlockBuilder::new(chain);
+        builder.add_transaction(signed_transaction(3, chain_id)).unwrap();
+
+        let mut block = builder.create_block().unwrap().unwrap();
+        assert_eq!(block.difficulty, 8);
+        builder.mine(&mut block).unwrap();
+        assert!(block.current_block_hash.as_ref().unwrap().value.starts_with("00"));
+        assert!(block.validate(builder.chain().network()).is_ok());
+    }
+
+    #[test]
+    fn test_create_block_without_miner_has_no_coinbase() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let mut builder = BlockBuilder::new(chain);
+        builder.add_transaction(signed_transaction(3, chain_id)).unwrap();
+
+        let block = builder.create_block().unwrap().unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert!(!block.transactions[0].is_coinbase());
+    }
+
+    #[test]
+    fn test_update_chain_builds_on_new_tip() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let mut builder = BlockBuilder::new(chain.clone());
+
+        let mut longer = chain;
+        let genesis_hash = longer.genesis_block().current_block_hash.clone().unwrap();
+        let reward = Transaction::coinbase(Address::generate().0, longer.network().block_subsidy);
+        let mut block = Block::new(1, vec![reward], genesis_hash, 1).with_base_fee(longer.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        let tip_hash = longer.add_block(block).unwrap();
+
+        builder.update_chain(longer);
+        builder.add_transaction(signed_transaction(3, chain_id)).unwrap();
+        let next = builder.create_block().unwrap().unwrap();
+        assert_eq!(next.index, 2);
+        assert_eq!(next.previous_block_hash, Some(tip_hash));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_update_chain_drops_conflicting_pending_transaction() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let (alice, alice_key, _) = Address::generate();
+        let mut builder = BlockBuilder::new(chain.clone());
+
+        // The competing chain funds Alice and already spends Alice's nonce 0
+        let mut longer = chain;
+        let genesis_hash = longer.genesis_block().current_block_hash.clone().unwrap();
+        let funding = Transaction::coinbase(alice.clone(), longer.network().block_subsidy);
+        let mut block = Block::new(1, vec![funding], genesis_hash, 1).with_base_fee(longer.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        let tip_hash = longer.add_block(block).unwrap();
+
+        let mut spend = Transaction::new(alice.clone(), Address::generate().0, 10).with_gas(TRANSFER_GAS, 2);
+        spend.sign(&alice_key, chain_id).unwrap();
+        let mut block = Block::new(2, vec![spend], tip_hash, 1).with_base_fee(longer.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        longer.add_block(block).unwrap();
+
+        let mut pending = Transaction::new(alice, Address::generate().0, 5).with_gas(TRANSFER_GAS, 1);
+        pending.sign(&alice_key, chain_id).unwrap();
+        builder.add_transaction(pending).unwrap();
+        assert_eq!(builder.get_pending_transaction_count(), 1);
+
+        builder.update_chain(longer);
+        assert_eq!(builder.get_pending_transaction_count(), 0);
+    }
+
+    #[test]
+    fn test_pool_revalidated_against_shared_state() {
+        let mut chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let (alice, alice_key, _) = Address::generate();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let funding = Transaction::coinbase(alice.clone(), chain.network().block_subsidy);
+        let mut block = Block::new(1, vec![funding], genesis_hash, 1).with_base_fee(chain.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        chain.add_block(block).unwrap();
+
+        // The builder's own chain funds Alice, the node's state does not
+        let state = Arc::new(RwLock::new(AccountState::new()));
+        let mut builder = BlockBuilder::new(chain.clone()).with_state(state.clone());
+        let mut pending = Transaction::new(alice.clone(), Address::generate().0, 5).with_gas(TRANSFER_GAS, 1);
+        pending.sign(&alice_key, chain_id).unwrap();
+        builder.add_transaction(pending).unwrap();
+
+        builder.update_chain(chain.clone());
+        assert_eq!(builder.get_pending_transaction_count(), 0);
+    }
+
+    #[test]
+    fn test_block_mined_on_moved_tip_abandoned() {
+        let mut chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let subsidy = chain.network().block_subsidy;
+        let (alice, alice_key, _) = Address::generate();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let funding = Transaction::coinbase(alice.clone(), subsidy);
+        let mut block = Block::new(1, vec![funding], genesis_hash, 1).with_base_fee(chain.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        let funded_hash = chain.add_block(block).unwrap();
+        let shared = RwLock::new(chain);
+
+        let mut builder = BlockBuilder::new(shared.read().unwrap().detached());
+        let mut spend = Transaction::new(alice, Address::generate().0, 10).with_gas(TRANSFER_GAS, 2);
+        spend.sign(&alice_key, chain_id).unwrap();
+        builder.add_transaction(spend.clone()).unwrap();
+        let mut stale = builder.create_block().unwrap().unwrap();
+        builder.mine(&mut stale).unwrap();
+
+        // A peer's block takes the tip while ours is being mined
+        let base_fee = shared.read().unwrap().next_base_fee();
+        let reward = Transaction::coinbase(Address::generate().0, subsidy);
+        let mut competing = Block::new(2, vec![reward], funded_hash, 1).with_base_fee(base_fee);
+        competing.mine_block(competing.difficulty).unwrap();
+        let competing_hash = shared.write().unwrap().add_block(competing).unwrap();
+
+        let result = builder.commit_mined(&shared, stale, |chain, block| chain.add_block(block));
+        assert!(matches!(result, Err(StoreError::StaleBlock(_))));
+        assert_eq!(shared.read().unwrap().blocks.len(), 3);
+        assert_eq!(builder.chain().tip_header(), shared.read().unwrap().tip_header());
+        assert_eq!(builder.get_pending_transaction_count(), 1);
+
+        // The abandoned transaction is mined again, on the new tip
+        builder.mine_onto(&shared, |chain, block| chain.add_block(block)).unwrap();
+        let chain = shared.read().unwrap();
+        assert_eq!(chain.blocks[3].previous_block_hash, Some(competing_hash));
+        assert!(chain.blocks[3].transactions.contains(&spend));
+        assert_eq!(builder.chain().tip_header(), chain.tip_header());
+        assert_eq!(builder.get_pending_transaction_count(), 0);
+    }
+
+    #[test]
+    fn test_failed_commit_returns_transactions_to_pool() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let shared = RwLock::new(chain.detached());
+        let mut builder = BlockBuilder::new(chain).with_miner_address(Address::generate().0);
+        let pending = [signed_transaction(3, chain_id), signed_transaction(4, chain_id)];
+        for tx in &pending {
+            builder.add_transaction(tx.clone()).unwrap();
+        }
+
+        let result = builder.mine_onto(&shared, |_, _| Err(StoreError::IoError(std::io::Error::other("disk full"))));
+        assert!(matches!(result, Err(StoreError::IoError(_))));
+        assert_eq!(shared.read().unwrap().blocks.len(), 1);
+        let pool = builder.transaction_pool.lock().unwrap();
+        assert_eq!(pool.pending_count(), 2);
+        assert!(pending.iter().all(|tx| pool.contains(&tx.id)));
+    }
+
+    #[test]
+    fn test_mined_transaction_confirmed_to_submitter() {
+        let mut chain = Chain::from_genesis(&GenesisConfig { difficulty: 1, ..GenesisConfig::default() });
+        let chain_id = chain.network().chain_id;
+        let (alice, alice_key, _) = Address::generate();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let funding = Transaction::coinbase(alice.clone(), chain.network().block_subsidy);
+        let mut block = Block::new(1, vec![funding], genesis_hash, 1).with_base_fee(chain.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        chain.add_block(block).unwrap();
+
+        let base_fee = chain.next_base_fee();
+        let mut builder = BlockBuilder::new(chain);
+        let mut spend = Transaction::new(alice, Address::generate().0, 10).with_gas(TRANSFER_GAS, base_fee + 1);
+        spend.sign(&alice_key, chain_id).unwrap();
+        let (_, confirmation) = builder.transaction_pool.lock().unwrap().submit_with_confirmation(spend, 0).unwrap();
+        assert!(confirmation.try_recv().is_err());
+
+        let shared = RwLock::new(builder.chain().detached());
+        builder.mine_onto(&shared, |chain, block| chain.add_block(block)).unwrap();
+        assert_eq!(confirmation.try_recv().unwrap(), TxOutcome::Included(2));
+    }
+
+    #[test]
+    fn test_create_block_on_empty_chain_is_an_error() {
+        let mut chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        chain.blocks.clear();
+        let mut builder = BlockBuilder::new(chain);
+        builder.add_transaction(signed_transaction(3, chain_id)).unwrap();
+
+        assert!(matches!(builder.create_block(), Err(StoreError::ValidationError(_))));
+        assert_eq!(builder.get_pending_transaction_count(), 1);
+    }
+
+    #[test]
+    fn test_failed_block_build_restores_pool() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let mut builder = BlockBuilder::new(chain).with_miner_address(Address::generate().0);
+        // Tips large enough that the coinbase reward overflows
+        let pending = [signed_transaction(3, chain_id), signed_transaction(u64::MAX - 20, chain_id)];
+        for tx in &pending {
+            builder.add_transaction(tx.clone()).unwrap();
+        }
+
+        assert!(matches!(builder.create_block(), Err(StoreError::ValidationError(_))));
+        let pool = builder.transaction_pool.lock().unwrap();
+        assert_eq!(pool.pending_count(), 2);
+        assert!(pending.iter().all(|tx| pool.contains(&tx.id)));
+    }
+
+    #[test]
+    fn test_create_block_with_empty_pool_is_none() {
+        let mut builder = BlockBuilder::new(Chain::new_easy());
+        assert!(builder.should_create_block());
+        assert!(matches!(builder.create_block(), Ok(None)));
+        let shared = RwLock::new(builder.chain().detached());
+        let mined = builder.mine_onto(&shared, |chain, block| chain.add_block(block));
+        assert!(matches!(mined, Err(StoreError::NoBlockToCreate())));
+    }
+
+    #[test]
+    fn test_block_time_limit_triggers_with_mock_clock() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let clock = Arc::new(MockClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap()));
+        let mut builder = BlockBuilder::new(chain).with_clock(clock.clone());
+
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+        let block = builder.create_block().unwrap().unwrap();
+        assert_eq!(block.timestamp, clock.now());
+
+        // Nothing pending and the block time limit has not elapsed yet
+        assert!(!builder.should_create_block());
+        clock.advance(Duration::seconds(599));
+        assert!(!builder.should_create_block());
+        clock.advance(Duration::seconds(1));
+        assert!(builder.should_create_block());
+
+        // A transaction short of a batch waits for the timer
+        builder.create_block().unwrap();
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+        builder.create_block().unwrap().unwrap();
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+        assert!(!builder.should_create_block());
+        clock.advance(Duration::seconds(600));
+        assert_eq!(builder.create_block().unwrap().unwrap().transactions.len(), 1);
+    }
+
+    #[test]
+    fn test_full_batch_triggers_before_block_time_limit() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let clock = Arc::new(MockClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap()));
+        let mut builder = BlockBuilder::new(chain).with_clock(clock.clone()).with_batch_target(3, usize::MAX);
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+        builder.create_block().unwrap().unwrap();
+
+        // A single transaction no longer makes a block, a full batch does
+        for pending in 1..=3 {
+            assert!(!builder.should_create_block());
+            builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+            assert_eq!(builder.get_pending_transaction_count(), pending);
+        }
+        assert!(builder.should_create_block());
+        assert_eq!(builder.create_block().unwrap().unwrap().transactions.len(), 3);
+
+        // So does a batch by size
+        let size = builder.transaction_pool.lock().unwrap().estimate_transaction_size(&signed_transaction(1, chain_id));
+        let mut builder = builder.with_batch_target(usize::MAX, 2 * size);
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+        assert!(!builder.should_create_block());
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+        assert!(builder.should_create_block());
+    }
+
+    /// Clock moving a minute forward every time it is read, standing in for mining that takes long
+    struct SlowMiningClock(MockClock);
+
+    impl Clock for SlowMiningClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0.advance(Duration::minutes(1));
+            self.0.now()
+        }
+    }
+
+    #[test]
+    fn test_slow_mining_refreshes_block_timestamp() {
+        let chain = Chain::new_easy();
+        let chain_id = chain.network().chain_id;
+        let clock = Arc::new(SlowMiningClock(MockClock::new(Utc::now())));
+        let mut builder = BlockBuilder::new(chain).with_clock(clock.clone()).with_max_timestamp_drift(30);
+        builder.add_transaction(signed_transaction(1, chain_id)).unwrap();
+
+        let mut block = builder.create_block().unwrap().unwrap();
+        let started = block.timestamp;
+        builder.mine(&mut block).unwrap();
+        let completed = clock.now();
+
+        assert!(block.timestamp > started);
+        assert!(completed - block.timestamp <= Duration::minutes(1));
+        assert!(block.validate(builder.chain().network()).is_ok());
+    }
+}
@@ -1,24 +1,75 @@
-use crate::block::Block;
+use crate::address::Address;
+use crate::block::{Block, BlockHeader, BlockSummary, MIN_DIFFICULTY};
+use crate::codec::Codec;
+use crate::config::Config;
+use crate::events::{ChainEvent, EventBus};
+use crate::export::{ExportFormat, HistoryRow};
 use crate::hash::Hash;
+use crate::merkle::ProofStep;
+use crate::network::{GenesisConfig, NetworkConfig};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::{env, fs};
-use crate::store::{Store, StoreError};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use crate::snapshot::read_snapshot;
+use crate::state::AccountState;
+use crate::store::{BlockStore, FileStore, MemoryStore, Store, StoreError};
+use crate::transaction::Transaction;
+
+/// Most a retarget changes the work per block by, as a power of two: 2 bits is 4x up or down
+const MAX_RETARGET_BITS: u32 = 2;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct Chain {
-    difficulty: i8,
+    /// Difficulty of the genesis block, see `next_difficulty` for the blocks after it
+    difficulty: u32,
     genesis_block_hash: Hash,
     initialized_at: DateTime<Utc>,
-    #[serde(skip)]
     pub(crate) blocks: Vec<Block>,
+    #[serde(skip)]
+    network: NetworkConfig,
+    /// Transaction id to block index and position in that block, derived from `blocks`
+    #[serde(skip)]
+    tx_index: HashMap<String, (u64, usize)>,
+    /// Block hash to block index, derived from `blocks`
+    #[serde(skip)]
+    hash_index: HashMap<String, u64>,
+    #[serde(skip)]
+    events: EventBus,
+    /// Account state after the tip with the tip's hash, kept by `save_batch` so new blocks are applied to it
+    /// instead of replaying the chain from genesis
+    #[serde(skip)]
+    tip_state: Option<(Hash, AccountState)>,
+    #[serde(skip, default = "default_store")]
+    store: Arc<dyn BlockStore>,
+}
+
+fn default_store() -> Arc<dyn BlockStore> {
+    Arc::new(MemoryStore::new())
 }
 
 impl Chain {
-    pub(crate) fn load_or_create() -> Self {
-        let base_path = env::var("BLOCKCHAIN_DATA_PATH").unwrap_or_else(|_| ".".to_string());
-        let blockchain_file = format!("{}/blockchain.json", base_path);
+    /// Load the chain from disk, validating it, or create a new one
+    pub(crate) fn load_or_create(config: &Config) -> Result<Self, StoreError> {
+        Self::load_or_create_validated(config)
+    }
+
+    /// Load the chain from disk and refuse it if it fails `validate`
+    pub(crate) fn load_or_create_validated(config: &Config) -> Result<Self, StoreError> {
+        Self::open(Self::file_store(config), true, config)
+    }
+
+    /// Load the chain from disk without validating it, for trusted environments
+    pub(crate) fn load_unchecked(config: &Config) -> Result<Self, StoreError> {
+        Self::open(Self::file_store(config), false, config)
+    }
+
+    fn file_store(config: &Config) -> Arc<dyn BlockStore> {
+        let extension = match config.codec {
+            Codec::Json => "json",
+            Codec::Binary => "bin",
+        };
+        let blockchain_file = format!("{}/blockchain.{}", config.data_path, extension);
 
         if let Some(parent) = std::path::Path::new(&blockchain_file).parent() {
             std::fs::create_dir_all(parent).unwrap_or_else(|e| {
@@ -26,63 +77,1733 @@ impl Chain {
             });
         }
 
-        if Path::new(&blockchain_file).exists() {
-            println!("Loading Blockchain from file...");
-            Self::load_from_file(&blockchain_file)
+        Arc::new(FileStore::new(blockchain_file).with_codec(config.codec))
+    }
+
+    /// Seed the node from a snapshot file written by `export_snapshot`, for fast bootstrapping
+    pub(crate) fn import_bootstrap(path: &str, config: &Config) -> Result<Self, StoreError> {
+        Self::bootstrap(path, Self::file_store(config), config)
+    }
+
+    /// Adopt the snapshot at `path` once it is fully validated, then persist it to `store`
+    pub(crate) fn bootstrap(path: &str, store: Arc<dyn BlockStore>, config: &Config) -> Result<Self, StoreError> {
+        let chain = Self::from_blocks(read_snapshot(path)?)?.with_network(config.network());
+        chain.check_genesis()?;
+        chain.validate()?;
+
+        let chain = chain.with_store(store);
+        chain.store.persist(&chain)?;
+        println!("Bootstrapped Blockchain from {} at height {}", path, chain.blocks.len() - 1);
+        Ok(chain)
+    }
+
+    /// Keep the stored blocks but throw away everything derived from them, replaying the blocks from genesis to
+    /// recompute it, for recovering from a chain file whose derived fields are corrupt
+    pub(crate) fn reindex(config: &Config) -> Result<Self, StoreError> {
+        Self::reindex_store(Self::file_store(config), config)
+    }
+
+    /// Rebuild the chain stored in `store` block by block, validating each again, then persist the result
+    fn reindex_store(store: Arc<dyn BlockStore>, config: &Config) -> Result<Self, StoreError> {
+        let stored = store
+            .load()?
+            .ok_or_else(|| StoreError::ValidationError("No stored chain to reindex".to_string()))?;
+        let mut blocks = stored.blocks.into_iter();
+        let genesis = blocks
+            .next()
+            .ok_or_else(|| StoreError::ValidationError("Chain has no genesis block".to_string()))?;
+
+        let mut chain = Self::from_blocks(vec![genesis])?.with_network(config.network()).with_store(store);
+        chain.initialized_at = stored.initialized_at;
+        chain.save_batch(blocks.collect())?;
+        println!("Reindexed Blockchain at height {}", chain.blocks.len() - 1);
+        Ok(chain)
+    }
+
+    /// Chain made of `blocks`, the first of which is taken as genesis
+    fn from_blocks(blocks: Vec<Block>) -> Result<Self, StoreError> {
+        let genesis = blocks
+            .first()
+            .ok_or_else(|| StoreError::ValidationError("Snapshot has no genesis block".to_string()))?;
+        let genesis_block_hash = genesis
+            .current_block_hash
+            .clone()
+            .ok_or_else(|| StoreError::ValidationError("Snapshot genesis block has no hash".to_string()))?;
+
+        let mut chain = Chain {
+            initialized_at: Utc::now(),
+            genesis_block_hash,
+            difficulty: genesis.difficulty,
+            blocks,
+            network: NetworkConfig::default(),
+            tx_index: HashMap::new(),
+            hash_index: HashMap::new(),
+            events: EventBus::new(),
+            tip_state: None,
+            store: default_store(),
+        };
+        chain.rebuild_index();
+        Ok(chain)
+    }
+
+    /// Load the chain persisted in `store`, or create and persist a new one
+    fn open(store: Arc<dyn BlockStore>, validate: bool, config: &Config) -> Result<Self, StoreError> {
+        match store.load()? {
+            Some(mut chain) => {
+                chain.rebuild_index();
+                let chain = chain.with_network(config.network());
+                chain.check_genesis()?;
+                if validate {
+                    chain.validate()?;
+                }
+                println!("Loaded Blockchain from file");
+                Ok(chain.with_store(store))
+            }
+            None => {
+                println!("Initializing new Blockchain...");
+                let chain = Self::from_genesis(&config.genesis())
+                    .with_network(config.network())
+                    .with_store(store);
+                if let Err(e) = chain.store.persist(&chain) {
+                    eprintln!("Failed to save new blockchain: {}", e);
+                }
+                Ok(chain)
+            }
+        }
+    }
+
+    /// Build a fresh chain holding only the genesis block, kept in memory until given another store
+    #[cfg(test)]
+    pub(crate) fn new() -> Self {
+        Self::from_genesis(&GenesisConfig::default())
+    }
+
+    /// Fresh chain mined at the minimum difficulty, cheap enough for tests to build many blocks on
+    #[cfg(test)]
+    pub(crate) fn new_easy() -> Self {
+        Self::from_genesis(&GenesisConfig { difficulty: MIN_DIFFICULTY, ..GenesisConfig::default() })
+    }
+
+    /// Build a fresh chain whose genesis block comes from `config`
+    pub(crate) fn from_genesis(config: &GenesisConfig) -> Self {
+        let genesis_block = Block::genesis_from(config);
+        let genesis_block_hash = genesis_block.current_block_hash.clone().unwrap();
+
+        let mut chain = Chain {
+            initialized_at: Utc::now(),
+            genesis_block_hash,
+            difficulty: genesis_block.difficulty,
+            blocks: vec![genesis_block],
+            network: NetworkConfig { chain_id: config.network_id, ..NetworkConfig::default() },
+            tx_index: HashMap::new(),
+            hash_index: HashMap::new(),
+            events: EventBus::new(),
+            tip_state: None,
+            store: default_store(),
+        };
+        chain.rebuild_index();
+        chain
+    }
+
+    /// Persist the chain to `store` from now on
+    pub(crate) fn with_store(mut self, store: Arc<dyn BlockStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub(crate) fn store(&self) -> &Arc<dyn BlockStore> {
+        &self.store
+    }
+
+    /// A copy of the chain that no longer writes to this chain's store
+    pub(crate) fn detached(&self) -> Chain {
+        self.clone().with_store(default_store())
+    }
+
+    /// Coins in circulation: everything minted by coinbases minus every fee paid.
+    /// Tips come back through the coinbase, so what remains removed is the burned base fee.
+    /// Returns `None` if the ledger overflows or goes negative, which a valid chain never does.
+    pub(crate) fn total_supply(&self) -> Option<u64> {
+        let mut minted: u64 = 0;
+        let mut fees_paid: u64 = 0;
+
+        for tx in self.blocks.iter().flat_map(|block| &block.transactions) {
+            if tx.is_coinbase() {
+                minted = minted.checked_add(tx.amount)?;
+            } else {
+                fees_paid = fees_paid.checked_add(tx.fee)?;
+            }
+        }
+
+        minted.checked_sub(fees_paid)
+    }
+
+    /// The newest `window` blocks, or the whole chain when it is shorter
+    fn recent_blocks(&self, window: usize) -> &[Block] {
+        &self.blocks[self.blocks.len().saturating_sub(window)..]
+    }
+
+    /// Summaries of the newest `count` blocks, newest first
+    pub(crate) fn recent_summaries(&self, count: usize) -> Vec<BlockSummary> {
+        self.recent_blocks(count).iter().rev().map(Block::summary).collect()
+    }
+
+    /// Seconds between the first and last of the newest `window` blocks, `None` with fewer than two blocks
+    fn recent_time_span(&self, window: usize) -> Option<f64> {
+        let (first, last) = match self.recent_blocks(window) {
+            [first, .., last] => (first, last),
+            _ => return None,
+        };
+        Some((last.timestamp - first.timestamp).num_milliseconds() as f64 / 1000.0)
+    }
+
+    /// Mean seconds between consecutive blocks among the newest `window` blocks, the window is
+    /// clamped to the chain length and at least two blocks are needed
+    pub(crate) fn average_block_time(&self, window: usize) -> Option<f64> {
+        let intervals = self.recent_blocks(window).len().checked_sub(1)?;
+        Some(self.recent_time_span(window)? / intervals as f64)
+    }
+
+    /// Transactions per second over the newest `window` blocks. The oldest block of the window
+    /// only opens the time span, so its transactions are not counted.
+    pub(crate) fn transactions_per_second(&self, window: usize) -> Option<f64> {
+        let span = self.recent_time_span(window).filter(|span| *span > 0.0)?;
+        let transactions: usize = self.recent_blocks(window)[1..].iter().map(|block| block.transactions.len()).sum();
+        Some(transactions as f64 / span)
+    }
+
+    /// Mean JSON-encoded size in bytes of the newest `window` blocks
+    pub(crate) fn average_block_size(&self, window: usize) -> Option<f64> {
+        let blocks = self.recent_blocks(window);
+        if blocks.is_empty() {
+            return None;
+        }
+        let total: usize = blocks.iter().map(Block::size).sum();
+        Some(total as f64 / blocks.len() as f64)
+    }
+
+    /// Median timestamp of the newest `retarget_window` blocks, immune to a single skewed timestamp
+    pub(crate) fn median_time_past(&self) -> Option<DateTime<Utc>> {
+        Self::median_time(self.recent_blocks(self.network.retarget_window.max(1)))
+    }
+
+    fn median_time(blocks: &[Block]) -> Option<DateTime<Utc>> {
+        let mut timestamps: Vec<DateTime<Utc>> = blocks.iter().map(|block| block.timestamp).collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied()
+    }
+
+    /// Difficulty the next block must be mined at, see `difficulty_after`
+    pub(crate) fn next_difficulty(&self) -> u32 {
+        self.difficulty_after(&self.blocks)
+    }
+
+    /// Difficulty of the block following `blocks`. It only changes at the first block of every window of
+    /// `retarget_window` blocks, so each window's block times count towards a single step. The median times
+    /// of the newest window of blocks and of the window before it give the elapsed time, so one manipulated
+    /// timestamp cannot move the difficulty. Difficulty counts leading zero bits, each one doubling the work:
+    /// it moves by one bit for every doubling of the block time away from the target, at most
+    /// `MAX_RETARGET_BITS` per window.
+    fn difficulty_after(&self, blocks: &[Block]) -> u32 {
+        let current = blocks.last().map_or(GenesisConfig::default().difficulty, |tip| tip.difficulty);
+        let window = self.network.retarget_window.max(1);
+        let len = blocks.len();
+        if len < 2 * window || !len.is_multiple_of(window) {
+            return current;
+        }
+
+        let (Some(earlier), Some(recent)) = (
+            Self::median_time(&blocks[len - 2 * window..len - window]),
+            Self::median_time(&blocks[len - window..]),
+        ) else {
+            return current;
+        };
+
+        let elapsed = u64::try_from((recent - earlier).num_seconds()).unwrap_or(0).max(1);
+        let expected = self.network.target_block_time_secs.saturating_mul(window as u64).max(1);
+        // Whole doublings between the two, the integer log2 of their ratio
+        let doublings = |slow: u64, fast: u64| (slow / fast).checked_ilog2().unwrap_or(0).min(MAX_RETARGET_BITS);
+        if elapsed < expected {
+            current.saturating_add(doublings(expected, elapsed))
         } else {
-            println!("Initializing new Blockchain...");
-            Self::create_new_chain(blockchain_file)
+            current.saturating_sub(doublings(elapsed, expected)).max(MIN_DIFFICULTY)
         }
     }
 
+    /// Base fee the next block must carry
+    pub(crate) fn next_base_fee(&self) -> u64 {
+        self.blocks.last().map_or(self.network.initial_base_fee, |parent| self.base_fee_after(parent))
+    }
+
+    /// Base fee of the child of `parent`, raised when the parent was fuller than the
+    /// target and lowered when it was emptier, by at most an eighth per block
+    fn base_fee_after(&self, parent: &Block) -> u64 {
+        let parent_base_fee = if parent.index == 0 {
+            self.network.initial_base_fee
+        } else {
+            parent.base_fee
+        };
+        if parent.index == 0 || parent_base_fee == 0 {
+            return parent_base_fee;
+        }
+
+        let target = self.network.target_block_transactions.max(1) as u64;
+        let used = parent.transactions.iter().filter(|tx| !tx.is_coinbase()).count() as u64;
+        let delta = |difference: u64| {
+            let scaled = u128::from(parent_base_fee) * u128::from(difference) / u128::from(target) / 8;
+            u64::try_from(scaled).unwrap_or(u64::MAX)
+        };
+
+        if used > target {
+            parent_base_fee.saturating_add(delta(used - target).max(1))
+        } else {
+            parent_base_fee.saturating_sub(delta(target - used))
+        }
+    }
+
+    pub(crate) fn get_block(&self, index: u64) -> Option<&Block> {
+        self.blocks.get(usize::try_from(index).ok()?)
+    }
+
+    /// The first block of the chain, present in every chain built by `new` or accepted by `open`
+    pub(crate) fn genesis_block(&self) -> &Block {
+        self.blocks.first().expect("Chain has no genesis block")
+    }
+
+    /// Check the stored genesis hash still names the first block, the two are kept in separate fields
+    pub(crate) fn check_genesis(&self) -> Result<(), StoreError> {
+        let Some(genesis_block) = self.blocks.first() else {
+            return Err(StoreError::ValidationError("Chain has no genesis block".to_string()));
+        };
+
+        if genesis_block.current_block_hash.as_ref() != Some(&self.genesis_block_hash) {
+            return Err(StoreError::ValidationError(format!(
+                "Genesis block hash does not match the stored genesis hash {}", self.genesis_block_hash.value
+            )));
+        }
+        Ok(())
+    }
+
+    /// Block with hash `hash`, looked up in the hash index
+    pub(crate) fn get_block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        self.get_block(*self.hash_index.get(&hash.value)?)
+            .filter(|block| block.current_block_hash.as_ref() == Some(hash))
+    }
+
+    /// Height of the newest block whose hash appears in `other_hashes`, a peer's block hashes or a sparse
+    /// locator in any order. `None` when the peer shares no block with us, not even genesis.
+    pub(crate) fn find_common_ancestor(&self, other_hashes: &[Hash]) -> Option<u64> {
+        let other: HashSet<&str> = other_hashes.iter().map(|hash| hash.value.as_str()).collect();
+        self.blocks
+            .iter()
+            .rev()
+            .find(|block| block.current_block_hash.as_ref().is_some_and(|hash| other.contains(hash.value.as_str())))
+            .map(|block| block.index)
+    }
+
+    /// Blocks of ours above the common ancestor with `other_hashes`, 0 when the peer extends our tip
+    pub(crate) fn fork_depth(&self, other_hashes: &[Hash]) -> Option<u64> {
+        let ancestor = self.find_common_ancestor(other_hashes)?;
+        Some(self.blocks.len() as u64 - 1 - ancestor)
+    }
+
+    /// Header of the newest block, announced to peers when it is mined
+    pub(crate) fn tip_header(&self) -> Option<BlockHeader> {
+        self.blocks.last().map(Block::header)
+    }
+
+    /// Expected hashes spent on every block, genesis included, 2 to the power of each difficulty. Sync prefers
+    /// the chain with the most work rather than the most blocks, which cheap low-difficulty blocks could fake.
+    pub(crate) fn total_work(&self) -> u64 {
+        Self::work(&self.blocks)
+    }
+
+    /// Expected hashes spent on `blocks`, see `total_work`
+    fn work(blocks: &[Block]) -> u64 {
+        blocks
+            .iter()
+            .map(|block| 1u64.checked_shl(block.difficulty).unwrap_or(u64::MAX))
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Newest block with the total work of the chain ending at it, as reported to syncing peers
+    pub(crate) fn tip_work(&self) -> Option<(BlockHeader, u64)> {
+        Some((self.tip_header()?, self.total_work()))
+    }
+
+    pub(crate) fn with_network(mut self, network: NetworkConfig) -> Self {
+        self.network = network;
+        // Coinbase maturity comes from the network
+        self.tip_state = None;
+        self
+    }
+
+    /// Account state after the tip: the one kept by `save_batch` while it still belongs to the tip,
+    /// otherwise replayed from genesis
+    pub(crate) fn state_at_tip(&self) -> Result<AccountState, StoreError> {
+        let tip_hash = self.blocks.last().and_then(|tip| tip.current_block_hash.as_ref());
+        match &self.tip_state {
+            Some((hash, state)) if Some(hash) == tip_hash => Ok(state.clone()),
+            _ => AccountState::from_chain(self),
+        }
+    }
+
+    /// Keep `state`, the account state after the current tip, for `state_at_tip`
+    pub(crate) fn keep_tip_state(&mut self, state: AccountState) {
+        self.tip_state = self.blocks.last().and_then(|tip| tip.current_block_hash.clone()).map(|hash| (hash, state));
+    }
+
+    pub(crate) fn network(&self) -> &NetworkConfig {
+        &self.network
+    }
+
+    /// Chain id of the network this chain belongs to
+    pub(crate) fn network_id(&self) -> u64 {
+        self.network.chain_id
+    }
+
     pub(crate) fn add_block(&mut self, block: Block) -> Result<Hash, StoreError> {
         let hash = self.save(block)?;
+        if let Some(header) = self.tip_header() {
+            self.events.publish(ChainEvent::BlockAdded(header));
+        }
         Ok(hash)
     }
 
-    fn create_new_chain(file_to_save: String) -> Self {
-        let initialized_at = Utc::now();
-        let genesis_block = Block::genesis();
-        let genesis_block_hash = genesis_block.current_block_hash.clone().unwrap();
+    /// Bus receiving an event for every block added, shared by clones of this chain
+    pub(crate) fn events(&self) -> &EventBus {
+        &self.events
+    }
 
-        let chain = Chain {
-            initialized_at,
-            genesis_block_hash,
-            difficulty: 4,
-            blocks: vec![genesis_block],
+    /// Every transaction involving the address across the chain, paired with its block
+    pub(crate) fn history(&self, addr: &Address) -> Vec<(&Block, &Transaction)> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.transactions_for_address(addr).into_iter().map(move |tx| (block, tx)))
+            .collect()
+    }
+
+    /// Every transaction involving the address as CSV or JSON rows, oldest first, for accounting
+    pub(crate) fn export_history(&self, addr: &Address, format: ExportFormat) -> String {
+        let rows: Vec<HistoryRow> =
+            self.history(addr).into_iter().map(|(block, tx)| HistoryRow::new(block, tx, addr)).collect();
+        crate::export::render(&rows, format)
+    }
+
+    /// Confirmed transaction `tx_id` with the index of its block, looked up in the transaction index
+    pub(crate) fn find_transaction(&self, tx_id: &str) -> Option<(u64, &Transaction)> {
+        let (height, position) = *self.tx_index.get(tx_id)?;
+        self.get_block(height)?
+            .transactions
+            .get(position)
+            .filter(|tx| tx.id == tx_id)
+            .map(|tx| (height, tx))
+    }
+
+    /// Header of the block holding confirmed transaction `tx_id` with the proof that it is in that block,
+    /// for a light client to check with `verify_block_inclusion`
+    pub(crate) fn merkle_proof_for(&self, tx_id: &str) -> Option<(BlockHeader, Vec<ProofStep>)> {
+        let (height, _) = self.find_transaction(tx_id)?;
+        let block = self.get_block(height)?;
+        Some((block.header(), block.merkle_proof_for(tx_id)?))
+    }
+
+    /// Whether `proof` shows `tx` is in the block of a trusted `header`. The leaf is derived from the
+    /// transaction id, so the id must match the transaction contents as well.
+    pub(crate) fn verify_block_inclusion(header: &BlockHeader, tx: &Transaction, proof: &[ProofStep]) -> bool {
+        tx.has_valid_id() && crate::merkle::verify(&header.merkle_root, &crate::merkle::leaf(tx.id.as_bytes()), proof)
+    }
+
+    /// Index of the block holding transaction `tx_id`, `None` while it is pending or unknown
+    pub(crate) fn transaction_height(&self, tx_id: &str) -> Option<u64> {
+        self.find_transaction(tx_id).map(|(height, _)| height)
+    }
+
+    /// Record the hash of the block at `block_position` and where its transactions live, called once it is
+    /// part of the chain
+    pub(crate) fn index_block(&mut self, block_position: usize) {
+        let Some(block) = self.blocks.get(block_position) else {
+            return;
         };
+        if let Some(hash) = &block.current_block_hash {
+            self.hash_index.insert(hash.value.clone(), block.index);
+        }
+        for (position, tx) in block.transactions.iter().enumerate() {
+            self.tx_index.insert(tx.id.clone(), (block.index, position));
+        }
+    }
 
-        chain.save_to_file(&file_to_save);
-        chain
+    /// Drop the transaction index entries of blocks below `height`, returning how many were dropped
+    pub(crate) fn prune_index_below(&mut self, height: u64) -> usize {
+        let before = self.tx_index.len();
+        self.tx_index.retain(|_, (block_index, _)| *block_index >= height);
+        before - self.tx_index.len()
+    }
+
+    /// Recompute every index derived from `blocks`, for when they are missing or stale. Runs on load and
+    /// after a reorg.
+    pub(crate) fn rebuild_index(&mut self) {
+        self.tx_index.clear();
+        self.hash_index.clear();
+        for block_position in 0..self.blocks.len() {
+            self.index_block(block_position);
+        }
     }
 
-    fn load_from_file(blockchain_file: &str) -> Chain {
-        match fs::read_to_string(blockchain_file) {
-            Ok(content) => {
-                serde_json::from_str::<Chain>(&content).unwrap_or_else(|e| {
-                    panic!("Failed to parse blockchain file: {}", e)
-                })
+    /// Switch to `branch`, consecutive blocks forking off this chain, when it carries more work than the blocks
+    /// it replaces, forks at most `max_reorg_depth` blocks below the tip and leaves finalized blocks in place.
+    /// The branch is validated on a candidate copy of the chain that only replaces this one once complete, so a
+    /// failing block leaves this chain untouched.
+    /// Returns the transactions of the dropped blocks the branch does not include, for the pool to take back.
+    pub(crate) fn try_reorg(&mut self, branch: Vec<Block>) -> Result<Vec<Transaction>, StoreError> {
+        let fork_point = branch
+            .first()
+            .and_then(|block| block.previous_block_hash.as_ref())
+            .and_then(|hash| self.get_block_by_hash(hash))
+            .map(|block| block.index as usize)
+            .ok_or_else(|| StoreError::ValidationError("Branch does not fork off this chain".to_string()))?;
+        let depth = (self.blocks.len() - 1 - fork_point) as u64;
+        if depth > self.network.max_reorg_depth {
+            eprintln!(
+                "Refusing reorg onto a branch forking {} blocks below the tip, past the limit of {}: possible long-range attack",
+                depth, self.network.max_reorg_depth
+            );
+            return Err(StoreError::ValidationError(format!(
+                "Branch forks {} blocks below the tip, deeper than the maximum reorg depth {}",
+                depth, self.network.max_reorg_depth
+            )));
+        }
+        self.check_finality(fork_point as u64 + 1)?;
+        let (branch_work, replaced_work) = (Self::work(&branch), Self::work(&self.blocks[fork_point + 1..]));
+        if branch_work <= replaced_work {
+            return Err(StoreError::ValidationError(format!(
+                "Branch of {} blocks from height {} carries {} work, no more than the {} of the blocks it replaces",
+                branch.len(), fork_point, branch_work, replaced_work
+            )));
+        }
+
+        let branch_headers: Vec<BlockHeader> = branch.iter().map(Block::header).collect();
+        let mut fork_state = self.state_at_tip()?;
+        for block in self.blocks[fork_point + 1..].iter().rev() {
+            fork_state.revert_block(block);
+        }
+        let mut candidate = self.detached();
+        candidate.blocks.truncate(fork_point + 1);
+        candidate.rebuild_index();
+        candidate.keep_tip_state(fork_state);
+        candidate.save_batch(branch)?;
+
+        let dropped = self.replace(candidate)?;
+        for header in branch_headers {
+            self.events.publish(ChainEvent::BlockAdded(header));
+        }
+        println!("Reorganized chain from height {}, new height {}", fork_point, self.blocks.len() - 1);
+
+        Ok(dropped
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .filter(|tx| !tx.is_coinbase() && self.find_transaction(&tx.id).is_none())
+            .collect())
+    }
+
+    /// Persist `candidate` to this chain's store and swap it in, returning the blocks this chain held that
+    /// `candidate` does not. Nothing changes if the write fails, so the chain is never seen half replaced.
+    pub(crate) fn replace(&mut self, candidate: Chain) -> Result<Vec<Block>, StoreError> {
+        let candidate = candidate.with_store(self.store.clone());
+        self.store.persist(&candidate)?;
+
+        let old = std::mem::replace(self, Chain { events: self.events.clone(), ..candidate });
+        let kept = old
+            .blocks
+            .iter()
+            .zip(&self.blocks)
+            .take_while(|(old, new)| old.current_block_hash == new.current_block_hash)
+            .count();
+        Ok(old.blocks.into_iter().skip(kept).collect())
+    }
+
+    /// Number of blocks from the one holding `tx_id` up to the tip, 1 when it is in the tip itself
+    pub(crate) fn confirmations(&self, tx_id: &str) -> Option<u64> {
+        let height = self.transaction_height(tx_id)?;
+        let tip = self.blocks.last()?.index;
+        Some(tip.saturating_sub(height) + 1)
+    }
+
+    pub(crate) fn is_confirmed(&self, tx_id: &str, min_confirmations: u64) -> bool {
+        self.confirmations(tx_id).is_some_and(|confirmations| confirmations >= min_confirmations)
+    }
+
+    /// Verify hash linkage, block hashes, proof of work, checkpoints and that every
+    /// transaction can be replayed against the balances built up by earlier blocks
+    pub(crate) fn validate(&self) -> Result<(), StoreError> {
+        let newest_checkpoint = self.network.newest_checkpoint_height();
+        let mut state = AccountState::new().with_coinbase_maturity(self.network.coinbase_maturity);
+
+        for (position, block) in self.blocks.iter().enumerate() {
+            if block.index != position as u64 {
+                return Err(StoreError::ValidationError(format!(
+                    "Block at position {} has index {}", position, block.index
+                )));
             }
-            Err(e) => {
-                panic!("Failed to read blockchain file: {}", e);
+
+            block.validate(&self.network)?;
+            self.check_checkpoint(block)?;
+            state.at_height(block.index);
+            for tx in &block.transactions {
+                state.apply_transaction(tx)?;
+            }
+
+            if position == 0 {
+                continue;
+            }
+
+            let previous = &self.blocks[position - 1];
+            if block.previous_block_hash != previous.current_block_hash {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} does not link to block {}", block.index, previous.index
+                )));
+            }
+
+            let expected_difficulty = self.difficulty_after(&self.blocks[..position]);
+            if block.difficulty != expected_difficulty {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} difficulty {} does not match expected {}",
+                    block.index, block.difficulty, expected_difficulty
+                )));
+            }
+
+            let expected_base_fee = self.base_fee_after(previous);
+            if block.base_fee != expected_base_fee {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} base fee {} does not match expected {}", block.index, block.base_fee, expected_base_fee
+                )));
+            }
+
+            let trusted = self.network.skip_pow_below_checkpoint
+                && newest_checkpoint.is_some_and(|height| block.index <= height);
+            if !trusted && !block.has_valid_proof_of_work() {
+                return Err(StoreError::ValidationError(format!(
+                    "Block {} does not meet its difficulty", block.index
+                )));
             }
         }
+
+        Ok(())
     }
 
-    fn save_to_file(&self, filename: &str) {
-        match serde_json::to_string_pretty(self) {
-            Ok(json) => {
-                if let Err(e) = fs::write(filename, json) {
-                    eprintln!("Failed to save blockchain to {}: {}", filename, e);
-                } else {
-                    println!("Blockchain saved to {}", filename);
-                }
-            }
-            Err(e) => {
-                eprintln!("Error serializing blockchain: {}", e);
-                return;
+    /// Height of the newest final block, `finality_depth` below the tip. `None` while finality is disabled or
+    /// the chain is not yet that long.
+    pub(crate) fn finalized_height(&self) -> Option<u64> {
+        let depth = self.network.finality_depth?;
+        self.blocks.last()?.index.checked_sub(depth)
+    }
+
+    /// Refuse to write a block at `height` when a final block already sits there
+    pub(crate) fn check_finality(&self, height: u64) -> Result<(), StoreError> {
+        match self.finalized_height() {
+            Some(finalized) if height <= finalized => Err(StoreError::ValidationError(format!(
+                "Block {} would replace a finalized block, the chain is final up to height {}", height, finalized
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject a block sitting at a checkpoint height with a different hash
+    pub(crate) fn check_checkpoint(&self, block: &Block) -> Result<(), StoreError> {
+        match self.network.checkpoint_at(block.index) {
+            Some(expected) if block.current_block_hash.as_ref() != Some(expected) => {
+                Err(StoreError::ValidationError(format!(
+                    "Block {} conflicts with checkpoint {}", block.index, expected.value
+                )))
             }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::network::{DEFAULT_CHAIN_ID, DEFAULT_DIFFICULTY};
+    use secp256k1::SecretKey;
+    use crate::transaction::TRANSFER_GAS;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn next_block(chain: &Chain, transactions: Vec<Transaction>) -> Block {
+        let previous = chain.blocks.last().unwrap();
+        let mut block = Block::new(
+            previous.index + 1,
+            transactions,
+            previous.current_block_hash.clone().unwrap(),
+            chain.next_difficulty(),
+        )
+        .with_base_fee(chain.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        block
+    }
+
+    fn append_block(chain: &mut Chain, transactions: Vec<Transaction>) {
+        let block = next_block(chain, transactions);
+        chain.add_block(block).unwrap();
+    }
+
+    /// Memory store counting how often the chain is written
+    #[derive(Default)]
+    struct CountingStore {
+        inner: MemoryStore,
+        persists: AtomicUsize,
+    }
+
+    impl BlockStore for CountingStore {
+        fn persist(&self, chain: &Chain) -> Result<(), StoreError> {
+            self.persists.fetch_add(1, Ordering::Relaxed);
+            self.inner.persist(chain)
+        }
+
+        fn load(&self) -> Result<Option<Chain>, StoreError> {
+            self.inner.load()
+        }
+    }
+
+    /// `count` consecutive blocks extending `chain`, each paying the subsidy to a fresh account
+    fn batch_of(chain: &Chain, count: usize) -> Vec<Block> {
+        let mut scratch = chain.detached();
+        (0..count)
+            .map(|_| {
+                let reward = Transaction::coinbase(Address::generate().0, scratch.network().block_subsidy);
+                let block = next_block(&scratch, vec![reward]);
+                scratch.add_block(block.clone()).unwrap();
+                block
+            })
+            .collect()
+    }
+
+    /// Mine a block paying the subsidy to a fresh account and return its keys
+    fn fund_account(chain: &mut Chain) -> (Address, SecretKey) {
+        let (address, secret_key, _) = Address::generate();
+        let subsidy = chain.network().block_subsidy;
+        append_block(chain, vec![Transaction::coinbase(address.clone(), subsidy)]);
+        (address, secret_key)
+    }
+
+    fn transfer(chain: &Chain, from: &Address, secret_key: &SecretKey, fee: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new(from.clone(), Address::generate().0, 10)
+            .with_nonce(nonce)
+            .with_gas(TRANSFER_GAS, fee);
+        tx.sign(secret_key, chain.network().chain_id).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_block_statistics() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut chain = Chain::from_genesis(&GenesisConfig { timestamp: start, ..GenesisConfig::default() });
+        let clock = MockClock::new(start);
+        for (seconds, transactions) in [(10, 1), (20, 2), (30, 3)] {
+            clock.advance(chrono::Duration::seconds(seconds));
+            let previous = chain.blocks.last().unwrap();
+            let rewards = (0..transactions)
+                .map(|_| Transaction::coinbase(Address::generate().0, chain.network().block_subsidy))
+                .collect::<Vec<_>>();
+            let previous_hash = previous.current_block_hash.clone().unwrap();
+            let block = Block::new_with_clock(previous.index + 1, rewards, previous_hash, 4, &clock);
+            // Only the statistics read these blocks, so they skip `add_block` and mining
+            chain.blocks.push(block);
+        }
+
+        // Blocks at 0s, 10s, 30s and 60s
+        assert_eq!(chain.average_block_time(4), Some(20.0));
+        assert_eq!(chain.average_block_time(2), Some(30.0));
+        assert_eq!(chain.average_block_time(100), Some(20.0));
+        assert_eq!(chain.average_block_time(1), None);
+        assert_eq!(chain.transactions_per_second(4), Some(0.1));
+        assert_eq!(chain.transactions_per_second(2), Some(0.1));
+        assert_eq!(chain.transactions_per_second(1), None);
+
+        let sizes: Vec<usize> = chain.blocks.iter().map(|block| serde_json::to_vec(block).unwrap().len()).collect();
+        assert_eq!(chain.average_block_size(100), Some(sizes.iter().sum::<usize>() as f64 / 4.0));
+        assert_eq!(chain.average_block_size(1), Some(sizes[3] as f64));
+        assert_eq!(chain.average_block_size(0), None);
+        assert_eq!(Chain::new_easy().average_block_time(10), None);
+    }
+
+    /// Chain whose blocks after genesis are stamped `offsets` seconds after the genesis block
+    fn timed_chain(offsets: impl IntoIterator<Item = i64>) -> Chain {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut chain = Chain::from_genesis(&GenesisConfig { timestamp: start, ..GenesisConfig::default() });
+        for offset in offsets {
+            let clock = MockClock::new(start + chrono::Duration::seconds(offset));
+            let previous = chain.blocks.last().unwrap();
+            let previous_hash = previous.current_block_hash.clone().unwrap();
+            let block = Block::new_with_clock(previous.index + 1, vec![], previous_hash, 4, &clock);
+            // Only timestamps matter here, so the blocks skip `add_block` and mining
+            chain.blocks.push(block);
+        }
+        chain
+    }
+
+    #[test]
+    fn test_retarget_ignores_outlier_timestamp() {
+        let target = NetworkConfig::default().target_block_time_secs as i64;
+        // Genesis and 32 more blocks, three windows, so the next block starts a window and retargets
+        let steady = timed_chain((1..33).map(|height| height * target));
+        assert_eq!(steady.next_difficulty(), 4);
+
+        // One block claims to be hours ahead, the median of its window does not move
+        let skewed = timed_chain((1..33).map(|height| if height == 30 { 100 * target } else { height * target }));
+        assert_eq!(skewed.next_difficulty(), 4);
+        assert_eq!(skewed.median_time_past(), steady.median_time_past());
+
+        // Too short to retarget
+        assert_eq!(timed_chain((1..5).map(|height| height * 10)).next_difficulty(), 4);
+    }
+
+    #[test]
+    fn test_retarget_changes_work_4x_at_most() {
+        let target = NetworkConfig::default().target_block_time_secs as i64;
+        // One bit, twice the work, for blocks twice as fast; two bits at most however fast they come
+        assert_eq!(timed_chain((1..33).map(|height| height * target / 2)).next_difficulty(), 5);
+        assert_eq!(timed_chain((1..33).map(|height| height * target / 5)).next_difficulty(), 6);
+        assert_eq!(timed_chain(1..33).next_difficulty(), 6);
+        assert_eq!(timed_chain((1..33).map(|height| height * target * 2)).next_difficulty(), 3);
+        assert_eq!(timed_chain((1..33).map(|height| height * target * 5)).next_difficulty(), 2);
+        assert_eq!(timed_chain((1..33).map(|height| height * target * 100)).next_difficulty(), 2);
+        assert_eq!(timed_chain((1..33).map(|height| height * target * 3 / 2)).next_difficulty(), 4);
+
+        let mut easiest = timed_chain((1..33).map(|height| height * target * 5));
+        for block in &mut easiest.blocks {
+            block.difficulty = 1;
+        }
+        assert_eq!(easiest.next_difficulty(), 1);
+    }
+
+    /// Chain retargeting every 2 blocks from difficulty 6, its genesis stamped a month ago
+    fn retargeting_chain() -> Chain {
+        let genesis = GenesisConfig {
+            timestamp: Utc::now() - chrono::Duration::days(30),
+            difficulty: 6,
+            ..GenesisConfig::default()
+        };
+        Chain::from_genesis(&genesis).with_network(NetworkConfig { retarget_window: 2, ..NetworkConfig::default() })
+    }
+
+    #[test]
+    fn test_blocks_on_target_keep_difficulty_flat() {
+        let mut chain = retargeting_chain();
+        let target = chain.network().target_block_time_secs as i64;
+        for height in 1..=12 {
+            append_block_at(&mut chain, height * target);
+        }
+        assert!(chain.blocks.iter().all(|block| block.difficulty == 6));
+        assert_eq!(chain.next_difficulty(), 6);
+    }
+
+    #[test]
+    fn test_slow_window_moves_difficulty_one_step() {
+        let mut chain = retargeting_chain();
+        let target = chain.network().target_block_time_secs as i64;
+        let mut offset = 0;
+        for height in 1..=11 {
+            // Blocks 6 and 7 make a window a hundred times slower than the target
+            offset += if (6..=7).contains(&height) { 100 * target } else { target };
+            append_block_at(&mut chain, offset);
+        }
+
+        // Every later window starting at the target pace, the slow one lowers the difficulty once, by the cap
+        let difficulties: Vec<u32> = chain.blocks.iter().map(|block| block.difficulty).collect();
+        assert_eq!(difficulties, [vec![6; 8], vec![6 - MAX_RETARGET_BITS; 4]].concat());
+        assert_eq!(chain.next_difficulty(), 6 - MAX_RETARGET_BITS);
+    }
+
+    #[test]
+    fn test_new_chain_starts_with_genesis() {
+        let chain = Chain::new_easy();
+        assert_eq!(chain.blocks.len(), 1);
+        assert_eq!(chain.blocks[0].index, 0);
+    }
+
+    #[test]
+    fn test_genesis_extra_data_changes_genesis_hash() {
+        let mainnet = GenesisConfig {
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            difficulty: 4,
+            extra_data: b"ola mainnet".to_vec(),
+            ..GenesisConfig::default()
+        };
+        let testnet = GenesisConfig { extra_data: b"ola testnet".to_vec(), ..mainnet.clone() };
+
+        assert_eq!(Chain::from_genesis(&mainnet).genesis_block_hash, Chain::from_genesis(&mainnet).genesis_block_hash);
+        assert_ne!(Chain::from_genesis(&mainnet).genesis_block_hash, Chain::from_genesis(&testnet).genesis_block_hash);
+        assert!(Chain::from_genesis(&testnet).blocks[0].data.ends_with(b"ola testnet"));
+    }
+
+    #[test]
+    fn test_network_id_and_version_change_genesis_hash() {
+        let mainnet = GenesisConfig {
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            ..GenesisConfig::default()
+        };
+        let other_network = GenesisConfig { network_id: mainnet.network_id + 1, ..mainnet.clone() };
+        let next_version = GenesisConfig { version: mainnet.version + 1, ..mainnet.clone() };
+
+        let genesis_hash = Chain::from_genesis(&mainnet).genesis_block_hash;
+        assert_ne!(genesis_hash, Chain::from_genesis(&other_network).genesis_block_hash);
+        assert_ne!(genesis_hash, Chain::from_genesis(&next_version).genesis_block_hash);
+        assert!(Chain::from_genesis(&other_network).validate().is_ok());
+    }
+
+    #[test]
+    fn test_network_id_follows_configuration() {
+        assert_eq!(Chain::new_easy().network_id(), DEFAULT_CHAIN_ID);
+        let chain = Chain::from_genesis(&GenesisConfig { network_id: 7, ..GenesisConfig::default() });
+        assert_eq!(chain.network_id(), 7);
+
+        let config = Config { chain_id: 9, ..Config::default() };
+        assert_eq!(Chain::new_easy().with_network(config.network()).network_id(), 9);
+        assert_eq!(config.genesis().network_id, 9);
+    }
+
+    #[test]
+    fn test_confirmations_grow_with_new_blocks() {
+        let mut chain = Chain::new_easy();
+        fund_account(&mut chain);
+        let tx_id = chain.blocks[1].transactions[0].id.clone();
+        assert_eq!(chain.confirmations(&tx_id), Some(1));
+        assert!(chain.is_confirmed(&tx_id, 1));
+        assert!(!chain.is_confirmed(&tx_id, 2));
+
+        fund_account(&mut chain);
+        fund_account(&mut chain);
+        assert_eq!(chain.confirmations(&tx_id), Some(3));
+        assert!(chain.is_confirmed(&tx_id, 3));
+        assert!(!chain.is_confirmed(&tx_id, 4));
+
+        assert_eq!(chain.confirmations("unknown"), None);
+        assert!(!chain.is_confirmed("unknown", 0));
+    }
+
+    #[test]
+    fn test_tip_header_follows_newest_block() {
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        assert_eq!(chain.tip_header().unwrap().hash, genesis_hash);
+
+        fund_account(&mut chain);
+        let tip = chain.tip_header().unwrap();
+        assert_eq!(tip.index, 1);
+        assert_eq!(chain.get_block_by_hash(&tip.hash).unwrap().index, 1);
+        assert!(chain.get_block_by_hash(&Hash::genesis()).is_none());
+    }
+
+    #[test]
+    fn test_total_work_sums_difficulties() {
+        let mut chain = Chain::from_genesis(&GenesisConfig { difficulty: 2, ..GenesisConfig::default() });
+        assert_eq!(chain.total_work(), 4);
+        append_block(&mut chain, vec![]);
+        append_block(&mut chain, vec![]);
+        assert_eq!(chain.total_work(), 12);
+        assert_eq!(chain.tip_work(), Some((chain.tip_header().unwrap(), 12)));
+
+        // Each bit of difficulty doubles the work, one harder block outweighs several easier ones
+        let harder = Chain::from_genesis(&GenesisConfig { difficulty: 4, ..GenesisConfig::default() });
+        assert!(harder.total_work() > chain.total_work());
+    }
+
+    #[test]
+    fn test_history_filters_by_address() {
+        let alice = Address::generate().0;
+        let bob = Address::generate().0;
+        let carol = Address::generate().0;
+
+        // History only reads blocks, so skip validation and push them directly
+        let mut chain = Chain::new_easy();
+        let blocks = [
+            vec![
+                Transaction::new(alice.clone(), bob.clone(), 10),
+                Transaction::new(bob.clone(), carol.clone(), 20),
+            ],
+            vec![Transaction::new(carol.clone(), bob.clone(), 30)],
+            vec![Transaction::new(bob.clone(), alice.clone(), 40)],
+        ];
+        for transactions in blocks {
+            let block = next_block(&chain, transactions);
+            chain.blocks.push(block);
+        }
+
+        let alice_history = chain.history(&alice);
+        let summary: Vec<(u64, u64)> = alice_history.iter().map(|(block, tx)| (block.index, tx.amount)).collect();
+        assert_eq!(summary, vec![(1, 10), (3, 40)]);
+
+        let bob_history = chain.history(&bob);
+        assert_eq!(bob_history.len(), 4);
+
+        let carol_history = chain.history(&carol);
+        let summary: Vec<(u64, u64)> = carol_history.iter().map(|(block, tx)| (block.index, tx.amount)).collect();
+        assert_eq!(summary, vec![(1, 20), (2, 30)]);
+
+        let stranger = Address::generate().0;
+        assert!(chain.history(&stranger).is_empty());
+    }
+
+    #[test]
+    fn test_history_exported_as_csv_and_json() {
+        let alice = Address::generate().0;
+        let bob = Address::generate().0;
+        let carol = Address::generate().0;
+
+        let mut chain = Chain::new_easy();
+        let to_bob = Transaction::new(alice.clone(), bob.clone(), 10).with_gas(TRANSFER_GAS, 2);
+        let from_bob = Transaction::new(bob.clone(), alice.clone(), 40).with_gas(TRANSFER_GAS, 3);
+        let blocks = [
+            vec![to_bob.clone(), Transaction::new(bob.clone(), carol.clone(), 20)],
+            vec![from_bob.clone()],
+        ];
+        for transactions in blocks {
+            let block = next_block(&chain, transactions);
+            chain.blocks.push(block);
+        }
+
+        let csv = chain.export_history(&alice, ExportFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "block_index,timestamp,transaction_id,direction,counterparty,amount,fee");
+        assert_eq!(lines.len(), 3);
+        let first = format!("1,{},{},out,{},10,2", chain.blocks[1].timestamp.to_rfc3339(), to_bob.id, bob.value);
+        let second = format!("2,{},{},in,{},40,0", chain.blocks[2].timestamp.to_rfc3339(), from_bob.id, bob.value);
+        assert_eq!(lines[1], first);
+        assert_eq!(lines[2], second);
+
+        let json: serde_json::Value = serde_json::from_str(&chain.export_history(&alice, ExportFormat::Json)).unwrap();
+        let rows = json.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["block_index"], 1);
+        assert_eq!(rows[0]["direction"], "out");
+        assert_eq!(rows[0]["counterparty"], bob.value);
+        assert_eq!(rows[0]["amount"], 10);
+        assert_eq!(rows[0]["fee"], 2);
+        assert_eq!(rows[1]["transaction_id"], from_bob.id);
+        assert_eq!(rows[1]["direction"], "in");
+        assert_eq!(rows[1]["amount"], 40);
+        assert_eq!(rows[1]["fee"], 0);
+
+        let stranger = Address::generate().0;
+        assert_eq!(chain.export_history(&stranger, ExportFormat::Csv).lines().count(), 1);
+        assert_eq!(chain.export_history(&stranger, ExportFormat::Json), "[]");
+    }
+
+    #[test]
+    fn test_serde_round_trip_keeps_blocks() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        append_block(&mut chain, vec![tx]);
+        append_block(&mut chain, vec![]);
+
+        let restored: Chain = serde_json::from_str(&serde_json::to_string(&chain).unwrap()).unwrap();
+        assert_eq!(restored.blocks.len(), 4);
+        assert_eq!(block_hashes(&restored), block_hashes(&chain));
+        assert_eq!(restored.blocks[2].transactions[0].id, chain.blocks[2].transactions[0].id);
+        assert!(restored.blocks.iter().all(Block::has_valid_hash));
+        restored.check_genesis().unwrap();
+        restored.validate().unwrap();
+    }
+
+    #[test]
+    fn test_verify_block_inclusion() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let transfers: Vec<Transaction> =
+            (0..4).map(|nonce| transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, nonce)).collect();
+        let mut transactions = vec![Transaction::coinbase(Address::generate().0, chain.network().block_subsidy + 4)];
+        transactions.extend(transfers.clone());
+        append_block(&mut chain, transactions);
+
+        for tx in &transfers {
+            let (header, proof) = chain.merkle_proof_for(&tx.id).unwrap();
+            assert_eq!(header, chain.blocks[2].header());
+            assert!(Chain::verify_block_inclusion(&header, tx, &proof));
+        }
+        assert!(chain.merkle_proof_for("unknown").is_none());
+
+        // The same proof against another block's root
+        let (header, proof) = chain.merkle_proof_for(&transfers[1].id).unwrap();
+        let other = chain.blocks[1].header();
+        assert!(!Chain::verify_block_inclusion(&other, &transfers[1], &proof));
+
+        // A transaction changed after the fact, with or without recomputing its id
+        let mut tampered = transfers[1].clone();
+        tampered.amount += 1;
+        assert!(!Chain::verify_block_inclusion(&header, &tampered, &proof));
+        let nonce = tampered.nonce;
+        let tampered = tampered.with_nonce(nonce);
+        assert!(!Chain::verify_block_inclusion(&header, &tampered, &proof));
+        assert!(!Chain::verify_block_inclusion(&header, &transfers[2], &proof));
+    }
+
+    #[test]
+    fn test_find_transaction_after_add() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let coinbase_id = chain.blocks[1].transactions[0].id.clone();
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        let tx_id = tx.id.clone();
+        let coinbase = Transaction::coinbase(Address::generate().0, chain.network().block_subsidy + 1);
+        append_block(&mut chain, vec![coinbase, tx]);
+
+        assert_eq!(chain.find_transaction(&coinbase_id).map(|(height, _)| height), Some(1));
+        let (height, found) = chain.find_transaction(&tx_id).unwrap();
+        assert_eq!((height, found.id.as_str()), (2, tx_id.as_str()));
+        assert_eq!(chain.tx_index.get(&tx_id), Some(&(2, 1)));
+        assert!(chain.find_transaction("unknown").is_none());
+
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        store.persist(&chain).unwrap();
+        let loaded = Chain::open(store, true, &Config::default()).unwrap();
+        assert_eq!(loaded.transaction_height(&tx_id), Some(2));
+    }
+
+    #[test]
+    fn test_reindex_recovers_corrupt_derived_state() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        let tx_id = tx.id.clone();
+        append_block(&mut chain, vec![tx]);
+        let replayed = AccountState::from_chain(&chain).unwrap();
+
+        let mut corrupt = chain.clone();
+        corrupt.genesis_block_hash = Hash::new(b"corrupt");
+        corrupt.rebuild_index();
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        store.persist(&corrupt).unwrap();
+        assert!(Chain::open(store.clone(), true, &Config::default()).is_err());
+
+        let reindexed = Chain::reindex_store(store.clone(), &Config::default()).unwrap();
+        assert_eq!(block_hashes(&reindexed), block_hashes(&chain));
+        assert_eq!(reindexed.difficulty, MIN_DIFFICULTY);
+        let state = AccountState::from_chain(&reindexed).unwrap();
+        assert_eq!(state.balance(&alice), replayed.balance(&alice));
+        assert_eq!(state.nonce(&alice), replayed.nonce(&alice));
+        assert_eq!(reindexed.transaction_height(&tx_id), Some(2));
+
+        // The repaired chain was persisted and loads normally
+        let loaded = Chain::open(store, true, &Config::default()).unwrap();
+        assert_eq!(loaded.blocks.len(), chain.blocks.len());
+    }
+
+    #[test]
+    fn test_rebuild_index_restores_lookups() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        let tx_id = tx.id.clone();
+        append_block(&mut chain, vec![tx]);
+        let tip = chain.tip_header().unwrap();
+
+        chain.tx_index.clear();
+        chain.hash_index.clear();
+        assert!(chain.get_block_by_hash(&tip.hash).is_none());
+        assert!(chain.find_transaction(&tx_id).is_none());
+
+        chain.rebuild_index();
+        assert_eq!(chain.get_block_by_hash(&tip.hash).unwrap().index, 2);
+        assert_eq!(chain.get_block_by_hash(&chain.genesis_block_hash.clone()).unwrap().index, 0);
+        assert_eq!(chain.transaction_height(&tx_id), Some(2));
+    }
+
+    #[test]
+    fn test_reorg_moves_transactions_in_index() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        let tx_id = tx.id.clone();
+
+        let mut fork = chain.clone();
+        append_block(&mut chain, vec![tx.clone()]);
+        fund_account(&mut chain);
+        let dropped_id = chain.blocks[3].transactions[0].id.clone();
+        assert_eq!(chain.transaction_height(&tx_id), Some(2));
+
+        append_block(&mut fork, vec![]);
+        append_block(&mut fork, vec![tx]);
+        append_block(&mut fork, vec![]);
+
+        // No more work than the current chain
+        assert!(chain.try_reorg(fork.blocks[2..4].to_vec()).is_err());
+        assert_eq!(chain.transaction_height(&tx_id), Some(2));
+
+        chain.try_reorg(fork.blocks[2..].to_vec()).unwrap();
+        assert_eq!(block_hashes(&chain), block_hashes(&fork));
+        assert_eq!(chain.find_transaction(&tx_id).map(|(height, _)| height), Some(3));
+        assert!(chain.find_transaction(&dropped_id).is_none());
+    }
+
+    #[test]
+    fn test_tip_state_follows_add_block_and_reorg() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let mut fork = chain.clone();
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        append_block(&mut chain, vec![tx]);
+        let (tip_hash, state) = chain.tip_state.clone().unwrap();
+        assert_eq!(Some(tip_hash), chain.blocks.last().unwrap().current_block_hash);
+        assert_eq!(state, AccountState::from_chain(&chain).unwrap());
+
+        append_block(&mut fork, vec![]);
+        fund_account(&mut fork);
+        chain.try_reorg(fork.blocks[2..].to_vec()).unwrap();
+        let (tip_hash, state) = chain.tip_state.clone().unwrap();
+        assert_eq!(Some(tip_hash), chain.blocks.last().unwrap().current_block_hash);
+        assert_eq!(state, AccountState::from_chain(&chain).unwrap());
+        assert_eq!(state.nonce(&alice), 0);
+    }
+
+    /// Append an empty block stamped `secs` seconds after the genesis block
+    fn append_block_at(chain: &mut Chain, secs: i64) {
+        let clock = MockClock::new(chain.genesis_block().timestamp + chrono::Duration::seconds(secs));
+        let previous = chain.blocks.last().unwrap();
+        let previous_hash = previous.current_block_hash.clone().unwrap();
+        let difficulty = chain.next_difficulty();
+        let mut block = Block::new_with_clock(previous.index + 1, vec![], previous_hash, difficulty, &clock)
+            .with_base_fee(chain.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_reorg_onto_shorter_branch_with_more_work() {
+        let network = NetworkConfig { retarget_window: 1, ..NetworkConfig::default() };
+        let target = network.target_block_time_secs as i64;
+        let genesis = GenesisConfig {
+            timestamp: Utc::now() - chrono::Duration::days(1),
+            difficulty: MIN_DIFFICULTY,
+            ..GenesisConfig::default()
+        };
+        let mut chain = Chain::from_genesis(&genesis).with_network(network);
+        let mut fork = chain.detached();
+
+        // Slow blocks keep the difficulty at its minimum, fast ones raise it
+        for height in 1..=4 {
+            append_block_at(&mut chain, height * 4 * target);
+        }
+        for height in 1..=3 {
+            append_block_at(&mut fork, height);
+        }
+        assert!(fork.blocks.len() < chain.blocks.len());
+        assert!(fork.total_work() > chain.total_work());
+        let cheap = chain.blocks[1..].to_vec();
+
+        chain.try_reorg(fork.blocks[1..].to_vec()).unwrap();
+        assert_eq!(block_hashes(&chain), block_hashes(&fork));
+
+        // The longer branch carrying less work is refused
+        let error = chain.try_reorg(cheap).unwrap_err();
+        assert!(error.to_string().contains("no more than"));
+        assert_eq!(block_hashes(&chain), block_hashes(&fork));
+    }
+
+    #[test]
+    fn test_failed_reorg_leaves_chain_untouched() {
+        let store = Arc::new(CountingStore::default());
+        let mut chain = Chain::new_easy().with_store(store.clone());
+        let (alice, alice_key) = fund_account(&mut chain);
+        let fork = chain.detached();
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        append_block(&mut chain, vec![tx.clone()]);
+        let persists = store.persists.load(Ordering::Relaxed);
+        let hashes = block_hashes(&chain);
+
+        // The second block of the longer branch is invalid, the first one alone would have been accepted
+        let mut branch = batch_of(&fork, 3);
+        branch[1].nonce += 1;
+        let events = chain.events().subscribe();
+        assert!(chain.try_reorg(branch).is_err());
+
+        assert_eq!(block_hashes(&chain), hashes);
+        assert_eq!(chain.transaction_height(&tx.id), Some(2));
+        assert_eq!(store.persists.load(Ordering::Relaxed), persists);
+        assert_eq!(block_hashes(&store.load().unwrap().unwrap()), hashes);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_reorg_returns_dropped_transactions() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key) = fund_account(&mut chain);
+        let fork = chain.detached();
+        let tx = transfer(&chain, &alice, &alice_key, chain.next_base_fee() + 1, 0);
+        append_block(&mut chain, vec![tx.clone()]);
+
+        let branch = batch_of(&fork, 2);
+        let events = chain.events().subscribe();
+        let returned = chain.try_reorg(branch.clone()).unwrap();
+        assert_eq!(returned, vec![tx.clone()]);
+        assert!(chain.find_transaction(&tx.id).is_none());
+        assert_eq!(chain.tip_header(), branch.last().map(Block::header));
+        assert_eq!(block_hashes(&chain.store().load().unwrap().unwrap()), block_hashes(&chain));
+        assert_eq!(events.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_reorg_depth_limited() {
+        let network = NetworkConfig { max_reorg_depth: 2, ..NetworkConfig::default() };
+        let mut chain = Chain::new_easy().with_network(network);
+        fund_account(&mut chain);
+        let fork = chain.clone();
+        for _ in 0..3 {
+            fund_account(&mut chain);
+        }
+
+        // Forking 3 blocks below the tip is refused however long the branch is
+        let mut deep = fork.clone();
+        for _ in 0..10 {
+            fund_account(&mut deep);
+        }
+        let error = chain.try_reorg(deep.blocks[2..].to_vec()).unwrap_err();
+        assert!(error.to_string().contains("maximum reorg depth"));
+        assert_eq!(chain.blocks.len(), 5);
+
+        // Forking 2 blocks below the tip is within the limit
+        let mut shallow = chain.clone();
+        shallow.blocks.truncate(3);
+        for _ in 0..3 {
+            fund_account(&mut shallow);
+        }
+        chain.try_reorg(shallow.blocks[3..].to_vec()).unwrap();
+        assert_eq!(block_hashes(&chain), block_hashes(&shallow));
+    }
+
+    #[test]
+    fn test_finality_advances_with_the_tip() {
+        let network = NetworkConfig { finality_depth: Some(2), ..NetworkConfig::default() };
+        let mut chain = Chain::new_easy().with_network(network);
+        assert_eq!(chain.finalized_height(), None);
+        fund_account(&mut chain);
+        assert_eq!(chain.finalized_height(), None);
+        fund_account(&mut chain);
+        assert_eq!(chain.finalized_height(), Some(0));
+        fund_account(&mut chain);
+        assert_eq!(chain.finalized_height(), Some(1));
+
+        assert_eq!(Chain::new_easy().finalized_height(), None);
+    }
+
+    #[test]
+    fn test_finalized_blocks_cannot_be_replaced() {
+        let network = NetworkConfig { finality_depth: Some(2), ..NetworkConfig::default() };
+        let mut chain = Chain::new_easy().with_network(network);
+        fund_account(&mut chain);
+        let fork = chain.clone();
+        for _ in 0..3 {
+            fund_account(&mut chain);
+        }
+        assert_eq!(chain.finalized_height(), Some(2));
+
+        // Forking off block 1 would replace final block 2, however long the branch and within the reorg depth
+        let mut branch = fork.clone();
+        for _ in 0..10 {
+            fund_account(&mut branch);
+        }
+        let error = chain.try_reorg(branch.blocks[2..].to_vec()).unwrap_err();
+        assert!(error.to_string().contains("finalized"));
+        assert_eq!(chain.blocks.len(), 5);
+
+        // A competing block at a final height is refused too
+        let competing = branch.blocks[2].clone();
+        assert!(chain.add_block(competing).unwrap_err().to_string().contains("finalized"));
+        assert_eq!(chain.blocks.len(), 5);
+
+        // Forking off the newest final block leaves it in place
+        let mut shallow = chain.clone();
+        shallow.blocks.truncate(3);
+        for _ in 0..3 {
+            fund_account(&mut shallow);
+        }
+        chain.try_reorg(shallow.blocks[3..].to_vec()).unwrap();
+        assert_eq!(block_hashes(&chain), block_hashes(&shallow));
+    }
+
+    #[test]
+    fn test_block_added_event_delivered() {
+        let mut chain = Chain::new_easy();
+        let events = chain.events().subscribe();
+        fund_account(&mut chain);
+
+        let tip = chain.tip_header().unwrap();
+        assert_eq!(events.try_recv().unwrap(), ChainEvent::BlockAdded(tip));
+        assert!(events.try_recv().is_err());
+
+        // A rejected block publishes nothing
+        let mut unmined = next_block(&chain, vec![]);
+        unmined.nonce += 1;
+        assert!(chain.add_block(unmined).is_err());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_add_block_rejects_unmined_block() {
+        let mut chain = Chain::from_genesis(&GenesisConfig { difficulty: 16, ..GenesisConfig::default() });
+        let previous = chain.blocks.last().unwrap();
+        // Hashed correctly but never mined, so the hash almost surely lacks the 16 leading zero bits
+        let unmined = Block::new(
+            previous.index + 1,
+            vec![],
+            previous.current_block_hash.clone().unwrap(),
+            chain.next_difficulty(),
+        )
+        .with_base_fee(chain.next_base_fee());
+        assert_eq!(unmined.difficulty, 16);
+        assert!(unmined.has_valid_hash());
+        assert!(!unmined.has_valid_proof_of_work());
+
+        let Err(StoreError::ValidationError(message)) = chain.add_block(unmined) else {
+            panic!("an unmined block should be rejected")
         };
+        assert!(message.contains("does not meet its difficulty"));
+        assert_eq!(chain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_validate_mined_chain() {
+        let mut chain = Chain::new_easy();
+        append_block(&mut chain, vec![]);
+        append_block(&mut chain, vec![]);
+        assert!(chain.validate().is_ok());
     }
 
+    #[test]
+    fn test_validate_rejects_broken_link() {
+        let mut chain = Chain::new_easy();
+        append_block(&mut chain, vec![]);
+        append_block(&mut chain, vec![]);
+        chain.blocks[2].previous_block_hash = Some(Hash::genesis());
+        assert!(chain.validate().is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_match_passes() {
+        let mut chain = Chain::new_easy();
+        append_block(&mut chain, vec![]);
+        let checkpoint_hash = chain.blocks[1].current_block_hash.clone().unwrap();
+
+        let mut network = NetworkConfig::default();
+        network.checkpoints.insert(1, checkpoint_hash);
+        let mut chain = chain.with_network(network);
+
+        assert!(chain.validate().is_ok());
+        append_block(&mut chain, vec![]);
+        assert!(chain.validate().is_ok());
+    }
+
+    #[test]
+    fn test_checkpoint_conflict_rejected() {
+        let mut network = NetworkConfig::default();
+        network.checkpoints.insert(1, Hash::new(b"trusted block"));
+
+        let mut chain = Chain::new_easy();
+        append_block(&mut chain, vec![]);
+        let chain = chain.with_network(network.clone());
+        assert!(matches!(chain.validate(), Err(StoreError::ValidationError(_))));
+
+        // Incoming blocks at the checkpoint height are refused too
+        let mut chain = Chain::new_easy().with_network(network);
+        let block = next_block(&chain, vec![]);
+        assert!(chain.add_block(block).is_err());
+        assert_eq!(chain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_skip_pow_below_checkpoint() {
+        let mut chain = Chain::new();
+        // Hashed but never mined, so it does not meet the default difficulty
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let unmined = Block::new(1, vec![], genesis_hash, DEFAULT_DIFFICULTY);
+        let unmined_hash = unmined.current_block_hash.clone().unwrap();
+        chain.blocks.push(unmined);
+
+        let mut network = NetworkConfig::default();
+        network.checkpoints.insert(1, unmined_hash);
+        let chain = chain.with_network(network.clone());
+        assert!(chain.validate().is_err());
+
+        network.skip_pow_below_checkpoint = true;
+        let chain = chain.with_network(network);
+        assert!(chain.validate().is_ok());
+    }
+
+    #[test]
+    fn test_memory_backed_chain_add_and_get() {
+        let store = Arc::new(MemoryStore::new());
+        let mut chain = Chain::new_easy().with_store(store.clone());
+        append_block(&mut chain, vec![]);
+        append_block(&mut chain, vec![]);
+
+        assert_eq!(chain.get_block(2).unwrap().index, 2);
+        assert!(chain.get_block(3).is_none());
+
+        let persisted = store.load().unwrap().unwrap();
+        assert_eq!(persisted.blocks.len(), 3);
+        assert_eq!(persisted.blocks[2].current_block_hash, chain.blocks[2].current_block_hash);
+    }
+
+    #[test]
+    fn test_memory_store_discarded_on_drop() {
+        let store = Arc::new(MemoryStore::new());
+        let weak_store = Arc::downgrade(&store);
+
+        let mut chain = Chain::new_easy().with_store(store);
+        append_block(&mut chain, vec![]);
+        assert!(weak_store.upgrade().is_some());
+
+        drop(chain);
+        assert!(weak_store.upgrade().is_none());
+        assert_eq!(Chain::new_easy().blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_base_fee_follows_block_fullness() {
+        let network = NetworkConfig {
+            initial_base_fee: 80,
+            target_block_transactions: 1,
+            block_subsidy: 1_000,
+            ..NetworkConfig::default()
+        };
+        let mut chain = Chain::new_easy().with_network(network);
+        assert_eq!(chain.next_base_fee(), 80);
+
+        let (sender, secret_key) = fund_account(&mut chain);
+        // An empty block against a target of one lowers the fee by 1/8
+        assert_eq!(chain.next_base_fee(), 70);
+
+        let transactions = (0..3).map(|nonce| transfer(&chain, &sender, &secret_key, 100, nonce)).collect();
+        append_block(&mut chain, transactions);
+        // Three transactions against a target of one raises the fee by 2/8
+        assert_eq!(chain.next_base_fee(), 87);
+
+        append_block(&mut chain, vec![]);
+        assert_eq!(chain.next_base_fee(), 77);
+        assert!(chain.validate().is_ok());
+
+        let mut wrong_fee = next_block(&chain, vec![]).with_base_fee(1);
+        wrong_fee.mine_block(1).unwrap();
+        assert!(chain.add_block(wrong_fee).is_err());
+    }
+
+    #[test]
+    fn test_supply_grows_by_subsidy() {
+        let mut chain = Chain::new_easy();
+        let subsidy = chain.network().block_subsidy;
+        assert_eq!(chain.total_supply(), Some(0));
+
+        let (miner, miner_key) = fund_account(&mut chain);
+        assert_eq!(chain.total_supply(), Some(subsidy));
+
+        // Without burning the fee returns to the miner, leaving supply at one subsidy per block
+        let tx = transfer(&chain, &miner, &miner_key, 4, 0);
+        append_block(&mut chain, vec![Transaction::coinbase(miner, subsidy + 4), tx]);
+        assert_eq!(chain.total_supply(), Some(2 * subsidy));
+    }
+
+    #[test]
+    fn test_supply_decreases_by_burned_fees() {
+        let network = NetworkConfig {
+            initial_base_fee: 3,
+            ..NetworkConfig::default()
+        };
+        let mut chain = Chain::new_easy().with_network(network);
+        let subsidy = chain.network().block_subsidy;
+        let (sender, secret_key) = fund_account(&mut chain);
+        assert_eq!(chain.next_base_fee(), 3);
+
+        let transactions = vec![
+            Transaction::coinbase(Address::generate().0, subsidy + 2 + 2),
+            transfer(&chain, &sender, &secret_key, 5, 0),
+            transfer(&chain, &sender, &secret_key, 5, 1),
+        ];
+        append_block(&mut chain, transactions);
+
+        assert_eq!(chain.blocks[2].burned_fees(), Some(6));
+        assert_eq!(chain.total_supply(), Some(2 * subsidy - 6));
+    }
+
+    #[test]
+    fn test_immature_coinbase_cannot_be_spent() {
+        let network = NetworkConfig { coinbase_maturity: 2, ..NetworkConfig::default() };
+        let mut chain = Chain::new_easy().with_network(network);
+        let (miner, secret_key) = fund_account(&mut chain);
+
+        let spend = transfer(&chain, &miner, &secret_key, 1, 0);
+        let block = next_block(&chain, vec![spend.clone()]);
+        assert!(matches!(chain.add_block(block), Err(StoreError::ValidationError(_))));
+
+        fund_account(&mut chain);
+        append_block(&mut chain, vec![spend]);
+        assert_eq!(chain.blocks.len(), 4);
+        assert!(chain.validate().is_ok());
+    }
+
+    fn block_hashes(chain: &Chain) -> Vec<Hash> {
+        chain.blocks.iter().map(|block| block.current_block_hash.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_find_common_ancestor_of_diverging_chains() {
+        let mut chain = Chain::new_easy();
+        fund_account(&mut chain);
+        fund_account(&mut chain);
+        let mut fork = chain.clone();
+        fund_account(&mut chain);
+        fund_account(&mut chain);
+        fund_account(&mut fork);
+
+        assert_eq!(chain.find_common_ancestor(&block_hashes(&fork)), Some(2));
+        assert_eq!(chain.fork_depth(&block_hashes(&fork)), Some(2));
+        assert_eq!(fork.fork_depth(&block_hashes(&chain)), Some(1));
+
+        // A sparse locator, newest first, finds the same ancestor
+        let locator: Vec<Hash> = block_hashes(&fork).into_iter().rev().skip(1).step_by(2).collect();
+        assert_eq!(chain.find_common_ancestor(&locator), Some(2));
+        assert_eq!(chain.fork_depth(&block_hashes(&chain)), Some(0));
+    }
+
+    #[test]
+    fn test_find_common_ancestor_without_shared_history() {
+        let mut chain = Chain::new_easy();
+        fund_account(&mut chain);
+        let other_genesis =
+            GenesisConfig { extra_data: b"other".to_vec(), difficulty: MIN_DIFFICULTY, ..GenesisConfig::default() };
+        let mut stranger = Chain::from_genesis(&other_genesis);
+        fund_account(&mut stranger);
+
+        assert_eq!(chain.find_common_ancestor(&block_hashes(&stranger)), None);
+        assert_eq!(chain.fork_depth(&block_hashes(&stranger)), None);
+        assert_eq!(chain.find_common_ancestor(&[]), None);
+
+        // Sharing only genesis is still a common ancestor
+        let mut sibling = chain.clone();
+        sibling.blocks.truncate(1);
+        fund_account(&mut sibling);
+        assert_eq!(chain.find_common_ancestor(&block_hashes(&sibling)), Some(0));
+    }
+
+    #[test]
+    fn test_block_with_overdraft_rejected() {
+        let mut chain = Chain::new_easy();
+        let (sender, secret_key) = fund_account(&mut chain);
+        let subsidy = chain.network().block_subsidy;
+
+        let mut overdraft = Transaction::new(sender.clone(), Address::generate().0, subsidy)
+            .with_nonce(0)
+            .with_gas(TRANSFER_GAS, 1);
+        overdraft.sign(&secret_key, chain.network().chain_id).unwrap();
+
+        let block = next_block(&chain, vec![overdraft]);
+        assert!(matches!(chain.add_block(block.clone()), Err(StoreError::ValidationError(_))));
+        assert_eq!(chain.blocks.len(), 2);
+
+        chain.blocks.push(block);
+        assert!(chain.validate().is_err());
+    }
+
+    #[test]
+    fn test_block_with_forged_signature_rejected() {
+        let mut chain = Chain::new_easy();
+        let (victim, _) = fund_account(&mut chain);
+        let (_, forger_key, _) = Address::generate();
+
+        let forged = transfer(&chain, &victim, &forger_key, 1, 0);
+        let block = next_block(&chain, vec![forged]);
+        assert!(chain.add_block(block).is_err());
+        assert_eq!(chain.blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_open_validated_chain() {
+        let mut chain = Chain::new_easy();
+        fund_account(&mut chain);
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        store.persist(&chain).unwrap();
+
+        let loaded = Chain::open(store, true, &Config::default()).unwrap();
+        assert_eq!(loaded.blocks.len(), 2);
+        assert_eq!(loaded.blocks[1].current_block_hash, chain.blocks[1].current_block_hash);
+    }
+
+    #[test]
+    fn test_open_rejects_tampered_block_hash() {
+        let mut chain = Chain::new_easy();
+        fund_account(&mut chain);
+        chain.blocks[1].current_block_hash = Some(Hash::new(b"tampered"));
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        store.persist(&chain).unwrap();
+
+        assert!(matches!(Chain::open(store.clone(), true, &Config::default()), Err(StoreError::ValidationError(_))));
+
+        // Trusted environments can still skip validation
+        let unchecked = Chain::open(store, false, &Config::default()).unwrap();
+        assert_eq!(unchecked.blocks[1].current_block_hash, Some(Hash::new(b"tampered")));
+    }
+
+    #[test]
+    fn test_open_creates_chain_when_store_is_empty() {
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        let chain = Chain::open(store.clone(), true, &Config::default()).unwrap();
+        assert_eq!(chain.blocks.len(), 1);
+        assert!(store.load().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_genesis_block_accessor() {
+        let chain = Chain::new_easy();
+        let genesis = chain.genesis_block();
+        assert_eq!(genesis.index, 0);
+        assert_eq!(genesis.current_block_hash.as_ref(), Some(&chain.genesis_block_hash));
+        assert!(chain.check_genesis().is_ok());
+    }
+
+    #[test]
+    fn test_reloaded_chain_passes_genesis_check() {
+        let chain = Chain::new_easy();
+        let restored: Chain = serde_json::from_str(&serde_json::to_string(&chain).unwrap()).unwrap();
+        assert_eq!(restored.blocks.len(), 1);
+        assert!(restored.check_genesis().is_ok());
+    }
+
+    #[test]
+    fn test_open_rejects_mismatched_genesis_hash() {
+        let mut chain = Chain::new_easy();
+        chain.genesis_block_hash = Hash::new(b"another network");
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        store.persist(&chain).unwrap();
+
+        assert!(matches!(Chain::open(store.clone(), true, &Config::default()), Err(StoreError::ValidationError(_))));
+        assert!(matches!(Chain::open(store, false, &Config::default()), Err(StoreError::ValidationError(_))));
+    }
+
+    #[test]
+    fn test_save_batch_persists_all_blocks_once() {
+        let store = Arc::new(CountingStore::default());
+        let mut chain = Chain::new_easy().with_store(store.clone());
+        let batch = batch_of(&chain, 3);
+
+        let hashes = chain.save_batch(batch.clone()).unwrap();
+        let expected: Vec<Hash> = batch.iter().map(|block| block.current_block_hash.clone().unwrap()).collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(store.persists.load(Ordering::Relaxed), 1);
+        assert_eq!(store.load().unwrap().unwrap().blocks.len(), 4);
+        assert_eq!(chain.transaction_height(&batch[2].transactions[0].id), Some(3));
+    }
+
+    #[test]
+    fn test_save_batch_with_invalid_block_applies_nothing() {
+        let store = Arc::new(CountingStore::default());
+        let mut chain = Chain::new_easy().with_store(store.clone());
+        let mut batch = batch_of(&chain, 3);
+        batch[1].nonce += 1;
+
+        assert!(matches!(chain.save_batch(batch.clone()), Err(StoreError::ValidationError(_))));
+        assert_eq!(chain.blocks.len(), 1);
+        assert_eq!(store.persists.load(Ordering::Relaxed), 0);
+        assert!(chain.find_transaction(&batch[0].transactions[0].id).is_none());
+    }
 }
@@ -0,0 +1,141 @@
+#[cfg(test)]
+use chrono::Duration;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Largest difference from a peer's clock tolerated before warning about it
+pub(crate) const MAX_CLOCK_SKEW_SECS: i64 = 90;
+
+/// Peers needed before their median offset says anything about our own clock
+const MIN_PEERS_FOR_MEDIAN: usize = 3;
+
+/// Source of the current time, swappable so time-dependent logic can be tested
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// Reads the operating system clock
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+pub(crate) struct MockClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn new(start: DateTime<Utc>) -> Self {
+        Self { now: Mutex::new(start) }
+    }
+
+    pub(crate) fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+    }
+
+    pub(crate) fn set(&self, time: DateTime<Utc>) {
+        *self.now.lock().unwrap() = time;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// Offsets between peer clocks and ours, learned from handshake timestamps
+#[derive(Default)]
+pub(crate) struct PeerClockOffsets {
+    offsets: HashMap<String, i64>,
+}
+
+impl PeerClockOffsets {
+    /// Remember how far `peer` is ahead of us (negative when behind), in seconds
+    pub(crate) fn record(&mut self, peer: &str, local: DateTime<Utc>, remote: DateTime<Utc>) -> i64 {
+        let offset = (remote - local).num_seconds();
+        self.offsets.insert(peer.to_string(), offset);
+        offset
+    }
+
+    pub(crate) fn is_skewed(offset: i64) -> bool {
+        offset.abs() > MAX_CLOCK_SKEW_SECS
+    }
+
+    pub(crate) fn median(&self) -> Option<i64> {
+        if self.offsets.is_empty() {
+            return None;
+        }
+
+        let mut offsets: Vec<i64> = self.offsets.values().copied().collect();
+        offsets.sort_unstable();
+        let middle = offsets.len() / 2;
+        if offsets.len().is_multiple_of(2) {
+            Some((offsets[middle - 1] + offsets[middle]) / 2)
+        } else {
+            Some(offsets[middle])
+        }
+    }
+
+    /// Whether most peers agree with each other but not with us, meaning our own clock is off
+    pub(crate) fn local_clock_is_outlier(&self) -> bool {
+        self.offsets.len() >= MIN_PEERS_FOR_MEDIAN && self.median().is_some_and(Self::is_skewed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock_only_moves_when_advanced() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::seconds(90));
+        assert_eq!(clock.now().timestamp(), 1_700_000_090);
+
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn test_median_offset_detects_local_outlier() {
+        let local = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut offsets = PeerClockOffsets::default();
+        assert_eq!(offsets.median(), None);
+
+        assert_eq!(offsets.record("10.0.0.1", local, local + Duration::seconds(10)), 10);
+        assert!(!offsets.local_clock_is_outlier());
+
+        // A single skewed peer does not tell which side is wrong
+        let mut single = PeerClockOffsets::default();
+        single.record("10.0.0.9", local, local + Duration::seconds(3_600));
+        assert!(!single.local_clock_is_outlier());
+
+        // One peer far off does not make us the outlier
+        offsets.record("10.0.0.2", local, local + Duration::seconds(3_600));
+        offsets.record("10.0.0.3", local, local - Duration::seconds(5));
+        assert_eq!(offsets.median(), Some(10));
+        assert!(!offsets.local_clock_is_outlier());
+
+        // Most peers agree we are behind
+        offsets.record("10.0.0.4", local, local + Duration::seconds(600));
+        offsets.record("10.0.0.5", local, local + Duration::seconds(700));
+        assert_eq!(offsets.median(), Some(600));
+        assert!(offsets.local_clock_is_outlier());
+    }
+}
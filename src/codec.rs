@@ -0,0 +1,103 @@
+use crate::binary;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Serialization format used for the peer protocol and chain persistence
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Codec {
+    /// Human-readable format every node speaks, used until a handshake agrees on something else
+    #[default]
+    Json,
+    /// Compact binary format, see `binary`
+    Binary,
+}
+
+impl Codec {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "json" => Some(Codec::Json),
+            "binary" | "bincode" => Some(Codec::Binary),
+            _ => None,
+        }
+    }
+
+    /// Codec both sides of a connection use: the shared preference, or JSON when they differ
+    pub(crate) fn negotiate(self, other: Codec) -> Codec {
+        if self == other {
+            self
+        } else {
+            Codec::Json
+        }
+    }
+
+    pub(crate) fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Codec::Binary => binary::to_vec(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub(crate) fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Codec::Binary => binary::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::block::Block;
+    use crate::hash::Hash;
+    use crate::message::Message;
+    use crate::network::DEFAULT_CHAIN_ID;
+    use crate::transaction::Transaction;
+
+    fn sample_block() -> Block {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        Block::new(1, vec![tx], Hash::genesis(), 4)
+    }
+
+    #[test]
+    fn test_block_round_trip_through_each_codec() {
+        let block = sample_block();
+        for codec in [Codec::Json, Codec::Binary] {
+            let decoded: Block = codec.decode(&codec.encode(&block).unwrap()).unwrap();
+            assert_eq!(decoded.current_block_hash, block.current_block_hash);
+            assert_eq!(decoded.timestamp, block.timestamp);
+            assert_eq!(decoded.transactions[0].id, block.transactions[0].id);
+            assert!(decoded.has_valid_hash());
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip_through_each_codec() {
+        let message = Message::NewBlock(sample_block());
+        for codec in [Codec::Json, Codec::Binary] {
+            match codec.decode(&codec.encode(&message).unwrap()).unwrap() {
+                Message::NewBlock(block) => assert!(block.has_valid_hash()),
+                _ => panic!("Expected a NewBlock message"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_smaller_than_json() {
+        let message = Message::NewBlock(sample_block());
+        let json = Codec::Json.encode(&message).unwrap();
+        let binary = Codec::Binary.encode(&message).unwrap();
+        assert!(binary.len() < json.len(), "binary {} >= json {}", binary.len(), json.len());
+    }
+
+    #[test]
+    fn test_negotiate_falls_back_to_json() {
+        assert_eq!(Codec::Binary.negotiate(Codec::Binary), Codec::Binary);
+        assert_eq!(Codec::Binary.negotiate(Codec::Json), Codec::Json);
+        assert_eq!(Codec::parse(" Bincode "), Some(Codec::Binary));
+        assert_eq!(Codec::parse("xml"), None);
+    }
+}
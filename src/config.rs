@@ -0,0 +1,411 @@
+use crate::address::Address;
+use crate::block::MIN_DIFFICULTY;
+use crate::block_builder::{
+    DEFAULT_BLOCK_TIME_LIMIT_SECS, DEFAULT_MAX_TIMESTAMP_DRIFT_SECS, DEFAULT_MIN_PENDING_BYTES,
+    DEFAULT_MIN_TRANSACTIONS,
+};
+use crate::codec::Codec;
+use crate::message::DEFAULT_MAX_FRAME_SIZE;
+use crate::network::{GenesisConfig, NetworkConfig, DEFAULT_CHAIN_ID};
+use crate::node::{NodeRole, DEFAULT_HANDSHAKE_TIMEOUT};
+use crate::peer::{AccessList, IpRange, PeerNode};
+use crate::secure::Encryption;
+use crate::transaction_pool::{DEFAULT_HIGH_PRIORITY_RESERVE, DEFAULT_MAX_ORPHANS, DEFAULT_MAX_POOL_BYTES};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs};
+
+/// Capacity of the transaction pool feeding the block builder
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PoolLimits {
+    pub max_transactions: usize,
+    pub max_size: usize,
+    pub max_per_sender: usize,
+    /// Transactions held while waiting for an earlier nonce of their sender
+    pub max_orphans: usize,
+    /// Estimated size of all pending transactions together
+    pub max_bytes: usize,
+    /// Percentage of every block's transaction count and size kept for high-priority transactions
+    pub high_priority_reserve: usize,
+}
+
+impl Default for PoolLimits {
+    fn default() -> Self {
+        Self {
+            max_transactions: 1000,
+            max_size: 1024 * 1024,
+            max_per_sender: 64,
+            max_orphans: DEFAULT_MAX_ORPHANS,
+            max_bytes: DEFAULT_MAX_POOL_BYTES,
+            high_priority_reserve: DEFAULT_HIGH_PRIORITY_RESERVE,
+        }
+    }
+}
+
+/// When the block builder builds a block and how long it may spend mining it
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BlockSchedule {
+    /// Seconds after which a block is built however few transactions are pending
+    pub block_time_limit: u64,
+    /// Pending transactions that make a block worth building before the time limit
+    pub min_transactions: usize,
+    /// Pending bytes that make a block worth building before the time limit
+    pub min_pending_bytes: usize,
+    /// Seconds a block being mined may fall behind the clock before its timestamp is refreshed
+    pub max_timestamp_drift: u64,
+}
+
+impl Default for BlockSchedule {
+    fn default() -> Self {
+        Self {
+            block_time_limit: DEFAULT_BLOCK_TIME_LIMIT_SECS,
+            min_transactions: DEFAULT_MIN_TRANSACTIONS,
+            min_pending_bytes: DEFAULT_MIN_PENDING_BYTES,
+            max_timestamp_drift: DEFAULT_MAX_TIMESTAMP_DRIFT_SECS,
+        }
+    }
+}
+
+/// Every setting of a node, read once at startup and handed to `Node`, `Chain` and `BlockBuilder`
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub ip: IpAddr,
+    pub port: u16,
+    /// Peers given up front, merged with the known-peers file
+    pub peers: Vec<PeerNode>,
+    /// Directory holding the chain file and the known-peers file
+    pub data_path: String,
+    pub rpc_port: Option<u16>,
+    pub miner_address: Option<Address>,
+    pub codec: Codec,
+    pub refuse_skewed_peers: bool,
+    pub max_frame_size: usize,
+    /// Snapshot replacing the stored chain at startup
+    pub import_bootstrap: Option<String>,
+    /// Snapshot file the stored chain is written to at startup, the node exits once it is written
+    pub export_snapshot: Option<String>,
+    /// Recompute everything derived from the stored blocks at startup, see `Chain::reindex`
+    pub reindex: bool,
+    /// Validate the stored chain on load, only worth turning off for a chain file that cannot have been tampered with
+    pub verify_on_load: bool,
+    pub chain_id: u64,
+    /// Difficulty of the genesis block of a new chain
+    pub difficulty: u32,
+    pub pool_limits: PoolLimits,
+    pub block_schedule: BlockSchedule,
+    /// Deepest fork below the tip a peer can make this node switch to
+    pub max_reorg_depth: u64,
+    /// Depth below the tip at which blocks become final, see `NetworkConfig::finality_depth`
+    pub finality_depth: Option<u64>,
+    /// Peers synced with at startup, others are only tried when one of these fails
+    pub max_sync_peers: usize,
+    /// Longest wait for each handshake or sync reply before moving on to the next peer
+    pub handshake_timeout: Duration,
+    /// Whether peer connections are encrypted, see `secure`
+    pub encryption: Encryption,
+    /// Addresses inbound peer connections are accepted from
+    pub access: AccessList,
+    /// Whether the node mines, keeps the whole history or may prune it
+    pub role: NodeRole,
+    /// Blocks whose transactions stay indexed at startup, older ones are pruned, see `Node::prune`
+    pub prune_keep_recent: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 9999,
+            peers: Vec::new(),
+            data_path: ".".to_string(),
+            rpc_port: None,
+            miner_address: None,
+            codec: Codec::default(),
+            refuse_skewed_peers: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            import_bootstrap: None,
+            export_snapshot: None,
+            reindex: false,
+            verify_on_load: true,
+            chain_id: DEFAULT_CHAIN_ID,
+            difficulty: GenesisConfig::default().difficulty,
+            pool_limits: PoolLimits::default(),
+            block_schedule: BlockSchedule::default(),
+            max_reorg_depth: NetworkConfig::default().max_reorg_depth,
+            finality_depth: NetworkConfig::default().finality_depth,
+            max_sync_peers: 3,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            encryption: Encryption::default(),
+            access: AccessList::default(),
+            role: NodeRole::default(),
+            prune_keep_recent: None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from environment variables, `NODE_IP` and `NODE_PORT` are required
+    pub(crate) fn from_env() -> Result<Self, String> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Read the configuration from a file of `KEY=VALUE` lines using the environment variable names
+    pub(crate) fn from_file(path: &str) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+        Self::parse(&content)
+    }
+
+    /// Parse `KEY=VALUE` lines, skipping blank lines and `#` comments
+    pub(crate) fn parse(content: &str) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("Malformed config line: {}", line))?;
+            values.insert(key.trim().to_string(), value.trim().trim_matches('"').to_string());
+        }
+        Self::from_lookup(|key| values.get(key).cloned())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let defaults = Self::default();
+        let get = |key: &str| lookup(key).map(|value| value.trim().to_string()).filter(|value| !value.is_empty());
+
+        let ip = required(&get, "NODE_IP")?;
+        let port = required(&get, "NODE_PORT")?;
+        let miner_address = get("MINER_ADDRESS")
+            .map(|value| miner_address(&value).ok_or_else(|| format!("MINER_ADDRESS {} is not a valid address", value)))
+            .transpose()?;
+        let codec = get("SERIALIZATION_FORMAT")
+            .map(|value| Codec::parse(&value).ok_or_else(|| format!("Unknown SERIALIZATION_FORMAT {}", value)))
+            .transpose()?
+            .unwrap_or(defaults.codec);
+        let encryption = get("PEER_ENCRYPTION")
+            .map(|value| Encryption::parse(&value).ok_or_else(|| format!("Unknown PEER_ENCRYPTION {}", value)))
+            .transpose()?
+            .unwrap_or(defaults.encryption);
+        let role = get("NODE_ROLE")
+            .map(|value| NodeRole::parse(&value).ok_or_else(|| format!("Unknown NODE_ROLE {}", value)))
+            .transpose()?
+            .unwrap_or(defaults.role);
+        let difficulty = optional(&get, "DIFFICULTY")?.unwrap_or(defaults.difficulty);
+        if difficulty < MIN_DIFFICULTY {
+            return Err(format!("DIFFICULTY must be at least {}", MIN_DIFFICULTY));
+        }
+
+        Ok(Self {
+            ip,
+            port,
+            peers: get("NODES").map(|nodes| PeerNode::parse_list(&nodes)).unwrap_or_default(),
+            data_path: get("BLOCKCHAIN_DATA_PATH").unwrap_or(defaults.data_path),
+            rpc_port: optional(&get, "RPC_PORT")?,
+            miner_address,
+            codec,
+            refuse_skewed_peers: get("REFUSE_SKEWED_PEERS").is_some_and(|value| value == "true"),
+            max_frame_size: optional(&get, "MAX_FRAME_SIZE")?.unwrap_or(defaults.max_frame_size),
+            import_bootstrap: get("IMPORT_BOOTSTRAP"),
+            export_snapshot: get("EXPORT_SNAPSHOT"),
+            reindex: get("REINDEX").is_some_and(|value| value == "true"),
+            verify_on_load: get("VERIFY_ON_LOAD").is_none_or(|value| value != "false"),
+            chain_id: optional(&get, "CHAIN_ID")?.unwrap_or(defaults.chain_id),
+            difficulty,
+            pool_limits: PoolLimits {
+                max_transactions: optional(&get, "POOL_MAX_TRANSACTIONS")?
+                    .unwrap_or(defaults.pool_limits.max_transactions),
+                max_size: optional(&get, "POOL_MAX_SIZE")?.unwrap_or(defaults.pool_limits.max_size),
+                max_per_sender: optional(&get, "POOL_MAX_PER_SENDER")?
+                    .unwrap_or(defaults.pool_limits.max_per_sender),
+                max_orphans: optional(&get, "POOL_MAX_ORPHANS")?.unwrap_or(defaults.pool_limits.max_orphans),
+                max_bytes: optional(&get, "POOL_MAX_BYTES")?.unwrap_or(defaults.pool_limits.max_bytes),
+                high_priority_reserve: optional(&get, "POOL_HIGH_PRIORITY_RESERVE")?
+                    .unwrap_or(defaults.pool_limits.high_priority_reserve),
+            },
+            block_schedule: BlockSchedule {
+                block_time_limit: optional(&get, "BLOCK_TIME_LIMIT")?
+                    .unwrap_or(defaults.block_schedule.block_time_limit),
+                min_transactions: optional(&get, "BLOCK_MIN_TRANSACTIONS")?
+                    .unwrap_or(defaults.block_schedule.min_transactions),
+                min_pending_bytes: optional(&get, "BLOCK_MIN_PENDING_BYTES")?
+                    .unwrap_or(defaults.block_schedule.min_pending_bytes),
+                max_timestamp_drift: optional(&get, "MAX_TIMESTAMP_DRIFT")?
+                    .unwrap_or(defaults.block_schedule.max_timestamp_drift),
+            },
+            max_reorg_depth: optional(&get, "MAX_REORG_DEPTH")?.unwrap_or(defaults.max_reorg_depth),
+            finality_depth: optional(&get, "FINALITY_DEPTH")?.or(defaults.finality_depth),
+            max_sync_peers: optional(&get, "MAX_SYNC_PEERS")?.unwrap_or(defaults.max_sync_peers),
+            handshake_timeout: optional(&get, "HANDSHAKE_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.handshake_timeout),
+            encryption,
+            access: AccessList {
+                allow: get("PEER_ALLOWLIST").map(|value| IpRange::parse_list(&value)).transpose()?.unwrap_or_default(),
+                deny: get("PEER_DENYLIST").map(|value| IpRange::parse_list(&value)).transpose()?.unwrap_or_default(),
+            },
+            role,
+            prune_keep_recent: optional(&get, "PRUNE_KEEP_RECENT")?,
+        })
+    }
+
+    pub(crate) fn peers_file(&self) -> String {
+        format!("{}/peers.json", self.data_path)
+    }
+
+    /// File holding the static key this node presents in encrypted sessions, see `secure::NodeKey`
+    pub(crate) fn node_key_file(&self) -> String {
+        format!("{}/node.key", self.data_path)
+    }
+
+    pub(crate) fn network(&self) -> NetworkConfig {
+        NetworkConfig {
+            chain_id: self.chain_id,
+            max_reorg_depth: self.max_reorg_depth,
+            finality_depth: self.finality_depth,
+            ..NetworkConfig::default()
+        }
+    }
+
+    /// Genesis of a chain created from scratch
+    pub(crate) fn genesis(&self) -> GenesisConfig {
+        GenesisConfig {
+            difficulty: self.difficulty,
+            network_id: self.chain_id,
+            ..GenesisConfig::default()
+        }
+    }
+}
+
+/// Address given as `0x` and 40 hex digits, or as the 33-byte compressed public key it belongs to in hex
+fn miner_address(value: &str) -> Option<Address> {
+    let address = Address { value: value.to_lowercase(), raw_bytes: None };
+    if address.is_valid() {
+        return Some(address);
+    }
+    let compressed: [u8; 33] = hex::decode(value.trim_start_matches("0x")).ok()?.try_into().ok()?;
+    Address::from_compressed_public_key(&compressed)
+}
+
+fn required<T: FromStr>(get: &impl Fn(&str) -> Option<String>, key: &str) -> Result<T, String> {
+    optional(get, key)?.ok_or_else(|| format!("{} is not set", key))
+}
+
+fn optional<T: FromStr>(get: &impl Fn(&str) -> Option<String>, key: &str) -> Result<Option<T>, String> {
+    get(key)
+        .map(|value| value.parse().map_err(|_| format!("Failed to parse {} value {}", key, value)))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    const FIXTURE: &str = r#"
+        # Second node of the local network
+        NODE_IP=::1
+        NODE_PORT=9998
+        NODES=127.0.0.1:9999, 127.0.0.1:9997
+        BLOCKCHAIN_DATA_PATH="/var/lib/ola"
+        RPC_PORT=8545
+        SERIALIZATION_FORMAT=binary
+        REFUSE_SKEWED_PEERS=true
+        CHAIN_ID=7
+        DIFFICULTY=2
+        POOL_MAX_TRANSACTIONS=50
+        POOL_MAX_BYTES=65536
+        MAX_REORG_DEPTH=20
+        FINALITY_DEPTH=10
+        PEER_ENCRYPTION=require
+        PEER_DENYLIST=10.0.0.0/8, 192.168.1.7
+        HANDSHAKE_TIMEOUT_MS=1500
+        NODE_ROLE=archive
+        POOL_HIGH_PRIORITY_RESERVE=20
+        BLOCK_TIME_LIMIT=60
+        BLOCK_MIN_TRANSACTIONS=10
+        PRUNE_KEEP_RECENT=500
+        VERIFY_ON_LOAD=false
+    "#;
+
+    #[test]
+    fn test_parse_fixture() {
+        let config = Config::parse(FIXTURE).unwrap();
+        assert_eq!(config.ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(config.port, 9998);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers_file(), "/var/lib/ola/peers.json");
+        assert_eq!(config.rpc_port, Some(8545));
+        assert_eq!(config.codec, Codec::Binary);
+        assert!(config.refuse_skewed_peers);
+        assert_eq!(config.network().chain_id, 7);
+        assert_eq!(config.genesis().difficulty, 2);
+        assert_eq!(config.pool_limits.max_transactions, 50);
+        assert_eq!(config.pool_limits.max_per_sender, PoolLimits::default().max_per_sender);
+        assert_eq!(config.pool_limits.max_bytes, 65536);
+        assert_eq!(config.pool_limits.high_priority_reserve, 20);
+        assert_eq!(config.block_schedule.block_time_limit, 60);
+        assert_eq!(config.block_schedule.min_transactions, 10);
+        assert_eq!(config.block_schedule.min_pending_bytes, DEFAULT_MIN_PENDING_BYTES);
+        assert_eq!(config.prune_keep_recent, Some(500));
+        assert!(!config.verify_on_load);
+        assert_eq!(config.network().max_reorg_depth, 20);
+        assert_eq!(config.network().finality_depth, Some(10));
+        assert_eq!(config.encryption, Encryption::Required);
+        assert_eq!(config.handshake_timeout, Duration::from_millis(1500));
+        assert_eq!(config.role, NodeRole::Archive);
+        assert!(config.access.allow.is_empty());
+        assert_eq!(config.access.deny.len(), 2);
+        assert!(!config.access.permits(&"192.168.1.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_defaults_and_errors() {
+        let config = Config::parse("NODE_IP=127.0.0.1\nNODE_PORT=9999").unwrap();
+        let defaults = Config::default();
+        assert!(config.peers.is_empty());
+        assert_eq!(config.data_path, ".");
+        assert_eq!(config.rpc_port, None);
+        assert_eq!(config.codec, Codec::Json);
+        assert_eq!(config.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+        assert_eq!(config.chain_id, defaults.chain_id);
+        assert_eq!(config.difficulty, defaults.difficulty);
+        assert_eq!(config.pool_limits, defaults.pool_limits);
+        assert_eq!(config.max_reorg_depth, defaults.max_reorg_depth);
+        assert_eq!(config.finality_depth, None);
+        assert_eq!(config.max_sync_peers, 3);
+        assert_eq!(config.encryption, Encryption::Disabled);
+        assert_eq!(config.block_schedule, defaults.block_schedule);
+        assert_eq!(config.prune_keep_recent, None);
+        assert!(config.verify_on_load);
+
+        assert!(Config::parse("NODE_PORT=9999").unwrap_err().contains("NODE_IP"));
+        assert!(Config::parse("NODE_IP=127.0.0.1\nNODE_PORT=99999").is_err());
+        assert!(Config::parse("NODE_IP=127.0.0.1\nNODE_PORT=9999\nMINER_ADDRESS=nope").is_err());
+        assert!(Config::parse("NODE_IP").is_err());
+        assert!(Config::parse("NODE_IP=127.0.0.1\nNODE_PORT=9999\nPEER_ENCRYPTION=tls").is_err());
+        assert!(Config::parse("NODE_IP=127.0.0.1\nNODE_PORT=9999\nDIFFICULTY=0").unwrap_err().contains("DIFFICULTY"));
+    }
+
+    #[test]
+    fn test_from_lookup() {
+        let vars: HashMap<&str, &str> =
+            HashMap::from([("NODE_IP", "10.0.0.2"), ("NODE_PORT", "7000"), ("POOL_MAX_SIZE", "2048")]);
+        let config = Config::from_lookup(|key| vars.get(key).map(|value| value.to_string())).unwrap();
+
+        assert_eq!(config.ip, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.pool_limits.max_size, 2048);
+    }
+
+    #[test]
+    fn test_miner_address_from_compressed_public_key() {
+        let (address, _, public_key) = Address::generate();
+        let compressed = hex::encode(Address::compress_pubkey(&public_key));
+        let with_miner =
+            |miner: &str| Config::parse(&format!("NODE_IP=127.0.0.1\nNODE_PORT=9999\nMINER_ADDRESS={}", miner));
+        assert_eq!(with_miner(&compressed).unwrap().miner_address, Some(address.clone()));
+        assert_eq!(with_miner(&address.value).unwrap().miner_address, Some(address));
+        assert!(with_miner(&compressed[..64]).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::codec::Codec;
+use crate::node::{Node, NodeInfo};
+use crate::peer::PeerNode;
+use crate::secure::{PeerSecurity, PeerStream};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+/// Open connections to peers by socket address, reused for every message instead of connecting each time
+#[derive(Default)]
+pub(crate) struct PeerConnections {
+    open: Mutex<HashMap<String, (PeerStream, Codec)>>,
+}
+
+impl PeerConnections {
+    /// Run `exchange` over the connection to `peer`, opening a session with `codec` and `security` when none
+    /// is open. A pooled connection the peer has closed since, or that fails during `exchange`, is replaced by a
+    /// fresh one and `exchange` is tried again. The connection stays open for the next message unless it failed.
+    pub(crate) fn with_connection<T>(
+        &self,
+        peer: &PeerNode,
+        codec: Codec,
+        security: &PeerSecurity,
+        mut exchange: impl FnMut(&mut PeerStream, Codec) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let address = peer.socket_addr();
+        // Taken out of the map so the lock is not held while talking to the peer
+        let pooled = self.open.lock().unwrap().remove(&address);
+        if let Some((mut stream, agreed)) = pooled.filter(|(stream, _)| Self::is_open(stream)) {
+            match exchange(&mut stream, agreed) {
+                Ok(value) => {
+                    self.insert(peer, stream, agreed);
+                    return Ok(value);
+                }
+                Err(e) => eprintln!("Connection to peer {} failed, reconnecting: {}", address, e),
+            }
+        }
+
+        let (mut stream, agreed) = Node::open_session(peer, codec, security)?;
+        let value = exchange(&mut stream, agreed)?;
+        self.insert(peer, stream, agreed);
+        Ok(value)
+    }
+
+    /// Keep `stream`, a session with `peer` agreed on `codec`, for later messages
+    pub(crate) fn insert(&self, peer: &PeerNode, stream: PeerStream, codec: Codec) {
+        self.open.lock().unwrap().insert(peer.socket_addr(), (stream, codec));
+    }
+
+    /// Close every connection, peers see them end
+    #[cfg(test)]
+    pub(crate) fn close_all(&self) {
+        self.open.lock().unwrap().clear();
+    }
+
+    /// Number of connections held open
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    /// Whether the peer has not closed `stream`. Unread replies to earlier requests count as open.
+    fn is_open(stream: &PeerStream) -> bool {
+        let tcp = stream.tcp();
+        if tcp.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let open = match tcp.peek(&mut [0u8; 1]) {
+            Ok(read) => read > 0,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        };
+        tcp.set_nonblocking(false).is_ok() && open
+    }
+}
@@ -0,0 +1,80 @@
+use secp256k1::{All, Message, Secp256k1};
+use sha2::{Digest, Sha256};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+static CONTEXT: OnceLock<Secp256k1<All>> = OnceLock::new();
+/// Contexts built so far, at most one
+#[cfg(test)]
+static CONTEXTS_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Shared secp256k1 context for every signing and verification path.
+/// Building a context allocates and precomputes tables, so it is done once per process.
+pub(crate) fn secp() -> &'static Secp256k1<All> {
+    CONTEXT.get_or_init(|| {
+        #[cfg(test)]
+        CONTEXTS_CREATED.fetch_add(1, Ordering::Relaxed);
+        Secp256k1::new()
+    })
+}
+
+/// What a signature vouches for. The domain's tag is hashed into every signed message, so a signature made
+/// in one domain never verifies in another, nor over the bare digest, even by the same key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SigningDomain {
+    Transaction,
+}
+
+impl SigningDomain {
+    fn tag(self) -> &'static [u8] {
+        match self {
+            SigningDomain::Transaction => b"OlaChain/transaction",
+        }
+    }
+}
+
+/// Message to sign or verify for `digest` in `domain`
+pub(crate) fn domain_message(domain: SigningDomain, digest: &[u8; 32]) -> Message {
+    let mut hasher = Sha256::new();
+    hasher.update(domain.tag());
+    hasher.update(digest);
+    Message::from_digest(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::network::DEFAULT_CHAIN_ID;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn test_context_created_once_across_many_verifications() {
+        let (from, secret_key, public_key) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        for _ in 0..1_000 {
+            assert!(tx.verify_signature(public_key, DEFAULT_CHAIN_ID));
+            assert!(tx.is_signed_by_sender(DEFAULT_CHAIN_ID));
+        }
+
+        assert_eq!(CONTEXTS_CREATED.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_signature_only_valid_in_its_domain() {
+        let (_, secret_key, public_key) = Address::generate();
+        let digest = [7u8; 32];
+        let transaction = domain_message(SigningDomain::Transaction, &digest);
+        let bare = Message::from_digest(digest);
+
+        let tx_signature = secp().sign_ecdsa(transaction, &secret_key);
+        assert!(secp().verify_ecdsa(transaction, &tx_signature, &public_key).is_ok());
+        assert!(secp().verify_ecdsa(bare, &tx_signature, &public_key).is_err());
+
+        let bare_signature = secp().sign_ecdsa(bare, &secret_key);
+        assert!(secp().verify_ecdsa(transaction, &bare_signature, &public_key).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+use crate::block::BlockHeader;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Something that happened to the chain or the transaction pool, delivered to subscribers
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ChainEvent {
+    BlockAdded(BlockHeader),
+    /// Id of a transaction admitted to the pool
+    TxAccepted(String),
+}
+
+/// Fans events out to every subscriber. Clones share their subscribers, so a chain and the pool
+/// built from it can publish to the same consumers.
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<ChainEvent>>>>,
+}
+
+impl EventBus {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive every event published from now on
+    pub(crate) fn subscribe(&self) -> Receiver<ChainEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Deliver `event` to every subscriber, forgetting those whose receiver was dropped
+    pub(crate) fn publish(&self, event: ChainEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash;
+
+    #[test]
+    fn test_events_reach_every_subscriber() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.clone().subscribe();
+
+        bus.publish(ChainEvent::TxAccepted("tx".to_string()));
+        assert_eq!(first.try_recv().unwrap(), ChainEvent::TxAccepted("tx".to_string()));
+        assert_eq!(second.try_recv().unwrap(), ChainEvent::TxAccepted("tx".to_string()));
+
+        drop(first);
+        let header = BlockHeader { index: 1, hash: Hash::new(b"block"), merkle_root: Hash::new(&[]) };
+        bus.publish(ChainEvent::BlockAdded(header.clone()));
+        assert_eq!(second.try_recv().unwrap(), ChainEvent::BlockAdded(header));
+        assert_eq!(bus.subscriber_count(), 1);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::transaction::Transaction;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Layout of an exported transaction history, see `Chain::export_history`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ExportFormat {
+    /// One line per transaction after a header line
+    Csv,
+    /// An array of objects
+    Json,
+}
+
+impl ExportFormat {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a transaction paid the exported address or was paid by it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+const CSV_HEADER: &str = "block_index,timestamp,transaction_id,direction,counterparty,amount,fee";
+
+/// One transaction as seen from the exported address
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct HistoryRow {
+    pub block_index: u64,
+    /// Time of the block including the transaction
+    pub timestamp: DateTime<Utc>,
+    pub transaction_id: String,
+    pub direction: Direction,
+    /// Recipient of an outgoing transaction, sender of an incoming one
+    pub counterparty: String,
+    /// Everything an outgoing transaction paid out, or what an incoming one paid the address
+    pub amount: u64,
+    /// Fee paid by the address, 0 for incoming transactions
+    pub fee: u64,
+}
+
+impl HistoryRow {
+    /// `tx` from `block` as seen by `address`, which sent or receives it. A transaction to oneself counts as
+    /// outgoing, so its fee shows up.
+    pub(crate) fn new(block: &Block, tx: &Transaction, address: &Address) -> Self {
+        let (direction, counterparty, amount, fee) = if &tx.from == address {
+            (Direction::Out, &tx.to, tx.total_amount().unwrap_or(u64::MAX), tx.fee)
+        } else {
+            let received = tx.outputs().filter(|(to, _)| *to == address).map(|(_, amount)| amount).sum();
+            (Direction::In, &tx.from, received, 0)
+        };
+        HistoryRow {
+            block_index: block.index,
+            timestamp: block.timestamp,
+            transaction_id: tx.id.clone(),
+            direction,
+            counterparty: counterparty.value.clone(),
+            amount,
+            fee,
+        }
+    }
+}
+
+/// Render `rows` in `format`
+pub(crate) fn render(rows: &[HistoryRow], format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Csv => {
+            let mut csv = String::from(CSV_HEADER);
+            for row in rows {
+                csv.push_str(&format!(
+                    "\n{},{},{},{},{},{},{}",
+                    row.block_index,
+                    row.timestamp.to_rfc3339(),
+                    row.transaction_id,
+                    row.direction.as_str(),
+                    row.counterparty,
+                    row.amount,
+                    row.fee
+                ));
+            }
+            csv.push('\n');
+            csv
+        }
+        // Rows hold only strings and numbers, serializing them cannot fail
+        ExportFormat::Json => serde_json::to_string_pretty(rows).expect("History rows always serialize"),
+    }
+}
@@ -1,31 +1,97 @@
 use serde::{Deserialize, Serialize};
 use sha2::digest::Update;
 use sha2::{Digest, Sha256};
+use std::str::FromStr;
 
-#[derive(Clone, Serialize, Deserialize)]
+/// A SHA-256 digest, kept both as raw bytes and as the lowercase hex string it is shown and serialized as
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "SerializedHash")]
 pub(crate) struct Hash {
     pub(crate) value: String,
+    #[serde(skip)]
+    bytes: [u8; 32],
+}
+
+/// Serialized form of a `Hash`, only the hex string is stored
+#[derive(Deserialize)]
+struct SerializedHash {
+    value: String,
+}
+
+impl TryFrom<SerializedHash> for Hash {
+    type Error = String;
+
+    fn try_from(serialized: SerializedHash) -> Result<Self, Self::Error> {
+        serialized.value.parse()
+    }
+}
+
+/// Parses the canonical form only: exactly 64 lowercase hex characters
+impl FromStr for Hash {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let canonical = value.len() == 64 && value.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'));
+        if !canonical {
+            return Err(format!("Invalid hash {}, expected 64 lowercase hex characters", value));
+        }
+        Hash::from_hex(value).ok_or_else(|| format!("Invalid hash {}", value))
+    }
+}
+
+impl TryFrom<&str> for Hash {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
 }
 
 impl Hash {
     pub(crate) fn new(bytes: &[u8]) -> Self {
         let mut hasher = Sha256::new();
         Update::update(&mut hasher, bytes);
-        let result = hasher.finalize();
+        Self::from_bytes_raw(hasher.finalize().into())
+    }
 
+    /// Wrap an already computed digest
+    pub(crate) fn from_bytes_raw(bytes: [u8; 32]) -> Self {
         Hash {
-            value: hex::encode(result),
+            value: hex::encode(bytes),
+            bytes,
         }
     }
 
+    /// Parse 64 hex characters, in either case
+    pub(crate) fn from_hex(value: &str) -> Option<Self> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(value, &mut bytes).ok()?;
+        Some(Self::from_bytes_raw(bytes))
+    }
+
     pub(crate) fn genesis() -> Self {
-        Hash {
-            value: "0".repeat(64),
+        Self::from_bytes_raw([0; 32])
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    /// Number of leading zero bits of the hash, a finer measure of work than hex zeros
+    pub(crate) fn leading_zero_bits(&self) -> u32 {
+        let mut bits = 0;
+        for byte in self.bytes {
+            bits += byte.leading_zeros();
+            if byte != 0 {
+                break;
+            }
         }
+        bits
     }
 
-    fn validate(hash: String) -> bool {
-        true
+    /// Whether the hash satisfies a difficulty expressed in leading zero bits, each bit doubling the work
+    pub(crate) fn meets_difficulty(&self, difficulty: u32) -> bool {
+        self.leading_zero_bits() >= difficulty
     }
 }
 
@@ -65,7 +131,67 @@ mod tests {
     }
 
     #[test]
-    fn test_hash_validate() {
-        assert!(Hash::validate("valid_hash".to_string()));
+    fn test_leading_zero_bits() {
+        let hash = |value: &str| Hash::from_hex(value).unwrap();
+
+        assert_eq!(Hash::genesis().leading_zero_bits(), 256);
+        assert_eq!(hash(&format!("f{}", "0".repeat(63))).leading_zero_bits(), 0);
+        assert_eq!(hash(&format!("1{}", "0".repeat(63))).leading_zero_bits(), 3);
+        assert_eq!(hash(&format!("0000{}", "f".repeat(60))).leading_zero_bits(), 16);
+        assert_eq!(hash(&format!("00007{}", "f".repeat(59))).leading_zero_bits(), 17);
+        assert_eq!(hash(&format!("000001{}", "f".repeat(58))).leading_zero_bits(), 23);
+    }
+
+    #[test]
+    fn test_meets_difficulty() {
+        let hash = Hash::from_hex(&format!("00007{}", "f".repeat(59))).unwrap();
+        assert!(hash.meets_difficulty(17));
+        assert!(!hash.meets_difficulty(18));
+        assert!(Hash::genesis().meets_difficulty(256));
+    }
+
+    #[test]
+    fn test_as_bytes_matches_hex() {
+        let hash = Hash::new(b"hello world");
+        assert_eq!(hex::encode(hash.as_bytes()), hash.value);
+        assert_eq!(Hash::genesis().as_bytes(), &[0u8; 32]);
+    }
+
+    #[test]
+    fn test_from_bytes_raw_yields_hex() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0xab;
+        bytes[31] = 0x01;
+        let hash = Hash::from_bytes_raw(bytes);
+        assert_eq!(hash.value, format!("ab{}01", "0".repeat(60)));
+        assert_eq!(Hash::from_hex(&hash.value.to_uppercase()), Some(hash));
+    }
+
+    #[test]
+    fn test_invalid_hex_rejected() {
+        assert!(Hash::from_hex("not hex").is_none());
+        assert!(Hash::from_hex("abcd").is_none());
+        assert!(serde_json::from_str::<Hash>(r#"{"value":"not hex"}"#).is_err());
+
+        let hash = Hash::new(b"round trip");
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(serde_json::from_str::<Hash>(&json).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_parse_canonical_hex_only() {
+        let hash = Hash::new(b"parse me");
+        assert_eq!(hash.value.parse::<Hash>(), Ok(hash.clone()));
+        assert_eq!(Hash::try_from(hash.value.as_str()), Ok(hash.clone()));
+        assert_eq!("0".repeat(64).parse::<Hash>(), Ok(Hash::genesis()));
+
+        assert!(hash.value.to_uppercase().parse::<Hash>().is_err());
+        assert!(hash.value[..63].parse::<Hash>().is_err());
+        assert!(format!("{}0", hash.value).parse::<Hash>().is_err());
+        assert!(format!("zz{}", &hash.value[2..]).parse::<Hash>().is_err());
+        assert!(Hash::try_from("").is_err());
+
+        let uppercase = format!(r#"{{"value":"{}"}}"#, hash.value.to_uppercase());
+        assert!(serde_json::from_str::<Hash>(&uppercase).is_err());
     }
 }
@@ -1,8 +1,23 @@
+mod binary;
 mod block;
 mod chain;
+mod clock;
+mod codec;
+mod config;
+mod connections;
+mod crypto;
+mod events;
+mod export;
 mod hash;
+mod merkle;
+mod message;
+mod network;
 mod node;
 mod peer;
+mod rpc;
+mod secure;
+mod snapshot;
+mod state;
 mod store;
 mod transaction;
 mod address;
@@ -10,13 +25,133 @@ mod block_builder;
 mod transaction_pool;
 
 use dotenv::dotenv;
+use std::env;
+use crate::address::Address;
 use crate::chain::Chain;
+use crate::codec::Codec;
+use crate::config::Config;
+use crate::export::ExportFormat;
 use crate::node::Node;
+use crate::snapshot::export_snapshot;
+use crate::transaction::Transaction;
+use secp256k1::SecretKey;
 
 fn main() {
     dotenv().ok();
+    // --new-account prints a fresh key pair and its address, for MINER_ADDRESS, without starting the node
+    if env::args().any(|arg| arg == "--new-account") {
+        let (address, secret_key, public_key) = Address::generate();
+        println!("Address: {}", address.value);
+        println!("Public key: {}", hex::encode(Address::compress_pubkey(&public_key)));
+        println!("Secret key: {}", secret_key.display_secret());
+        return;
+    }
     println!("Starting Ola node");
-    let chain = Chain::load_or_create();
-    Node::me(chain).start();
+    // --config <path> reads the settings from a file of KEY=VALUE lines instead of the environment
+    let config = match env::args().skip_while(|arg| arg != "--config").nth(1) {
+        Some(path) => Config::from_file(&path),
+        None => Config::from_env(),
+    };
+    let mut config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // --sign-transaction <secret key> <recipient> <amount> <nonce> <gas price> [<recipient> <amount>]... prints the
+    // signed transfer as the raw hex /sendrawtransaction takes, without starting the node
+    if env::args().any(|arg| arg == "--sign-transaction") {
+        let args: Vec<String> = env::args().skip_while(|arg| arg != "--sign-transaction").skip(1).collect();
+        match sign_transaction(&args, config.chain_id) {
+            Ok(raw) => println!("{}", raw),
+            Err(e) => {
+                eprintln!("Failed to sign transaction: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // A snapshot given with --import-bootstrap <path> or IMPORT_BOOTSTRAP replaces the stored chain
+    if let Some(path) = env::args().skip_while(|arg| arg != "--import-bootstrap").nth(1) {
+        config.import_bootstrap = Some(path);
+    }
+    // --export-snapshot <path> or EXPORT_SNAPSHOT writes the loaded chain to a snapshot instead of running the node
+    if let Some(path) = env::args().skip_while(|arg| arg != "--export-snapshot").nth(1) {
+        config.export_snapshot = Some(path);
+    }
+    // --reindex or REINDEX=true replays the stored blocks to rebuild everything derived from them
+    if env::args().any(|arg| arg == "--reindex") {
+        config.reindex = true;
+    }
+    let loaded = match &config.import_bootstrap {
+        Some(path) => Chain::import_bootstrap(path, &config),
+        None if config.reindex => Chain::reindex(&config),
+        None if !config.verify_on_load => Chain::load_unchecked(&config),
+        None => Chain::load_or_create(&config),
+    };
+    let chain = match loaded {
+        Ok(chain) => chain,
+        Err(e) => {
+            eprintln!("Failed to load blockchain: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // --export-history <address> prints the address's transactions as CSV, or as JSON with --history-format json
+    if let Some(address) = env::args().skip_while(|arg| arg != "--export-history").nth(1) {
+        let address = Address { value: address.to_lowercase(), raw_bytes: None };
+        let format = env::args().skip_while(|arg| arg != "--history-format").nth(1);
+        let format = match format.as_deref().map(ExportFormat::parse) {
+            None => ExportFormat::Csv,
+            Some(Some(format)) => format,
+            Some(None) => {
+                eprintln!("Unknown history format, expected csv or json");
+                std::process::exit(1);
+            }
+        };
+        if !address.is_valid() {
+            eprintln!("{} is not a valid address", address.value);
+            std::process::exit(1);
+        }
+        print!("{}", chain.export_history(&address, format));
+        return;
+    }
+    if let Some(path) = &config.export_snapshot {
+        if let Err(e) = export_snapshot(&chain, path, Codec::Binary) {
+            eprintln!("Failed to export snapshot to {}: {}", path, e);
+            std::process::exit(1);
+        }
+        println!("Exported Blockchain at height {} to {}", chain.blocks.len() - 1, path);
+        return;
+    }
+    println!(
+        "Network {} with genesis block {}",
+        chain.network_id(),
+        chain.genesis_block().current_block_hash.as_ref().map_or("unmined", |hash| hash.value.as_str())
+    );
+    Node::from_config(&config, chain).start();
     println!("Stopping Ola node");
 }
+
+/// Raw hex of the transfer described by the `--sign-transaction` arguments, signed for `chain_id`
+fn sign_transaction(args: &[String], chain_id: u64) -> Result<String, String> {
+    let number = |arg: Option<&String>, name: &str| -> Result<u64, String> {
+        let arg = arg.ok_or_else(|| format!("Missing {}", name))?;
+        arg.parse().map_err(|_| format!("Invalid {} {}", name, arg))
+    };
+    let secret_key: SecretKey = args.first().ok_or("Missing secret key")?.parse().map_err(|_| "Invalid secret key")?;
+    let nonce = number(args.get(3), "nonce")?;
+    let gas_price = number(args.get(4), "gas price")?;
+
+    let recipients = args.get(1..3).into_iter().chain(args.get(5..).unwrap_or_default().chunks(2));
+    let mut outputs = Vec::new();
+    for recipient in recipients {
+        let to = Address { value: recipient[0].to_lowercase(), raw_bytes: None };
+        if !to.is_valid() {
+            return Err(format!("{} is not a valid address", to.value));
+        }
+        outputs.push((to, number(recipient.get(1), "amount")?));
+    }
+
+    Transaction::signed_transfer(&secret_key, &outputs, nonce, gas_price, chain_id).map(|tx| tx.to_raw_hex())
+}
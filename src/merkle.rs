@@ -0,0 +1,132 @@
+use crate::hash::Hash;
+
+/// Prefixes keeping leaves and inner nodes apart, so an inner node never passes for a leaf in a proof
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Step of an inclusion proof: the sibling hash, and whether the sibling sits on the left
+pub(crate) type ProofStep = (Hash, bool);
+
+/// Leaf committing to `data`, a transaction id for block trees
+pub(crate) fn leaf(data: &[u8]) -> Hash {
+    let mut input = Vec::with_capacity(1 + data.len());
+    input.push(LEAF_PREFIX);
+    input.extend_from_slice(data);
+    Hash::new(&input)
+}
+
+fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut input = Vec::with_capacity(65);
+    input.push(NODE_PREFIX);
+    input.extend_from_slice(left.as_bytes());
+    input.extend_from_slice(right.as_bytes());
+    Hash::new(&input)
+}
+
+/// Next level up: pairs are hashed together and a last node without a pair moves up unchanged.
+/// Duplicating it instead would give two different lists the same root.
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => parent(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Root of the binary tree over `leaves`, the hash of nothing when there are none
+pub(crate) fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::new(&[]);
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Siblings from the leaf at `position` up to the root, `None` if there is no such leaf.
+/// Levels where the node moves up without a pair add no step.
+pub(crate) fn proof(leaves: &[Hash], mut position: usize) -> Option<Vec<ProofStep>> {
+    if position >= leaves.len() {
+        return None;
+    }
+
+    let mut steps = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if let Some(hash) = level.get(sibling) {
+            steps.push((hash.clone(), sibling < position));
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(steps)
+}
+
+/// Whether `proof` leads from `leaf` up to `root`
+pub(crate) fn verify(root: &Hash, leaf: &Hash, proof: &[ProofStep]) -> bool {
+    let computed = proof.iter().fold(leaf.clone(), |node, (sibling, sibling_on_left)| {
+        if *sibling_on_left {
+            parent(sibling, &node)
+        } else {
+            parent(&node, sibling)
+        }
+    });
+    computed == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<Hash> {
+        (0..count).map(|index| leaf(format!("tx-{}", index).as_bytes())).collect()
+    }
+
+    #[test]
+    fn test_every_leaf_proves_inclusion() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (position, leaf) in leaves.iter().enumerate() {
+                let proof = proof(&leaves, position).unwrap();
+                assert!(verify(&root, leaf, &proof), "leaf {} of {}", position, count);
+            }
+            assert!(proof(&leaves, count).is_none());
+        }
+    }
+
+    #[test]
+    fn test_tampered_proof_rejected() {
+        let leaves = leaves(5);
+        let root = root(&leaves);
+        let proof = proof(&leaves, 2).unwrap();
+
+        assert!(!verify(&root, &leaves[3], &proof));
+        let mut flipped = proof.clone();
+        flipped[0].1 = !flipped[0].1;
+        assert!(!verify(&root, &leaves[2], &flipped));
+        assert!(!verify(&root, &leaves[2], &proof[1..]));
+    }
+
+    #[test]
+    fn test_root_commits_to_order_and_count() {
+        let leaves = leaves(3);
+        let mut reordered = leaves.clone();
+        reordered.swap(0, 1);
+        assert_ne!(root(&leaves), root(&reordered));
+
+        // An unpaired last leaf is not duplicated, so repeating it changes the root
+        let mut repeated = leaves.clone();
+        repeated.push(leaves[2].clone());
+        assert_ne!(root(&leaves), root(&repeated));
+        assert_eq!(root(&leaves[..1]), leaves[0]);
+        assert_eq!(root(&[]), Hash::new(&[]));
+    }
+}
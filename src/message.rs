@@ -0,0 +1,213 @@
+use crate::block::{Block, BlockHeader};
+use crate::codec::Codec;
+use crate::hash::Hash;
+use crate::transaction::Transaction;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+use std::{error, fmt};
+
+/// Messages exchanged between peers
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum Message {
+    /// Opens a connection, always sent as JSON, after the Noise handshake on encrypted connections. The reply
+    /// carries the codec both sides use from then on, and each side's current time so clock skew can be detected.
+    /// `tip` is the sender's newest block, when it tells.
+    Hello {
+        codec: Codec,
+        timestamp: DateTime<Utc>,
+        #[serde(default)]
+        tip: Option<BlockHeader>,
+    },
+    SyncRequest,
+    /// Answers `SyncRequest` with the sender's tip and the total work of its chain, see `Chain::total_work`
+    SyncResponse {
+        tip: Option<BlockHeader>,
+        total_work: u64,
+    },
+    NewBlock(Block),
+    /// A peer mined or accepted a block, the receiver asks for the body with `GetBlock` if it is missing
+    NewBlockAnnounce(BlockHeader),
+    GetBlock(Hash),
+    NewTransaction(Transaction),
+    /// Asks for the pending transactions of the peer, answered with `Mempool`
+    GetMempool,
+    /// Pending transactions, highest fee first, at most `MAX_MEMPOOL_TRANSACTIONS` and `MAX_MEMPOOL_BYTES`
+    Mempool(Vec<Transaction>),
+}
+
+/// Most transactions sent or accepted in one `Mempool` message
+pub(crate) const MAX_MEMPOOL_TRANSACTIONS: usize = 1000;
+/// Largest total transaction size sent in one `Mempool` message
+pub(crate) const MAX_MEMPOOL_BYTES: usize = 1024 * 1024;
+
+/// Largest frame body accepted from a peer unless configured otherwise
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// A whole frame was read but its body is not a message this node understands, e.g. a variant added by a
+/// newer version. The stream stays aligned on frame boundaries, so the connection can carry on.
+#[derive(Debug)]
+struct UnknownMessage(String);
+
+impl fmt::Display for UnknownMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown message: {}", self.0)
+    }
+}
+
+impl error::Error for UnknownMessage {}
+
+/// Whether `error` comes from a frame read in full whose body could not be decoded
+pub(crate) fn is_unknown_message(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<UnknownMessage>())
+}
+
+/// Write a message as a 4-byte big-endian length followed by its JSON body
+pub(crate) fn write_frame<W: Write>(writer: &mut W, message: &Message) -> io::Result<()> {
+    write_frame_with(writer, message, Codec::Json)
+}
+
+/// Write a message as a 4-byte big-endian length followed by its body encoded with `codec`
+pub(crate) fn write_frame_with<W: Write>(writer: &mut W, message: &Message, codec: Codec) -> io::Result<()> {
+    let body = codec.encode(message).map_err(io::Error::other)?;
+    let length = u32::try_from(body.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Message too large"))?;
+
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()
+}
+
+/// Read one length-prefixed message written by `write_frame`
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> io::Result<Message> {
+    read_frame_with(reader, Codec::Json)
+}
+
+/// Read one length-prefixed message written by `write_frame_with` using the same `codec`
+pub(crate) fn read_frame_with<R: Read>(reader: &mut R, codec: Codec) -> io::Result<Message> {
+    read_frame_limited(reader, codec, DEFAULT_MAX_FRAME_SIZE)
+}
+
+/// Read one length-prefixed message, refusing bodies over `max_frame_size` before allocating them
+pub(crate) fn read_frame_limited<R: Read>(reader: &mut R, codec: Codec, max_frame_size: usize) -> io::Result<Message> {
+    let mut length_bytes = [0u8; 4];
+    reader.read_exact(&mut length_bytes)?;
+    let length = u32::from_be_bytes(length_bytes) as usize;
+    if length > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the {} byte limit", length, max_frame_size),
+        ));
+    }
+
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    codec.decode(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, UnknownMessage(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_unknown_message_leaves_stream_aligned() {
+        let mut buffer = Vec::new();
+        let body = br#"{"FutureMessage":{"field":1}}"#;
+        buffer.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(body);
+        write_frame(&mut buffer, &Message::SyncRequest).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert!(read_frame(&mut reader).is_err_and(|e| is_unknown_message(&e)));
+        assert!(matches!(read_frame(&mut reader).unwrap(), Message::SyncRequest));
+
+        let mut oversized = Cursor::new(u32::MAX.to_be_bytes().to_vec());
+        assert!(read_frame(&mut oversized).is_err_and(|e| !is_unknown_message(&e)));
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let block = Block::new(1, vec![], Hash::genesis(), 4);
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Message::SyncRequest).unwrap();
+        write_frame(&mut buffer, &Message::NewBlock(block.clone())).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert!(matches!(read_frame(&mut reader).unwrap(), Message::SyncRequest));
+        match read_frame(&mut reader).unwrap() {
+            Message::NewBlock(received) => {
+                assert_eq!(received.current_block_hash, block.current_block_hash);
+            }
+            _ => panic!("Expected a NewBlock message"),
+        }
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_binary_frame_round_trip() {
+        let block = Block::new(1, vec![], Hash::genesis(), 4);
+        let mut buffer = Vec::new();
+        write_frame_with(&mut buffer, &Message::NewBlockAnnounce(block.header()), Codec::Binary).unwrap();
+
+        match read_frame_with(&mut Cursor::new(buffer.clone()), Codec::Binary).unwrap() {
+            Message::NewBlockAnnounce(header) => assert_eq!(header, block.header()),
+            _ => panic!("Expected a NewBlockAnnounce message"),
+        }
+        assert!(read_frame(&mut Cursor::new(buffer)).is_err());
+    }
+
+    #[test]
+    fn test_malformed_hash_in_message_rejected() {
+        let hash = Hash::new(b"requested block");
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Message::GetBlock(hash.clone())).unwrap();
+        let frame = String::from_utf8(buffer[4..].to_vec()).unwrap();
+        let read = |body: String| {
+            let mut framed = (body.len() as u32).to_be_bytes().to_vec();
+            framed.extend_from_slice(body.as_bytes());
+            read_frame(&mut Cursor::new(framed))
+        };
+
+        assert!(matches!(read(frame.clone()).unwrap(), Message::GetBlock(decoded) if decoded == hash));
+        let uppercase = read(frame.replace(&hash.value, &hash.value.to_uppercase()));
+        assert!(uppercase.is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+        assert!(read(frame.replace(&hash.value, &hash.value[..62])).is_err());
+    }
+
+    #[test]
+    fn test_truncated_frame_is_an_error() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Message::SyncResponse { tip: None, total_work: 0 }).unwrap();
+        buffer.truncate(buffer.len() - 1);
+
+        let mut reader = Cursor::new(buffer);
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_oversized_length_rejected_before_reading_body() {
+        // Only the header is present, reading a body would fail with UnexpectedEof instead
+        let mut reader = Cursor::new(u32::MAX.to_be_bytes().to_vec());
+        match read_frame_limited(&mut reader, Codec::Json, 1024) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("Expected an oversized frame to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_large_frame_within_limit_accepted() {
+        let mut block = Block::new(1, vec![], Hash::genesis(), 4);
+        block.data = vec![7; 512 * 1024];
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Message::NewBlock(block)).unwrap();
+        let frame_size = buffer.len() - 4;
+
+        match read_frame_limited(&mut Cursor::new(buffer.clone()), Codec::Json, frame_size).unwrap() {
+            Message::NewBlock(received) => assert_eq!(received.data.len(), 512 * 1024),
+            _ => panic!("Expected a NewBlock message"),
+        }
+        assert!(read_frame_limited(&mut Cursor::new(buffer), Codec::Json, frame_size - 1).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::clock::MAX_CLOCK_SKEW_SECS;
+use crate::hash::Hash;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+pub(crate) const DEFAULT_CHAIN_ID: u64 = 1;
+
+/// Version of the chain format committed into the genesis block, raised when networks must not share a genesis
+pub(crate) const CHAIN_VERSION: u32 = 1;
+
+/// Consensus parameters every node on the same network must agree on
+#[derive(Clone)]
+pub(crate) struct NetworkConfig {
+    /// Committed into every transaction signature so it cannot be replayed on another network
+    pub chain_id: u64,
+    /// Amount minted by the coinbase transaction of every block
+    pub block_subsidy: u64,
+    /// Trusted block hashes at known heights
+    pub checkpoints: BTreeMap<u64, Hash>,
+    /// Skip proof-of-work re-verification for blocks at or below the newest checkpoint
+    pub skip_pow_below_checkpoint: bool,
+    /// Smallest transaction amount accepted into the pool, if bounded
+    pub min_amount: Option<u64>,
+    /// Largest transaction amount accepted into the pool, if bounded
+    pub max_amount: Option<u64>,
+    /// Base fee of the first block after genesis, 0 disables fee burning
+    pub initial_base_fee: u64,
+    /// Transaction count per block the base fee steers towards
+    pub target_block_transactions: usize,
+    /// Largest gas limit a single transaction may declare
+    pub max_gas_limit: u64,
+    /// Largest total `Transaction::weight` of a block, whatever its size in bytes
+    pub max_block_weight: u64,
+    /// Confirmations after which a transaction is reported as confirmed
+    pub confirmation_depth: u64,
+    /// Verify the signatures of large blocks on several threads
+    pub parallel_signature_verification: bool,
+    /// Block interval difficulty retargeting steers towards
+    pub target_block_time_secs: u64,
+    /// Blocks whose median timestamp stands for the time of a window when retargeting
+    pub retarget_window: usize,
+    /// Blocks that must be built on top of a coinbase before its reward can be spent, 0 disables the lock
+    pub coinbase_maturity: u64,
+    /// Deepest fork, in blocks below the tip, the chain will reorganize onto
+    pub max_reorg_depth: u64,
+    /// Blocks at least this far below the tip are final and can never be reorganized away, `None` disables finality
+    pub finality_depth: Option<u64>,
+    /// Seconds a transaction may be timestamped after the block including it, allowing for clock differences
+    pub transaction_time_tolerance_secs: u64,
+    /// Seconds a transaction may be timestamped before the block including it
+    pub max_transaction_age_secs: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            chain_id: DEFAULT_CHAIN_ID,
+            block_subsidy: 50,
+            checkpoints: BTreeMap::new(),
+            skip_pow_below_checkpoint: false,
+            min_amount: None,
+            max_amount: None,
+            initial_base_fee: 0,
+            target_block_transactions: 100,
+            max_gas_limit: 1_000_000,
+            max_block_weight: 100_000,
+            confirmation_depth: 6,
+            parallel_signature_verification: true,
+            target_block_time_secs: 600,
+            retarget_window: 11,
+            coinbase_maturity: 0,
+            max_reorg_depth: 100,
+            finality_depth: None,
+            transaction_time_tolerance_secs: MAX_CLOCK_SKEW_SECS as u64,
+            max_transaction_age_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Leading zero bits of the genesis block hash, about 65 thousand hashes per block
+pub(crate) const DEFAULT_DIFFICULTY: u32 = 16;
+
+/// Contents of the genesis block, which fix the network's genesis hash
+#[derive(Clone)]
+pub(crate) struct GenesisConfig {
+    pub timestamp: DateTime<Utc>,
+    pub difficulty: u32,
+    /// Chain id of the network, committed into the genesis hash so no two networks share a genesis
+    pub network_id: u64,
+    /// Chain format version, committed into the genesis hash alongside the network id
+    pub version: u32,
+    /// Arbitrary bytes hashed into the genesis block so each network gets its own genesis hash
+    pub extra_data: Vec<u8>,
+}
+
+impl Default for GenesisConfig {
+    fn default() -> Self {
+        Self {
+            timestamp: Utc::now(),
+            difficulty: DEFAULT_DIFFICULTY,
+            network_id: DEFAULT_CHAIN_ID,
+            version: CHAIN_VERSION,
+            extra_data: Vec::new(),
+        }
+    }
+}
+
+impl GenesisConfig {
+    /// Data of the genesis block: network id and chain version, followed by `extra_data`
+    pub(crate) fn block_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(12 + self.extra_data.len());
+        data.extend_from_slice(&self.network_id.to_le_bytes());
+        data.extend_from_slice(&self.version.to_le_bytes());
+        data.extend_from_slice(&self.extra_data);
+        data
+    }
+}
+
+impl NetworkConfig {
+    pub fn checkpoint_at(&self, height: u64) -> Option<&Hash> {
+        self.checkpoints.get(&height)
+    }
+
+    pub fn newest_checkpoint_height(&self) -> Option<u64> {
+        self.checkpoints.keys().next_back().copied()
+    }
+
+    pub fn check_gas_limit(&self, gas_limit: u64) -> Result<(), String> {
+        if gas_limit > self.max_gas_limit {
+            return Err(format!("Gas limit {} is above the maximum of {}", gas_limit, self.max_gas_limit));
+        }
+        Ok(())
+    }
+
+    pub fn check_amount(&self, amount: u64) -> Result<(), String> {
+        if let Some(min_amount) = self.min_amount {
+            if amount < min_amount {
+                return Err(format!("Transaction amount {} is below the minimum of {}", amount, min_amount));
+            }
+        }
+
+        if let Some(max_amount) = self.max_amount {
+            if amount > max_amount {
+                return Err(format!("Transaction amount {} is above the maximum of {}", amount, max_amount));
+            }
+        }
+
+        Ok(())
+    }
+}
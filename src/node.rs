@@ -1,11 +1,37 @@
+use crate::address::Address;
+use crate::block::{Block, BlockHeader};
 use crate::chain::Chain;
-use crate::peer::PeerNode;
-use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
+use crate::clock::{Clock, PeerClockOffsets, SystemClock};
+use crate::peer::{AccessList, PeerNode, PeerScores};
 use std::net::{IpAddr, TcpListener, TcpStream};
-use std::{env, io};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use std::io;
 use crate::block_builder::BlockBuilder;
+use crate::codec::Codec;
+use crate::config::{BlockSchedule, Config, PoolLimits};
+use crate::connections::PeerConnections;
+use crate::events::ChainEvent;
+use crate::message::{
+    is_unknown_message, read_frame, read_frame_limited, read_frame_with, write_frame, write_frame_with, Message,
+    DEFAULT_MAX_FRAME_SIZE, MAX_MEMPOOL_BYTES, MAX_MEMPOOL_TRANSACTIONS,
+};
+use crate::rpc::RpcContext;
+use crate::secure::{self, Encryption, NodeKey, PeerSecurity, PeerStream};
+use crate::state::AccountState;
+use crate::hash::Hash;
 use crate::store::StoreError;
+use crate::transaction::Transaction;
+use crate::transaction_pool::TransactionPool;
+
+/// How long an announcer waits for a peer to ask for the announced block
+const ANNOUNCE_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a syncing node waits for each handshake or sync reply unless configured otherwise
+pub(crate) const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Most blocks downloaded from a peer when catching up with it, see `catch_up_with`
+const MAX_CATCH_UP_BLOCKS: usize = 1000;
 
 pub(crate) trait NodeInfo {
     fn ip(&self) -> IpAddr;
@@ -15,13 +41,82 @@ pub(crate) trait NodeInfo {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// What a node is run for, deciding which background tasks it starts and what it may discard
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum NodeRole {
+    /// Validates and relays blocks and transactions, and may prune old history
+    FullNode,
+    /// A full node that also runs the mining loop, the behaviour of every node before roles existed
+    #[default]
+    Miner,
+    /// Keeps and serves the whole history, never prunes
+    Archive,
+}
+
+impl NodeRole {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "full" | "fullnode" => Some(NodeRole::FullNode),
+            "miner" => Some(NodeRole::Miner),
+            "archive" => Some(NodeRole::Archive),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn mines(self) -> bool {
+        self == NodeRole::Miner
+    }
+
+    pub(crate) fn may_prune(self) -> bool {
+        self != NodeRole::Archive
+    }
+}
+
+/// Outcome of the `Hello` exchange on an outgoing connection
+struct Handshake {
+    stream: PeerStream,
+    codec: Codec,
+    /// Time and tip reported by the peer
+    peer_time: DateTime<Utc>,
+    peer_tip: Option<BlockHeader>,
+}
+
+#[derive(Clone)]
 pub(crate) struct Node {
     ip: IpAddr,
     port: u16,
-    #[serde(skip)]
-    peers: Vec<PeerNode>,
-    chain: Chain
+    /// Known peers, shared with the mining loop and RPC
+    peers: Arc<RwLock<Vec<PeerNode>>>,
+    chain: Arc<RwLock<Chain>>,
+    peers_file: Option<String>,
+    rpc_port: Option<u16>,
+    miner_address: Option<Address>,
+    codec: Codec,
+    clock: Arc<dyn Clock>,
+    clock_offsets: Arc<Mutex<PeerClockOffsets>>,
+    refuse_skewed_peers: bool,
+    max_frame_size: usize,
+    /// Strikes against peers that sent messages we could not decode
+    peer_scores: Arc<Mutex<PeerScores>>,
+    /// Connections to peers kept open for gossip, shared with the mining loop
+    connections: Arc<PeerConnections>,
+    /// Pending transactions, shared by the mining loop, RPC and peer connections
+    transaction_pool: Arc<Mutex<TransactionPool>>,
+    /// Balances and nonces at the tip, updated block by block as the chain grows
+    state: Arc<RwLock<AccountState>>,
+    /// Peers synced with at startup, see `contact_peers`
+    max_sync_peers: usize,
+    /// Longest wait for each reply while syncing, a peer exceeding it is skipped for the next one
+    handshake_timeout: Duration,
+    /// Whether peer connections are encrypted, and the node key presented in them
+    security: PeerSecurity,
+    /// Addresses inbound connections are accepted from
+    access: AccessList,
+    role: NodeRole,
+    /// When and how the mining loop builds blocks
+    block_schedule: BlockSchedule,
+    /// Blocks whose transactions stay indexed at startup, see `prune`
+    prune_keep_recent: Option<u64>,
 }
 
 impl NodeInfo for Node {
@@ -35,81 +130,435 @@ impl NodeInfo for Node {
 }
 
 impl Node {
-    pub(crate) fn me(chain: Chain) -> Self {
-        match (env::var("NODE_IP"), env::var("NODE_PORT")) {
-            (Ok(ip_str), Ok(port_str)) => {
-                match (ip_str.trim().parse::<IpAddr>(), port_str.trim().parse::<u16>()) {
-                    (Ok(ip), Ok(port)) => {
-                        let peers = PeerNode::get_peers_node_ips_from_env();
-                        println!("Peers {}", peers.clone().iter().len());
-                        Node { ip, port, peers, chain }
-                    },
-                    (Err(_), _) => panic!("Failed to parse NODE_IP as IpAddr"),
-                    (_, Err(_)) => panic!("Failed to parse NODE_PORT as u16"),
+    /// Node described by `config`, with the peers it lists merged with the known-peers file
+    pub(crate) fn from_config(config: &Config, chain: Chain) -> Self {
+        let peers_file = config.peers_file();
+        let peers = PeerNode::merge(config.peers.clone(), PeerNode::load_known_peers(&peers_file));
+
+        let mut node = Node::new(config.ip, config.port, peers, chain)
+            .with_peers_file(peers_file)
+            .with_codec(config.codec)
+            .with_encryption(config.encryption)
+            .with_access_list(config.access.clone())
+            .with_role(config.role)
+            .with_pool_limits(config.pool_limits.clone());
+        node.refuse_skewed_peers = config.refuse_skewed_peers;
+        node.max_frame_size = config.max_frame_size;
+        node.rpc_port = config.rpc_port;
+        node.miner_address = config.miner_address.clone();
+        node.max_sync_peers = config.max_sync_peers;
+        node.handshake_timeout = config.handshake_timeout;
+        node.block_schedule = config.block_schedule.clone();
+        node.prune_keep_recent = config.prune_keep_recent;
+        println!("Peers {}", node.peer_count());
+        if config.encryption.offered() {
+            match NodeKey::load_or_create(&config.node_key_file()) {
+                Ok(key) => {
+                    println!("Node key {}", key.public_key());
+                    node = node.with_node_key(key);
                 }
+                Err(e) => eprintln!("Failed to load the node key, using one for this run only: {}", e),
+            }
+        }
+        node
+    }
+
+    pub(crate) fn new(ip: IpAddr, port: u16, peers: Vec<PeerNode>, chain: Chain) -> Self {
+        let transaction_pool = BlockBuilder::pool_for(&chain, &PoolLimits::default());
+        let state = AccountState::from_chain(&chain).unwrap_or_else(|e| {
+            eprintln!("Failed to replay the chain into account state: {}", e);
+            AccountState::new()
+        });
+        Node {
+            ip,
+            port,
+            peers: Arc::new(RwLock::new(peers)),
+            chain: Arc::new(RwLock::new(chain)),
+            peers_file: None,
+            rpc_port: None,
+            miner_address: None,
+            codec: Codec::Json,
+            clock: Arc::new(SystemClock),
+            clock_offsets: Arc::new(Mutex::new(PeerClockOffsets::default())),
+            refuse_skewed_peers: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            peer_scores: Arc::new(Mutex::new(PeerScores::default())),
+            connections: Arc::new(PeerConnections::default()),
+            transaction_pool: Arc::new(Mutex::new(transaction_pool)),
+            state: Arc::new(RwLock::new(state)),
+            max_sync_peers: Config::default().max_sync_peers,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            security: PeerSecurity::default(),
+            access: AccessList::default(),
+            role: NodeRole::default(),
+            block_schedule: BlockSchedule::default(),
+            prune_keep_recent: None,
+        }
+    }
+
+    /// Remember learned peers in `path` across restarts
+    pub(crate) fn with_peers_file(mut self, path: String) -> Self {
+        self.peers_file = Some(path);
+        self
+    }
+
+    /// Prefer `codec` on peer connections, peers that do not support it fall back to JSON
+    pub(crate) fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Encrypt peer connections according to `encryption`
+    pub(crate) fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.security.encryption = encryption;
+        self
+    }
+
+    /// Identify this node with `key` in encrypted sessions instead of a key generated for this run
+    pub(crate) fn with_node_key(mut self, key: NodeKey) -> Self {
+        self.security.key = Arc::new(key);
+        self
+    }
+
+    /// Only accept inbound connections from addresses `access` permits
+    pub(crate) fn with_access_list(mut self, access: AccessList) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Run as `role`, see `NodeRole`
+    pub(crate) fn with_role(mut self, role: NodeRole) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Forget where the transactions of all but the `keep_recent` most recent blocks are, so they can
+    /// no longer be looked up by id. The blocks themselves stay, the chain is validated from them on load.
+    /// Archive nodes refuse, they serve the whole history. Returns how many lookups were dropped.
+    pub(crate) fn prune(&self, keep_recent: u64) -> Result<usize, String> {
+        if !self.role.may_prune() {
+            return Err("Archive nodes keep their whole history".to_string());
+        }
+        let mut chain = self.chain.write().unwrap();
+        let Some(below) = chain.blocks.last().and_then(|tip| (tip.index + 1).checked_sub(keep_recent)) else {
+            return Ok(0);
+        };
+        Ok(chain.prune_index_below(below))
+    }
+
+    /// Replace the transaction pool with an empty one sized by `limits`
+    pub(crate) fn with_pool_limits(self, limits: PoolLimits) -> Self {
+        let pool = BlockBuilder::pool_for(&self.chain.read().unwrap(), &limits);
+        *self.transaction_pool.lock().unwrap() = pool;
+        self
+    }
+
+    /// Receive an event for every block added to the chain and every transaction admitted to the pool
+    pub(crate) fn subscribe(&self) -> Receiver<ChainEvent> {
+        self.chain.read().unwrap().events().subscribe()
+    }
+
+    /// Balance of `address` at the tip
+    #[cfg(test)]
+    pub(crate) fn balance(&self, address: &Address) -> u64 {
+        self.state.read().unwrap().balance(address)
+    }
+
+    /// Add `block` to `chain` and apply it to the live account state
+    fn adopt_block(chain: &mut Chain, state: &RwLock<AccountState>, block: Block) -> Result<Hash, StoreError> {
+        let hash = chain.add_block(block)?;
+        let mut state = state.write().unwrap();
+        if let Err(e) = chain.blocks.last().map_or(Ok(()), |tip| state.apply_block(tip)) {
+            eprintln!("Account state diverged from the chain, replaying it: {}", e);
+            *state = AccountState::from_chain(chain)?;
+        }
+        Ok(hash)
+    }
+
+    /// Switch the chain to `branch` with `Chain::try_reorg`, move the live state from the dropped blocks to the
+    /// adopted ones and give the transactions of the dropped blocks back to the pool. Returns how many the pool
+    /// took back.
+    fn adopt_branch(&self, branch: Vec<Block>) -> Result<usize, StoreError> {
+        let mut chain = self.chain.write().unwrap();
+        let fork_height = branch
+            .first()
+            .and_then(|block| block.previous_block_hash.as_ref())
+            .and_then(|hash| chain.get_block_by_hash(hash))
+            .map_or(chain.blocks.len(), |block| block.index as usize + 1);
+        let dropped_blocks = chain.blocks.get(fork_height..).unwrap_or_default().to_vec();
+        let returned = chain.try_reorg(branch)?;
+
+        let adopted = chain.blocks.get(fork_height..).unwrap_or_default().to_vec();
+        let mut state = self.state.read().unwrap().clone();
+        for block in dropped_blocks.iter().rev() {
+            state.revert_block(block);
+        }
+        if let Err(e) = adopted.iter().try_for_each(|block| state.apply_block(block)) {
+            eprintln!("Account state diverged from the chain, replaying it: {}", e);
+            state = AccountState::from_chain(&chain)?;
+        }
+        drop(chain);
+
+        let mut pool = self.transaction_pool.lock().unwrap();
+        for block in &adopted {
+            pool.confirm_block(block);
+        }
+        let ids: Vec<String> = returned.iter().map(|tx| tx.id.clone()).collect();
+        for tx in returned {
+            let account_nonce = state.nonce(&tx.from);
+            if let Err(e) = pool.submit(tx, account_nonce) {
+                eprintln!("Dropping transaction from a reorganized block: {}", e);
+            }
+        }
+        pool.revalidate_against_state(&state);
+        *self.state.write().unwrap() = state;
+        Ok(ids.iter().filter(|id| pool.contains(id)).count())
+    }
+
+    /// Read time from `clock` instead of the system clock
+    #[cfg(test)]
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Record the time a peer reported in its handshake, returning whether to keep talking to it
+    fn accept_peer_clock(&self, peer: &str, remote: DateTime<Utc>) -> bool {
+        let mut clock_offsets = self.clock_offsets.lock().unwrap();
+        let offset = clock_offsets.record(peer, self.clock.now(), remote);
+        if clock_offsets.local_clock_is_outlier() {
+            eprintln!(
+                "Local clock is {}s away from the median of peer clocks, check the system time",
+                clock_offsets.median().unwrap_or_default()
+            );
+        }
+        drop(clock_offsets);
+
+        if !PeerClockOffsets::is_skewed(offset) {
+            return true;
+        }
+
+        eprintln!("Clock of peer {} is off by {}s", peer, offset);
+        !self.refuse_skewed_peers
+    }
+
+    pub(crate) fn peer_count(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+
+    /// Address, last contact, reported tip and latency of every known peer
+    #[cfg(test)]
+    pub(crate) fn peers(&self) -> Vec<crate::peer::PeerInfo> {
+        self.peers.read().unwrap().iter().map(PeerNode::info).collect()
+    }
+
+    fn register_peer(&self, peer: PeerNode) {
+        let mut peers = self.peers.write().unwrap();
+        if peers.contains(&peer) {
+            return;
+        }
+
+        println!("New peer registered: {}", peer.socket_addr());
+        peers.push(peer);
+        if let Some(path) = &self.peers_file {
+            if let Err(e) = PeerNode::save_known_peers(path, &peers) {
+                eprintln!("Failed to save known peers to {}: {}", path, e);
             }
-            (Err(_), _) => panic!("NODE_IP environment variable is not set"),
-            (_, Err(_)) => panic!("NODE_PORT environment variable is not set"),
         }
     }
 
     pub(crate) fn start(&mut self) {
+        self.prune_history();
+        self.start_rpc();
         self.contact_peers();
+        self.relay_transactions();
         self.building_new_block();
         self.listen_for_connections();
     }
 
-    fn handle_client(&mut self, mut stream: TcpStream) {
-        let mut buffer = [0; 1024];
+    /// Prune down to the `prune_keep_recent` newest blocks when configured, see `prune`
+    fn prune_history(&self) {
+        let Some(keep_recent) = self.prune_keep_recent else {
+            return;
+        };
+        match self.prune(keep_recent) {
+            Ok(pruned) => println!("Pruned {} transaction lookups below the newest {} blocks", pruned, keep_recent),
+            Err(e) => eprintln!("Not pruning: {}", e),
+        }
+    }
+
+    /// Send every transaction admitted to the pool on to the known peers, on a thread of its own. Peers skip
+    /// transactions they already hold, so a relayed transaction does not come back around.
+    fn relay_transactions(&self) -> std::thread::JoinHandle<()> {
+        let events = self.subscribe();
+        let node = self.clone();
+        std::thread::spawn(move || {
+            for event in events {
+                let ChainEvent::TxAccepted(id) = event else {
+                    continue;
+                };
+                let transaction = node.transaction_pool.lock().unwrap().get(&id).cloned();
+                if let Some(transaction) = transaction {
+                    node.broadcast(&Message::NewTransaction(transaction));
+                }
+            }
+        })
+    }
 
+    fn handle_client(&self, stream: TcpStream) {
+        let peer = stream.peer_addr().map(|address| address.ip().to_string()).unwrap_or_default();
+        if self.peer_scores.lock().unwrap().is_banned(&peer) {
+            eprintln!("Refusing banned peer {}", peer);
+            return;
+        }
+        let mut stream = match self.accept_session(stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Refusing peer {}: {}", peer, e);
+                return;
+            }
+        };
+        if let Some(key) = stream.peer_key() {
+            println!("Encrypted session with peer {} presenting key {}", peer, key);
+        }
+        let mut codec = Codec::Json;
         loop {
-            match stream.read(&mut buffer) {
-                Ok(n) if n == 0 => {
+            let message = match read_frame_limited(&mut stream, codec, self.max_frame_size) {
+                Ok(message) => message,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                     println!("Connection closed by client");
                     return;
                 }
-                Ok(n) => {
-                    let message = String::from_utf8_lossy(&buffer[..n]);
-                    println!("Received message: {}", message);
+                // Likely a newer peer, skip the message rather than dropping the connection
+                Err(e) if is_unknown_message(&e) => {
+                    let peer = Self::peer_ip(&stream);
+                    eprintln!("Ignoring message from {}: {}", peer, e);
+                    if self.peer_scores.lock().unwrap().penalize(&peer) {
+                        eprintln!("Banning peer {} after repeated unknown messages", peer);
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Failed to read from connection: {}", e);
+                    return;
+                }
+            };
 
-                    if message.starts_with("SYNC_REQUEST") {
-                        if let Ok(peer_addr) = stream.peer_addr() {
-                            let peer_node = PeerNode::new(peer_addr.ip(), peer_addr.port());
-                            self.peers.push(peer_node);
-                            println!("New peer registered: {}", peer_addr);
-                        }
+            match message {
+                Message::Hello { codec: preferred, timestamp, .. } => {
+                    let peer = Self::peer_ip(&stream);
+                    if !self.accept_peer_clock(&peer, timestamp) {
+                        eprintln!("Refusing peer {} with a skewed clock", peer);
+                        return;
+                    }
+
+                    let agreed = self.codec.negotiate(preferred);
+                    let reply = Message::Hello {
+                        codec: agreed,
+                        timestamp: self.clock.now(),
+                        tip: self.chain.read().unwrap().tip_header(),
+                    };
+                    if let Err(e) = write_frame(&mut stream, &reply) {
+                        eprintln!("Failed to answer handshake: {}", e);
+                        return;
+                    }
+                    codec = agreed;
+                }
+                Message::SyncRequest => {
+                    if let Ok(peer_addr) = stream.tcp().peer_addr() {
+                        self.register_peer(PeerNode::new(peer_addr.ip(), peer_addr.port()));
+                    }
 
-                        let response = "SYNC_RESPONSE".as_bytes();
-                        if let Err(e) = stream.write_all(response) {
-                            eprintln!("Failed to send sync response: {}", e);
-                            return;
+                    let (tip, total_work) = match self.chain.read().unwrap().tip_work() {
+                        Some((tip, total_work)) => (Some(tip), total_work),
+                        None => (None, 0),
+                    };
+                    let response = Message::SyncResponse { tip, total_work };
+                    if let Err(e) = write_frame_with(&mut stream, &response, codec) {
+                        eprintln!("Failed to send sync response: {}", e);
+                        return;
+                    }
+                }
+                Message::SyncResponse { .. } => {
+                    println!("Received sync response");
+                }
+                Message::NewBlock(block) => {
+                    let index = block.index;
+                    let mut chain = self.chain.write().unwrap();
+                    // Measured before adopting, the block moves the tip once it is in
+                    let parent = block.previous_block_hash.as_ref();
+                    match parent.and_then(|parent| chain.fork_depth(std::slice::from_ref(parent))) {
+                        Some(depth) => println!("Block {} from peer has fork depth {}", index, depth),
+                        None => println!("Block {} from peer does not build on any block we know", index),
+                    }
+                    match Self::adopt_block(&mut chain, &self.state, block) {
+                        Ok(hash) => {
+                            println!("Accepted block {} from peer with hash : {}", index, hash.value);
+                            if let Some(tip) = chain.blocks.last() {
+                                self.transaction_pool.lock().unwrap().confirm_block(tip);
+                            }
                         }
-                    } else {
-                        // Echo other messages
-                        if let Err(e) = stream.write(&buffer[..n]) {
-                            eprintln!("Failed to send response: {}", e);
-                            return;
+                        Err(e) => {
+                            eprintln!("Rejected block {} from peer: {}", index, e);
                         }
                     }
                 }
-                Err(e) => {
-                    eprintln!("Failed to read from connection: {}", e);
-                    return;
+                Message::NewBlockAnnounce(header) => {
+                    if self.chain.read().unwrap().get_block_by_hash(&header.hash).is_some() {
+                        continue;
+                    }
+
+                    println!("Requesting block {} announced by peer", header.index);
+                    if let Err(e) = write_frame_with(&mut stream, &Message::GetBlock(header.hash), codec) {
+                        eprintln!("Failed to request block {}: {}", header.index, e);
+                        return;
+                    }
+                }
+                Message::GetBlock(hash) => {
+                    let block = self.chain.read().unwrap().get_block_by_hash(&hash).cloned();
+                    let Some(block) = block else {
+                        eprintln!("Peer requested unknown block {}", hash.value);
+                        continue;
+                    };
+
+                    if let Err(e) = write_frame_with(&mut stream, &Message::NewBlock(block), codec) {
+                        eprintln!("Failed to send block {}: {}", hash.value, e);
+                        return;
+                    }
+                }
+                Message::NewTransaction(transaction) => {
+                    println!("Received transaction {}", transaction.id);
+                    self.accept_transactions(vec![transaction]);
+                }
+                Message::GetMempool => {
+                    let pending =
+                        self.transaction_pool.lock().unwrap().snapshot(MAX_MEMPOOL_TRANSACTIONS, MAX_MEMPOOL_BYTES);
+                    if let Err(e) = write_frame_with(&mut stream, &Message::Mempool(pending), codec) {
+                        eprintln!("Failed to send mempool: {}", e);
+                        return;
+                    }
+                }
+                Message::Mempool(transactions) => {
+                    let added = self.accept_transactions(transactions);
+                    println!("Added {} pending transactions from peer", added);
                 }
             }
         }
     }
 
-    fn listen_for_connections(&mut self) {
+    fn peer_ip(stream: &PeerStream) -> String {
+        stream.tcp().peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+    }
+
+    /// Serve every connection on its own thread, peers keep theirs open between messages
+    fn listen_for_connections(&self) {
         let listener = TcpListener::bind(self.socket_addr()).expect("Failed to bind to address");
         println!("Node is now listening on {}", self.socket_addr());
 
         for stream in listener.incoming() {
             match stream {
-                Ok(stream) => {
-                    println!("New connection from : {}", stream.peer_addr().unwrap());
-                    self.handle_client(stream);
-                }
+                Ok(stream) => self.accept(stream),
                 Err(e) => {
                     eprintln!("Connection failed : {}", e);
                 }
@@ -117,48 +566,396 @@ impl Node {
         }
     }
 
-    fn contact_peers(&self) {
-        if self.peers.is_empty() {
+    /// Serve `stream` on a thread of its own, or close it right away when the access list refuses the peer
+    fn accept(&self, stream: TcpStream) {
+        let Ok(peer) = stream.peer_addr() else {
+            return;
+        };
+        if !self.access.permits(&peer.ip()) {
+            eprintln!("Refusing connection from {}: address not allowed", peer);
+            return;
+        }
+
+        println!("New connection from : {}", peer);
+        let node = self.clone();
+        std::thread::spawn(move || node.handle_client(stream));
+    }
+
+    /// Sync with up to `max_sync_peers` peers in `PeerNode::sync_order`, moving on to the next peer
+    /// only when one fails, so a starting node does not hit every peer at once
+    fn contact_peers(&mut self) {
+        let peers = PeerNode::sync_order(&self.peers.read().unwrap());
+        if peers.is_empty() {
             eprintln!("No peers to sync with.");
             return;
         }
 
-        println!("Syncing with {} peers...", self.peers.len());
-        self.peers.iter().for_each(|peer| {
-            match (self.connect_to_peer(peer)) {
-                Ok(mut stream) => {
-                    println!("Syncing with peer: {}...", peer.socket_addr());
+        println!("Syncing with up to {} of {} peers...", self.max_sync_peers, peers.len());
+        let mut synced = 0;
+        for peer in peers {
+            if synced >= self.max_sync_peers {
+                break;
+            }
+            match self.sync_with(&peer) {
+                Ok(()) => synced += 1,
+                Err(e) => eprintln!("Failed to sync with peer {}: {}", peer.socket_addr(), e),
+            }
+        }
+        if let Some(target) = self.sync_target() {
+            println!("Peer {} has the chain with the most work", target.socket_addr());
+            match self.catch_up_with(&target) {
+                Ok(returned) => println!(
+                    "Caught up with peer {}, {} transactions went back to the pool",
+                    target.socket_addr(),
+                    returned
+                ),
+                Err(e) => eprintln!("Failed to catch up with peer {}: {}", target.socket_addr(), e),
+            }
+        }
+    }
+
+    /// Download the blocks of `peer` we lack, walking back from its tip to a block we hold, and switch to them
+    /// with `adopt_branch`. Returns how many transactions of our dropped blocks went back to the pool.
+    fn catch_up_with(&self, peer: &PeerNode) -> Result<usize, StoreError> {
+        let Some(tip) = peer.info().tip else {
+            return Ok(0);
+        };
+        let mut hash = tip.hash;
+        let mut branch = Vec::new();
+        while self.chain.read().unwrap().get_block_by_hash(&hash).is_none() {
+            if branch.len() >= MAX_CATCH_UP_BLOCKS {
+                return Err(StoreError::ValidationError(format!(
+                    "Peer is more than {} blocks ahead", MAX_CATCH_UP_BLOCKS
+                )));
+            }
+            let block = self.fetch_block(peer, &hash).map_err(StoreError::IoError)?;
+            let Some(previous) = block.previous_block_hash.clone() else {
+                return Err(StoreError::ValidationError("Peer chain has another genesis block".to_string()));
+            };
+            branch.push(block);
+            hash = previous;
+        }
+        if branch.is_empty() {
+            return Ok(0);
+        }
 
-                    // Send sync request
-                    let sync_message = "SYNC_REQUEST".as_bytes();
-                    if let Err(e) = stream.write_all(sync_message) {
-                        eprintln!("Failed to send sync request to {}: {}", peer.socket_addr(), e);
-                        return;
-                    }
+        branch.reverse();
+        self.adopt_branch(branch)
+    }
 
-                    eprintln!("Synced with peer: {}", peer.socket_addr());
-                }
-                Err(e) => {
-                    eprintln!("Failed to sync with peer {}: {}", peer.socket_addr(), e);
+    /// Ask `peer` for the block with `hash`, ignoring replies carrying any other block
+    fn fetch_block(&self, peer: &PeerNode, hash: &Hash) -> io::Result<Block> {
+        self.connections.with_connection(peer, self.codec, &self.security, |stream, codec| {
+            write_frame_with(stream, &Message::GetBlock(hash.clone()), codec)?;
+            stream.tcp().set_read_timeout(Some(self.handshake_timeout))?;
+            loop {
+                let reply = read_frame_limited(stream, codec, self.max_frame_size)
+                    .map_err(|e| Self::reply_timed_out(e, "block", self.handshake_timeout))?;
+                if let Message::NewBlock(block) = reply {
+                    if block.current_block_hash.as_ref() == Some(hash) {
+                        stream.tcp().set_read_timeout(None)?;
+                        return Ok(block);
+                    }
                 }
             }
         })
     }
 
-    fn connect_to_peer(&self, peer: &PeerNode) -> io::Result<TcpStream> {
+    fn sync_with(&mut self, peer: &PeerNode) -> io::Result<()> {
+        let started = std::time::Instant::now();
+        let stream = Self::secure_connect(peer, &self.security, self.handshake_timeout)?;
+        let tip = self.chain.read().unwrap().tip_header();
+        let Handshake { mut stream, codec, peer_time, peer_tip } =
+            Self::handshake(stream, self.codec, self.clock.as_ref(), self.handshake_timeout, tip)?;
+        let latency_ms = started.elapsed().as_millis() as u64;
+        if let Some(known) = self.peers.write().unwrap().iter_mut().find(|known| *known == peer) {
+            known.record_handshake(latency_ms);
+            known.record_tip(peer_tip);
+        }
+
+        if !self.accept_peer_clock(&peer.ip().to_string(), peer_time) {
+            return Err(io::Error::other("peer clock is skewed"));
+        }
+        println!("Syncing with peer: {}...", peer.socket_addr());
+
+        write_frame_with(&mut stream, &Message::SyncRequest, codec)?;
+        let (peer_tip, total_work) = self.read_sync_response(&mut stream, codec)?;
+        if let Some(known) = self.peers.write().unwrap().iter_mut().find(|known| *known == peer) {
+            known.record_tip(peer_tip);
+            known.record_work(total_work);
+        }
+        eprintln!("Synced with peer: {}", peer.socket_addr());
+
+        match self.fetch_mempool(&mut stream, codec) {
+            Ok(added) => println!("Added {} pending transactions from {}", added, peer.socket_addr()),
+            Err(e) => eprintln!("Failed to fetch mempool from {}: {}", peer.socket_addr(), e),
+        }
+        // Later gossip to this peer goes over the same connection
+        self.connections.insert(peer, stream, codec);
+        Ok(())
+    }
+
+    /// Wait for the answer to a `SyncRequest` sent on `stream`, returning the peer's tip and total work
+    fn read_sync_response(&self, stream: &mut PeerStream, codec: Codec) -> io::Result<(Option<BlockHeader>, u64)> {
+        stream.tcp().set_read_timeout(Some(self.handshake_timeout))?;
+        // Replies to earlier requests on the connection may arrive first
+        loop {
+            let reply = read_frame_limited(stream, codec, self.max_frame_size)
+                .map_err(|e| Self::reply_timed_out(e, "sync response", self.handshake_timeout))?;
+            if let Message::SyncResponse { tip, total_work } = reply {
+                return Ok((tip, total_work));
+            }
+        }
+    }
+
+    /// Name the wait that ran out when `error` is a read timeout, other errors pass through
+    fn reply_timed_out(error: io::Error, waiting_for: &str, timeout: Duration) -> io::Error {
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                io::Error::new(io::ErrorKind::TimedOut, format!("No {} within {:?}", waiting_for, timeout))
+            }
+            _ => error,
+        }
+    }
+
+    /// Peer to catch up with: the one whose chain carries the most work, not the one with the highest tip
+    pub(crate) fn sync_target(&self) -> Option<PeerNode> {
+        let peers = self.peers.read().unwrap();
+        let target = PeerNode::sync_target(&peers)?;
+        let own_work = self.chain.read().unwrap().total_work();
+        (target.total_work() > Some(own_work)).then(|| target.clone())
+    }
+
+    /// Ask the peer on `stream` for its pending transactions and add the valid ones to our pool
+    fn fetch_mempool(&self, stream: &mut PeerStream, codec: Codec) -> io::Result<usize> {
+        write_frame_with(stream, &Message::GetMempool, codec)?;
+        stream.tcp().set_read_timeout(Some(ANNOUNCE_REPLY_TIMEOUT))?;
+        // Replies to earlier requests on the connection may arrive first
+        loop {
+            if let Message::Mempool(transactions) = read_frame_limited(stream, codec, self.max_frame_size)? {
+                return Ok(self.accept_transactions(transactions));
+            }
+        }
+    }
+
+    /// Add transactions learned from peers to the pool, or hold them until their nonce gap fills,
+    /// skipping known and invalid ones. Returns how many were taken.
+    fn accept_transactions(&self, transactions: Vec<Transaction>) -> usize {
+        let chain_id = self.chain.read().unwrap().network().chain_id;
+        let state = self.state.read().unwrap();
+        let mut pool = self.transaction_pool.lock().unwrap();
+        let mut added = 0;
+        for tx in transactions.into_iter().take(MAX_MEMPOOL_TRANSACTIONS) {
+            if pool.contains(&tx.id) || !tx.is_signed_by_sender(chain_id) {
+                continue;
+            }
+            let id = tx.id.clone();
+            let account_nonce = state.nonce(&tx.from);
+            match pool.submit(tx, account_nonce) {
+                Ok(_) => added += 1,
+                Err(e) => eprintln!("Ignoring transaction {} from peer: {}", id, e),
+            }
+        }
+        added
+    }
+
+    fn connect(peer: &PeerNode) -> io::Result<TcpStream> {
         let socket = (peer.ip(), peer.port());
         TcpStream::connect(socket)
     }
 
-    fn building_new_block(&self) {
-        let block_builder = BlockBuilder::new(self.chain.clone());
-        let mut block_builder_clone = block_builder.clone();
+    /// Connect to `peer` and agree on the codec and encryption for the rest of the connection.
+    /// Plaintext JSON needs no `Hello` since every peer speaks it.
+    pub(crate) fn open_session(
+        peer: &PeerNode,
+        codec: Codec,
+        security: &PeerSecurity,
+    ) -> io::Result<(PeerStream, Codec)> {
+        let stream = Self::secure_connect(peer, security, DEFAULT_HANDSHAKE_TIMEOUT)?;
+        if codec == Codec::Json {
+            return Ok((stream, codec));
+        }
+
+        let handshake = Self::handshake(stream, codec, &SystemClock, DEFAULT_HANDSHAKE_TIMEOUT, None)?;
+        Ok((handshake.stream, handshake.codec))
+    }
+
+    /// Connect to `peer`, opening with a Noise handshake unless encryption is disabled, before anything else is
+    /// sent. A peer whose key is pinned must present that key. Only a node preferring encryption falls back to
+    /// plaintext, and never with a pinned peer, whose plaintext connection could come from anyone.
+    fn secure_connect(peer: &PeerNode, security: &PeerSecurity, timeout: Duration) -> io::Result<PeerStream> {
+        let stream = Self::connect(peer)?;
+        if !security.encryption.offered() {
+            return Ok(PeerStream::Plain(stream));
+        }
+
+        stream.set_read_timeout(Some(timeout))?;
+        let session = match secure::initiate(stream, &security.key) {
+            Ok(session) => session,
+            Err(e) if security.encryption == Encryption::Preferred && peer.key().is_none() => {
+                eprintln!("Peer {} does not encrypt, connecting in plaintext: {}", peer.socket_addr(), e);
+                return Self::connect(peer).map(PeerStream::Plain);
+            }
+            Err(e) => {
+                let message = format!("Peer does not support encryption: {}", e);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+            }
+        };
+        session.get_ref().set_read_timeout(None)?;
+
+        let presented = session.peer_key();
+        if let Some(pinned) = peer.key().filter(|pinned| presented.as_deref() != Some(*pinned)) {
+            let message = format!("Peer presented key {:?}, {} is pinned", presented, pinned);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        }
+        Ok(PeerStream::Encrypted(Box::new(session)))
+    }
+
+    /// Take an incoming connection, encrypted when it opens with a Noise handshake. Refuses that handshake when
+    /// encryption is disabled, and plaintext when it is required.
+    fn accept_session(&self, stream: TcpStream) -> io::Result<PeerStream> {
+        let refuse = |reason: &str| Err(io::Error::new(io::ErrorKind::PermissionDenied, reason.to_string()));
+        stream.set_read_timeout(Some(self.handshake_timeout))?;
+        let encrypted = secure::opens_with_handshake(&stream)?;
+        let stream = match (encrypted, self.security.encryption) {
+            (true, Encryption::Disabled) => return refuse("encryption is disabled"),
+            (true, _) => PeerStream::Encrypted(Box::new(secure::respond(stream, &self.security.key)?)),
+            (false, Encryption::Required) => return refuse("plaintext connections are refused"),
+            (false, _) => PeerStream::Plain(stream),
+        };
+        stream.tcp().set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Exchange `Hello` messages over `stream`, telling the peer about our `tip`. A peer that accepted the
+    /// connection but sends no reply within `timeout` fails the handshake instead of stalling the caller.
+    fn handshake(
+        mut stream: PeerStream,
+        codec: Codec,
+        clock: &dyn Clock,
+        timeout: Duration,
+        tip: Option<BlockHeader>,
+    ) -> io::Result<Handshake> {
+        write_frame(&mut stream, &Message::Hello { codec, timestamp: clock.now(), tip })?;
+        stream.tcp().set_read_timeout(Some(timeout))?;
+        let reply = read_frame(&mut stream).map_err(|e| Self::reply_timed_out(e, "handshake", timeout))?;
+        stream.tcp().set_read_timeout(None)?;
+        let Message::Hello { codec: agreed, timestamp, tip: peer_tip } = reply else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Expected a handshake reply"));
+        };
+        Ok(Handshake { stream, codec: agreed, peer_time: timestamp, peer_tip })
+    }
+
+    /// Send a message to every known peer, reporting the outcome for each one
+    pub(crate) fn broadcast(&self, message: &Message) -> Vec<(PeerNode, io::Result<()>)> {
+        let peers = self.peers.read().unwrap().clone();
+        Self::broadcast_to(&peers, &self.connections, self.codec, &self.security, message)
+    }
+
+    fn broadcast_to(
+        peers: &[PeerNode],
+        connections: &PeerConnections,
+        codec: Codec,
+        security: &PeerSecurity,
+        message: &Message,
+    ) -> Vec<(PeerNode, io::Result<()>)> {
+        peers
+            .iter()
+            .map(|peer| {
+                let result = connections
+                    .with_connection(peer, codec, security, |stream, codec| write_frame_with(stream, message, codec));
+                if let Err(e) = &result {
+                    eprintln!("Failed to send message to peer {}: {}", peer.socket_addr(), e);
+                }
+                (peer.clone(), result)
+            })
+            .collect()
+    }
+
+    /// Announce `block` by its header and send the body only to the peers that ask for it
+    fn announce_to(
+        peers: &[PeerNode],
+        connections: &PeerConnections,
+        codec: Codec,
+        security: &PeerSecurity,
+        block: &Block,
+    ) -> Vec<(PeerNode, io::Result<()>)> {
+        let announce = Message::NewBlockAnnounce(block.header());
+        peers
+            .iter()
+            .map(|peer| {
+                let result = connections.with_connection(peer, codec, security, |stream, codec| {
+                    write_frame_with(stream, &announce, codec)?;
+                    stream.tcp().set_read_timeout(Some(ANNOUNCE_REPLY_TIMEOUT))?;
+                    match read_frame_with(stream, codec) {
+                        Ok(Message::GetBlock(hash)) if block.current_block_hash.as_ref() == Some(&hash) => {
+                            write_frame_with(stream, &Message::NewBlock(block.clone()), codec)
+                        }
+                        Ok(_) => Ok(()),
+                        // No request means the peer already holds the block
+                        Err(e) if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+                        ) => Ok(()),
+                        Err(e) => Err(e),
+                    }
+                });
+                if let Err(e) = &result {
+                    eprintln!("Failed to announce block to peer {}: {}", peer.socket_addr(), e);
+                }
+                (peer.clone(), result)
+            })
+            .collect()
+    }
+
+    fn start_rpc(&self) {
+        let Some(rpc_port) = self.rpc_port else {
+            return;
+        };
+
+        let address = format!("{}:{}", self.ip, rpc_port);
+        let context = RpcContext::new(self.chain.clone())
+            .with_transaction_pool(self.transaction_pool.clone())
+            .with_peers(self.peers.clone())
+            .with_state(self.state.clone());
         std::thread::spawn(move || {
+            if let Err(e) = crate::rpc::serve(&address, context) {
+                eprintln!("RPC server stopped: {}", e);
+            }
+        });
+    }
+
+    /// Start the mining loop on a thread of its own, only miners do
+    fn building_new_block(&self) -> Option<std::thread::JoinHandle<()>> {
+        if !self.role.mines() {
+            println!("Running as {:?}, not mining", self.role);
+            return None;
+        }
+        let mut block_builder = BlockBuilder::new(self.chain.read().unwrap().detached())
+            .with_transaction_pool(self.transaction_pool.clone())
+            .with_schedule(&self.block_schedule)
+            .with_state(self.state.clone());
+        if let Some(miner_address) = &self.miner_address {
+            block_builder = block_builder.with_miner_address(miner_address.clone());
+        }
+        let chain = self.chain.clone();
+        let state = self.state.clone();
+        let peers = self.peers.clone();
+        let codec = self.codec;
+        let security = self.security.clone();
+        let connections = self.connections.clone();
+        Some(std::thread::spawn(move || {
             loop {
-                match block_builder_clone.mine_and_add_block() {
+                match block_builder.mine_onto(&chain, |chain, block| Self::adopt_block(chain, &state, block)) {
                     Ok(hash) => {
                         println!("Successfully mined new block to the chain. with hash : {}", hash.value);
+                        if let Some(block) = block_builder.chain().blocks.last() {
+                            println!("{}", block);
+                            let peers = peers.read().unwrap().clone();
+                            Self::announce_to(&peers, &connections, codec, &security, block);
+                        }
                     }
+                    Err(StoreError::StaleBlock(e)) => println!("Abandoned mined block: {}", e),
                     Err(e) => {
                         if matches!(e, StoreError::NoBlockToCreate()) {
                             eprintln!("Failed to mine block: {}", e);
@@ -168,7 +965,796 @@ impl Node {
 
                 std::thread::sleep(std::time::Duration::from_secs(5));
             }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{BlockHeader, MIN_DIFFICULTY};
+    use crate::clock::MockClock;
+    use crate::hash::Hash;
+    use crate::network::{GenesisConfig, NetworkConfig, DEFAULT_CHAIN_ID};
+    use crate::peer::MAX_PEER_STRIKES;
+    use crate::transaction::TRANSFER_GAS;
+    use std::env;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn local_node(peers: Vec<PeerNode>) -> Node {
+        Node::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, peers, Chain::new_easy())
+    }
+
+    fn unused_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn test_broadcast_reports_partial_failures() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let reachable_port = listener.local_addr().unwrap().port();
+        let receiver = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_frame(&mut stream).unwrap()
+        });
+
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let node = local_node(vec![
+            PeerNode::new(localhost, reachable_port),
+            PeerNode::new(localhost, unused_port()),
+        ]);
+
+        let results = node.broadcast(&Message::SyncRequest);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0.port(), reachable_port);
+        assert!(results[0].1.is_ok());
+        assert!(results[1].1.is_err());
+
+        assert!(matches!(receiver.join().unwrap(), Message::SyncRequest));
+    }
+
+    /// A node whose pool holds a transfer the chain funds, so a miner has a block to build
+    fn node_with_pending_transfer(role: NodeRole) -> Node {
+        let (alice, secret_key, _) = Address::generate();
+        let mut chain = Chain::new_easy();
+        let subsidy = chain.network().block_subsidy;
+        let funding = next_block(&chain, vec![Transaction::coinbase(alice.clone(), subsidy)]);
+        chain.add_block(funding).unwrap();
+        let node = Node::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, vec![], chain).with_role(role);
+        let mut tx = Transaction::new(alice, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        node.transaction_pool.lock().unwrap().add_transaction(tx).unwrap();
+        node
+    }
+
+    #[test]
+    fn test_miner_mines_pending_transactions() {
+        let node = node_with_pending_transfer(NodeRole::Miner);
+        assert!(node.building_new_block().is_some());
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while node.chain.read().unwrap().blocks.len() < 3 {
+            assert!(std::time::Instant::now() < deadline, "No block mined");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(node.chain.read().unwrap().blocks[2].transactions.iter().any(|tx| !tx.is_coinbase()));
+    }
+
+    #[test]
+    fn test_non_miners_do_not_mine() {
+        for role in [NodeRole::FullNode, NodeRole::Archive] {
+            let node = node_with_pending_transfer(role);
+            assert!(node.building_new_block().is_none());
+            assert_eq!(node.chain.read().unwrap().blocks.len(), 2);
+            assert_eq!(node.transaction_pool.lock().unwrap().pending_count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_archive_node_refuses_to_prune() {
+        let archive = node_with_pending_transfer(NodeRole::Archive);
+        let coinbase_id = archive.chain.read().unwrap().blocks[1].transactions[0].id.clone();
+        assert!(archive.prune(0).is_err());
+        assert!(archive.chain.read().unwrap().find_transaction(&coinbase_id).is_some());
+
+        let full = node_with_pending_transfer(NodeRole::FullNode);
+        let coinbase_id = full.chain.read().unwrap().blocks[1].transactions[0].id.clone();
+        assert_eq!(full.prune(1), Ok(0));
+        assert_eq!(full.prune(0), Ok(1));
+        assert!(full.chain.read().unwrap().find_transaction(&coinbase_id).is_none());
+    }
+
+    fn signed_transfer(fee: u64) -> Transaction {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, fee);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_mempool_fetched_from_peer_on_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_port = listener.local_addr().unwrap().port();
+        let peer = local_node(vec![]);
+        let pending = vec![signed_transfer(3), signed_transfer(5)];
+        for tx in &pending {
+            peer.transaction_pool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+        }
+        let peer_tip = peer.chain.read().unwrap().tip_header();
+        let serving = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            peer.handle_client(stream);
+        });
+
+        let mut node = local_node(vec![PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), peer_port)]);
+        node.contact_peers();
+        node.connections.close_all();
+        serving.join().unwrap();
+
+        assert!(peer_tip.is_some());
+        assert_eq!(node.peers()[0].tip, peer_tip);
+        let mut pool = node.transaction_pool.lock().unwrap();
+        assert_eq!(pool.pending_count(), 2);
+        assert!(pending.iter().all(|tx| pool.contains(&tx.id)));
+        assert_eq!(pool.pull_transactions_for_block()[0], pending[1]);
+    }
+
+    #[test]
+    fn test_subscriber_notified_of_transactions_and_blocks() {
+        let node = local_node(vec![]).with_pool_limits(PoolLimits::default());
+        let events = node.subscribe();
+
+        let tx = signed_transfer(2);
+        assert_eq!(node.accept_transactions(vec![tx.clone()]), 1);
+        assert_eq!(events.try_recv().unwrap(), ChainEvent::TxAccepted(tx.id));
+
+        let mut chain = node.chain.write().unwrap();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        let header = block.header();
+        assert_eq!(chain.add_block(block).unwrap(), header.hash);
+        assert_eq!(events.try_recv().unwrap(), ChainEvent::BlockAdded(header));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_live_state_follows_adopted_blocks() {
+        let node = local_node(vec![]);
+        let miner = Address::generate().0;
+        let mut chain = node.chain.write().unwrap();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let subsidy = chain.network().block_subsidy;
+        let mut block = Block::new(1, vec![Transaction::coinbase(miner.clone(), subsidy)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+
+        Node::adopt_block(&mut chain, &node.state, block).unwrap();
+        drop(chain);
+        assert_eq!(node.balance(&miner), subsidy);
+        assert_eq!(*node.state.read().unwrap(), AccountState::from_chain(&node.chain.read().unwrap()).unwrap());
+    }
+
+    fn next_block(chain: &Chain, transactions: Vec<Transaction>) -> Block {
+        let tip = chain.blocks.last().unwrap();
+        let mut block = Block::new(tip.index + 1, transactions, tip.current_block_hash.clone().unwrap(), 1)
+            .with_base_fee(chain.next_base_fee());
+        block.mine_block(1).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_reorg_returns_dropped_transactions_to_pool() {
+        let node = local_node(vec![]);
+        let (alice, alice_key, _) = Address::generate();
+        let mut chain = node.chain.write().unwrap();
+        let subsidy = chain.network().block_subsidy;
+        let funding = next_block(&chain, vec![Transaction::coinbase(alice.clone(), subsidy)]);
+        Node::adopt_block(&mut chain, &node.state, funding).unwrap();
+        let mut fork = chain.detached();
+
+        let mut tx = Transaction::new(alice.clone(), Address::generate().0, 10)
+            .with_gas(TRANSFER_GAS, chain.next_base_fee() + 1);
+        tx.sign(&alice_key, DEFAULT_CHAIN_ID).unwrap();
+        let spend = next_block(&chain, vec![tx.clone()]);
+        Node::adopt_block(&mut chain, &node.state, spend).unwrap();
+        drop(chain);
+        assert!(node.balance(&alice) < subsidy);
+
+        let branch: Vec<Block> = (0..2)
+            .map(|_| {
+                let block = next_block(&fork, vec![Transaction::coinbase(Address::generate().0, subsidy)]);
+                fork.add_block(block.clone()).unwrap();
+                block
+            })
+            .collect();
+        assert_eq!(node.adopt_branch(branch).unwrap(), 1);
+
+        assert!(node.transaction_pool.lock().unwrap().contains(&tx.id));
+        assert_eq!(node.balance(&alice), subsidy);
+        assert_eq!(*node.state.read().unwrap(), AccountState::from_chain(&node.chain.read().unwrap()).unwrap());
+    }
+
+    /// Peer node answering the first connection made to it, and its port
+    fn serving_peer() -> (u16, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let serving = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            local_node(vec![]).handle_client(stream);
+        });
+        (port, serving)
+    }
+
+    /// Chain extended by one block per entry of `difficulties`, mined at that difficulty
+    /// Append an empty block stamped `secs` seconds after the genesis block
+    fn append_block_at(chain: &mut Chain, secs: i64) {
+        let clock = MockClock::new(chain.genesis_block().timestamp + chrono::Duration::seconds(secs));
+        let previous = chain.blocks.last().unwrap();
+        let previous_hash = previous.current_block_hash.clone().unwrap();
+        let difficulty = chain.next_difficulty();
+        let mut block = Block::new_with_clock(previous.index + 1, vec![], previous_hash, difficulty, &clock)
+            .with_base_fee(chain.next_base_fee());
+        block.mine_block(block.difficulty).unwrap();
+        chain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_sync_targets_most_work_not_highest_tip() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let serve = |chain: Chain| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let peer = Node::new(localhost, 0, vec![], chain);
+            let serving = std::thread::spawn(move || peer.handle_client(listener.accept().unwrap().0));
+            (port, serving)
+        };
+        // Retargeting after every block lets a few fast blocks outweigh many slow ones
+        let network = NetworkConfig { retarget_window: 1, ..NetworkConfig::default() };
+        let target = network.target_block_time_secs as i64;
+        let genesis = GenesisConfig {
+            timestamp: Utc::now() - chrono::Duration::days(1),
+            difficulty: MIN_DIFFICULTY,
+            ..GenesisConfig::default()
+        };
+        let chain = Chain::from_genesis(&genesis).with_network(network);
+
+        // Many cheap blocks, which the node already holds, against a few expensive ones on the same genesis
+        let mut longer = chain.detached();
+        for height in 1..=6 {
+            append_block_at(&mut longer, height * 4 * target);
+        }
+        let mut heavier = chain.detached();
+        for height in 1..=3 {
+            append_block_at(&mut heavier, height);
+        }
+        assert!(heavier.total_work() > longer.total_work());
+        let heavy_tip = heavier.tip_header();
+        let (long_port, long) = serve(longer.detached());
+        let (heavy_port, heavy) = serve(heavier);
+
+        // Catching up means reorganizing onto the shorter chain
+        let peers = vec![PeerNode::new(localhost, long_port), PeerNode::new(localhost, heavy_port)];
+        let mut node = Node::new(localhost, 0, peers, longer);
+        node.contact_peers();
+        node.connections.close_all();
+        long.join().unwrap();
+        heavy.join().unwrap();
+
+        let peers = node.peers();
+        assert_eq!(peers.iter().map(|peer| peer.tip.as_ref().unwrap().index).max(), Some(6));
+        assert_eq!(node.chain.read().unwrap().tip_header(), heavy_tip);
+        assert!(node.sync_target().is_none());
+    }
+
+    #[test]
+    fn test_catch_up_with_peer_holding_more_work() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut node = local_node(vec![PeerNode::new(localhost, listener.local_addr().unwrap().port())]);
+
+        // The node's own chain three blocks further
+        let mut longer = node.chain.read().unwrap().detached();
+        for _ in 0..3 {
+            let reward = Transaction::coinbase(Address::generate().0, longer.network().block_subsidy);
+            let block = next_block(&longer, vec![reward]);
+            longer.add_block(block).unwrap();
+        }
+        let tip = longer.tip_header();
+        let peer = Node::new(localhost, 0, vec![], longer);
+        let serving = std::thread::spawn(move || peer.handle_client(listener.accept().unwrap().0));
+
+        node.contact_peers();
+        node.connections.close_all();
+        serving.join().unwrap();
+
+        let chain = node.chain.read().unwrap();
+        assert_eq!(chain.tip_header(), tip);
+        assert_eq!(*node.state.read().unwrap(), AccountState::from_chain(&chain).unwrap());
+        assert!(node.sync_target().is_none());
+    }
+
+    #[test]
+    fn test_silent_peer_abandoned_after_handshake_timeout() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        // Connections complete in the backlog, but nothing ever answers them
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (serving_port, serving) = serving_peer();
+        let measured = |port, latency_ms| {
+            let mut peer = PeerNode::new(localhost, port);
+            peer.record_handshake(latency_ms);
+            peer
+        };
+
+        let mut node = local_node(vec![measured(silent.local_addr().unwrap().port(), 1), measured(serving_port, 5)]);
+        node.max_sync_peers = 1;
+        node.handshake_timeout = Duration::from_millis(200);
+        let started = std::time::Instant::now();
+        node.contact_peers();
+        node.connections.close_all();
+        serving.join().unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let peers = node.peers();
+        assert!(peers[0].tip.is_none());
+        assert!(peers[1].tip.is_some());
+        // A timeout is not misbehaviour, the silent peer is neither banned nor forgotten
+        assert!(!node.peer_scores.lock().unwrap().is_banned("127.0.0.1"));
+        assert_eq!(node.peer_count(), 2);
+    }
+
+    #[test]
+    fn test_sync_contacts_selected_peers_and_falls_back() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (fast_port, fast) = serving_peer();
+        let (slow_port, slow) = serving_peer();
+        let spare = TcpListener::bind("127.0.0.1:0").unwrap();
+        spare.set_nonblocking(true).unwrap();
+
+        // The fastest known peer is down, the next one in line replaces it
+        let measured = |port, latency_ms| {
+            let mut peer = PeerNode::new(localhost, port);
+            peer.record_handshake(latency_ms);
+            peer
+        };
+        let peers = vec![
+            PeerNode::new(localhost, spare.local_addr().unwrap().port()),
+            measured(slow_port, 10),
+            measured(unused_port(), 1),
+            measured(fast_port, 5),
+        ];
+        let mut node = local_node(peers);
+        node.max_sync_peers = 2;
+        node.contact_peers();
+        node.connections.close_all();
+        fast.join().unwrap();
+        slow.join().unwrap();
+
+        assert_eq!(spare.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(node.peers.read().unwrap()[2].info().latency_ms, Some(1));
+    }
+
+    #[test]
+    fn test_peer_count_and_info_follow_registered_peers() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let node = local_node(vec![PeerNode::new(localhost, 9000)]);
+        assert_eq!(node.peer_count(), 1);
+
+        node.register_peer(PeerNode::new(localhost, 9001));
+        node.register_peer(PeerNode::new(localhost, 9000));
+        assert_eq!(node.peer_count(), 2);
+
+        let peers = node.peers();
+        let addresses: Vec<&str> = peers.iter().map(|peer| peer.address.as_str()).collect();
+        assert_eq!(addresses, vec!["127.0.0.1:9000", "127.0.0.1:9001"]);
+        assert!(peers.iter().all(|peer| peer.tip.is_none() && peer.latency_ms.is_none()));
+    }
+
+    #[test]
+    fn test_known_and_forged_peer_transactions_are_skipped() {
+        let node = local_node(vec![]);
+        let known = signed_transfer(2);
+        assert_eq!(node.accept_transactions(vec![known.clone()]), 1);
+
+        let mut forged = signed_transfer(2);
+        forged.sign(&Address::generate().1, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(node.accept_transactions(vec![known, forged]), 0);
+        assert_eq!(node.transaction_pool.lock().unwrap().pending_count(), 1);
+    }
+
+    /// Listener counting the connections it accepts and passing on every message it reads. The first
+    /// connection is closed after `close_first_after` messages, when given.
+    fn counting_peer(close_first_after: Option<usize>) -> (u16, Arc<AtomicUsize>, Receiver<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let (sender, received) = std::sync::mpsc::channel();
+        let counter = accepted.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { return };
+                let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+                let limit = close_first_after.filter(|_| first).unwrap_or(usize::MAX);
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..limit {
+                        let Ok(message) = read_frame(&mut stream) else { return };
+                        sender.send(message).unwrap();
+                    }
+                });
+            }
+        });
+        (port, accepted, received)
+    }
+
+    #[test]
+    fn test_broadcasts_reuse_one_connection() {
+        let (port, accepted, received) = counting_peer(None);
+        let node = local_node(vec![PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)]);
+
+        for _ in 0..3 {
+            assert!(node.broadcast(&Message::SyncRequest)[0].1.is_ok());
+        }
+        for _ in 0..3 {
+            let message = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert!(matches!(message, Message::SyncRequest));
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(node.connections.len(), 1);
+    }
+
+    #[test]
+    fn test_dropped_connection_reopened() {
+        let (port, accepted, received) = counting_peer(Some(1));
+        let node = local_node(vec![PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)]);
+
+        assert!(node.broadcast(&Message::SyncRequest)[0].1.is_ok());
+        assert!(matches!(received.recv_timeout(Duration::from_secs(5)).unwrap(), Message::SyncRequest));
+
+        // The peer has closed the first connection, the next message goes over a new one
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(node.broadcast(&Message::GetMempool)[0].1.is_ok());
+        assert!(matches!(received.recv_timeout(Duration::from_secs(5)).unwrap(), Message::GetMempool));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(node.connections.len(), 1);
+    }
+
+    #[test]
+    fn test_broadcast_without_peers() {
+        let node = local_node(vec![]);
+        assert!(node.broadcast(&Message::SyncRequest).is_empty());
+    }
+
+    /// Announce `header` to `node` and return what it sends back, if anything
+    fn announce_to_node(node: &Node, header: BlockHeader) -> Option<Message> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let announcer = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write_frame(&mut stream, &Message::NewBlockAnnounce(header)).unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            read_frame(&mut stream).ok()
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        node.handle_client(stream);
+        announcer.join().unwrap()
+    }
+
+    #[test]
+    fn test_announced_block_already_held_is_not_requested() {
+        let node = local_node(vec![]);
+        let header = node.chain.read().unwrap().tip_header().unwrap();
+
+        assert!(announce_to_node(&node, header).is_none());
+    }
+
+    #[test]
+    fn test_announced_missing_block_is_requested() {
+        let node = local_node(vec![]);
+        let unknown = Block::new(1, vec![], Hash::genesis(), 4);
+        let header = unknown.header();
+
+        match announce_to_node(&node, header.clone()) {
+            Some(Message::GetBlock(hash)) => assert_eq!(hash, header.hash),
+            _ => panic!("Expected a GetBlock request"),
+        }
+    }
+
+    #[test]
+    fn test_announce_sends_body_on_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let block = Block::new(1, vec![], Hash::genesis(), 4);
+        let peer = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let Message::NewBlockAnnounce(header) = read_frame(&mut stream).unwrap() else {
+                panic!("Expected a block announcement");
+            };
+            write_frame(&mut stream, &Message::GetBlock(header.hash)).unwrap();
+            read_frame(&mut stream).unwrap()
+        });
+
+        let peers = vec![PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)];
+        let security = PeerSecurity::default();
+        let results = Node::announce_to(&peers, &PeerConnections::default(), Codec::Json, &security, &block);
+        assert!(results[0].1.is_ok());
+
+        match peer.join().unwrap() {
+            Message::NewBlock(received) => assert_eq!(received.current_block_hash, block.current_block_hash),
+            _ => panic!("Expected the block body"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_switches_connection_to_binary() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let peer = PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+            let (mut stream, codec) = Node::open_session(&peer, Codec::Binary, &PeerSecurity::default()).unwrap();
+            write_frame_with(&mut stream, &Message::SyncRequest, codec).unwrap();
+            let reply = read_frame_with(&mut stream, codec).unwrap();
+            (codec, reply)
+        });
+
+        let node = local_node(vec![]).with_codec(Codec::Binary);
+        let (stream, _) = listener.accept().unwrap();
+        node.handle_client(stream);
+
+        let (codec, reply) = client.join().unwrap();
+        assert_eq!(codec, Codec::Binary);
+        assert!(matches!(reply, Message::SyncResponse { .. }));
+    }
+
+    #[test]
+    fn test_handshake_falls_back_to_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || {
+            let peer = PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+            let (mut stream, codec) = Node::open_session(&peer, Codec::Binary, &PeerSecurity::default()).unwrap();
+            write_frame_with(&mut stream, &Message::SyncRequest, codec).unwrap();
+            read_frame_with(&mut stream, codec).unwrap();
+            codec
+        });
+
+        let node = local_node(vec![]);
+        let (stream, _) = listener.accept().unwrap();
+        node.handle_client(stream);
+
+        assert_eq!(client.join().unwrap(), Codec::Json);
+    }
+
+    #[test]
+    fn test_encrypted_nodes_sync_over_encrypted_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_port = listener.local_addr().unwrap().port();
+        let peer = local_node(vec![]).with_encryption(Encryption::Required).with_codec(Codec::Binary);
+        let pending = signed_transfer(3);
+        peer.transaction_pool.lock().unwrap().add_transaction(pending.clone()).unwrap();
+        let serving = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            peer.handle_client(stream);
+        });
+
+        let mut node = local_node(vec![PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), peer_port)])
+            .with_encryption(Encryption::Preferred)
+            .with_codec(Codec::Binary);
+        node.contact_peers();
+        node.connections.close_all();
+        serving.join().unwrap();
+
+        assert!(node.transaction_pool.lock().unwrap().contains(&pending.id));
+        assert!(node.peers()[0].latency_ms.is_some());
+    }
+
+    #[test]
+    fn test_pinned_node_key_checked_before_any_message() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let key = NodeKey::generate();
+        let public_key = key.public_key();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let serving = std::thread::spawn(move || {
+            let node = local_node(vec![]).with_encryption(Encryption::Required).with_node_key(key);
+            for _ in 0..2 {
+                node.handle_client(listener.accept().unwrap().0);
+            }
+        });
+
+        let preferred = PeerSecurity::new(Encryption::Preferred);
+        let pinned = PeerNode::new(localhost, port).with_key(public_key.clone());
+        let (mut stream, codec) = Node::open_session(&pinned, Codec::Binary, &preferred).unwrap();
+        assert!(stream.is_encrypted());
+        assert_eq!(stream.peer_key(), Some(public_key));
+        write_frame_with(&mut stream, &Message::SyncRequest, codec).unwrap();
+        assert!(matches!(read_frame_with(&mut stream, codec).unwrap(), Message::SyncResponse { .. }));
+        drop(stream);
+
+        // Someone else's key, as a man in the middle would present, and no plaintext fallback either
+        let impostor = PeerNode::new(localhost, port).with_key(NodeKey::generate().public_key());
+        let refused = Node::open_session(&impostor, Codec::Binary, &preferred).map(|_| ());
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_preferred_encryption_falls_back_to_plaintext_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), listener.local_addr().unwrap().port());
+        let serving = std::thread::spawn(move || {
+            let node = local_node(vec![]);
+            for _ in 0..2 {
+                node.handle_client(listener.accept().unwrap().0);
+            }
+        });
+
+        let (mut stream, codec) =
+            Node::open_session(&peer, Codec::Json, &PeerSecurity::new(Encryption::Preferred)).unwrap();
+        assert!(!stream.is_encrypted());
+        write_frame_with(&mut stream, &Message::SyncRequest, codec).unwrap();
+        assert!(matches!(read_frame_with(&mut stream, codec).unwrap(), Message::SyncResponse { .. }));
+        drop(stream);
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn test_plaintext_peer_refused_when_encryption_required() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        for codec in [Codec::Json, Codec::Binary] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let peer = PeerNode::new(localhost, listener.local_addr().unwrap().port());
+            let client = std::thread::spawn(move || {
+                let (mut stream, codec) = Node::open_session(&peer, codec, &PeerSecurity::default())?;
+                assert!(!stream.is_encrypted());
+                write_frame_with(&mut stream, &Message::SyncRequest, codec)?;
+                read_frame_with(&mut stream, codec)
+            });
+
+            let node = local_node(vec![]).with_encryption(Encryption::Required).with_codec(codec);
+            let (stream, _) = listener.accept().unwrap();
+            node.handle_client(stream);
+            assert!(client.join().unwrap().is_err(), "{:?} peer got an answer", codec);
+        }
+
+        // A node requiring encryption also refuses to talk to a plaintext listener
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = PeerNode::new(localhost, listener.local_addr().unwrap().port());
+        let serving = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            local_node(vec![]).handle_client(stream);
+        });
+        let refused = Node::open_session(&peer, Codec::Json, &PeerSecurity::new(Encryption::Required)).map(|_| ());
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        serving.join().unwrap();
+    }
+
+    /// Frame holding a message from some future protocol version
+    fn unknown_frame() -> Vec<u8> {
+        let body = br#"{"FutureMessage":{"field":1}}"#;
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    /// Connect to a node accepting through `access`, send a sync request and read the reply
+    fn sync_through_access_list(access: AccessList) -> io::Result<Message> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address)?;
+            write_frame(&mut stream, &Message::SyncRequest)?;
+            read_frame(&mut stream)
+        });
+
+        let node = local_node(vec![]).with_access_list(access);
+        let (stream, _) = listener.accept().unwrap();
+        node.accept(stream);
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn test_allowlisted_peer_served() {
+        let access = AccessList { allow: vec!["127.0.0.0/8".parse().unwrap()], deny: vec![] };
+        assert!(matches!(sync_through_access_list(access).unwrap(), Message::SyncResponse { .. }));
+    }
+
+    #[test]
+    fn test_denylisted_peer_dropped() {
+        let access = AccessList { allow: vec![], deny: vec!["127.0.0.1".parse().unwrap()] };
+        assert!(sync_through_access_list(access).is_err());
+
+        // Allowing a range leaves every address outside it refused
+        let access = AccessList { allow: vec!["10.0.0.0/8".parse().unwrap()], deny: vec![] };
+        assert!(sync_through_access_list(access).is_err());
+    }
+
+    #[test]
+    fn test_single_unknown_message_tolerated() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address)?;
+            io::Write::write_all(&mut stream, &unknown_frame())?;
+            write_frame(&mut stream, &Message::SyncRequest)?;
+            read_frame(&mut stream)
+        });
+
+        let node = local_node(vec![]);
+        let (stream, _) = listener.accept().unwrap();
+        node.handle_client(stream);
+        assert!(matches!(client.join().unwrap().unwrap(), Message::SyncResponse { .. }));
+        assert!(!node.peer_scores.lock().unwrap().is_banned("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_repeated_unknown_messages_ban_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address)?;
+            for _ in 0..MAX_PEER_STRIKES {
+                io::Write::write_all(&mut stream, &unknown_frame())?;
+            }
+            write_frame(&mut stream, &Message::SyncRequest)?;
+            read_frame(&mut stream)
         });
 
+        let node = local_node(vec![]);
+        let (stream, _) = listener.accept().unwrap();
+        node.handle_client(stream);
+        assert!(client.join().unwrap().is_err());
+        assert!(node.peer_scores.lock().unwrap().is_banned("127.0.0.1"));
+
+        // Later connections from the banned peer are turned away straight away
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address)?;
+            write_frame(&mut stream, &Message::SyncRequest)?;
+            read_frame(&mut stream)
+        });
+        let (stream, _) = listener.accept().unwrap();
+        node.handle_client(stream);
+        assert!(client.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_peer_clock_within_tolerance_accepted() {
+        let clock = Arc::new(MockClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap()));
+        let mut node = local_node(vec![]).with_clock(clock.clone());
+        node.refuse_skewed_peers = true;
+
+        assert!(node.accept_peer_clock("10.0.0.1", clock.now() + chrono::Duration::seconds(60)));
+        assert!(node.accept_peer_clock("10.0.0.2", clock.now() - chrono::Duration::seconds(90)));
+    }
+
+    #[test]
+    fn test_peer_clock_with_large_skew_flagged() {
+        let clock = Arc::new(MockClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap()));
+        let mut node = local_node(vec![]).with_clock(clock.clone());
+        let skewed = clock.now() + chrono::Duration::seconds(3_600);
+
+        // Only a warning unless the node is configured to refuse such peers
+        assert!(node.accept_peer_clock("10.0.0.1", skewed));
+        node.refuse_skewed_peers = true;
+        assert!(!node.accept_peer_clock("10.0.0.1", skewed));
+        assert_eq!(node.clock_offsets.lock().unwrap().median(), Some(3_600));
+    }
+
+    #[test]
+    fn test_learned_peer_persisted_and_reloaded() {
+        let dir = env::temp_dir().join(format!("ola-node-peers-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let peers_file = dir.join("peers.json").to_string_lossy().into_owned();
+
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let node = local_node(vec![]).with_peers_file(peers_file.clone());
+        node.register_peer(PeerNode::new(localhost, 9100));
+        node.register_peer(PeerNode::new(localhost, 9100));
+        assert_eq!(node.peer_count(), 1);
+
+        let reloaded = PeerNode::load_known_peers(&peers_file);
+        assert_eq!(reloaded, vec![PeerNode::new(localhost, 9100)]);
     }
 }
@@ -1,12 +1,138 @@
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::str::FromStr;
+use std::{fs, io};
+use crate::block::BlockHeader;
 use crate::node::NodeInfo;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// Most peers kept in the known-peers file
+const MAX_KNOWN_PEERS: usize = 256;
+/// Peers not seen for this many days are forgotten on load
+const STALE_PEER_DAYS: i64 = 14;
+/// Messages a peer may send that this node cannot decode before it is banned
+pub(crate) const MAX_PEER_STRIKES: u32 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct PeerNode {
     ip: IpAddr,
     port: u16,
+    #[serde(default = "Utc::now")]
+    last_seen: DateTime<Utc>,
+    /// Round trip of the last successful handshake, in milliseconds
+    #[serde(default)]
+    latency_ms: Option<u64>,
+    /// Tip the peer reported in its last handshake
+    #[serde(default)]
+    tip: Option<BlockHeader>,
+    /// Total work of the peer's chain as of its last sync response
+    #[serde(default)]
+    total_work: Option<u64>,
+    /// Node key the peer must present in encrypted sessions, as hex, when pinned with `IP:PORT@KEY`
+    #[serde(default)]
+    key: Option<String>,
+}
+
+/// What a node knows about one of its peers, as reported by `Node::peers`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct PeerInfo {
+    pub address: String,
+    pub last_seen: DateTime<Utc>,
+    pub tip: Option<BlockHeader>,
+    pub latency_ms: Option<u64>,
+}
+
+/// Misbehaviour counted against peers by IP, a peer reaching `MAX_PEER_STRIKES` is banned
+#[derive(Default)]
+pub(crate) struct PeerScores {
+    strikes: HashMap<String, u32>,
+}
+
+impl PeerScores {
+    /// Count one strike against `peer`, returning whether it is now banned
+    pub(crate) fn penalize(&mut self, peer: &str) -> bool {
+        let strikes = self.strikes.entry(peer.to_string()).or_default();
+        *strikes += 1;
+        *strikes >= MAX_PEER_STRIKES
+    }
+
+    pub(crate) fn is_banned(&self, peer: &str) -> bool {
+        self.strikes.get(peer).is_some_and(|strikes| *strikes >= MAX_PEER_STRIKES)
+    }
+}
+
+/// Block of addresses in CIDR notation, `10.0.0.0/8` or `fd00::/8`. A bare address is a block of one.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match value.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = ip.trim().parse().map_err(|_| format!("Invalid address in range {}", value))?;
+        let width = Self::bits(network).1;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("Invalid prefix length in range {}", value))?,
+            None => width,
+        };
+        Ok(IpRange { network, prefix })
+    }
+}
+
+impl IpRange {
+    /// Whether `ip` falls in the range, IPv4 addresses mapped into IPv6 count as IPv4
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, width) = Self::bits(self.network);
+        let (ip, ip_width) = Self::bits(ip.to_canonical());
+        width == ip_width && (network ^ ip).checked_shr(128 - u32::from(self.prefix)).unwrap_or(0) == 0
+    }
+
+    /// Address bits aligned to the top of a `u128`, with how many of them there are
+    fn bits(ip: IpAddr) -> (u128, u8) {
+        match ip {
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)) << 96, 32),
+            IpAddr::V6(ip) => (u128::from(ip), 128),
+        }
+    }
+
+    /// Comma separated ranges, as in the `PEER_ALLOWLIST` and `PEER_DENYLIST` settings
+    pub(crate) fn parse_list(value: &str) -> Result<Vec<IpRange>, String> {
+        value.split(',').map(str::trim).filter(|range| !range.is_empty()).map(str::parse).collect()
+    }
+}
+
+/// Address ranges inbound connections are checked against. A denied range always wins, and once any range is
+/// allowed only addresses in an allowed range get in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AccessList {
+    pub allow: Vec<IpRange>,
+    pub deny: Vec<IpRange>,
+}
+
+impl AccessList {
+    pub(crate) fn permits(&self, ip: &IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+impl PartialEq for PeerNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.ip == other.ip && self.port == other.port
+    }
 }
 
 impl NodeInfo for PeerNode {
@@ -24,41 +150,308 @@ impl NodeInfo for PeerNode {
 }
 
 impl PeerNode {
+    /// Peer at `ip`, with IPv4-mapped IPv6 addresses unmapped so both spellings name the same peer
     pub(crate) fn new(ip: IpAddr, port: u16) -> Self {
-        PeerNode { ip, port }
-    }
-
-    pub(crate) fn get_peers_node_ips_from_env() -> Vec<PeerNode> {
-        match env::var("NODES") {
-            Ok(ips) => ips
-                .split(',')
-                .filter_map(|socket_addr| {
-                    let parts: Vec<&str> = socket_addr.trim().split(':').collect();
-                    if parts.len() == 2 {
-                        match (parts[0].parse::<IpAddr>(), parts[1].parse::<u16>()) {
-                            (Ok(ip), Ok(port)) => Some(PeerNode::new(ip, port)),
-                            _ => {
-                                eprintln!(
-                                    "Invalid socket address in NODES environment variable: {}",
-                                    socket_addr
-                                );
-                                None
-                            }
-                        }
-                    } else {
-                        eprintln!(
-                            "Invalid format in NODES environment variable: {}. Expected IP:PORT",
-                            socket_addr
-                        );
+        PeerNode {
+            ip: ip.to_canonical(),
+            port,
+            last_seen: Utc::now(),
+            latency_ms: None,
+            tip: None,
+            total_work: None,
+            key: None,
+        }
+    }
+
+    /// Pin the node key the peer must present, see `secure::NodeKey::public_key`
+    #[cfg(test)]
+    pub(crate) fn with_key(mut self, key: String) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub(crate) fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// Remember a successful handshake that took `latency_ms`
+    pub(crate) fn record_handshake(&mut self, latency_ms: u64) {
+        self.latency_ms = Some(latency_ms);
+        self.last_seen = Utc::now();
+    }
+
+    /// Remember the tip the peer reported
+    pub(crate) fn record_tip(&mut self, tip: Option<BlockHeader>) {
+        self.tip = tip;
+    }
+
+    /// Remember the total work the peer reported for its chain
+    pub(crate) fn record_work(&mut self, total_work: u64) {
+        self.total_work = Some(total_work);
+    }
+
+    pub(crate) fn total_work(&self) -> Option<u64> {
+        self.total_work
+    }
+
+    /// Peer whose chain carries the most work, the one to catch up with. Peers that never reported
+    /// their work are skipped, and among equals the first listed wins.
+    pub(crate) fn sync_target(peers: &[PeerNode]) -> Option<&PeerNode> {
+        peers
+            .iter()
+            .filter(|peer| peer.total_work.is_some())
+            .rev()
+            .max_by_key(|peer| peer.total_work)
+    }
+
+    pub(crate) fn info(&self) -> PeerInfo {
+        PeerInfo {
+            address: self.socket_addr(),
+            last_seen: self.last_seen,
+            tip: self.tip.clone(),
+            latency_ms: self.latency_ms,
+        }
+    }
+
+    /// Order in which to try peers for sync: lowest known latency first, then peers never measured,
+    /// most recently seen first, with the address breaking ties so every run picks the same peers
+    pub(crate) fn sync_order(peers: &[PeerNode]) -> Vec<PeerNode> {
+        let mut ordered = peers.to_vec();
+        ordered.sort_by(|a, b| {
+            let latency = |peer: &PeerNode| peer.latency_ms.unwrap_or(u64::MAX);
+            latency(a)
+                .cmp(&latency(b))
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then((a.ip, a.port).cmp(&(b.ip, b.port)))
+        });
+        ordered
+    }
+
+    /// Load previously learned peers, dropping stale entries and anything past the cap
+    pub(crate) fn load_known_peers(path: &str) -> Vec<PeerNode> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                eprintln!("Failed to read known peers from {}: {}", path, e);
+                return Vec::new();
+            }
+        };
+
+        let peers = match serde_json::from_str::<Vec<PeerNode>>(&content) {
+            Ok(peers) => peers,
+            Err(e) => {
+                eprintln!("Failed to parse known peers from {}: {}", path, e);
+                return Vec::new();
+            }
+        };
+
+        let oldest_allowed = Utc::now() - Duration::days(STALE_PEER_DAYS);
+        let fresh = peers
+            .into_iter()
+            .filter(|peer| peer.last_seen >= oldest_allowed)
+            .map(|peer| PeerNode { ip: peer.ip.to_canonical(), ..peer })
+            .collect();
+        Self::capped(Self::merge(Vec::new(), fresh))
+    }
+
+    pub(crate) fn save_known_peers(path: &str, peers: &[PeerNode]) -> io::Result<()> {
+        let peers = Self::capped(peers.to_vec());
+        let json = serde_json::to_string_pretty(&peers).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+
+    /// Combine two peer lists, keeping the first occurrence of each address
+    pub(crate) fn merge(mut peers: Vec<PeerNode>, others: Vec<PeerNode>) -> Vec<PeerNode> {
+        for peer in others {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    fn capped(mut peers: Vec<PeerNode>) -> Vec<PeerNode> {
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        peers.truncate(MAX_KNOWN_PEERS);
+        peers
+    }
+
+    /// Parse a comma separated list of `IP:PORT` peers, IPv6 addresses may be bracketed and `@KEY` pins the
+    /// peer's node key, skipping malformed entries and entries naming the same peer twice
+    pub(crate) fn parse_list(value: &str) -> Vec<PeerNode> {
+        let peers = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let (socket_addr, key) = match entry.split_once('@') {
+                    Some((socket_addr, key)) => (socket_addr, Some(key.trim().to_lowercase())),
+                    None => (entry, None),
+                };
+                let Some((ip, port)) = socket_addr.rsplit_once(':') else {
+                    eprintln!("Invalid format in NODES: {}. Expected IP:PORT", socket_addr);
+                    return None;
+                };
+                let ip = ip.trim_start_matches('[').trim_end_matches(']');
+                match (ip.parse::<IpAddr>(), port.parse::<u16>()) {
+                    (Ok(ip), Ok(port)) => Some(PeerNode { key, ..PeerNode::new(ip, port) }),
+                    _ => {
+                        eprintln!("Invalid socket address in NODES: {}", socket_addr);
                         None
                     }
-                })
-                .collect::<Vec<PeerNode>>(),
-            Err(_) => {
-                println!("No NODES peer provided");
-                Vec::new()
-            }
+                }
+            })
+            .collect();
+        Self::merge(Vec::new(), peers)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash;
+    use std::env;
+    use std::net::Ipv4Addr;
+
+    fn temp_peers_file(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("ola-peers-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("peers.json").to_string_lossy().into_owned()
+    }
+
+    fn localhost_peer(port: u16) -> PeerNode {
+        PeerNode::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    #[test]
+    fn test_access_list_ranges() {
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+        let access = AccessList {
+            allow: IpRange::parse_list("10.0.0.0/8, fd00::/8").unwrap(),
+            deny: IpRange::parse_list("10.1.2.3").unwrap(),
+        };
+        assert!(access.permits(&ip("10.200.0.1")));
+        assert!(access.permits(&ip("::ffff:10.0.0.1")));
+        assert!(access.permits(&ip("fd12::1")));
+        assert!(!access.permits(&ip("10.1.2.3")));
+        assert!(!access.permits(&ip("11.0.0.1")));
+        assert!(!access.permits(&ip("fe80::1")));
+
+        assert!(AccessList::default().permits(&ip("192.168.0.1")));
+        assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains(&ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn test_known_peers_round_trip() {
+        let path = temp_peers_file("round-trip");
+        assert!(PeerNode::load_known_peers(&path).is_empty());
+
+        PeerNode::save_known_peers(&path, &[localhost_peer(9000), localhost_peer(9001)]).unwrap();
+        let loaded = PeerNode::load_known_peers(&path);
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.contains(&localhost_peer(9000)));
+        assert!(loaded.contains(&localhost_peer(9001)));
+    }
+
+    #[test]
+    fn test_stale_peers_pruned_on_load() {
+        let path = temp_peers_file("stale");
+        let mut stale = localhost_peer(9000);
+        stale.last_seen = Utc::now() - Duration::days(STALE_PEER_DAYS + 1);
+
+        PeerNode::save_known_peers(&path, &[stale, localhost_peer(9001)]).unwrap();
+        assert_eq!(PeerNode::load_known_peers(&path), vec![localhost_peer(9001)]);
+    }
+
+    #[test]
+    fn test_known_peers_capped() {
+        let path = temp_peers_file("capped");
+        let peers: Vec<PeerNode> = (0..MAX_KNOWN_PEERS as u16 + 10).map(localhost_peer).collect();
+
+        PeerNode::save_known_peers(&path, &peers).unwrap();
+        assert_eq!(PeerNode::load_known_peers(&path).len(), MAX_KNOWN_PEERS);
+    }
+
+    #[test]
+    fn test_ipv4_mapped_addresses_are_canonical() {
+        let mapped: IpAddr = "::ffff:127.0.0.1".parse().unwrap();
+        assert_eq!(PeerNode::new(mapped, 9000), localhost_peer(9000));
+        assert_eq!(PeerNode::new(mapped, 9000).ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let peers = PeerNode::parse_list("127.0.0.1:9000, [::ffff:127.0.0.1]:9000, ::ffff:127.0.0.1:9000, [::1]:9001");
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0], localhost_peer(9000));
+        assert_eq!(peers[1].ip(), "::1".parse::<IpAddr>().unwrap());
+
+        let merged = PeerNode::merge(vec![localhost_peer(9000)], vec![PeerNode::new(mapped, 9000)]);
+        assert_eq!(merged.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_list_pins_keys() {
+        let key = "ab".repeat(32);
+        let peers = PeerNode::parse_list(&format!("127.0.0.1:9000@{}, [::1]:9001", key.to_uppercase()));
+        assert_eq!(peers[0].key(), Some(key.as_str()));
+        assert_eq!(peers[1].key(), None);
+    }
+
+    #[test]
+    fn test_sync_order_prefers_fast_then_fresh_peers() {
+        let now = Utc::now();
+        let peer = |port, latency_ms, age_days| PeerNode {
+            latency_ms,
+            last_seen: now - Duration::days(age_days),
+            ..localhost_peer(port)
+        };
+        let peers = vec![peer(9000, None, 2), peer(9001, Some(40), 0), peer(9002, None, 1), peer(9003, Some(5), 3)];
+
+        let ports: Vec<u16> = PeerNode::sync_order(&peers).iter().map(|peer| peer.port()).collect();
+        assert_eq!(ports, vec![9003, 9001, 9002, 9000]);
+        let reversed: Vec<PeerNode> = peers.into_iter().rev().collect();
+        let ports_again: Vec<u16> = PeerNode::sync_order(&reversed).iter().map(|peer| peer.port()).collect();
+        assert_eq!(ports_again, ports);
+    }
+
+    #[test]
+    fn test_sync_target_prefers_most_work_over_height() {
+        let peer = |port, index, total_work: Option<u64>| PeerNode {
+            tip: Some(BlockHeader { index, hash: Hash::genesis(), merkle_root: Hash::genesis() }),
+            total_work,
+            ..localhost_peer(port)
+        };
+        let peers = vec![
+            peer(9000, 50, Some(54)),
+            peer(9001, 10, Some(84)),
+            peer(9002, 90, None),
+            peer(9003, 12, Some(84)),
+        ];
+        assert_eq!(PeerNode::sync_target(&peers).unwrap().port(), 9001);
+        assert!(PeerNode::sync_target(&peers[2..3]).is_none());
+    }
+
+    #[test]
+    fn test_peer_banned_after_repeated_strikes() {
+        let mut scores = PeerScores::default();
+        for _ in 1..MAX_PEER_STRIKES {
+            assert!(!scores.penalize("10.0.0.1"));
         }
+        assert!(!scores.is_banned("10.0.0.1"));
+        assert!(scores.penalize("10.0.0.1"));
+        assert!(scores.is_banned("10.0.0.1"));
+        assert!(!scores.is_banned("10.0.0.2"));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_merge_without_duplicates() {
+        let env_peers = vec![localhost_peer(9000), localhost_peer(9001)];
+        let known = vec![localhost_peer(9001), localhost_peer(9002)];
+
+        let merged = PeerNode::merge(env_peers, known);
+        assert_eq!(merged, vec![localhost_peer(9000), localhost_peer(9001), localhost_peer(9002)]);
+    }
+}
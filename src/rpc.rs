@@ -0,0 +1,787 @@
+use crate::address::Address;
+use crate::block::BlockHeader;
+use crate::chain::Chain;
+use crate::hash::Hash;
+use crate::peer::PeerNode;
+use crate::state::AccountState;
+use crate::transaction::Transaction;
+use crate::transaction_pool::{TransactionPool, TxOutcome};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Largest request body the RPC server accepts
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// Newest blocks the `/stats` averages are taken over
+const STATS_WINDOW: usize = 100;
+/// Blocks `/blocks` lists
+const LISTED_BLOCKS: usize = 20;
+/// Longest `/sendrawtransaction/wait` waits for the transaction to be mined before reporting it pending
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(crate) struct RpcRequest {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+pub(crate) struct RpcResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl RpcResponse {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self { status, body: json!({ "error": message }) }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Node state the RPC handlers read from
+#[derive(Clone)]
+pub(crate) struct RpcContext {
+    pub chain: Arc<RwLock<Chain>>,
+    /// Pool submitted transactions go to, submissions and simulations are refused without it
+    pub transaction_pool: Option<Arc<Mutex<TransactionPool>>>,
+    /// Peers of the node, listed by `/peers`
+    pub peers: Option<Arc<RwLock<Vec<PeerNode>>>>,
+    /// Balances and nonces at the tip, balance lookups are refused without it
+    pub state: Option<Arc<RwLock<AccountState>>>,
+}
+
+impl RpcContext {
+    pub(crate) fn new(chain: Arc<RwLock<Chain>>) -> Self {
+        Self { chain, transaction_pool: None, peers: None, state: None }
+    }
+
+    pub(crate) fn with_transaction_pool(mut self, pool: Arc<Mutex<TransactionPool>>) -> Self {
+        self.transaction_pool = Some(pool);
+        self
+    }
+
+    pub(crate) fn with_peers(mut self, peers: Arc<RwLock<Vec<PeerNode>>>) -> Self {
+        self.peers = Some(peers);
+        self
+    }
+
+    pub(crate) fn with_state(mut self, state: Arc<RwLock<AccountState>>) -> Self {
+        self.state = Some(state);
+        self
+    }
+}
+
+pub(crate) fn route(context: &RpcContext, request: &RpcRequest) -> RpcResponse {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["supply"]) => supply(context),
+        ("GET", ["stats"]) => stats(context),
+        ("GET", ["blocks"]) => blocks(context),
+        ("GET", ["peers"]) => peers(context),
+        ("GET", ["fee"]) => fee_estimate(context),
+        ("GET", ["block", "hash", hash]) => block_by_hash(context, hash),
+        ("GET", ["tx", tx_id, "confirmations"]) => confirmations(context, tx_id),
+        ("GET", ["tx", tx_id, "proof"]) => inclusion_proof(context, tx_id),
+        ("POST", ["tx", "verifyproof"]) => verify_inclusion_proof(context, &request.body),
+        ("GET", ["address", address, "balance"]) => balance(context, address),
+        ("POST", ["sendrawtransaction"]) => send_raw_transaction(context, &request.body),
+        ("POST", ["sendrawtransaction", "wait"]) => send_raw_transaction_and_wait(context, &request.body),
+        ("POST", ["simulatetx"]) => simulate_transaction(context, &request.body),
+        _ => RpcResponse::error(404, "Not found"),
+    }
+}
+
+fn supply(context: &RpcContext) -> RpcResponse {
+    match context.chain.read().unwrap().total_supply() {
+        Some(total_supply) => RpcResponse::ok(json!({ "total_supply": total_supply })),
+        None => RpcResponse::error(500, "Supply accounting overflowed"),
+    }
+}
+
+fn stats(context: &RpcContext) -> RpcResponse {
+    let chain = context.chain.read().unwrap();
+    RpcResponse::ok(json!({
+        "height": chain.blocks.len().saturating_sub(1),
+        "window": STATS_WINDOW,
+        "average_block_time": chain.average_block_time(STATS_WINDOW),
+        "transactions_per_second": chain.transactions_per_second(STATS_WINDOW),
+        "average_block_size": chain.average_block_size(STATS_WINDOW),
+        "median_time_past": chain.median_time_past(),
+    }))
+}
+
+/// Summaries of the newest blocks, newest first
+fn blocks(context: &RpcContext) -> RpcResponse {
+    let summaries = context.chain.read().unwrap().recent_summaries(LISTED_BLOCKS);
+    RpcResponse::ok(json!({ "blocks": summaries }))
+}
+
+fn block_by_hash(context: &RpcContext, hash: &str) -> RpcResponse {
+    let hash: Hash = match hash.parse() {
+        Ok(hash) => hash,
+        Err(e) => return RpcResponse::error(400, &e),
+    };
+    match context.chain.read().unwrap().get_block_by_hash(&hash) {
+        Some(block) => RpcResponse::ok(block.to_json()),
+        None => RpcResponse::error(404, "Block not found"),
+    }
+}
+
+fn peers(context: &RpcContext) -> RpcResponse {
+    let peers: Vec<_> = match &context.peers {
+        Some(peers) => peers.read().unwrap().iter().map(PeerNode::info).collect(),
+        None => Vec::new(),
+    };
+    RpcResponse::ok(json!({ "count": peers.len(), "peers": peers }))
+}
+
+/// Fee a transaction needs to get into the next block: the chain's base fee and the pool's dynamic minimum
+fn fee_estimate(context: &RpcContext) -> RpcResponse {
+    let base_fee = context.chain.read().unwrap().next_base_fee();
+    let min_fee = context.transaction_pool.as_ref().map_or(0, |pool| pool.lock().unwrap().dynamic_min_fee());
+    RpcResponse::ok(json!({
+        "base_fee": base_fee,
+        "min_fee": min_fee,
+        "suggested_fee": base_fee.max(min_fee),
+    }))
+}
+
+fn confirmations(context: &RpcContext, tx_id: &str) -> RpcResponse {
+    let chain = context.chain.read().unwrap();
+    match chain.confirmations(tx_id) {
+        Some(confirmations) => RpcResponse::ok(json!({
+            "confirmations": confirmations,
+            "confirmed": chain.is_confirmed(tx_id, chain.network().confirmation_depth),
+        })),
+        None => RpcResponse::error(404, "Transaction not found in any block"),
+    }
+}
+
+/// Header of the block holding `tx_id` with the merkle proof that it is in that block, each step giving the
+/// sibling hash and whether it sits on the left
+fn inclusion_proof(context: &RpcContext, tx_id: &str) -> RpcResponse {
+    match context.chain.read().unwrap().merkle_proof_for(tx_id) {
+        Some((header, proof)) => {
+            let steps: Vec<Value> = proof.iter().map(|(hash, left)| json!({ "hash": hash, "left": left })).collect();
+            RpcResponse::ok(json!({ "block": header, "proof": steps }))
+        }
+        None => RpcResponse::error(404, "Transaction not found in any block"),
+    }
+}
+
+/// Body of `/tx/verifyproof`: the transaction as `to_raw_hex` along with the block header and proof steps
+/// `/tx/{id}/proof` returned for it
+#[derive(Deserialize)]
+struct ProofCheck {
+    transaction: String,
+    block: BlockHeader,
+    proof: Vec<ProofCheckStep>,
+}
+
+#[derive(Deserialize)]
+struct ProofCheckStep {
+    hash: Hash,
+    left: bool,
+}
+
+/// Report whether the proof in the request body shows its transaction is in the given block, and that block is
+/// part of this chain
+fn verify_inclusion_proof(context: &RpcContext, body: &str) -> RpcResponse {
+    let check: ProofCheck = match serde_json::from_str(body) {
+        Ok(check) => check,
+        Err(e) => return RpcResponse::error(400, &format!("Invalid proof: {}", e)),
+    };
+    let transaction = match Transaction::from_raw_hex(&check.transaction) {
+        Ok(transaction) => transaction,
+        Err(e) => return RpcResponse::error(400, &e),
+    };
+
+    let known = context.chain.read().unwrap().get_block_by_hash(&check.block.hash).map(|block| block.header());
+    let proof: Vec<(Hash, bool)> = check.proof.into_iter().map(|step| (step.hash, step.left)).collect();
+    let included = Chain::verify_block_inclusion(&check.block, &transaction, &proof);
+    RpcResponse::ok(json!({
+        "id": transaction.id,
+        "included": included,
+        "known_block": known.as_ref() == Some(&check.block),
+    }))
+}
+
+fn balance(context: &RpcContext, address: &str) -> RpcResponse {
+    let address = Address { value: address.to_lowercase(), raw_bytes: None };
+    if !address.is_valid() {
+        return RpcResponse::error(400, "Invalid address");
+    }
+    let Some(state) = &context.state else {
+        return RpcResponse::error(503, "This node does not track balances");
+    };
+
+    let state = state.read().unwrap();
+    RpcResponse::ok(json!({
+        "balance": state.balance(&address),
+        "spendable": state.spendable_balance(&address),
+        "nonce": state.nonce(&address),
+    }))
+}
+
+/// Decode the transaction in a submission body, the `to_raw_hex` encoding, along with the pool it goes to
+/// and the sender's nonce at the tip
+fn submission<'a>(
+    context: &'a RpcContext,
+    body: &str,
+) -> Result<(Transaction, &'a Arc<Mutex<TransactionPool>>, u64), RpcResponse> {
+    let transaction = Transaction::from_raw_hex(body).map_err(|e| RpcResponse::error(400, &e))?;
+
+    let chain_id = context.chain.read().unwrap().network().chain_id;
+    if !transaction.is_valid() || transaction.is_coinbase() || !transaction.is_signed_by_sender(chain_id) {
+        return Err(RpcResponse::error(400, "Transaction is invalid or not signed by its sender"));
+    }
+
+    let Some(pool) = &context.transaction_pool else {
+        return Err(RpcResponse::error(503, "This node does not accept transactions"));
+    };
+    let Some(state) = &context.state else {
+        return Err(RpcResponse::error(503, "This node does not track account nonces"));
+    };
+    let account_nonce = state.read().unwrap().nonce(&transaction.from);
+    Ok((transaction, pool, account_nonce))
+}
+
+/// Submit a transaction given as the `to_raw_hex` encoding in the request body. A transaction ahead of the
+/// sender's nonce is held as an orphan until the missing nonces arrive.
+fn send_raw_transaction(context: &RpcContext, body: &str) -> RpcResponse {
+    let (transaction, pool, account_nonce) = match submission(context, body) {
+        Ok(submission) => submission,
+        Err(response) => return response,
+    };
+    let id = transaction.id.clone();
+    match pool.lock().unwrap().submit(transaction, account_nonce) {
+        Ok(held) => RpcResponse::ok(json!({ "id": id, "orphan": held })),
+        Err(e) => RpcResponse::error(400, &e),
+    }
+}
+
+/// Submit like `send_raw_transaction`, then wait up to `CONFIRMATION_TIMEOUT` to report the transaction
+/// included, dropped or still pending
+fn send_raw_transaction_and_wait(context: &RpcContext, body: &str) -> RpcResponse {
+    let (transaction, pool, account_nonce) = match submission(context, body) {
+        Ok(submission) => submission,
+        Err(response) => return response,
+    };
+    let id = transaction.id.clone();
+    // Bound first so the pool is unlocked while waiting
+    let submitted = pool.lock().unwrap().submit_with_confirmation(transaction, account_nonce);
+    let confirmation = match submitted {
+        Ok((_held, confirmation)) => confirmation,
+        Err(e) => return RpcResponse::error(400, &e),
+    };
+
+    match confirmation.recv_timeout(CONFIRMATION_TIMEOUT) {
+        Ok(TxOutcome::Included(block_index)) => {
+            RpcResponse::ok(json!({ "id": id, "status": "included", "block_index": block_index }))
+        }
+        Ok(TxOutcome::Dropped) => RpcResponse::ok(json!({ "id": id, "status": "dropped" })),
+        Err(_) => RpcResponse::ok(json!({ "id": id, "status": "pending" })),
+    }
+}
+
+/// Report whether the transaction in the request body, as `to_raw_hex`, would be accepted without submitting it
+fn simulate_transaction(context: &RpcContext, body: &str) -> RpcResponse {
+    let transaction = match Transaction::from_raw_hex(body) {
+        Ok(transaction) => transaction,
+        Err(e) => return RpcResponse::error(400, &e),
+    };
+    let Some(pool) = &context.transaction_pool else {
+        return RpcResponse::error(503, "This node does not accept transactions");
+    };
+
+    let chain = context.chain.read().unwrap();
+    match pool.lock().unwrap().simulate(&transaction, &chain) {
+        Ok(()) => RpcResponse::ok(json!({ "id": transaction.id, "accepted": true })),
+        Err(rejection) => RpcResponse::ok(json!({
+            "id": transaction.id,
+            "accepted": false,
+            "reason": rejection.to_string(),
+        })),
+    }
+}
+
+/// Serve JSON RPC requests over HTTP on `address`, each connection on a thread of its own so a request waiting
+/// for its transaction to be mined does not hold up the others
+pub(crate) fn serve(address: &str, context: RpcContext) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("RPC is now listening on {}", address);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let context = context.clone();
+                std::thread::spawn(move || {
+                    let response = match read_request(&mut stream) {
+                        Ok(request) => route(&context, &request),
+                        Err(e) => RpcResponse::error(400, &e.to_string()),
+                    };
+                    if let Err(e) = write_response(&mut stream, &response) {
+                        eprintln!("Failed to send RPC response: {}", e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("RPC connection failed : {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn read_request(stream: &mut TcpStream) -> io::Result<RpcRequest> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed request line"));
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Request body too large"));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(RpcRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn write_response(stream: &mut TcpStream, response: &RpcResponse) -> io::Result<()> {
+    let body = response.body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::block::Block;
+    use crate::network::DEFAULT_CHAIN_ID;
+    use crate::transaction::TRANSFER_GAS;
+
+    fn get(context: &RpcContext, path: &str) -> RpcResponse {
+        let request = RpcRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            body: String::new(),
+        };
+        route(context, &request)
+    }
+
+    #[test]
+    fn test_supply_endpoint() {
+        let mut chain = Chain::new_easy();
+        let subsidy = chain.network().block_subsidy;
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![Transaction::coinbase(Address::generate().0, subsidy)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let response = get(&context, "/supply");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["total_supply"], subsidy);
+    }
+
+    #[test]
+    fn test_confirmations_endpoint() {
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let coinbase = Transaction::coinbase(Address::generate().0, chain.network().block_subsidy);
+        let mut block = Block::new(1, vec![coinbase.clone()], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let response = get(&context, &format!("/tx/{}/confirmations", coinbase.id));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["confirmations"], 1);
+        assert_eq!(response.body["confirmed"], false);
+
+        assert_eq!(get(&context, "/tx/unknown/confirmations").status, 404);
+    }
+
+    #[test]
+    fn test_stats_endpoint() {
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![Transaction::coinbase(Address::generate().0, 50)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+        let average_block_time = chain.average_block_time(STATS_WINDOW).unwrap();
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let response = get(&context, "/stats");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["height"], 1);
+        assert_eq!(response.body["average_block_time"], average_block_time);
+        assert!(response.body["average_block_size"].as_f64().unwrap() > 0.0);
+        assert!(response.body["median_time_past"].is_string());
+
+        let empty = RpcContext::new(Arc::new(RwLock::new(Chain::new_easy())));
+        assert!(get(&empty, "/stats").body["average_block_time"].is_null());
+    }
+
+    #[test]
+    fn test_blocks_endpoint_lists_newest_first() {
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![Transaction::coinbase(Address::generate().0, 50)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        let hash = chain.add_block(block).unwrap();
+        assert_eq!(chain.recent_summaries(1).len(), 1);
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let response = get(&context, "/blocks");
+        assert_eq!(response.status, 200);
+        let blocks = response.body["blocks"].as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0]["hash"], json!(hash));
+        assert_eq!(blocks[0]["transaction_count"], 1);
+        assert_eq!(blocks[1]["index"], 0);
+        assert!(blocks[0].get("transactions").is_none());
+    }
+
+    #[test]
+    fn test_block_by_hash_endpoint() {
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let coinbase = Transaction::coinbase(Address::generate().0, chain.network().block_subsidy);
+        let mut block = Block::new(1, vec![coinbase.clone()], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        let hash = chain.add_block(block.clone()).unwrap();
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let response = get(&context, &format!("/block/hash/{}", hash.value));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, block.to_json());
+        assert_eq!(response.body["transactions"][0]["id"], coinbase.id);
+
+        assert_eq!(get(&context, &format!("/block/hash/{}", Hash::new(b"unknown").value)).status, 404);
+        assert_eq!(get(&context, "/block/hash/not-a-hash").status, 400);
+        assert_eq!(get(&context, &format!("/block/hash/{}", hash.value.to_uppercase())).status, 400);
+    }
+
+    #[test]
+    fn test_peers_endpoint() {
+        let localhost = "127.0.0.1".parse().unwrap();
+        let peers = Arc::new(RwLock::new(vec![PeerNode::new(localhost, 9000)]));
+        let context = RpcContext::new(Arc::new(RwLock::new(Chain::new_easy()))).with_peers(peers.clone());
+        assert_eq!(get(&context, "/peers").body["count"], 1);
+
+        peers.write().unwrap().push(PeerNode::new(localhost, 9001));
+        let response = get(&context, "/peers");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["count"], 2);
+        assert_eq!(response.body["peers"][1]["address"], "127.0.0.1:9001");
+        assert!(response.body["peers"][1]["latency_ms"].is_null());
+    }
+
+    fn post(context: &RpcContext, path: &str, body: String) -> RpcResponse {
+        let request = RpcRequest {
+            method: "POST".to_string(),
+            path: path.to_string(),
+            body,
+        };
+        route(context, &request)
+    }
+
+    fn shared_pool(chain: &Chain) -> Arc<Mutex<TransactionPool>> {
+        let pool = TransactionPool::new(10, 1024 * 1024, 16).with_network(chain.network().clone());
+        Arc::new(Mutex::new(pool))
+    }
+
+    /// Context accepting submissions on top of `chain`, with the pool they go to
+    fn submitting_context(chain: Chain) -> (RpcContext, Arc<Mutex<TransactionPool>>) {
+        let pool = shared_pool(&chain);
+        let state = Arc::new(RwLock::new(AccountState::from_chain(&chain).unwrap()));
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)))
+            .with_transaction_pool(pool.clone())
+            .with_state(state);
+        (context, pool)
+    }
+
+    #[test]
+    fn test_send_raw_transaction() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        let (context, pool) = submitting_context(Chain::new_easy());
+        let response = post(&context, "/sendrawtransaction", tx.to_raw_hex());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["id"], tx.id);
+        assert_eq!(response.body["orphan"], false);
+        let pulled = pool.lock().unwrap().pull_transactions_for_block();
+        assert_eq!(pulled, vec![tx.clone()]);
+        assert!(pulled[0].is_signed_by_sender(DEFAULT_CHAIN_ID));
+
+        let mut unsigned = tx.clone();
+        unsigned.signature = None;
+        assert_eq!(post(&context, "/sendrawtransaction", unsigned.to_raw_hex()).status, 400);
+        assert_eq!(post(&context, "/sendrawtransaction", "not hex".to_string()).status, 400);
+
+        let read_only = RpcContext::new(Arc::new(RwLock::new(Chain::new_easy())));
+        assert_eq!(post(&read_only, "/sendrawtransaction", tx.to_raw_hex()).status, 503);
+    }
+
+    #[test]
+    fn test_send_raw_transaction_holds_nonce_gap_as_orphan() {
+        let (from, secret_key, _) = Address::generate();
+        let [first, second] = [0, 1].map(|nonce| {
+            let mut tx = Transaction::new(from.clone(), Address::generate().0, 10)
+                .with_nonce(nonce)
+                .with_gas(TRANSFER_GAS, 1);
+            tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+            tx
+        });
+
+        let (context, pool) = submitting_context(Chain::new_easy());
+        let response = post(&context, "/sendrawtransaction", second.to_raw_hex());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["orphan"], true);
+        assert_eq!(pool.lock().unwrap().orphan_count(), 1);
+
+        // Filling the gap moves the orphan into the pool behind it
+        let response = post(&context, "/sendrawtransaction", first.to_raw_hex());
+        assert_eq!(response.body["orphan"], false);
+        assert_eq!(pool.lock().unwrap().orphan_count(), 0);
+        assert_eq!(pool.lock().unwrap().pull_transactions_for_block(), vec![first, second]);
+    }
+
+    #[test]
+    fn test_simulate_transaction_endpoint() {
+        let (alice, secret_key, _) = Address::generate();
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![Transaction::coinbase(alice.clone(), 50)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+
+        let pool = shared_pool(&chain);
+        let context = RpcContext::new(Arc::new(RwLock::new(chain))).with_transaction_pool(pool.clone());
+        let mut payment = Transaction::new(alice.clone(), Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        payment.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        let response = post(&context, "/simulatetx", payment.to_raw_hex());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["accepted"], true);
+
+        let mut overdraft = Transaction::new(alice, Address::generate().0, 1_000).with_gas(TRANSFER_GAS, 1);
+        overdraft.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        let response = post(&context, "/simulatetx", overdraft.to_raw_hex());
+        assert_eq!(response.body["accepted"], false);
+        assert!(response.body["reason"].as_str().unwrap().contains("spendable"));
+
+        assert_eq!(pool.lock().unwrap().pending_count(), 0);
+    }
+
+    #[test]
+    fn test_fee_estimate_endpoint() {
+        let chain = Chain::new_easy();
+        let pool = Arc::new(Mutex::new(TransactionPool::new(1, 1024 * 1024, 1).with_min_fee(2)));
+        let context = RpcContext::new(Arc::new(RwLock::new(chain))).with_transaction_pool(pool.clone());
+        let response = get(&context, "/fee");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["min_fee"], 2);
+        assert_eq!(response.body["suggested_fee"], 2);
+
+        // A full pool asks for more than its cheapest pending transaction
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, 5);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        pool.lock().unwrap().add_transaction(tx.clone()).unwrap();
+        for _ in 0..20 {
+            assert!(pool.lock().unwrap().add_transaction(tx.clone()).is_err());
+        }
+        assert_eq!(get(&context, "/fee").body["suggested_fee"], 6);
+    }
+
+    #[test]
+    fn test_inclusion_proof_endpoint() {
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let coinbase = Transaction::coinbase(Address::generate().0, chain.network().block_subsidy);
+        let mut block = Block::new(1, vec![coinbase.clone()], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block.clone()).unwrap();
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let response = get(&context, &format!("/tx/{}/proof", coinbase.id));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["block"], json!(block.header()));
+        // The only transaction of its block is the merkle root itself
+        assert_eq!(response.body["proof"], json!([]));
+
+        assert_eq!(get(&context, "/tx/unknown/proof").status, 404);
+    }
+
+    #[test]
+    fn test_verify_inclusion_proof_endpoint() {
+        let (alice, secret_key, _) = Address::generate();
+        let mut chain = Chain::new_easy();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let funding = Transaction::coinbase(alice.clone(), chain.network().block_subsidy);
+        let mut block = Block::new(1, vec![funding], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        let funded_hash = chain.add_block(block).unwrap();
+        let transfers: Vec<Transaction> = (0..2)
+            .map(|nonce| {
+                let mut tx = Transaction::new(alice.clone(), Address::generate().0, 10)
+                    .with_nonce(nonce)
+                    .with_gas(TRANSFER_GAS, 1);
+                tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+                tx
+            })
+            .collect();
+        let mut block = Block::new(2, transfers.clone(), funded_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+
+        let context = RpcContext::new(Arc::new(RwLock::new(chain)));
+        let proof = get(&context, &format!("/tx/{}/proof", transfers[1].id)).body;
+        let check = |transaction: &Transaction, block: &Value, proof: &Value| {
+            let body = json!({ "transaction": transaction.to_raw_hex(), "block": block, "proof": proof });
+            post(&context, "/tx/verifyproof", body.to_string())
+        };
+
+        let response = check(&transfers[1], &proof["block"], &proof["proof"]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["id"], transfers[1].id);
+        assert_eq!(response.body["included"], true);
+        assert_eq!(response.body["known_block"], true);
+
+        // The proof of one transaction does not prove another
+        assert_eq!(check(&transfers[0], &proof["block"], &proof["proof"]).body["included"], false);
+
+        // A header this chain does not hold still gets its proof checked, but is reported unknown
+        let mut forged_block = proof["block"].clone();
+        forged_block["index"] = json!(7);
+        let response = check(&transfers[1], &forged_block, &proof["proof"]);
+        assert_eq!(response.body["included"], true);
+        assert_eq!(response.body["known_block"], false);
+
+        assert_eq!(post(&context, "/tx/verifyproof", "{}".to_string()).status, 400);
+    }
+
+    #[test]
+    fn test_balance_endpoint() {
+        let miner = Address::generate().0;
+        let mut chain = Chain::new_easy();
+        let subsidy = chain.network().block_subsidy;
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![Transaction::coinbase(miner.clone(), subsidy)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+
+        let state = AccountState::from_chain(&chain).unwrap();
+        let context = RpcContext::new(Arc::new(RwLock::new(chain))).with_state(Arc::new(RwLock::new(state)));
+        let response = get(&context, &format!("/address/{}/balance", miner.value));
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["balance"], subsidy);
+        assert_eq!(response.body["nonce"], 0);
+        assert_eq!(get(&context, "/address/nope/balance").status, 400);
+
+        let stateless = RpcContext::new(Arc::new(RwLock::new(Chain::new_easy())));
+        assert_eq!(get(&stateless, &format!("/address/{}/balance", miner.value)).status, 503);
+    }
+
+    #[test]
+    fn test_send_raw_transaction_and_wait_for_block() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        let (context, pool) = submitting_context(Chain::new_easy());
+        let raw = tx.to_raw_hex();
+        let waiting = std::thread::spawn(move || post(&context, "/sendrawtransaction/wait", raw));
+
+        while !pool.lock().unwrap().contains(&tx.id) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        pool.lock().unwrap().confirm_block(&Block::new(3, vec![tx.clone()], Hash::genesis(), 1));
+
+        let response = waiting.join().unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body["status"], "included");
+        assert_eq!(response.body["block_index"], 3);
+    }
+
+    #[test]
+    fn test_unknown_route() {
+        let context = RpcContext::new(Arc::new(RwLock::new(Chain::new_easy())));
+        assert_eq!(get(&context, "/nope").status, 404);
+    }
+
+    #[test]
+    fn test_serve_over_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let context = RpcContext::new(Arc::new(RwLock::new(Chain::new_easy())));
+        let server_address = address.clone();
+        std::thread::spawn(move || serve(&server_address, context));
+
+        let mut stream = loop {
+            if let Ok(stream) = TcpStream::connect(&address) {
+                break stream;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        stream.write_all(b"GET /supply HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with(r#"{"total_supply":0}"#));
+    }
+}
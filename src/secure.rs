@@ -0,0 +1,412 @@
+use snow::{Builder, HandshakeState, TransportState};
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, thread};
+
+/// Noise pattern of every encrypted session. XX sends both static keys encrypted, so each side learns the
+/// other's node key without either having to know it in advance.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Longest Noise message, handshake or transport, ciphertext and tag included
+const MAX_NOISE_MESSAGE: usize = 65535;
+/// Bytes of the authentication tag closing every transport message
+const TAG_SIZE: usize = 16;
+/// Opens a connection that starts with a Noise handshake instead of a plaintext frame. Read as a frame length
+/// it is far over any frame limit, so a node that does not encrypt drops the connection instead of waiting.
+const NOISE_PREAMBLE: &[u8; 8] = b"OLANOISE";
+
+/// Whether a node encrypts its peer connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Encryption {
+    /// Plaintext only, peers opening with a Noise handshake are dropped
+    #[default]
+    Disabled,
+    /// Encrypt with every peer that can, plaintext with those that cannot. An attacker on the path can make
+    /// a connection look like the second kind, except to peers with a pinned key.
+    Preferred,
+    /// Refuse peers that do not encrypt
+    Required,
+}
+
+impl Encryption {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "off" | "disabled" | "false" => Some(Encryption::Disabled),
+            "on" | "prefer" | "preferred" | "true" => Some(Encryption::Preferred),
+            "require" | "required" => Some(Encryption::Required),
+            _ => None,
+        }
+    }
+
+    /// Whether outgoing connections open with a Noise handshake
+    pub(crate) fn offered(self) -> bool {
+        self != Encryption::Disabled
+    }
+}
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Noise error: {}", e))
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(NOISE_PARAMS.parse().expect("Noise parameters are valid"))
+}
+
+/// Static X25519 key pair identifying a node in every encrypted session
+pub(crate) struct NodeKey {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl NodeKey {
+    pub(crate) fn generate() -> Self {
+        let keypair = builder().generate_keypair().expect("Noise key generation does not fail");
+        NodeKey { private: keypair.private, public: keypair.public }
+    }
+
+    /// Key stored at `path`, the private then the public key in hex on a line each. Generated and written there
+    /// the first time, so the node keeps its identity across restarts.
+    pub(crate) fn load_or_create(path: &str) -> io::Result<Self> {
+        if Path::new(path).exists() {
+            let content = fs::read_to_string(path)?;
+            let mut lines = content.lines().map(|line| hex::decode(line.trim()));
+            return match (lines.next(), lines.next()) {
+                (Some(Ok(private)), Some(Ok(public))) if private.len() == 32 && public.len() == 32 => {
+                    Ok(NodeKey { private, public })
+                }
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid node key in {}", path))),
+            };
+        }
+
+        let key = Self::generate();
+        fs::write(path, format!("{}\n{}\n", hex::encode(&key.private), key.public_key()))?;
+        Ok(key)
+    }
+
+    /// Public key as hex, the form peers pin it in
+    pub(crate) fn public_key(&self) -> String {
+        hex::encode(&self.public)
+    }
+}
+
+/// How a node treats encryption on its peer connections, and the key it proves its identity with
+#[derive(Clone)]
+pub(crate) struct PeerSecurity {
+    pub(crate) encryption: Encryption,
+    pub(crate) key: Arc<NodeKey>,
+}
+
+impl PeerSecurity {
+    /// `encryption` with a fresh node key, which lasts as long as the node
+    pub(crate) fn new(encryption: Encryption) -> Self {
+        PeerSecurity { encryption, key: Arc::new(NodeKey::generate()) }
+    }
+}
+
+impl Default for PeerSecurity {
+    fn default() -> Self {
+        Self::new(Encryption::default())
+    }
+}
+
+fn write_message<S: Write>(stream: &mut S, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Noise message too large"))?;
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(message)
+}
+
+fn read_message<S: Read>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut length_bytes = [0u8; 2];
+    stream.read_exact(&mut length_bytes)?;
+    let mut message = vec![0u8; u16::from_be_bytes(length_bytes) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Send our part of the handshake and read the peer's, until the handshake completes
+fn run_handshake<S: Read + Write>(mut stream: S, mut handshake: HandshakeState) -> io::Result<SecureStream<S>> {
+    let mut buffer = vec![0u8; MAX_NOISE_MESSAGE];
+    while !handshake.is_handshake_finished() {
+        if handshake.is_my_turn() {
+            let length = handshake.write_message(&[], &mut buffer).map_err(noise_error)?;
+            write_message(&mut stream, &buffer[..length])?;
+            stream.flush()?;
+        } else {
+            let message = read_message(&mut stream)?;
+            handshake.read_message(&message, &mut buffer).map_err(noise_error)?;
+        }
+    }
+
+    let transport = handshake.into_transport_mode().map_err(noise_error)?;
+    Ok(SecureStream { inner: stream, transport, outgoing: Vec::new(), incoming: Vec::new(), read_position: 0 })
+}
+
+/// Open an encrypted session over `stream` as the side that connected
+pub(crate) fn initiate<S: Read + Write>(mut stream: S, key: &NodeKey) -> io::Result<SecureStream<S>> {
+    stream.write_all(NOISE_PREAMBLE)?;
+    let handshake = builder().local_private_key(&key.private).and_then(Builder::build_initiator);
+    run_handshake(stream, handshake.map_err(noise_error)?)
+}
+
+/// Accept an encrypted session over `stream`, whose preamble was already seen by `opens_with_handshake`
+pub(crate) fn respond<S: Read + Write>(mut stream: S, key: &NodeKey) -> io::Result<SecureStream<S>> {
+    let mut preamble = [0u8; NOISE_PREAMBLE.len()];
+    stream.read_exact(&mut preamble)?;
+    if preamble != *NOISE_PREAMBLE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Connection does not open a Noise handshake"));
+    }
+    let handshake = builder().local_private_key(&key.private).and_then(Builder::build_responder);
+    run_handshake(stream, handshake.map_err(noise_error)?)
+}
+
+/// Whether the peer on `stream` opens with a Noise handshake rather than a plaintext frame, without consuming
+/// anything. Waits until enough bytes arrived to tell.
+pub(crate) fn opens_with_handshake(stream: &TcpStream) -> io::Result<bool> {
+    let mut peeked = [0u8; NOISE_PREAMBLE.len()];
+    loop {
+        let count = stream.peek(&mut peeked)?;
+        if count == 0 || peeked[..count] != NOISE_PREAMBLE[..count] {
+            return Ok(false);
+        }
+        if count == peeked.len() {
+            return Ok(true);
+        }
+        thread::yield_now();
+    }
+}
+
+/// Stream sending what is written between two flushes as Noise transport messages, each preceded by its
+/// 2-byte big-endian length. `write_frame_with` flushes once per message, so a frame is sent in as few messages
+/// as fit it. Noise numbers the messages, so a replayed, dropped or reordered one fails to decrypt.
+pub(crate) struct SecureStream<S> {
+    inner: S,
+    transport: TransportState,
+    outgoing: Vec<u8>,
+    incoming: Vec<u8>,
+    read_position: usize,
+}
+
+impl<S> SecureStream<S> {
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Static key the peer proved it holds during the handshake, as hex
+    pub(crate) fn peer_key(&self) -> Option<String> {
+        self.transport.get_remote_static().map(hex::encode)
+    }
+}
+
+impl<S: Read> SecureStream<S> {
+    fn read_record(&mut self) -> io::Result<()> {
+        let message = read_message(&mut self.inner)?;
+        self.incoming.resize(message.len(), 0);
+        let length = self.transport.read_message(&message, &mut self.incoming).map_err(noise_error)?;
+        self.incoming.truncate(length);
+        self.read_position = 0;
+        Ok(())
+    }
+}
+
+impl<S: Read> Read for SecureStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_position == self.incoming.len() {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            self.read_record()?;
+        }
+
+        let available = &self.incoming[self.read_position..];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.read_position += count;
+        Ok(count)
+    }
+}
+
+impl<S: Write> Write for SecureStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+        for chunk in self.outgoing.chunks(MAX_NOISE_MESSAGE - TAG_SIZE) {
+            let length = self.transport.write_message(chunk, &mut message).map_err(noise_error)?;
+            write_message(&mut self.inner, &message[..length])?;
+        }
+        self.outgoing.clear();
+        self.inner.flush()
+    }
+}
+
+/// Connection to a peer, encrypted when it opened with a Noise handshake
+pub(crate) enum PeerStream {
+    Plain(TcpStream),
+    Encrypted(Box<SecureStream<TcpStream>>),
+}
+
+impl PeerStream {
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            PeerStream::Plain(stream) => stream,
+            PeerStream::Encrypted(stream) => stream.get_ref(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_encrypted(&self) -> bool {
+        matches!(self, PeerStream::Encrypted(_))
+    }
+
+    /// Static key of the peer, `None` on a plaintext connection
+    pub(crate) fn peer_key(&self) -> Option<String> {
+        match self {
+            PeerStream::Plain(_) => None,
+            PeerStream::Encrypted(stream) => stream.peer_key(),
+        }
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Plain(stream) => stream.read(buf),
+            PeerStream::Encrypted(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Plain(stream) => stream.write(buf),
+            PeerStream::Encrypted(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PeerStream::Plain(stream) => stream.flush(),
+            PeerStream::Encrypted(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::message::{read_frame_with, write_frame_with, Message};
+    use std::env;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+
+    /// Sessions at both ends of an XX handshake run in memory: the initiator writes to a buffer, the responder
+    /// reads from one
+    fn memory_pair() -> (SecureStream<Vec<u8>>, SecureStream<Cursor<Vec<u8>>>) {
+        let build = |key: &NodeKey, initiator: bool| {
+            let builder = builder().local_private_key(&key.private).unwrap();
+            if initiator { builder.build_initiator() } else { builder.build_responder() }.unwrap()
+        };
+        let mut initiator = build(&NodeKey::generate(), true);
+        let mut responder = build(&NodeKey::generate(), false);
+        let (mut message, mut payload) = (vec![0u8; MAX_NOISE_MESSAGE], vec![0u8; MAX_NOISE_MESSAGE]);
+        while !initiator.is_handshake_finished() {
+            let (from, to) = if initiator.is_my_turn() {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+            let length = from.write_message(&[], &mut message).unwrap();
+            to.read_message(&message[..length], &mut payload).unwrap();
+        }
+
+        fn session<S>(inner: S, handshake: HandshakeState) -> SecureStream<S> {
+            let transport = handshake.into_transport_mode().unwrap();
+            SecureStream { inner, transport, outgoing: Vec::new(), incoming: Vec::new(), read_position: 0 }
+        }
+        (session(Vec::new(), initiator), session(Cursor::new(Vec::new()), responder))
+    }
+
+    #[test]
+    fn test_handshake_over_tcp_authenticates_node_keys() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (initiator_key, responder_key) = (NodeKey::generate(), Arc::new(NodeKey::generate()));
+        let responder_public = responder_key.public_key();
+        let responding = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            assert!(opens_with_handshake(&stream).unwrap());
+            let mut session = respond(stream, &responder_key).unwrap();
+            let message = read_frame_with(&mut session, Codec::Binary).unwrap();
+            write_frame_with(&mut session, &message, Codec::Binary).unwrap();
+            session.peer_key()
+        });
+
+        let mut session = initiate(TcpStream::connect(address).unwrap(), &initiator_key).unwrap();
+        assert_eq!(session.peer_key(), Some(responder_public));
+        write_frame_with(&mut session, &Message::SyncRequest, Codec::Binary).unwrap();
+        assert!(matches!(read_frame_with(&mut session, Codec::Binary).unwrap(), Message::SyncRequest));
+        assert_eq!(responding.join().unwrap(), Some(initiator_key.public_key()));
+    }
+
+    #[test]
+    fn test_frames_round_trip_encrypted() {
+        let (mut sending, mut receiving) = memory_pair();
+        write_frame_with(&mut sending, &Message::GetMempool, Codec::Json).unwrap();
+        write_frame_with(&mut sending, &Message::SyncRequest, Codec::Binary).unwrap();
+        // Too long for one Noise message, so it is sent as several
+        let long: Vec<u8> = (0..3 * MAX_NOISE_MESSAGE).map(|i| i as u8).collect();
+        sending.write_all(&long).unwrap();
+        sending.flush().unwrap();
+
+        let wire = sending.get_ref().clone();
+        assert!(!wire.windows(b"GetMempool".len()).any(|window| window == b"GetMempool"));
+        receiving.inner = Cursor::new(wire);
+        assert!(matches!(read_frame_with(&mut receiving, Codec::Json).unwrap(), Message::GetMempool));
+        assert!(matches!(read_frame_with(&mut receiving, Codec::Binary).unwrap(), Message::SyncRequest));
+        let mut received = vec![0u8; long.len()];
+        receiving.read_exact(&mut received).unwrap();
+        assert_eq!(received, long);
+    }
+
+    #[test]
+    fn test_tampered_or_replayed_record_rejected() {
+        let (mut sending, mut receiving) = memory_pair();
+        write_frame_with(&mut sending, &Message::SyncRequest, Codec::Json).unwrap();
+        let record = sending.get_ref().clone();
+
+        let mut tampered = record.clone();
+        tampered[5] ^= 1;
+        receiving.inner = Cursor::new(tampered);
+        assert!(read_frame_with(&mut receiving, Codec::Json).is_err_and(|e| e.kind() == io::ErrorKind::InvalidData));
+
+        let (_, mut receiving) = memory_pair();
+        let mut replayed = record.clone();
+        replayed.extend_from_slice(&record);
+        receiving.inner = Cursor::new(replayed);
+        assert!(read_frame_with(&mut receiving, Codec::Json).is_err());
+    }
+
+    #[test]
+    fn test_node_key_persisted() {
+        let dir = env::temp_dir().join(format!("ola-node-key-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.key").to_string_lossy().into_owned();
+
+        let created = NodeKey::load_or_create(&path).unwrap();
+        assert_eq!(NodeKey::load_or_create(&path).unwrap().public_key(), created.public_key());
+        fs::write(&path, "not a key").unwrap();
+        assert!(NodeKey::load_or_create(&path).is_err());
+
+        assert_eq!(Encryption::parse("Required"), Some(Encryption::Required));
+        assert_eq!(Encryption::parse("maybe"), None);
+    }
+}
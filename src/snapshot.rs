@@ -0,0 +1,141 @@
+use crate::block::Block;
+use crate::chain::Chain;
+use crate::codec::Codec;
+use crate::store::StoreError;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::{Read, Write};
+
+/// Marks a snapshot file, followed by one byte naming the codec of the blocks, then the encoded blocks
+/// compressed with zlib
+const SNAPSHOT_MAGIC: &[u8; 8] = b"OLASNAP1";
+
+/// Write every block of `chain` to `path`. The binary codec gives the smallest files.
+pub(crate) fn export_snapshot(chain: &Chain, path: &str, codec: Codec) -> Result<(), StoreError> {
+    let blocks = codec.encode(&chain.blocks).map_err(StoreError::CodecError)?;
+
+    let mut content = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 1 + blocks.len() / 2);
+    content.extend_from_slice(SNAPSHOT_MAGIC);
+    content.push(codec_tag(codec));
+    let mut encoder = ZlibEncoder::new(content, Compression::default());
+    encoder.write_all(&blocks).map_err(StoreError::IoError)?;
+    let content = encoder.finish().map_err(StoreError::IoError)?;
+
+    let temp_file = format!("{}.tmp", path);
+    fs::write(&temp_file, content).map_err(StoreError::IoError)?;
+    fs::rename(&temp_file, path).map_err(StoreError::IoError)
+}
+
+/// Read the blocks of a snapshot written by `export_snapshot`, without validating them
+pub(crate) fn read_snapshot(path: &str) -> Result<Vec<Block>, StoreError> {
+    let content = fs::read(path).map_err(StoreError::IoError)?;
+    let Some(body) = content.strip_prefix(SNAPSHOT_MAGIC.as_slice()) else {
+        return Err(StoreError::ValidationError(format!("{} is not a chain snapshot", path)));
+    };
+    let Some((&tag, blocks)) = body.split_first() else {
+        return Err(StoreError::ValidationError(format!("Snapshot {} is truncated", path)));
+    };
+
+    let codec = codec_from_tag(tag)
+        .ok_or_else(|| StoreError::ValidationError(format!("Snapshot {} uses unknown codec {}", path, tag)))?;
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(blocks)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| StoreError::ValidationError(format!("Snapshot {} is corrupt: {}", path, e)))?;
+    codec.decode(&decompressed).map_err(StoreError::CodecError)
+}
+
+fn codec_tag(codec: Codec) -> u8 {
+    match codec {
+        Codec::Json => 0,
+        Codec::Binary => 1,
+    }
+}
+
+fn codec_from_tag(tag: u8) -> Option<Codec> {
+    match tag {
+        0 => Some(Codec::Json),
+        1 => Some(Codec::Binary),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::config::Config;
+    use crate::hash::Hash;
+    use crate::store::{BlockStore, MemoryStore};
+    use crate::transaction::Transaction;
+    use std::env;
+    use std::sync::Arc;
+
+    fn temp_snapshot(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("ola-snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("chain.snapshot").to_string_lossy().into_owned()
+    }
+
+    fn chain_with_blocks(count: u64) -> Chain {
+        let mut chain = Chain::new_easy();
+        for index in 1..=count {
+            let previous_hash = chain.blocks.last().unwrap().current_block_hash.clone().unwrap();
+            let reward = Transaction::coinbase(Address::generate().0, chain.network().block_subsidy);
+            let mut block = Block::new(index, vec![reward], previous_hash, 1).with_base_fee(chain.next_base_fee());
+            block.mine_block(1).unwrap();
+            chain.add_block(block).unwrap();
+        }
+        chain
+    }
+
+    #[test]
+    fn test_import_valid_snapshot_sets_tip() {
+        let chain = chain_with_blocks(3);
+        for codec in [Codec::Json, Codec::Binary] {
+            let path = temp_snapshot(&format!("valid-{:?}", codec));
+            export_snapshot(&chain, &path, codec).unwrap();
+
+            let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+            let imported = Chain::bootstrap(&path, store.clone(), &Config::default()).unwrap();
+            assert_eq!(imported.tip_header(), chain.tip_header());
+            assert_eq!(imported.blocks.len(), 4);
+            assert!(store.load().unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_compressed() {
+        let chain = chain_with_blocks(20);
+        let path = temp_snapshot("compressed");
+        export_snapshot(&chain, &path, Codec::Json).unwrap();
+
+        let encoded = Codec::Json.encode(&chain.blocks).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < encoded.len() as u64 / 2);
+        assert_eq!(read_snapshot(&path).unwrap().len(), 21);
+
+        // Cut short, the compressed body no longer inflates
+        let content = fs::read(&path).unwrap();
+        fs::write(&path, &content[..content.len() - 10]).unwrap();
+        assert!(read_snapshot(&path).is_err());
+    }
+
+    #[test]
+    fn test_invalid_snapshot_aborts_import() {
+        let mut chain = chain_with_blocks(2);
+        chain.blocks[2].current_block_hash = Some(Hash::new(b"tampered"));
+        let path = temp_snapshot("tampered");
+        export_snapshot(&chain, &path, Codec::Binary).unwrap();
+
+        let store: Arc<dyn BlockStore> = Arc::new(MemoryStore::new());
+        assert!(matches!(Chain::bootstrap(&path, store.clone(), &Config::default()), Err(StoreError::ValidationError(_))));
+        assert!(store.load().unwrap().is_none());
+
+        let garbage = temp_snapshot("garbage");
+        fs::write(&garbage, b"definitely not a snapshot").unwrap();
+        assert!(Chain::bootstrap(&garbage, store, &Config::default()).is_err());
+    }
+}
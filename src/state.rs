@@ -0,0 +1,337 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::chain::Chain;
+use crate::store::StoreError;
+use crate::transaction::Transaction;
+use std::collections::HashMap;
+
+/// Balances and next expected nonces derived by replaying the chain.
+/// Zero balances and nonces are not stored, so states holding the same values compare equal.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AccountState {
+    balances: HashMap<String, u64>,
+    nonces: HashMap<String, u64>,
+    /// Blocks a coinbase reward waits before it can be spent, 0 makes it spendable at once
+    coinbase_maturity: u64,
+    /// Height of the block whose transactions are being applied
+    height: u64,
+    // address -> (height mined at, amount) of coinbase rewards, kept after they mature so blocks can be reverted
+    immature: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl AccountState {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lock coinbase rewards until `maturity` blocks were built on top of the block that minted them
+    pub(crate) fn with_coinbase_maturity(mut self, maturity: u64) -> Self {
+        self.coinbase_maturity = maturity;
+        self
+    }
+
+    /// Replay every transaction of the chain from genesis, leaving the state at the height of the next block
+    pub(crate) fn from_chain(chain: &Chain) -> Result<Self, StoreError> {
+        let mut state = Self::new().with_coinbase_maturity(chain.network().coinbase_maturity);
+        for block in &chain.blocks {
+            state.apply_block(block)?;
+        }
+        state.at_height(chain.blocks.len() as u64);
+        Ok(state)
+    }
+
+    /// Apply the following transactions as part of the block at `height`, rewards mined at least
+    /// `coinbase_maturity` blocks earlier become spendable
+    pub(crate) fn at_height(&mut self, height: u64) {
+        self.height = height;
+    }
+
+    /// Apply every transaction of `block`, leaving the state at the height of the next block.
+    /// A block with a failing transaction leaves the state untouched.
+    pub(crate) fn apply_block(&mut self, block: &Block) -> Result<(), StoreError> {
+        let height = self.height;
+        self.at_height(block.index);
+        for (applied, tx) in block.transactions.iter().enumerate() {
+            if let Err(e) = self.apply_transaction(tx) {
+                for tx in block.transactions[..applied].iter().rev() {
+                    self.revert_transaction(tx);
+                }
+                self.at_height(height);
+                return Err(e);
+            }
+        }
+        self.at_height(block.index + 1);
+        Ok(())
+    }
+
+    /// Undo `apply_block` for the newest applied block, as a reorg does when it drops the block
+    pub(crate) fn revert_block(&mut self, block: &Block) {
+        self.at_height(block.index);
+        for tx in block.transactions.iter().rev() {
+            self.revert_transaction(tx);
+        }
+    }
+
+    pub(crate) fn balance(&self, address: &Address) -> u64 {
+        self.balances.get(&address.value).copied().unwrap_or_default()
+    }
+
+    /// Balance minus the coinbase rewards still maturing
+    pub(crate) fn spendable_balance(&self, address: &Address) -> u64 {
+        let maturity = self.coinbase_maturity;
+        let locked = self.immature.get(&address.value).map_or(0, |rewards| {
+            rewards
+                .iter()
+                .filter(|(mined_at, _)| mined_at.saturating_add(maturity) > self.height)
+                .fold(0u64, |total, (_, amount)| total.saturating_add(*amount))
+        });
+        self.balance(address).saturating_sub(locked)
+    }
+
+    /// Nonce the next transaction from `address` must carry
+    pub(crate) fn nonce(&self, address: &Address) -> u64 {
+        self.nonces.get(&address.value).copied().unwrap_or_default()
+    }
+
+    /// Move funds for one transaction, rejecting overdrafts and out-of-order nonces. Nothing changes on error.
+    pub(crate) fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), StoreError> {
+        let mut updated: HashMap<&str, u64> = HashMap::new();
+        if !tx.is_coinbase() {
+            let expected_nonce = self.nonce(&tx.from);
+            if tx.nonce != expected_nonce {
+                return Err(StoreError::ValidationError(format!(
+                    "Transaction {} has nonce {} but {} was expected", tx.id, tx.nonce, expected_nonce
+                )));
+            }
+
+            let cost = tx.total_cost().ok_or_else(|| {
+                StoreError::ValidationError(format!("Transaction {} cost overflows", tx.id))
+            })?;
+            let balance = self.balance(&tx.from);
+            if balance < cost {
+                return Err(StoreError::ValidationError(format!(
+                    "Transaction {} spends {} but {} only holds {}", tx.id, cost, tx.from.value, balance
+                )));
+            }
+            let spendable = self.spendable_balance(&tx.from);
+            if spendable < cost {
+                return Err(StoreError::ValidationError(format!(
+                    "Transaction {} spends {} but only {} of the balance of {} has matured",
+                    tx.id, cost, spendable, tx.from.value
+                )));
+            }
+
+            updated.insert(&tx.from.value, balance - cost);
+        }
+
+        for (to, amount) in tx.outputs() {
+            let balance = updated.get(to.value.as_str()).copied().unwrap_or_else(|| self.balance(to));
+            let credited = balance.checked_add(amount).ok_or_else(|| {
+                StoreError::ValidationError(format!("Transaction {} overflows the balance of {}", tx.id, to.value))
+            })?;
+            updated.insert(&to.value, credited);
+        }
+
+        for (address, balance) in updated {
+            Self::set(&mut self.balances, address, balance);
+        }
+        if !tx.is_coinbase() {
+            Self::set(&mut self.nonces, &tx.from.value, tx.nonce + 1);
+        }
+        if tx.is_coinbase() && self.coinbase_maturity > 0 {
+            self.immature.entry(tx.to.value.clone()).or_default().push((self.height, tx.amount));
+        }
+        Ok(())
+    }
+
+    /// Undo `apply_transaction` for the newest applied transaction
+    fn revert_transaction(&mut self, tx: &Transaction) {
+        for (to, amount) in tx.outputs() {
+            let balance = self.balance(to).saturating_sub(amount);
+            Self::set(&mut self.balances, &to.value, balance);
+        }
+        if tx.is_coinbase() {
+            if let Some(rewards) = self.immature.get_mut(&tx.to.value) {
+                if let Some(position) = rewards.iter().rposition(|reward| *reward == (self.height, tx.amount)) {
+                    rewards.remove(position);
+                }
+                if rewards.is_empty() {
+                    self.immature.remove(&tx.to.value);
+                }
+            }
+            return;
+        }
+
+        let refunded = self.balance(&tx.from).saturating_add(tx.total_cost().unwrap_or_default());
+        Self::set(&mut self.balances, &tx.from.value, refunded);
+        Self::set(&mut self.nonces, &tx.from.value, tx.nonce);
+    }
+
+    fn set(values: &mut HashMap<String, u64>, address: &str, value: u64) {
+        if value == 0 {
+            values.remove(address);
+        } else {
+            values.insert(address.to_string(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::DEFAULT_CHAIN_ID;
+    use crate::transaction::TRANSFER_GAS;
+
+    fn transfer(from: &Address, secret_key: &secp256k1::SecretKey, to: &Address, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new(from.clone(), to.clone(), amount).with_nonce(nonce).with_gas(TRANSFER_GAS, 1);
+        tx.sign(secret_key, DEFAULT_CHAIN_ID).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_apply_transactions() {
+        let (alice, alice_key, _) = Address::generate();
+        let bob = Address::generate().0;
+        let mut state = AccountState::new();
+
+        state.apply_transaction(&Transaction::coinbase(alice.clone(), 100)).unwrap();
+        assert_eq!(state.balance(&alice), 100);
+
+        state.apply_transaction(&transfer(&alice, &alice_key, &bob, 30, 0)).unwrap();
+        assert_eq!(state.balance(&alice), 69);
+        assert_eq!(state.balance(&bob), 30);
+        assert_eq!(state.nonce(&alice), 1);
+    }
+
+    #[test]
+    fn test_overdraft_rejected() {
+        let (alice, alice_key, _) = Address::generate();
+        let bob = Address::generate().0;
+        let mut state = AccountState::new();
+        state.apply_transaction(&Transaction::coinbase(alice.clone(), 10)).unwrap();
+
+        assert!(state.apply_transaction(&transfer(&alice, &alice_key, &bob, 10, 0)).is_err());
+        assert_eq!(state.balance(&alice), 10);
+        assert_eq!(state.nonce(&alice), 0);
+    }
+
+    #[test]
+    fn test_overflowing_cost_rejected() {
+        let (alice, alice_key, _) = Address::generate();
+        let bob = Address::generate().0;
+        let mut state = AccountState::new();
+        state.apply_transaction(&Transaction::coinbase(alice.clone(), u64::MAX)).unwrap();
+
+        let error = state.apply_transaction(&transfer(&alice, &alice_key, &bob, u64::MAX, 0)).unwrap_err();
+        assert!(error.to_string().contains("overflows"));
+        assert_eq!(state.balance(&alice), u64::MAX);
+    }
+
+    #[test]
+    fn test_coinbase_spendable_after_maturity() {
+        let (miner, miner_key, _) = Address::generate();
+        let bob = Address::generate().0;
+        let mut state = AccountState::new().with_coinbase_maturity(3);
+        state.at_height(1);
+        state.apply_transaction(&Transaction::coinbase(miner.clone(), 100)).unwrap();
+        assert_eq!(state.balance(&miner), 100);
+        assert_eq!(state.spendable_balance(&miner), 0);
+
+        for height in 1..4 {
+            state.at_height(height);
+            let error = state.apply_transaction(&transfer(&miner, &miner_key, &bob, 10, 0)).unwrap_err();
+            assert!(error.to_string().contains("matured"));
+        }
+
+        state.at_height(4);
+        assert_eq!(state.spendable_balance(&miner), 100);
+        state.apply_transaction(&transfer(&miner, &miner_key, &bob, 10, 0)).unwrap();
+        assert_eq!(state.balance(&bob), 10);
+    }
+
+    #[test]
+    fn test_batched_transfer_pays_every_recipient() {
+        let (alice, alice_key, _) = Address::generate();
+        let (bob, carol) = (Address::generate().0, Address::generate().0);
+        let mut state = AccountState::new();
+        state.apply_transaction(&Transaction::coinbase(alice.clone(), 100)).unwrap();
+
+        let mut batch = Transaction::new(alice.clone(), bob.clone(), 30)
+            .with_output(carol.clone(), 20)
+            .with_output(bob.clone(), 5)
+            .with_gas(TRANSFER_GAS, 1);
+        batch.sign(&alice_key, DEFAULT_CHAIN_ID).unwrap();
+        state.apply_transaction(&batch).unwrap();
+        assert_eq!(state.balance(&alice), 44);
+        assert_eq!(state.balance(&bob), 35);
+        assert_eq!(state.balance(&carol), 20);
+        assert_eq!(state.nonce(&alice), 1);
+
+        // The outputs together overdraw Alice, nothing moves
+        let mut overdraft = Transaction::new(alice.clone(), bob.clone(), 40)
+            .with_nonce(1)
+            .with_output(carol.clone(), 4)
+            .with_gas(TRANSFER_GAS, 1);
+        overdraft.sign(&alice_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(state.apply_transaction(&overdraft).is_err());
+        assert_eq!((state.balance(&alice), state.balance(&bob), state.balance(&carol)), (44, 35, 20));
+    }
+
+    fn block(index: u64, transactions: Vec<Transaction>) -> Block {
+        Block::new(index, transactions, crate::hash::Hash::genesis(), 4)
+    }
+
+    #[test]
+    fn test_apply_then_revert_block() {
+        let (alice, alice_key, _) = Address::generate();
+        let (bob, carol) = (Address::generate().0, Address::generate().0);
+        let mut state = AccountState::new().with_coinbase_maturity(2);
+        state.apply_block(&block(0, vec![])).unwrap();
+        state.apply_block(&block(1, vec![Transaction::coinbase(alice.clone(), 100)])).unwrap();
+        state.apply_block(&block(2, vec![])).unwrap();
+        state.apply_block(&block(3, vec![])).unwrap();
+        let before = state.clone();
+
+        let next = block(
+            4,
+            vec![
+                Transaction::coinbase(carol.clone(), 50),
+                transfer(&alice, &alice_key, &bob, 30, 0),
+                transfer(&alice, &alice_key, &carol, 20, 1),
+            ],
+        );
+        state.apply_block(&next).unwrap();
+        assert_eq!((state.balance(&alice), state.balance(&bob), state.balance(&carol)), (48, 30, 70));
+        assert_eq!(state.spendable_balance(&carol), 20);
+        assert_eq!(state.nonce(&alice), 2);
+
+        state.revert_block(&next);
+        assert_eq!(state, before);
+        assert_eq!(state.spendable_balance(&alice), 100);
+    }
+
+    #[test]
+    fn test_failing_block_leaves_state_untouched() {
+        let (alice, alice_key, _) = Address::generate();
+        let bob = Address::generate().0;
+        let mut state = AccountState::new();
+        state.apply_block(&block(0, vec![Transaction::coinbase(alice.clone(), 100)])).unwrap();
+        let before = state.clone();
+
+        let overdraft = block(1, vec![transfer(&alice, &alice_key, &bob, 60, 0), transfer(&alice, &alice_key, &bob, 60, 1)]);
+        assert!(state.apply_block(&overdraft).is_err());
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn test_nonce_must_be_next() {
+        let (alice, alice_key, _) = Address::generate();
+        let bob = Address::generate().0;
+        let mut state = AccountState::new();
+        state.apply_transaction(&Transaction::coinbase(alice.clone(), 100)).unwrap();
+
+        assert!(state.apply_transaction(&transfer(&alice, &alice_key, &bob, 10, 1)).is_err());
+        assert!(state.apply_transaction(&transfer(&alice, &alice_key, &bob, 10, 0)).is_ok());
+        assert!(state.apply_transaction(&transfer(&alice, &alice_key, &bob, 10, 0)).is_err());
+    }
+}
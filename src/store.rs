@@ -1,39 +1,415 @@
 use crate::block::Block;
 use crate::chain::Chain;
+use crate::codec::Codec;
 use crate::hash::Hash;
+use crate::state::AccountState;
 use std::fmt;
 use std::fmt::Formatter;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
 
 pub trait Store<T> {
     fn save(&mut self, item: T) -> Result<Hash, StoreError>;
+    /// Save every item or none of them, persisting once
+    fn save_batch(&mut self, items: Vec<T>) -> Result<Vec<Hash>, StoreError>;
+}
+
+/// Backend a chain is persisted to whenever it changes
+pub(crate) trait BlockStore: Send + Sync {
+    fn persist(&self, chain: &Chain) -> Result<(), StoreError>;
+    /// The last persisted chain, or `None` if nothing was ever persisted
+    fn load(&self) -> Result<Option<Chain>, StoreError>;
 }
 
 #[derive(Debug)]
 pub enum StoreError {
     IoError(std::io::Error),
-    SerializationError(serde_json::Error),
+    CodecError(String),
     ValidationError(String),
     NoBlockToCreate(),
-    DuplicateBlockError(String),
+    /// A mined block whose parent is no longer the tip
+    StaleBlock(String),
+    /// A chain file written by an older version, which this one cannot read
+    OutdatedChainFile(String),
 }
 
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             StoreError::IoError(e) => write!(f, "IO error {}", e),
-            StoreError::SerializationError(e) => write!(f, "Serialization error {}", e),
+            StoreError::CodecError(e) => write!(f, "Codec error {}", e),
             StoreError::ValidationError(e) => write!(f, "Validation error: {}", e),
-            StoreError::DuplicateBlockError(e) => write!(f, "Duplicate block error: {}", e),
             StoreError::NoBlockToCreate() => write!(f, "No block to create in transaction pool"),
+            StoreError::StaleBlock(e) => write!(f, "Stale block: {}", e),
+            StoreError::OutdatedChainFile(path) => write!(
+                f,
+                "Chain file {} is from an older version without blocks, remove it and resync from peers",
+                path
+            ),
         }
     }
 }
 
 impl Store<Block> for Chain {
     fn save(&mut self, block: Block) -> Result<Hash, StoreError> {
-        let hash = block.current_block_hash.clone().unwrap();
-        self.blocks.push(block);
-        //TODO: Write it to disk ?
-        Ok(hash)
+        self.save_batch(vec![block]).map(|mut hashes| hashes.remove(0))
+    }
+
+    /// Validate and append `blocks` in order, then write the chain once. Sync applies many blocks at a time,
+    /// rewriting the whole chain file after each of them would cost quadratic I/O.
+    /// A block failing validation or a failed write leaves the chain as it was.
+    fn save_batch(&mut self, blocks: Vec<Block>) -> Result<Vec<Hash>, StoreError> {
+        let original_len = self.blocks.len();
+        let mut state = self.state_at_tip()?;
+        let mut hashes = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            if let Err(e) = validate_next(self, &block, &mut state) {
+                self.blocks.truncate(original_len);
+                return Err(e);
+            }
+            hashes.push(block.current_block_hash.clone().unwrap());
+            self.blocks.push(block);
+        }
+
+        if let Err(e) = self.store().clone().persist(self) {
+            self.blocks.truncate(original_len);
+            return Err(e);
+        }
+        for block_position in original_len..self.blocks.len() {
+            self.index_block(block_position);
+        }
+        self.keep_tip_state(state);
+        Ok(hashes)
+    }
+}
+
+/// Check that `block` can follow the tip of `chain` and apply it to `state`, the state at that tip
+fn validate_next(chain: &Chain, block: &Block, state: &mut AccountState) -> Result<(), StoreError> {
+    block.validate(chain.network())?;
+    chain.check_checkpoint(block)?;
+    chain.check_finality(block.index)?;
+
+    if let Some(tip) = chain.blocks.last() {
+        if block.index != tip.index + 1 {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} does not follow the tip at height {}", block.index, tip.index
+            )));
+        }
+        if block.previous_block_hash != tip.current_block_hash {
+            return Err(StoreError::ValidationError(format!(
+                "Block {} does not link to block {}", block.index, tip.index
+            )));
+        }
+    }
+
+    let expected_difficulty = chain.next_difficulty();
+    if block.difficulty != expected_difficulty {
+        return Err(StoreError::ValidationError(format!(
+            "Block {} difficulty {} does not match expected {}", block.index, block.difficulty, expected_difficulty
+        )));
+    }
+
+    let trusted = chain.network().skip_pow_below_checkpoint
+        && chain.network().newest_checkpoint_height().is_some_and(|height| block.index <= height);
+    if !trusted && !block.has_valid_proof_of_work() {
+        return Err(StoreError::ValidationError(format!("Block {} does not meet its difficulty", block.index)));
+    }
+
+    let expected_base_fee = chain.next_base_fee();
+    if block.base_fee != expected_base_fee {
+        return Err(StoreError::ValidationError(format!(
+            "Block {} base fee {} does not match expected {}", block.index, block.base_fee, expected_base_fee
+        )));
+    }
+
+    state.apply_block(block)
+}
+
+/// Persists the chain as a file, keeping the previous version as a `.bak`
+pub(crate) struct FileStore {
+    path: String,
+    codec: Codec,
+    /// Writes the temporary file, `fs::write` unless a test needs a write that goes wrong
+    write: fn(&str, &[u8]) -> io::Result<()>,
+}
+
+impl FileStore {
+    pub(crate) fn new(path: String) -> Self {
+        Self { path, codec: Codec::Json, write: |path, content| fs::write(path, content) }
+    }
+
+    /// Write the temporary file with `write` instead of `fs::write`
+    #[cfg(test)]
+    pub(crate) fn with_writer(mut self, write: fn(&str, &[u8]) -> io::Result<()>) -> Self {
+        self.write = write;
+        self
+    }
+
+    /// Encode the chain file with `codec` instead of JSON
+    pub(crate) fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    fn backup_path(&self) -> String {
+        format!("{}.bak", self.path)
+    }
+
+    fn read_chain_file(&self, filename: &str) -> Result<Chain, StoreError> {
+        let content = fs::read(filename).map_err(StoreError::IoError)?;
+        self.codec.decode::<Chain>(&content).map_err(|e| {
+            if self.codec == Codec::Json && Self::is_outdated_chain_file(&content) {
+                StoreError::OutdatedChainFile(filename.to_string())
+            } else {
+                StoreError::CodecError(e)
+            }
+        })
+    }
+
+    /// Whether `content` is the chain file of older versions, which kept the genesis hash and no blocks
+    fn is_outdated_chain_file(content: &[u8]) -> bool {
+        serde_json::from_slice::<serde_json::Value>(content)
+            .is_ok_and(|value| value.get("genesis_block_hash").is_some() && value.get("blocks").is_none())
+    }
+}
+
+impl BlockStore for FileStore {
+    /// Write to a temporary file then rename it over the primary, keeping the
+    /// previous primary as a `.bak` so an interrupted write never loses the chain.
+    /// The temporary file is read back first, a partial write is found now rather than on the next load.
+    fn persist(&self, chain: &Chain) -> Result<(), StoreError> {
+        let content = self.codec.encode(chain).map_err(StoreError::CodecError)?;
+
+        let temp_file = format!("{}.tmp", self.path);
+        (self.write)(&temp_file, &content).map_err(StoreError::IoError)?;
+        if let Err(e) = self.read_chain_file(&temp_file) {
+            let _ = fs::remove_file(&temp_file);
+            return Err(StoreError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Written chain file {} does not read back: {}", temp_file, e),
+            )));
+        }
+
+        if Path::new(&self.path).exists() {
+            fs::copy(&self.path, self.backup_path()).map_err(StoreError::IoError)?;
+        }
+        fs::rename(&temp_file, &self.path).map_err(StoreError::IoError)?;
+
+        println!("Blockchain saved to {}", self.path);
+        Ok(())
+    }
+
+    /// Load the chain, falling back to the previous good copy if the primary file is unreadable
+    fn load(&self) -> Result<Option<Chain>, StoreError> {
+        let backup_path = self.backup_path();
+        if !Path::new(&self.path).exists() && !Path::new(&backup_path).exists() {
+            return Ok(None);
+        }
+
+        match self.read_chain_file(&self.path) {
+            Ok(chain) => Ok(Some(chain)),
+            // The backup is just as old, only a resync replaces it
+            Err(e @ StoreError::OutdatedChainFile(_)) => Err(e),
+            Err(e) => {
+                eprintln!("Failed to load blockchain from {}: {}", self.path, e);
+                println!("Trying backup {}...", backup_path);
+                self.read_chain_file(&backup_path).map(Some)
+            }
+        }
+    }
+}
+
+/// Keeps the chain in memory only, everything is discarded when the store is dropped
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    chain: Mutex<Option<Chain>>,
+}
+
+impl MemoryStore {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn persist(&self, chain: &Chain) -> Result<(), StoreError> {
+        *self.chain.lock().unwrap() = Some(chain.detached());
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<Chain>, StoreError> {
+        Ok(self.chain.lock().unwrap().as_ref().map(Chain::detached))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_chain_file(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("ola-chain-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("blockchain.json").to_string_lossy().into_owned()
+    }
+
+    fn genesis_hash(chain: &Chain) -> Option<Hash> {
+        chain.blocks.first().and_then(|block| block.current_block_hash.clone())
+    }
+
+    #[test]
+    fn test_file_store_without_file_loads_nothing() {
+        let store = FileStore::new(temp_chain_file("empty"));
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_save_creates_and_rotates_backup() {
+        let store = FileStore::new(temp_chain_file("rotate"));
+        let backup = store.backup_path();
+
+        let first = Chain::new_easy();
+        store.persist(&first).unwrap();
+        assert!(Path::new(&store.path).exists());
+        assert!(!Path::new(&backup).exists());
+        assert!(!Path::new(&format!("{}.tmp", store.path)).exists());
+
+        let second = Chain::new_easy();
+        store.persist(&second).unwrap();
+        let backed_up = serde_json::to_string(&store.read_chain_file(&backup).unwrap()).unwrap();
+        assert_eq!(backed_up, serde_json::to_string(&first).unwrap());
+        let primary = serde_json::to_string(&store.read_chain_file(&store.path).unwrap()).unwrap();
+        assert_eq!(primary, serde_json::to_string(&second).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_file_falls_back_to_backup() {
+        let store = FileStore::new(temp_chain_file("truncated"));
+        let chain = Chain::new_easy();
+        store.persist(&chain).unwrap();
+        store.persist(&chain).unwrap();
+
+        let content = fs::read_to_string(&store.path).unwrap();
+        fs::write(&store.path, &content[..content.len() / 2]).unwrap();
+
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), serde_json::to_string(&chain).unwrap());
+    }
+
+    #[test]
+    fn test_corrupt_write_keeps_previous_file() {
+        let path = temp_chain_file("corrupt-write");
+        let chain = Chain::new_easy();
+        FileStore::new(path.clone()).persist(&chain).unwrap();
+        let good = fs::read(&path).unwrap();
+
+        let store =
+            FileStore::new(path.clone()).with_writer(|path, content| fs::write(path, &content[..content.len() / 2]));
+        assert!(matches!(store.persist(&chain), Err(StoreError::IoError(_))));
+        assert_eq!(fs::read(&path).unwrap(), good);
+        assert!(!Path::new(&store.backup_path()).exists());
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+        assert_eq!(genesis_hash(&store.load().unwrap().unwrap()), genesis_hash(&chain));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_load_fails_without_valid_backup() {
+        let store = FileStore::new(temp_chain_file("corrupt"));
+        fs::write(&store.path, "{ not json").unwrap();
+        assert!(store.load().is_err());
+    }
+
+    #[test]
+    fn test_outdated_chain_file_reported() {
+        let store = FileStore::new(temp_chain_file("outdated"));
+        fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/blockchain.json"), &store.path).unwrap();
+
+        let Err(error) = store.load() else { panic!("an outdated chain file loaded") };
+        assert!(matches!(error, StoreError::OutdatedChainFile(_)));
+        assert!(error.to_string().contains("resync"));
+
+        // Anything else that does not decode is still a codec error
+        fs::write(&store.path, b"{\"blocks\": 7}").unwrap();
+        assert!(matches!(store.read_chain_file(&store.path), Err(StoreError::CodecError(_))));
+    }
+
+    #[test]
+    fn test_binary_file_store_round_trip() {
+        let store = FileStore::new(temp_chain_file("binary")).with_codec(Codec::Binary);
+        let chain = Chain::new_easy();
+        store.persist(&chain).unwrap();
+
+        assert!(serde_json::from_slice::<serde_json::Value>(&fs::read(&store.path).unwrap()).is_err());
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(loaded.blocks.len(), 1);
+        assert_eq!(genesis_hash(&loaded), genesis_hash(&chain));
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), serde_json::to_string(&chain).unwrap());
+    }
+
+    fn mined_block(chain: &Chain, index: u64, previous_hash: Hash) -> Block {
+        let mut block = Block::new(index, vec![], previous_hash, 1).with_base_fee(chain.next_base_fee());
+        block.mine_block(1).unwrap();
+        block
+    }
+
+    #[test]
+    fn test_unlinked_block_rejected() {
+        let mut chain = Chain::new_easy();
+        let genesis = genesis_hash(&chain).unwrap();
+
+        let skipping = mined_block(&chain, 7, genesis.clone());
+        assert!(chain.save(skipping).unwrap_err().to_string().contains("does not follow"));
+        let orphaned = mined_block(&chain, 1, Hash::new(b"made up parent"));
+        assert!(chain.save(orphaned).unwrap_err().to_string().contains("does not link"));
+        assert_eq!(chain.blocks.len(), 1);
+
+        chain.save(mined_block(&chain, 1, genesis)).unwrap();
+        chain.validate().unwrap();
+    }
+
+    #[test]
+    fn test_unlinked_batch_rejected() {
+        let mut chain = Chain::new_easy();
+        let first = mined_block(&chain, 1, genesis_hash(&chain).unwrap());
+        let mut extended = chain.clone();
+        extended.save(first.clone()).unwrap();
+        let second = mined_block(&extended, 2, first.current_block_hash.clone().unwrap());
+
+        let error = chain.save_batch(vec![second.clone(), first.clone()]).unwrap_err();
+        assert!(error.to_string().contains("does not follow"));
+        let unlinked = mined_block(&extended, 2, Hash::new(b"made up parent"));
+        assert!(chain.save_batch(vec![first.clone(), unlinked]).is_err());
+        assert_eq!(chain.blocks.len(), 1);
+
+        assert_eq!(chain.save_batch(vec![first, second]).unwrap().len(), 2);
+        chain.validate().unwrap();
+    }
+
+    #[test]
+    fn test_block_off_the_retargeted_difficulty_rejected() {
+        let mut chain = Chain::new_easy();
+        let mut harder = Block::new(1, vec![], genesis_hash(&chain).unwrap(), 3).with_base_fee(chain.next_base_fee());
+        harder.mine_block(3).unwrap();
+        assert!(chain.save(harder.clone()).unwrap_err().to_string().contains("does not match expected"));
+
+        // Loaded from disk, the same block fails the full validation as well
+        let mut loaded = chain.clone();
+        loaded.blocks.push(harder);
+        assert!(loaded.validate().unwrap_err().to_string().contains("does not match expected"));
+        assert_eq!(chain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_memory_store_round_trip() {
+        let store = MemoryStore::new();
+        assert!(store.load().unwrap().is_none());
+
+        let chain = Chain::new_easy();
+        store.persist(&chain).unwrap();
+        let loaded = store.load().unwrap().unwrap();
+        assert_eq!(genesis_hash(&loaded), genesis_hash(&chain));
+    }
+}
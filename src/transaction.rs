@@ -1,11 +1,69 @@
 use crate::address::Address;
-use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use crate::binary;
+use crate::clock::{Clock, SystemClock};
+use crate::crypto::{domain_message, secp, SigningDomain};
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::fmt;
 
-pub trait Signer {
-    fn sign(&self, transaction: &Transaction) -> String;
-    fn verify_signature(&self, transaction: &Transaction) -> bool;
+/// Gas a plain transfer consumes, the default limit until a VM meters execution
+pub(crate) const TRANSFER_GAS: u64 = 1;
+
+/// Weight of any transaction, for its first recipient and the bytes it adds to a block
+pub(crate) const BASE_WEIGHT: u64 = 10;
+/// Weight of each recipient past the first, every one is another balance to credit
+pub(crate) const OUTPUT_WEIGHT: u64 = 5;
+/// Weight of each signature a transaction carries, verifying one costs far more than reading a transfer
+pub(crate) const SIGNATURE_WEIGHT: u64 = 100;
+
+/// Why a transaction would not be accepted, reported by `Transaction::validate_structure` and `TransactionPool::simulate`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TxRejection {
+    /// Broken by a pool admission rule: malformed, out of bounds, duplicate nonce or pool full
+    Invalid(String),
+    BadSignature,
+    FeeTooLow { fee: u64, base_fee: u64 },
+    BadNonce { expected: u64, actual: u64 },
+    InsufficientBalance { required: u64, available: u64 },
+}
+
+impl fmt::Display for TxRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TxRejection::Invalid(reason) => write!(f, "{}", reason),
+            TxRejection::BadSignature => write!(f, "Transaction is not signed by its sender"),
+            TxRejection::FeeTooLow { fee, base_fee } => {
+                write!(f, "Fee {} is below the base fee {}", fee, base_fee)
+            }
+            TxRejection::BadNonce { expected, actual } => write!(f, "Nonce {} but {} was expected", actual, expected),
+            TxRejection::InsufficientBalance { required, available } => {
+                write!(f, "Transaction costs {} but only {} is spendable", required, available)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Times this thread checked a transaction signature with secp256k1
+    static SIGNATURE_CHECKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Scheduling class of a transaction, high-priority ones get a reserved share of every block
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+/// Recipient of a batched transfer beyond the transaction's own `to` and `amount`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct TxOutput {
+    pub to: Address,
+    pub amount: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,20 +72,53 @@ pub(crate) struct Transaction {
     pub from: Address,
     pub to: Address,
     pub amount: u64,
+    /// Further recipients paid by the same transaction, empty for a plain transfer
+    #[serde(default)]
+    pub additional_outputs: Vec<TxOutput>,
+    /// Always `gas_limit * gas_price` until execution reports the gas actually used
     pub fee: u64,
+    pub gas_limit: u64,
+    pub gas_price: u64,
+    pub priority: Priority,
+    pub nonce: u64,
     pub timestamp: u64,
     pub signature: Option<String>,
 }
 
+/// Transactions are identified by their id, which covers every field except the signature
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Transaction {}
+
+impl std::hash::Hash for Transaction {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
 impl Transaction {
     pub fn new(from: Address, to: Address, amount: u64) -> Self {
+        Self::new_with_clock(from, to, amount, &SystemClock)
+    }
+
+    /// Build a transaction timestamped by `clock`
+    pub(crate) fn new_with_clock(from: Address, to: Address, amount: u64, clock: &dyn Clock) -> Self {
         let mut tx = Self {
             id: String::new(),
             fee: 0,
+            gas_limit: TRANSFER_GAS,
+            gas_price: 0,
+            priority: Priority::Normal,
+            nonce: 0,
             from,
             to,
             amount,
-            timestamp: chrono::Utc::now().timestamp() as u64,
+            additional_outputs: Vec::new(),
+            timestamp: clock.now().timestamp() as u64,
             signature: None,
         };
 
@@ -36,41 +127,240 @@ impl Transaction {
         tx
     }
 
-    pub fn sign(&mut self, private_key: &SecretKey) -> Result<(), String> {
-        let secp = Secp256k1::new();
+    /// Transfer from the owner of `secret_key` paying each of `outputs`, at `gas_price` per unit of gas and
+    /// signed for the network identified by `chain_id`. This is what wallets hand to `/sendrawtransaction`.
+    pub(crate) fn signed_transfer(
+        secret_key: &SecretKey,
+        outputs: &[(Address, u64)],
+        nonce: u64,
+        gas_price: u64,
+        chain_id: u64,
+    ) -> Result<Self, String> {
+        let Some(((to, amount), additional)) = outputs.split_first() else {
+            return Err("A transfer needs at least one recipient".to_string());
+        };
+        let from = Address::from_public_key(&secret_key.public_key(secp()).serialize_uncompressed());
+        let mut tx = additional
+            .iter()
+            .fold(Self::new(from, to.clone(), *amount), |tx, (to, amount)| tx.with_output(to.clone(), *amount))
+            .with_nonce(nonce)
+            .with_gas(TRANSFER_GAS, gas_price);
+        tx.validate_structure().map_err(|rejection| rejection.to_string())?;
+        tx.sign(secret_key, chain_id)?;
+        Ok(tx)
+    }
 
-        let tx_hash = self.calculate_hash();
-        let message = secp256k1::Message::from_digest(tx_hash);
+    /// Mint `amount` to the block producer, paid from the zero address and left unsigned
+    pub fn coinbase(to: Address, amount: u64) -> Self {
+        Self::new(Address::zero(), to, amount)
+    }
 
-        let signature = secp.sign_ecdsa(message, private_key);
-        self.signature = Some(hex::encode(signature.serialize_compact()));
+    pub fn is_coinbase(&self) -> bool {
+        self.from.is_zero()
+    }
+
+    /// Set the sender nonce, recomputing the transaction id
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self.id = hex::encode(self.calculate_hash());
+        self
+    }
+
+    /// Set the gas limit and price, deriving the fee from them and recomputing the transaction id
+    pub fn with_gas(mut self, gas_limit: u64, gas_price: u64) -> Self {
+        self.gas_limit = gas_limit;
+        self.gas_price = gas_price;
+        self.fee = gas_limit.saturating_mul(gas_price);
+        self.id = hex::encode(self.calculate_hash());
+        self
+    }
+
+    /// Pay `amount` to `to` as well, recomputing the transaction id
+    pub fn with_output(mut self, to: Address, amount: u64) -> Self {
+        self.additional_outputs.push(TxOutput { to, amount });
+        self.id = hex::encode(self.calculate_hash());
+        self
+    }
+
+    /// Every recipient with the amount it receives, starting with `to`
+    pub fn outputs(&self) -> impl Iterator<Item = (&Address, u64)> {
+        std::iter::once((&self.to, self.amount))
+            .chain(self.additional_outputs.iter().map(|output| (&output.to, output.amount)))
+    }
+
+    /// Cost of validating the transaction, counted against `NetworkConfig::max_block_weight`
+    pub fn weight(&self) -> u64 {
+        let signatures = if self.is_coinbase() { 0 } else { 1 };
+        let outputs = self.additional_outputs.len() as u64;
+        BASE_WEIGHT
+            .saturating_add(SIGNATURE_WEIGHT * signatures)
+            .saturating_add(OUTPUT_WEIGHT.saturating_mul(outputs))
+    }
+
+    /// Whether `address` receives funds from this transaction
+    pub fn pays_to(&self, address: &Address) -> bool {
+        self.outputs().any(|(to, _)| to == address)
+    }
+
+    /// Sum paid to all recipients, `None` if it overflows
+    pub fn total_amount(&self) -> Option<u64> {
+        self.outputs().try_fold(0u64, |total, (_, amount)| total.checked_add(amount))
+    }
+
+    /// Set the scheduling class, recomputing the transaction id
+    #[cfg(test)]
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self.id = hex::encode(self.calculate_hash());
+        self
+    }
+
+    /// Whether the fee is exactly `gas_limit * gas_price` with a non-zero price. Coinbases pay no gas.
+    pub fn has_valid_gas(&self) -> bool {
+        self.is_coinbase() || (self.gas_price > 0 && self.gas_limit.checked_mul(self.gas_price) == Some(self.fee))
+    }
+
+    /// Sign the transaction for the network identified by `chain_id`
+    pub fn sign(&mut self, private_key: &SecretKey, chain_id: u64) -> Result<(), String> {
+        let message = domain_message(SigningDomain::Transaction, &self.signing_hash(chain_id));
+
+        // Recoverable signature: 64 compact bytes followed by the recovery id
+        let signature = secp().sign_ecdsa_recoverable(message, private_key);
+        let (recovery_id, compact) = signature.serialize_compact();
+        let mut signature_bytes = compact.to_vec();
+        signature_bytes.push(i32::from(recovery_id) as u8);
+        self.signature = Some(hex::encode(signature_bytes));
 
         Ok(())
     }
 
-    pub fn verify_signature(&self, public_key: PublicKey) -> bool {
-        let Some(ref sig_str) = self.signature else {
+    /// Check the signature was made by `public_key` for the network identified by `chain_id`
+    #[cfg(test)]
+    pub fn verify_signature(&self, public_key: PublicKey, chain_id: u64) -> bool {
+        // Coinbase transactions are created by the block producer and carry no signature
+        if self.is_coinbase() {
+            return true;
+        }
+
+        let Some(signature) = self.recoverable_signature() else {
             return false;
         };
 
-        let secp = Secp256k1::new();
+        let message = domain_message(SigningDomain::Transaction, &self.signing_hash(chain_id));
 
-        let Ok(sig_bytes) = hex::decode(sig_str) else {
-            return false;
-        };
+        SIGNATURE_CHECKS.with(|count| count.set(count.get() + 1));
+        secp().verify_ecdsa(message, &signature.to_standard(), &public_key).is_ok()
+    }
 
-        let Ok(signature) = secp256k1::ecdsa::Signature::from_compact(&sig_bytes) else {
-            return false;
-        };
+    /// Public key that produced the signature for the network identified by `chain_id`
+    pub(crate) fn recover_signer(&self, chain_id: u64) -> Option<PublicKey> {
+        let signature = self.recoverable_signature()?;
 
-        let tx_hash = self.calculate_hash();
-        let message = secp256k1::Message::from_digest(tx_hash);
+        let message = domain_message(SigningDomain::Transaction, &self.signing_hash(chain_id));
+
+        #[cfg(test)]
+        SIGNATURE_CHECKS.with(|count| count.set(count.get() + 1));
+        secp().recover_ecdsa(message, &signature).ok()
+    }
+
+    /// Whether the transaction was signed by the owner of its `from` address
+    pub(crate) fn is_signed_by_sender(&self, chain_id: u64) -> bool {
+        self.verify_against(&self.from, chain_id)
+    }
+
+    /// Whether the key recovered from the signature for the network identified by `chain_id` owns `expected`.
+    /// Coinbases carry no signature and only verify against the zero address they are sent from.
+    pub fn verify_against(&self, expected: &Address, chain_id: u64) -> bool {
+        if self.is_coinbase() {
+            return expected.is_zero();
+        }
+
+        self.recover_signer(chain_id).is_some_and(|public_key| {
+            Address::from_public_key(&public_key.serialize_uncompressed()) == *expected
+        })
+    }
+
+    fn recoverable_signature(&self) -> Option<RecoverableSignature> {
+        let sig_bytes = hex::decode(self.signature.as_ref()?).ok()?;
+        let (compact, recovery_id) = sig_bytes.split_last_chunk::<1>()?;
+        let recovery_id = RecoveryId::try_from(i32::from(recovery_id[0])).ok()?;
+
+        RecoverableSignature::from_compact(compact, recovery_id).ok()
+    }
+
+    /// Canonical binary encoding of the signed transaction as hex, the form wallets hand to `/sendrawtransaction`
+    pub fn to_raw_hex(&self) -> String {
+        hex::encode(binary::to_vec(self).expect("Transactions always encode"))
+    }
+
+    /// Decode a transaction produced by `to_raw_hex`, refusing one whose id does not match its contents
+    pub fn from_raw_hex(raw: &str) -> Result<Transaction, String> {
+        let raw = raw.trim();
+        let bytes = hex::decode(raw.strip_prefix("0x").unwrap_or(raw)).map_err(|e| format!("Invalid hex: {}", e))?;
+        let transaction: Transaction =
+            binary::from_slice(&bytes).map_err(|e| format!("Invalid raw transaction: {}", e))?;
+
+        if !transaction.has_valid_id() {
+            return Err(format!("Transaction id {} does not match its contents", transaction.id));
+        }
+        Ok(transaction)
+    }
+
+    /// Whether the id is the hash of the current contents, the signature aside
+    pub(crate) fn has_valid_id(&self) -> bool {
+        self.id == hex::encode(self.calculate_hash())
+    }
 
-        secp.verify_ecdsa(message, &signature, &public_key).is_ok()
+    /// Amount paid to every recipient plus fee leaving the sender's balance, `None` if it overflows
+    pub fn total_cost(&self) -> Option<u64> {
+        self.total_amount()?.checked_add(self.fee)
     }
 
+    /// `validate_structure`, and transfers carry a signature, without checking it
     pub fn is_valid(&self) -> bool {
-        self.amount > 0 && self.from != self.to && self.signature.is_some()
+        self.validate_structure().is_ok() && (self.is_coinbase() || self.signature.is_some())
+    }
+
+    /// The checks needing no secp256k1 work, cheap enough to run before verifying the signature.
+    /// Coinbases pay a single recipient, transfers pay each recipient something and never the sender.
+    /// Addresses are compared by the bytes their strings spell, so the result is the same whether `raw_bytes`
+    /// were set locally, rebuilt on deserialization or never filled in.
+    pub(crate) fn validate_structure(&self) -> Result<(), TxRejection> {
+        if !self.from.is_valid() || !self.from.has_consistent_bytes() {
+            return Err(TxRejection::Invalid(format!("Sender {} is not a valid address", self.from.value)));
+        }
+        let sender = self.from.as_bytes();
+        for (to, amount) in self.outputs() {
+            if !to.is_valid() || !to.has_consistent_bytes() {
+                return Err(TxRejection::Invalid(format!("Recipient {} is not a valid address", to.value)));
+            }
+            if to.as_bytes() == sender {
+                return Err(TxRejection::Invalid(format!("Transaction {} pays its own sender", self.id)));
+            }
+            if amount == 0 {
+                return Err(TxRejection::Invalid(format!("Transaction {} pays {} nothing", self.id, to.value)));
+            }
+        }
+        if self.is_coinbase() && !self.additional_outputs.is_empty() {
+            return Err(TxRejection::Invalid("Coinbase transactions pay a single recipient".to_string()));
+        }
+        if self.total_cost().is_none() {
+            return Err(TxRejection::Invalid(format!("Transaction {} cost overflows", self.id)));
+        }
+        if !self.has_valid_gas() {
+            return Err(TxRejection::Invalid(format!(
+                "Fee {} is not the gas limit {} times the gas price {}",
+                self.fee, self.gas_limit, self.gas_price
+            )));
+        }
+        Ok(())
+    }
+
+    fn signing_hash(&self, chain_id: u64) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(chain_id.to_le_bytes());
+        hasher.update(self.calculate_hash());
+        hasher.finalize().into()
     }
 
     fn calculate_hash(&self) -> [u8; 32] {
@@ -79,11 +369,287 @@ impl Transaction {
         // Add transaction fields to hash input
         hasher.update(self.from.value.as_bytes());
         hasher.update(self.to.value.as_bytes());
-        hasher.update(&self.amount.to_le_bytes());
-        hasher.update(&self.fee.to_le_bytes());
-        hasher.update(&self.timestamp.to_le_bytes());
+        hasher.update(self.amount.to_le_bytes());
+        hasher.update(self.fee.to_le_bytes());
+        hasher.update(self.gas_limit.to_le_bytes());
+        hasher.update(self.gas_price.to_le_bytes());
+        hasher.update([self.priority as u8]);
+        hasher.update(self.nonce.to_le_bytes());
+        hasher.update(self.timestamp.to_le_bytes());
+        // Plain transfers keep the id they had before batched outputs existed
+        for output in &self.additional_outputs {
+            hasher.update(output.to.value.as_bytes());
+            hasher.update(output.amount.to_le_bytes());
+        }
 
         let result = hasher.finalize();
         result.into()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::DEFAULT_CHAIN_ID;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_coinbase_needs_no_signature() {
+        let miner = Address::generate().0;
+        let coinbase = Transaction::coinbase(miner, 50);
+
+        assert!(coinbase.is_coinbase());
+        assert!(coinbase.signature.is_none());
+        assert!(coinbase.is_valid());
+
+        let (_, _, unrelated_key) = Address::generate();
+        assert!(coinbase.verify_signature(unrelated_key, DEFAULT_CHAIN_ID));
+    }
+
+    #[test]
+    fn test_regular_transaction_needs_signature() {
+        let (from, secret_key, public_key) = Address::generate();
+        let to = Address::generate().0;
+        let mut tx = Transaction::new(from, to, 10).with_gas(TRANSFER_GAS, 1);
+
+        assert!(!tx.is_coinbase());
+        assert!(!tx.is_valid());
+        assert!(!tx.verify_signature(public_key, DEFAULT_CHAIN_ID));
+
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(tx.is_valid());
+        assert!(tx.verify_signature(public_key, DEFAULT_CHAIN_ID));
+    }
+
+    #[test]
+    fn test_signature_bound_to_chain_id() {
+        let (from, secret_key, public_key) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10);
+        tx.sign(&secret_key, 7).unwrap();
+
+        assert!(tx.verify_signature(public_key, 7));
+        assert!(!tx.verify_signature(public_key, 8));
+    }
+
+    #[test]
+    fn test_signed_by_sender() {
+        let (from, secret_key, public_key) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10);
+        assert!(!tx.is_signed_by_sender(DEFAULT_CHAIN_ID));
+
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(tx.recover_signer(DEFAULT_CHAIN_ID), Some(public_key));
+        assert!(tx.is_signed_by_sender(DEFAULT_CHAIN_ID));
+
+        let (_, other_key, _) = Address::generate();
+        tx.sign(&other_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(!tx.is_signed_by_sender(DEFAULT_CHAIN_ID));
+    }
+
+    #[test]
+    fn test_verify_against_expected_address() {
+        let (from, secret_key, _) = Address::generate();
+        let (other, other_key, _) = Address::generate();
+        let mut tx = Transaction::new(from.clone(), Address::generate().0, 10);
+        assert!(!tx.verify_against(&from, DEFAULT_CHAIN_ID));
+
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(tx.verify_against(&from, DEFAULT_CHAIN_ID));
+        assert!(!tx.verify_against(&other, DEFAULT_CHAIN_ID));
+        assert!(!tx.verify_against(&from, DEFAULT_CHAIN_ID + 1));
+
+        tx.sign(&other_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(!tx.verify_against(&from, DEFAULT_CHAIN_ID));
+        assert!(tx.verify_against(&other, DEFAULT_CHAIN_ID));
+
+        let coinbase = Transaction::coinbase(other.clone(), 50);
+        assert!(coinbase.verify_against(&Address::zero(), DEFAULT_CHAIN_ID));
+        assert!(!coinbase.verify_against(&other, DEFAULT_CHAIN_ID));
+    }
+
+    #[test]
+    fn test_total_cost() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 100).with_gas(TRANSFER_GAS, 5);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(tx.total_cost(), Some(105));
+        assert!(tx.is_valid());
+
+        tx.amount = u64::MAX;
+        assert_eq!(tx.total_cost(), None);
+        assert!(!tx.is_valid());
+    }
+
+    #[test]
+    fn test_fee_is_gas_limit_times_price() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(21, 3);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(tx.fee, 63);
+        assert!(tx.has_valid_gas());
+        assert!(tx.is_valid());
+
+        // A fee that does not match the gas is invalid
+        tx.fee = 64;
+        assert!(!tx.has_valid_gas());
+        assert!(!tx.is_valid());
+    }
+
+    #[test]
+    fn test_zero_gas_price_rejected() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(21, 0);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(!tx.has_valid_gas());
+        assert!(!tx.is_valid());
+    }
+
+    #[test]
+    fn test_gas_fields_covered_by_signature() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(21, 3);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        let mut repriced = tx.clone();
+        repriced.gas_limit = 7;
+        repriced.gas_price = 9;
+        assert!(repriced.has_valid_gas());
+        assert!(!repriced.is_signed_by_sender(DEFAULT_CHAIN_ID));
+        assert!(tx.is_signed_by_sender(DEFAULT_CHAIN_ID));
+    }
+
+    #[test]
+    fn test_equality_and_hashing_by_id() {
+        let (from, secret_key, _) = Address::generate();
+        let unsigned = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        let mut signed = unsigned.clone();
+        signed.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(signed, unsigned);
+        assert_eq!(signed.id, unsigned.id);
+
+        let other = unsigned.clone().with_nonce(1);
+        assert_ne!(other, unsigned);
+
+        let seen: HashSet<Transaction> = [unsigned, signed, other].into_iter().collect();
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn test_raw_hex_round_trip() {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_nonce(3).with_gas(TRANSFER_GAS, 2);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        let raw = tx.to_raw_hex();
+        let decoded = Transaction::from_raw_hex(&raw).unwrap();
+        assert_eq!(decoded, tx);
+        assert_eq!(decoded.signature, tx.signature);
+        assert!(decoded.is_signed_by_sender(DEFAULT_CHAIN_ID));
+        assert_eq!(Transaction::from_raw_hex(&format!("0x{}", raw)).unwrap(), tx);
+
+        let mut tampered = tx.clone();
+        tampered.amount = 1_000;
+        assert!(Transaction::from_raw_hex(&tampered.to_raw_hex()).unwrap_err().contains("does not match"));
+        assert!(Transaction::from_raw_hex("zz").is_err());
+        assert!(Transaction::from_raw_hex(&raw[..raw.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn test_signed_transfer_decodes_from_raw_hex() {
+        let (from, secret_key, _) = Address::generate();
+        let (bob, carol) = (Address::generate().0, Address::generate().0);
+        let outputs = [(bob.clone(), 10), (carol.clone(), 5)];
+        let tx = Transaction::signed_transfer(&secret_key, &outputs, 4, 3, DEFAULT_CHAIN_ID).unwrap();
+
+        let decoded = Transaction::from_raw_hex(&tx.to_raw_hex()).unwrap();
+        assert_eq!(decoded.from, from);
+        assert_eq!(decoded.outputs().collect::<Vec<_>>(), vec![(&bob, 10), (&carol, 5)]);
+        assert_eq!((decoded.nonce, decoded.gas_price, decoded.fee), (4, 3, 3 * TRANSFER_GAS));
+        assert!(decoded.is_signed_by_sender(DEFAULT_CHAIN_ID));
+
+        assert!(Transaction::signed_transfer(&secret_key, &[], 0, 1, DEFAULT_CHAIN_ID).is_err());
+        let to_self = Transaction::signed_transfer(&secret_key, &[(from, 10)], 0, 1, DEFAULT_CHAIN_ID);
+        assert!(to_self.unwrap_err().contains("own sender"));
+    }
+
+    #[test]
+    fn test_signature_covers_every_output() {
+        let (from, secret_key, _) = Address::generate();
+        let (bob, carol) = (Address::generate().0, Address::generate().0);
+        let single = Transaction::new(from.clone(), bob.clone(), 10).with_gas(TRANSFER_GAS, 1);
+        let mut batch = single.clone().with_output(carol.clone(), 20);
+        assert_ne!(batch.id, single.id);
+        assert_eq!(batch.total_amount(), Some(30));
+        assert_eq!(batch.total_cost(), Some(31));
+        assert!(batch.pays_to(&carol));
+
+        batch.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(batch.is_valid());
+        assert!(batch.is_signed_by_sender(DEFAULT_CHAIN_ID));
+
+        let mut redirected = batch.clone();
+        redirected.additional_outputs[0].amount = 25;
+        assert!(!redirected.is_signed_by_sender(DEFAULT_CHAIN_ID));
+        assert!(Transaction::from_raw_hex(&redirected.to_raw_hex()).is_err());
+        assert_eq!(Transaction::from_raw_hex(&batch.to_raw_hex()).unwrap().additional_outputs, batch.additional_outputs);
+
+        assert!(!batch.clone().with_output(from, 5).is_valid());
+        assert!(!batch.clone().with_output(Address::generate().0, 0).is_valid());
+        assert!(!Transaction::coinbase(bob, 50).with_output(carol, 50).is_valid());
+    }
+
+    #[test]
+    fn test_self_transfer_check_same_locally_and_over_the_wire() {
+        let (alice, secret_key, _) = Address::generate();
+        // The same address spelled in upper case and built without bytes, which compares by string locally
+        let shouted = Address { value: format!("0x{}", alice.value[2..].to_uppercase()), raw_bytes: None };
+        assert_ne!(shouted, alice);
+        let mut local = Transaction::new(alice.clone(), shouted, 10).with_gas(TRANSFER_GAS, 1);
+        local.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        let received: Transaction = serde_json::from_str(&serde_json::to_string(&local).unwrap()).unwrap();
+        assert_eq!(received.to, received.from);
+        assert!(!local.is_valid());
+        assert!(!received.is_valid());
+
+        let mut forged = Transaction::new(alice, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        forged.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(forged.is_valid());
+        forged.to.raw_bytes = Some(vec![0; 20]);
+        assert!(!forged.is_valid());
+    }
+
+    #[test]
+    fn test_structure_checked_without_signature_work() {
+        let checks = || SIGNATURE_CHECKS.with(|count| count.get());
+        let (from, secret_key, public_key) = Address::generate();
+        let mut to_self = Transaction::new(from.clone(), from.clone(), 10).with_gas(TRANSFER_GAS, 1);
+        to_self.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        let mut bad_gas = Transaction::new(from.clone(), Address::generate().0, 10).with_gas(TRANSFER_GAS, 0);
+        bad_gas.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        let before = checks();
+        assert!(to_self.validate_structure().unwrap_err().to_string().contains("own sender"));
+        assert!(bad_gas.validate_structure().unwrap_err().to_string().contains("gas price"));
+        let mut pool = crate::transaction_pool::TransactionPool::new(10, 1024 * 1024, 16);
+        assert!(pool.add_transaction(to_self).is_err());
+        assert_eq!(checks(), before);
+
+        // Signed by someone else: well formed, but the signature does not hold
+        let mut mis_signed = Transaction::new(from, Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        mis_signed.sign(&Address::generate().1, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(mis_signed.validate_structure(), Ok(()));
+        assert!(!mis_signed.verify_signature(public_key, DEFAULT_CHAIN_ID));
+        assert!(pool.add_transaction(mis_signed).unwrap_err().contains("not signed by"));
+        assert!(checks() > before);
+    }
+
+    #[test]
+    fn test_new_transaction_uses_clock() {
+        let start = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let clock = crate::clock::MockClock::new(start);
+
+        let tx = Transaction::new_with_clock(Address::generate().0, Address::generate().0, 10, &clock);
+        assert_eq!(tx.timestamp, 1_700_000_000);
+    }
+}
@@ -1,72 +1,369 @@
-use std::collections::VecDeque;
-use crate::transaction::Transaction;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use crate::block::Block;
+use crate::chain::Chain;
+use crate::clock::{Clock, SystemClock};
+use crate::events::{ChainEvent, EventBus};
+use crate::network::NetworkConfig;
+use crate::state::AccountState;
+use crate::transaction::{Priority, Transaction, BASE_WEIGHT};
+pub(crate) use crate::transaction::TxRejection;
+
+/// Orphans held when no other limit is given
+pub(crate) const DEFAULT_MAX_ORPHANS: usize = 100;
+/// Total estimated size of pending transactions when no other limit is given
+pub(crate) const DEFAULT_MAX_POOL_BYTES: usize = 16 * 1024 * 1024;
+/// Percentage of every block kept for high-priority transactions when no other share is given
+pub(crate) const DEFAULT_HIGH_PRIORITY_RESERVE: usize = 10;
+/// Seconds an orphan waits for the transactions filling its nonce gap before it is dropped
+const ORPHAN_TTL_SECS: u64 = 600;
+/// Fee samples `dynamic_min_fee` averages over, one per transaction offered and per block pulled
+const MIN_FEE_WINDOW: usize = 16;
+
+/// What became of a transaction whose submitter asked to hear about it, see `submit_with_confirmation`
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxOutcome {
+    /// Mined into the block at this index
+    Included(u64),
+    /// Left the pool without being mined, replaced or no longer valid
+    Dropped,
+}
+
+/// State of a pool saved by `TransactionPool::checkpoint`, for `restore` to roll back to
+pub struct PoolCheckpoint(TransactionPool);
+
+/// Transactions picked for the next block so far
+#[derive(Default)]
+struct Selection {
+    transactions: Vec<Transaction>,
+    ids: HashSet<String>,
+    total_size: usize,
+    total_weight: u64,
+}
 
 #[derive(Clone)]
 pub struct TransactionPool {
     pending_transactions: VecDeque<Transaction>,
-    by_fee: std::collections::BTreeMap<u64, Vec<Transaction>>,
+    by_fee: BTreeMap<u64, Vec<Transaction>>,
+    // sender address -> nonce -> transaction id
+    by_sender: HashMap<String, BTreeMap<u64, String>>,
     max_transactions_per_block: usize,
     max_block_size: usize,
+    max_pending_per_sender: usize,
+    // share of each block, in percent, kept for high-priority transactions
+    high_priority_reserve_percent: usize,
+    network: NetworkConfig,
+    events: EventBus,
+    // sender address -> nonce -> (transaction, unix time it was held at) for transactions ahead of the sender's nonce
+    orphans: HashMap<String, BTreeMap<u64, (Transaction, u64)>>,
+    max_orphans: usize,
+    clock: Arc<dyn Clock>,
+    // sum of `estimate_transaction_size` over the pending transactions
+    pending_bytes: usize,
+    max_pool_bytes: usize,
+    // fee admission never goes below, whatever the pressure on the pool
+    min_fee_floor: u64,
+    // newest fee samples, the fee needed to outbid the cheapest pending transaction while the pool was full
+    // and `min_fee_floor` while it had room
+    fee_samples: VecDeque<u64>,
+    // transaction id -> submitter waiting to hear whether it is mined
+    confirmations: HashMap<String, Sender<TxOutcome>>,
 }
 
 impl TransactionPool {
-    pub fn new(max_transactions_per_block: usize, max_block_size: usize) -> Self {
+    pub fn new(max_transactions_per_block: usize, max_block_size: usize, max_pending_per_sender: usize) -> Self {
         Self {
             pending_transactions: VecDeque::new(),
-            by_fee: std::collections::BTreeMap::new(),
+            by_fee: BTreeMap::new(),
+            by_sender: HashMap::new(),
             max_transactions_per_block,
-            max_block_size
+            max_block_size,
+            max_pending_per_sender,
+            high_priority_reserve_percent: DEFAULT_HIGH_PRIORITY_RESERVE,
+            network: NetworkConfig::default(),
+            events: EventBus::new(),
+            orphans: HashMap::new(),
+            max_orphans: DEFAULT_MAX_ORPHANS,
+            clock: Arc::new(SystemClock),
+            pending_bytes: 0,
+            max_pool_bytes: DEFAULT_MAX_POOL_BYTES,
+            min_fee_floor: 0,
+            fee_samples: VecDeque::new(),
+            confirmations: HashMap::new(),
         }
     }
 
+    /// Refuse transactions that would take the estimated size of everything pending over `max_pool_bytes`
+    pub fn with_max_pool_bytes(mut self, max_pool_bytes: usize) -> Self {
+        self.max_pool_bytes = max_pool_bytes;
+        self
+    }
+
+    /// Refuse transactions paying less than `min_fee`, `dynamic_min_fee` only rises above it
+    pub fn with_min_fee(mut self, min_fee: u64) -> Self {
+        self.min_fee_floor = min_fee;
+        self
+    }
+
+    /// Hold at most `max_orphans` transactions waiting for a nonce gap to fill, 0 refuses gapped transactions
+    pub fn with_max_orphans(mut self, max_orphans: usize) -> Self {
+        self.max_orphans = max_orphans;
+        self
+    }
+
+    /// Read time from `clock` instead of the system clock
+    #[cfg(test)]
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Keep `percent` of every block's transaction count and size for high-priority transactions
+    pub fn with_high_priority_reserve(mut self, percent: usize) -> Self {
+        self.high_priority_reserve_percent = percent.min(100);
+        self
+    }
+
+    pub fn with_network(mut self, network: NetworkConfig) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Publish accepted transactions on `events`, usually the bus of the chain the pool feeds
+    pub(crate) fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
     pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
-        if !transaction.is_valid() {
-            return Err("Invalid transaction".to_string());
+        self.sample_fee_pressure();
+        let replaced_id = self.check_admission(&transaction)?;
+        if !transaction.verify_against(&transaction.from, self.network.chain_id) {
+            return Err(format!("Transaction {} is not signed by {}", transaction.id, transaction.from.value));
         }
 
-        if self.pending_transactions.len() >= self.max_transactions_per_block {
-            return Err("Transaction pool is full".to_string());
+        if let Some(replaced_id) = replaced_id {
+            // Replacing a nonce invalidates every later transaction built on top of it
+            self.drop_transaction(&replaced_id);
         }
 
         let fee = transaction.fee;
+        self.by_sender
+            .entry(transaction.from.value.clone())
+            .or_default()
+            .insert(transaction.nonce, transaction.id.clone());
+        let id = transaction.id.clone();
+        self.pending_bytes += self.estimate_transaction_size(&transaction);
         self.pending_transactions.push_back(transaction.clone());
-        self.by_fee.entry(fee).or_insert_with(Vec::new).push(transaction);
+        self.by_fee.entry(fee).or_default().push(transaction);
+        self.check_invariants();
+        self.events.publish(ChainEvent::TxAccepted(id));
         Ok(())
     }
 
-    pub fn pull_transactions_for_block(&mut self) -> Vec<Transaction> {
-        let mut selected_txs = Vec::new();
-        let mut total_size = 0;
-        let mut tx_id_to_remove = Vec::new();
+    /// Tell submitters waiting on transactions of `block`, now part of the chain, that they were mined
+    pub fn confirm_block(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            if let Some(submitter) = self.confirmations.remove(&tx.id) {
+                // The submitter may have stopped listening
+                let _ = submitter.send(TxOutcome::Included(block.index));
+            }
+        }
+    }
 
-        for (_fee, transactions) in self.by_fee.iter().rev() {
-            for tx in transactions {
-                let tx_size = self.estimate_transaction_size(tx);
+    fn notify_dropped(&mut self, transaction_id: &str) {
+        if let Some(submitter) = self.confirmations.remove(transaction_id) {
+            let _ = submitter.send(TxOutcome::Dropped);
+        }
+    }
 
-                if selected_txs.len() >= self.max_transactions_per_block || total_size + tx_size > self.max_block_size {
-                    break;
-                }
+    /// Run every admission rule of `add_transaction` without touching the pool.
+    /// Returns the id of the pending transaction this one would replace, if any.
+    pub fn check_admission(&self, transaction: &Transaction) -> Result<Option<String>, String> {
+        // The zero address is reserved as the coinbase sender
+        if transaction.is_coinbase() {
+            return Err("Coinbase transactions are created by miners".to_string());
+        }
 
-                selected_txs.push(tx.clone());
-                tx_id_to_remove.push(tx.id.clone());
-                total_size += tx_size;
+        // Cheap checks first, the signature is only verified once the rest of admission passes
+        transaction.validate_structure().map_err(|rejection| rejection.to_string())?;
 
-                if selected_txs.len() >= self.max_transactions_per_block {
-                    break;
+        for (_, amount) in transaction.outputs() {
+            self.network.check_amount(amount)?;
+        }
+        self.network.check_gas_limit(transaction.gas_limit)?;
+
+        let min_fee = self.dynamic_min_fee();
+        if transaction.fee < min_fee {
+            return Err(format!("Fee {} is below the pool's minimum fee {}", transaction.fee, min_fee));
+        }
+
+        let sender = &transaction.from.value;
+        let replaced_id = self
+            .by_sender
+            .get(sender)
+            .and_then(|nonces| nonces.get(&transaction.nonce))
+            .cloned();
+
+        match &replaced_id {
+            Some(existing_id) => {
+                let existing_fee = self
+                    .pending_transactions
+                    .iter()
+                    .find(|tx| &tx.id == existing_id)
+                    .map(|tx| tx.fee)
+                    .unwrap_or_default();
+                if transaction.fee <= existing_fee {
+                    return Err("Transaction with the same nonce is already pending".to_string());
                 }
             }
+            None => {
+                if self.pending_transactions.len() >= self.max_transactions_per_block {
+                    return Err("Transaction pool is full".to_string());
+                }
 
-            if selected_txs.len() >= self.max_transactions_per_block {
+                let sender_pending = self.by_sender.get(sender).map_or(0, |nonces| nonces.len());
+                if sender_pending >= self.max_pending_per_sender {
+                    return Err("Sender has too many pending transactions".to_string());
+                }
+            }
+        }
+
+        let freed = replaced_id
+            .as_ref()
+            .and_then(|id| self.pending_transactions.iter().find(|tx| &tx.id == id))
+            .map_or(0, |tx| self.estimate_transaction_size(tx));
+        let size = self.estimate_transaction_size(transaction);
+        if self.pending_bytes - freed + size > self.max_pool_bytes {
+            return Err(format!(
+                "Transaction pool is full, {} more bytes would exceed its {} byte limit", size, self.max_pool_bytes
+            ));
+        }
+
+        Ok(replaced_id)
+    }
+
+    /// Dry run of admitting `transaction` on top of `chain`: the pool rules, the signature, the base fee,
+    /// and the nonce and balance left once the sender's earlier pending transactions are applied
+    pub(crate) fn simulate(&self, transaction: &Transaction, chain: &Chain) -> Result<(), TxRejection> {
+        self.check_admission(transaction).map_err(TxRejection::Invalid)?;
+
+        if !transaction.is_signed_by_sender(chain.network().chain_id) {
+            return Err(TxRejection::BadSignature);
+        }
+
+        let base_fee = chain.next_base_fee();
+        if transaction.fee < base_fee {
+            return Err(TxRejection::FeeTooLow { fee: transaction.fee, base_fee });
+        }
+
+        let mut state = chain.state_at_tip().map_err(|e| TxRejection::Invalid(e.to_string()))?;
+        let earlier = self
+            .by_sender
+            .get(&transaction.from.value)
+            .into_iter()
+            .flat_map(|nonces| nonces.range(..transaction.nonce).map(|(_, id)| id));
+        for id in earlier {
+            let pending = self.pending_transactions.iter().find(|tx| &tx.id == id);
+            if pending.is_none_or(|tx| state.apply_transaction(tx).is_err()) {
                 break;
             }
+        }
+
+        let expected = state.nonce(&transaction.from);
+        if transaction.nonce != expected {
+            return Err(TxRejection::BadNonce { expected, actual: transaction.nonce });
+        }
 
+        let required = transaction.total_cost().unwrap_or(u64::MAX);
+        let available = state.spendable_balance(&transaction.from);
+        if available < required {
+            return Err(TxRejection::InsufficientBalance { required, available });
         }
+        Ok(())
+    }
 
-        for tx_id in tx_id_to_remove {
-            self.remove_transaction(&tx_id);
+    pub fn pull_transactions_for_block(&mut self) -> Vec<Transaction> {
+        let mut selection = Selection::default();
+
+        // High-priority transactions go first, within the share of the block reserved for them
+        let reserved_count = self.max_transactions_per_block * self.high_priority_reserve_percent / 100;
+        let reserved_size = self.max_block_size * self.high_priority_reserve_percent / 100;
+        self.select_by_fee(&mut selection, reserved_count, reserved_size, |tx| tx.priority == Priority::High);
+
+        // The rest of the block is filled by fee, whatever the priority
+        self.select_by_fee(&mut selection, self.max_transactions_per_block, self.max_block_size, |_| true);
+
+        for tx in &selection.transactions {
+            self.remove_transaction(&tx.id);
         }
+        self.sample_fee_pressure();
 
-        selected_txs
+        selection.transactions
+    }
+
+    /// Add the highest-fee transactions matching `eligible` to `selection` until it holds `max_count`
+    /// transactions. Each sender's transactions are taken in nonce order without gaps, so a sender only
+    /// competes with its next nonce. A transaction that would take the selection over `max_size` bytes or
+    /// the block weight limit is skipped, together with the sender's later nonces.
+    fn select_by_fee(
+        &self,
+        selection: &mut Selection,
+        max_count: usize,
+        max_size: usize,
+        eligible: impl Fn(&Transaction) -> bool,
+    ) {
+        // Leave room for the coinbase the block builder adds
+        let max_weight = self.network.max_block_weight.saturating_sub(BASE_WEIGHT);
+        // Transaction id -> arrival order, which breaks ties between equal fees
+        let pending: HashMap<&str, (usize, &Transaction)> = self
+            .pending_transactions
+            .iter()
+            .enumerate()
+            .map(|(position, tx)| (tx.id.as_str(), (position, tx)))
+            .collect();
+        let next_of = |nonces: &BTreeMap<u64, String>, ids: &HashSet<String>| {
+            let mut previous = None;
+            for (nonce, id) in nonces {
+                if !ids.contains(id) {
+                    let follows = previous.is_none_or(|previous| previous + 1 == *nonce);
+                    return pending.get(id.as_str()).filter(|(_, tx)| follows && eligible(tx)).copied();
+                }
+                previous = Some(*nonce);
+            }
+            None
+        };
+
+        let mut candidates: BinaryHeap<(u64, Reverse<usize>)> = self
+            .by_sender
+            .values()
+            .filter_map(|nonces| next_of(nonces, &selection.ids))
+            .map(|(position, tx)| (tx.fee, Reverse(position)))
+            .collect();
+        while let Some((_fee, Reverse(position))) = candidates.pop() {
+            if selection.transactions.len() >= max_count {
+                break;
+            }
+
+            let tx = &self.pending_transactions[position];
+            let tx_size = self.estimate_transaction_size(tx);
+            let tx_weight = tx.weight();
+            if selection.total_size + tx_size > max_size || selection.total_weight + tx_weight > max_weight {
+                continue;
+            }
+
+            selection.transactions.push(tx.clone());
+            selection.ids.insert(tx.id.clone());
+            selection.total_size += tx_size;
+            selection.total_weight += tx_weight;
+
+            let next = self.by_sender.get(&tx.from.value).and_then(|nonces| next_of(nonces, &selection.ids));
+            if let Some((position, tx)) = next {
+                candidates.push((tx.fee, Reverse(position)));
+            }
+        }
     }
 
     pub fn estimate_transaction_size(&self, transaction: &Transaction) -> usize {
@@ -74,15 +371,940 @@ impl TransactionPool {
     }
 
     pub fn remove_transaction(&mut self, transaction_id: &str) {
+        if let Some(tx) = self.pending_transactions.iter().find(|tx| tx.id == transaction_id) {
+            let sender = tx.from.value.clone();
+            let nonce = tx.nonce;
+            let size = self.estimate_transaction_size(tx);
+            if let Some(nonces) = self.by_sender.get_mut(&sender) {
+                nonces.remove(&nonce);
+                if nonces.is_empty() {
+                    self.by_sender.remove(&sender);
+                }
+            }
+            self.pending_bytes = self.pending_bytes.saturating_sub(size);
+        }
+
         self.pending_transactions.retain(|tx| tx.id != transaction_id);
         for (_, transactions) in self.by_fee.iter_mut() {
             transactions.retain(|tx| tx.id != transaction_id);
         }
         self.by_fee.retain(|_, tx| !tx.is_empty());
+        self.check_invariants();
+    }
+
+    /// Remove a transaction that will not be mined, along with the sender's
+    /// higher-nonce transactions that can no longer be applied without it.
+    /// Returns the ids of every evicted transaction.
+    pub fn drop_transaction(&mut self, transaction_id: &str) -> Vec<String> {
+        let Some(tx) = self.pending_transactions.iter().find(|tx| tx.id == transaction_id) else {
+            return Vec::new();
+        };
+        let sender = tx.from.value.clone();
+        let nonce = tx.nonce;
+
+        let mut evicted = vec![transaction_id.to_string()];
+        if let Some(nonces) = self.by_sender.get(&sender) {
+            evicted.extend(nonces.range(nonce + 1..).map(|(_, id)| id.clone()));
+        }
+
+        for id in &evicted {
+            self.remove_transaction(id);
+            self.notify_dropped(id);
+        }
+        evicted
+    }
+
+    /// Raise or lower the fee floor to `new_min_fee`, evicting pending transactions that now pay less along
+    /// with the sender's later transactions depending on them, and orphans that pay less.
+    /// Returns the ids of every evicted pending transaction.
+    pub fn reprioritize(&mut self, new_min_fee: u64) -> Vec<String> {
+        self.min_fee_floor = new_min_fee;
+        let underpriced: Vec<String> = self
+            .by_fee
+            .range(..new_min_fee)
+            .flat_map(|(_, transactions)| transactions.iter().map(|tx| tx.id.clone()))
+            .collect();
+
+        let mut evicted = Vec::new();
+        for id in underpriced {
+            // Already gone when it followed an earlier underpriced transaction of the same sender
+            if self.contains(&id) {
+                evicted.extend(self.drop_transaction(&id));
+            }
+        }
+
+        for nonces in self.orphans.values_mut() {
+            nonces.retain(|_, (tx, _)| tx.fee >= new_min_fee);
+        }
+        self.orphans.retain(|_, nonces| !nonces.is_empty());
+        evicted
+    }
+
+    /// Drop pending transactions that can no longer be applied on top of `state`, typically the state after
+    /// a new block: nonces already used, and transactions the sender can no longer afford together with
+    /// the later ones that depend on them. Returns the ids of every dropped transaction.
+    pub fn revalidate_against_state(&mut self, state: &AccountState) -> Vec<String> {
+        let mut state = state.clone();
+        let mut dropped = Vec::new();
+
+        let senders: Vec<String> = self.by_sender.keys().cloned().collect();
+        for sender in senders {
+            let ids: Vec<String> = self
+                .by_sender
+                .get(&sender)
+                .map(|nonces| nonces.values().cloned().collect())
+                .unwrap_or_default();
+
+            for id in ids {
+                let Some(tx) = self.pending_transactions.iter().find(|tx| tx.id == id).cloned() else {
+                    continue;
+                };
+
+                let expected_nonce = state.nonce(&tx.from);
+                if tx.nonce < expected_nonce {
+                    self.remove_transaction(&id);
+                    self.notify_dropped(&id);
+                    dropped.push(id);
+                    continue;
+                }
+
+                // A gap means an earlier transaction has not arrived yet, keep waiting for it
+                if tx.nonce > expected_nonce {
+                    break;
+                }
+
+                if state.apply_transaction(&tx).is_err() {
+                    dropped.extend(self.drop_transaction(&id));
+                    break;
+                }
+            }
+        }
+
+        // Nonces filled by the new block may release orphans
+        self.expire_orphans();
+        let senders: Vec<String> = self.orphans.keys().cloned().collect();
+        for sender in senders {
+            let from = self.orphans.get(&sender).and_then(|nonces| nonces.values().next()).map(|(tx, _)| tx.from.clone());
+            if let Some(from) = from {
+                self.promote_orphans(&sender, state.nonce(&from));
+            }
+        }
+
+        dropped
+    }
+
+    /// Add `transaction` if it directly follows `account_nonce` and the sender's pending transactions,
+    /// otherwise hold it as an orphan until the missing nonces arrive. Returns whether it was held.
+    pub(crate) fn submit(&mut self, transaction: Transaction, account_nonce: u64) -> Result<bool, String> {
+        self.expire_orphans();
+        let sender = transaction.from.value.clone();
+        if transaction.nonce > self.next_nonce(&sender, account_nonce) {
+            self.hold_orphan(transaction)?;
+            return Ok(true);
+        }
+
+        self.add_transaction(transaction)?;
+        self.promote_orphans(&sender, account_nonce);
+        Ok(false)
+    }
+
+    /// `submit`, returning whether the transaction was held along with a channel told once it is mined or dropped
+    /// from the pool. An orphan's submitter hears nothing until the orphan joins the pool.
+    pub(crate) fn submit_with_confirmation(
+        &mut self,
+        transaction: Transaction,
+        account_nonce: u64,
+    ) -> Result<(bool, Receiver<TxOutcome>), String> {
+        let id = transaction.id.clone();
+        let held = self.submit(transaction, account_nonce)?;
+        let (sender, receiver) = channel();
+        self.confirmations.insert(id, sender);
+        Ok((held, receiver))
+    }
+
+    /// Nonce following `account_nonce` and the sender's consecutive pending transactions
+    fn next_nonce(&self, sender: &str, account_nonce: u64) -> u64 {
+        let mut next = account_nonce;
+        if let Some(nonces) = self.by_sender.get(sender) {
+            for nonce in nonces.range(account_nonce..).map(|(nonce, _)| *nonce) {
+                if nonce != next {
+                    break;
+                }
+                next += 1;
+            }
+        }
+        next
+    }
+
+    fn hold_orphan(&mut self, transaction: Transaction) -> Result<(), String> {
+        if self.max_orphans == 0 {
+            return Err(format!("Transaction nonce {} is ahead of the sender's", transaction.nonce));
+        }
+        self.check_admission(&transaction)?;
+        if !transaction.verify_against(&transaction.from, self.network.chain_id) {
+            return Err(format!("Transaction {} is not signed by {}", transaction.id, transaction.from.value));
+        }
+
+        if self.orphan_count() >= self.max_orphans {
+            let oldest = self
+                .orphans
+                .iter()
+                .flat_map(|(sender, nonces)| nonces.iter().map(move |(nonce, (_, held_at))| (*held_at, sender, *nonce)))
+                .min()
+                .map(|(_, sender, nonce)| (sender.clone(), nonce));
+            if let Some((sender, nonce)) = oldest {
+                self.remove_orphan(&sender, nonce);
+            }
+        }
+
+        let held_at = self.clock.now().timestamp() as u64;
+        self.orphans
+            .entry(transaction.from.value.clone())
+            .or_default()
+            .insert(transaction.nonce, (transaction, held_at));
+        Ok(())
+    }
+
+    fn remove_orphan(&mut self, sender: &str, nonce: u64) -> Option<Transaction> {
+        let nonces = self.orphans.get_mut(sender)?;
+        let removed = nonces.remove(&nonce).map(|(tx, _)| tx);
+        if nonces.is_empty() {
+            self.orphans.remove(sender);
+        }
+        removed
+    }
+
+    /// Move the sender's orphans that now follow on from `account_nonce` into the pool,
+    /// dropping those whose nonce was already taken
+    fn promote_orphans(&mut self, sender: &str, account_nonce: u64) {
+        loop {
+            let next = self.next_nonce(sender, account_nonce);
+            let stale: Vec<u64> = self
+                .orphans
+                .get(sender)
+                .map(|nonces| nonces.range(..next).map(|(nonce, _)| *nonce).collect())
+                .unwrap_or_default();
+            for nonce in stale {
+                self.remove_orphan(sender, nonce);
+            }
+
+            let Some(orphan) = self.remove_orphan(sender, next) else {
+                return;
+            };
+            let id = orphan.id.clone();
+            if let Err(e) = self.add_transaction(orphan) {
+                eprintln!("Dropping orphan transaction {}: {}", id, e);
+                self.notify_dropped(&id);
+                return;
+            }
+        }
+    }
+
+    /// Drop orphans held for longer than `ORPHAN_TTL_SECS`
+    fn expire_orphans(&mut self) {
+        let now = self.clock.now().timestamp() as u64;
+        for nonces in self.orphans.values_mut() {
+            nonces.retain(|_, (_, held_at)| now.saturating_sub(*held_at) < ORPHAN_TTL_SECS);
+        }
+        self.orphans.retain(|_, nonces| !nonces.is_empty());
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn contains(&self, transaction_id: &str) -> bool {
+        self.pending_transactions.iter().any(|tx| tx.id == transaction_id)
+    }
+
+    /// Pending transaction `transaction_id`, `None` once it is mined or dropped
+    pub fn get(&self, transaction_id: &str) -> Option<&Transaction> {
+        self.pending_transactions.iter().find(|tx| tx.id == transaction_id)
+    }
+
+    /// Save the pool as it is now, so work that takes transactions out of it can be undone with `restore`
+    pub fn checkpoint(&self) -> PoolCheckpoint {
+        PoolCheckpoint(self.clone())
+    }
+
+    /// Roll the pool back to `checkpoint`, forgetting every change made since
+    pub fn restore(&mut self, checkpoint: PoolCheckpoint) {
+        *self = checkpoint.0;
+    }
+
+    /// Pending transactions by descending fee, stopping before `max_count` or `max_size` bytes are exceeded
+    pub fn snapshot(&self, max_count: usize, max_size: usize) -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        let mut total_size = 0;
+        for tx in self.by_fee.values().rev().flatten() {
+            let tx_size = self.estimate_transaction_size(tx);
+            if transactions.len() >= max_count || total_size + tx_size > max_size {
+                break;
+            }
+            total_size += tx_size;
+            transactions.push(tx.clone());
+        }
+        transactions
+    }
+
+    /// Lowest fee the pool admits right now. It climbs towards the fee needed to outbid the cheapest pending
+    /// transaction while the pool stays full, and decays back to the floor as it drains.
+    pub fn dynamic_min_fee(&self) -> u64 {
+        if self.fee_samples.is_empty() {
+            return self.min_fee_floor;
+        }
+        let average = self.fee_samples.iter().sum::<u64>() / self.fee_samples.len() as u64;
+        average.max(self.min_fee_floor)
+    }
+
+    /// Whether the pool holds as many transactions or bytes as it may
+    pub fn is_full(&self) -> bool {
+        self.pending_transactions.len() >= self.max_transactions_per_block || self.pending_bytes >= self.max_pool_bytes
+    }
+
+    /// Record how hard it is to get into the pool at the moment, see `dynamic_min_fee`
+    fn sample_fee_pressure(&mut self) {
+        let sample = match self.by_fee.keys().next() {
+            Some(lowest_fee) if self.is_full() => lowest_fee.saturating_add(1),
+            _ => self.min_fee_floor,
+        };
+        if self.fee_samples.len() == MIN_FEE_WINDOW {
+            self.fee_samples.pop_front();
+        }
+        self.fee_samples.push_back(sample);
     }
 
     pub fn pending_count(&self) -> usize {
         self.pending_transactions.len()
     }
 
-}
\ No newline at end of file
+    /// Estimated size of every pending transaction together
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+
+    /// Whether `by_fee` holds exactly the pending transactions, each under its own fee, with no empty bucket
+    pub fn is_consistent(&self) -> bool {
+        let mut bucketed = Vec::new();
+        for (fee, transactions) in &self.by_fee {
+            if transactions.is_empty() || transactions.iter().any(|tx| tx.fee != *fee) {
+                return false;
+            }
+            bucketed.extend(transactions.iter().map(|tx| tx.id.as_str()));
+        }
+
+        let mut pending: Vec<&str> = self.pending_transactions.iter().map(|tx| tx.id.as_str()).collect();
+        bucketed.sort_unstable();
+        pending.sort_unstable();
+        bucketed == pending
+    }
+
+    /// Rebuild the fee buckets and the byte count from the pending transactions, which are the source of truth
+    pub fn repair(&mut self) {
+        self.by_fee.clear();
+        for tx in &self.pending_transactions {
+            self.by_fee.entry(tx.fee).or_default().push(tx.clone());
+        }
+        self.pending_bytes = self.pending_transactions.iter().map(|tx| self.estimate_transaction_size(tx)).sum();
+    }
+
+    /// Check the fee buckets after a change to the pool. Tests fail on a drift, other builds rebuild the buckets.
+    fn check_invariants(&mut self) {
+        #[cfg(test)]
+        self.debug_check_invariants();
+        #[cfg(not(test))]
+        if !self.is_consistent() {
+            eprintln!("Fee buckets drifted from the pending transactions, rebuilding them");
+            self.repair();
+        }
+    }
+
+    #[cfg(test)]
+    fn debug_check_invariants(&self) {
+        debug_assert!(self.is_consistent(), "Fee buckets drifted from the pending transactions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::DEFAULT_CHAIN_ID;
+    use crate::address::Address;
+    use crate::block::Block;
+    use crate::transaction::TRANSFER_GAS;
+
+    fn signed_transaction(amount: u64, fee: u64) -> Transaction {
+        let (from, secret_key, _) = Address::generate();
+        let to = Address::generate().0;
+        let mut tx = Transaction::new(from, to, amount).with_gas(TRANSFER_GAS, fee);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        tx
+    }
+
+    fn signed_transaction_from(from: &Address, secret_key: &secp256k1::SecretKey, nonce: u64, fee: u64) -> Transaction {
+        let to = Address::generate().0;
+        let mut tx = Transaction::new(from.clone(), to, 10).with_nonce(nonce).with_gas(TRANSFER_GAS, fee);
+        tx.sign(secret_key, DEFAULT_CHAIN_ID).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_pull_transactions_orders_by_fee() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        pool.add_transaction(signed_transaction(10, 1)).unwrap();
+        pool.add_transaction(signed_transaction(20, 5)).unwrap();
+        pool.add_transaction(signed_transaction(30, 3)).unwrap();
+
+        let fees: Vec<u64> = pool.pull_transactions_for_block().iter().map(|tx| tx.fee).collect();
+        assert_eq!(fees, vec![5, 3, 1]);
+        assert_eq!(pool.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_transaction_signed_by_another_key_rejected() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let mut forged = signed_transaction(10, 1);
+        forged.sign(&Address::generate().1, DEFAULT_CHAIN_ID).unwrap();
+
+        assert!(pool.add_transaction(forged).unwrap_err().contains("not signed by"));
+        assert_eq!(pool.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_pull_transactions_skips_duplicates() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let tx = signed_transaction(10, 2);
+        pool.add_transaction(tx.clone()).unwrap();
+        pool.add_transaction(signed_transaction(20, 1)).unwrap();
+
+        // Inject the same transaction into another bucket and twice into its own
+        pool.by_fee.entry(7).or_default().push(tx.clone());
+        pool.by_fee.entry(2).or_default().push(tx.clone());
+
+        let selected = pool.pull_transactions_for_block();
+        let mut seen = HashSet::new();
+        for selected_tx in &selected {
+            assert!(seen.insert(selected_tx.id.clone()), "duplicate transaction {}", selected_tx.id);
+        }
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].id, tx.id);
+        assert!(pool.by_fee.is_empty());
+    }
+
+    #[test]
+    fn test_pull_transactions_keeps_sender_nonce_order() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let first = signed_transaction_from(&sender, &secret_key, 0, 1);
+        let second = signed_transaction_from(&sender, &secret_key, 1, 5);
+        let other = signed_transaction(10, 3);
+        for tx in [first.clone(), second.clone(), other.clone()] {
+            pool.add_transaction(tx).unwrap();
+        }
+
+        // The higher fee of nonce 1 cannot pull it ahead of nonce 0, only nonce 0 competes with `other`
+        assert_eq!(pool.pull_transactions_for_block(), vec![other, first, second]);
+    }
+
+    #[test]
+    fn test_pull_transactions_stops_at_nonce_gap() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let first = signed_transaction_from(&sender, &secret_key, 0, 1);
+        let after_gap = signed_transaction_from(&sender, &secret_key, 2, 5);
+        pool.add_transaction(first.clone()).unwrap();
+        pool.add_transaction(after_gap.clone()).unwrap();
+
+        assert_eq!(pool.pull_transactions_for_block(), vec![first]);
+        assert!(pool.contains(&after_gap.id));
+    }
+
+    #[test]
+    fn test_per_sender_pending_limit() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 2);
+        let (sender, secret_key, _) = Address::generate();
+
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 0, 1)).unwrap();
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 1, 1)).unwrap();
+        let result = pool.add_transaction(signed_transaction_from(&sender, &secret_key, 2, 1));
+        assert_eq!(result, Err("Sender has too many pending transactions".to_string()));
+
+        // Other senders are unaffected
+        pool.add_transaction(signed_transaction(10, 1)).unwrap();
+        assert_eq!(pool.pending_count(), 3);
+    }
+
+    #[test]
+    fn test_pool_byte_cap_enforced_below_count_limit() {
+        let first = signed_transaction(10, 1);
+        let size = TransactionPool::new(1, 1, 1).estimate_transaction_size(&first);
+        let mut pool = TransactionPool::new(100, 1024 * 1024, 16).with_max_pool_bytes(size * 5 / 2);
+
+        pool.add_transaction(first.clone()).unwrap();
+        pool.add_transaction(signed_transaction(10, 1)).unwrap();
+        assert_eq!(pool.pending_bytes(), 2 * size);
+        let result = pool.add_transaction(signed_transaction(10, 1));
+        assert!(result.is_err_and(|e| e.contains("byte limit")));
+        assert_eq!(pool.pending_count(), 2);
+
+        // Replacing a pending transaction frees its bytes first
+        let (sender, secret_key, _) = Address::generate();
+        pool.remove_transaction(&first.id);
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 0, 1)).unwrap();
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 0, 2)).unwrap();
+        assert_eq!(pool.pending_count(), 2);
+        assert_eq!(pool.pending_bytes(), pool.pending_transactions.iter().map(|tx| pool.estimate_transaction_size(tx)).sum::<usize>());
+    }
+
+    #[test]
+    fn test_full_pool_raises_dynamic_min_fee() {
+        let mut pool = TransactionPool::new(3, 1024 * 1024, 1).with_min_fee(1);
+        assert_eq!(pool.dynamic_min_fee(), 1);
+        for fee in [10, 20, 30] {
+            pool.add_transaction(signed_transaction(10, fee)).unwrap();
+        }
+        assert!(pool.is_full());
+        assert!(pool.dynamic_min_fee() < 11);
+
+        // Offers keep arriving at a full pool, the minimum fee climbs towards outbidding the cheapest one
+        let mut raised = Vec::new();
+        for _ in 0..MIN_FEE_WINDOW {
+            assert!(pool.add_transaction(signed_transaction(10, 5)).is_err());
+            raised.push(pool.dynamic_min_fee());
+        }
+        assert!(raised.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert_eq!(pool.dynamic_min_fee(), 11);
+        let error = pool.check_admission(&signed_transaction(10, 5)).unwrap_err();
+        assert!(error.contains("minimum fee 11"), "{}", error);
+    }
+
+    #[test]
+    fn test_drained_pool_decays_dynamic_min_fee() {
+        let mut pool = TransactionPool::new(2, 1024 * 1024, 1).with_min_fee(1);
+        for fee in [10, 20] {
+            pool.add_transaction(signed_transaction(10, fee)).unwrap();
+        }
+        for _ in 0..MIN_FEE_WINDOW {
+            let _ = pool.add_transaction(signed_transaction(10, 5));
+        }
+        assert_eq!(pool.dynamic_min_fee(), 11);
+
+        // Mining empties the pool, every later sample falls back to the floor
+        assert_eq!(pool.pull_transactions_for_block().len(), 2);
+        let mut decayed = vec![pool.dynamic_min_fee()];
+        while pool.dynamic_min_fee() > 1 {
+            let _ = pool.add_transaction(signed_transaction(10, 0));
+            decayed.push(pool.dynamic_min_fee());
+            assert!(decayed.len() <= MIN_FEE_WINDOW + 1);
+        }
+        assert!(decayed.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(decayed[0] < 11);
+        pool.add_transaction(signed_transaction(10, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_gapped_transaction_held_until_gap_fills() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let txs: Vec<Transaction> = (0..4)
+            .map(|nonce| signed_transaction_from(&sender, &secret_key, nonce, 1))
+            .collect();
+
+        // The account already used nonce 0
+        assert_eq!(pool.submit(txs[3].clone(), 1), Ok(true));
+        assert_eq!(pool.submit(txs[2].clone(), 1), Ok(true));
+        assert_eq!((pool.pending_count(), pool.orphan_count()), (0, 2));
+
+        assert_eq!(pool.submit(txs[1].clone(), 1), Ok(false));
+        assert_eq!((pool.pending_count(), pool.orphan_count()), (3, 0));
+        assert!(txs[1..].iter().all(|tx| pool.contains(&tx.id)));
+    }
+
+    #[test]
+    fn test_orphans_released_by_new_block() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let mut state = AccountState::new();
+        state.apply_transaction(&Transaction::coinbase(sender.clone(), 100)).unwrap();
+        let first = signed_transaction_from(&sender, &secret_key, 0, 1);
+        let second = signed_transaction_from(&sender, &secret_key, 1, 1);
+        assert_eq!(pool.submit(second.clone(), 0), Ok(true));
+
+        // Nonce 0 was mined without passing through this pool
+        state.apply_transaction(&first).unwrap();
+        pool.revalidate_against_state(&state);
+        assert!(pool.contains(&second.id));
+        assert_eq!(pool.orphan_count(), 0);
+    }
+
+    #[test]
+    fn test_orphan_area_bounded_and_expiring() {
+        let clock = Arc::new(crate::clock::MockClock::new(chrono::Utc::now()));
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16).with_max_orphans(2).with_clock(clock.clone());
+        let orphan = || {
+            let (sender, secret_key, _) = Address::generate();
+            signed_transaction_from(&sender, &secret_key, 5, 1)
+        };
+
+        let oldest = orphan();
+        pool.submit(oldest.clone(), 0).unwrap();
+        clock.advance(chrono::Duration::seconds(1));
+        pool.submit(orphan(), 0).unwrap();
+        pool.submit(orphan(), 0).unwrap();
+        assert_eq!(pool.orphan_count(), 2);
+        assert!(!pool.orphans.contains_key(&oldest.from.value));
+
+        clock.advance(chrono::Duration::seconds(ORPHAN_TTL_SECS as i64));
+        pool.submit(signed_transaction(10, 1), 0).unwrap();
+        assert_eq!(pool.orphan_count(), 0);
+
+        let mut disabled = TransactionPool::new(10, 1024 * 1024, 16).with_max_orphans(0);
+        assert!(disabled.submit(orphan(), 0).is_err());
+    }
+
+    #[test]
+    fn test_drop_transaction_evicts_descendants() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let txs: Vec<Transaction> = (0..4)
+            .map(|nonce| signed_transaction_from(&sender, &secret_key, nonce, 1))
+            .collect();
+        for tx in &txs {
+            pool.add_transaction(tx.clone()).unwrap();
+        }
+        let unrelated = signed_transaction(10, 1);
+        pool.add_transaction(unrelated.clone()).unwrap();
+
+        let evicted = pool.drop_transaction(&txs[1].id);
+        assert_eq!(evicted, vec![txs[1].id.clone(), txs[2].id.clone(), txs[3].id.clone()]);
+        assert_eq!(pool.pending_count(), 2);
+
+        let remaining: Vec<String> = pool.pull_transactions_for_block().into_iter().map(|tx| tx.id).collect();
+        assert!(remaining.contains(&txs[0].id));
+        assert!(remaining.contains(&unrelated.id));
+    }
+
+    #[test]
+    fn test_replacing_nonce_evicts_descendants() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 0, 1)).unwrap();
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 1, 1)).unwrap();
+
+        let same_fee = signed_transaction_from(&sender, &secret_key, 0, 1);
+        assert!(pool.add_transaction(same_fee).is_err());
+
+        let replacement = signed_transaction_from(&sender, &secret_key, 0, 5);
+        pool.add_transaction(replacement.clone()).unwrap();
+
+        let remaining = pool.pull_transactions_for_block();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, replacement.id);
+    }
+
+    #[test]
+    fn test_replaced_transaction_reported_dropped() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let original = signed_transaction_from(&sender, &secret_key, 0, 1);
+        let (_, confirmation) = pool.submit_with_confirmation(original, 0).unwrap();
+
+        let replacement = signed_transaction_from(&sender, &secret_key, 0, 5);
+        let (_, replacement_confirmation) = pool.submit_with_confirmation(replacement.clone(), 0).unwrap();
+        assert_eq!(confirmation.try_recv().unwrap(), TxOutcome::Dropped);
+
+        let block = Block::new(1, pool.pull_transactions_for_block(), crate::hash::Hash::genesis(), 4);
+        pool.confirm_block(&block);
+        assert_eq!(replacement_confirmation.try_recv().unwrap(), TxOutcome::Included(1));
+    }
+
+    #[test]
+    fn test_raised_min_fee_evicts_underpriced_transactions() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16).with_min_fee(1);
+        let cheap = signed_transaction(10, 1);
+        let priced = signed_transaction(10, 5);
+        let (sender, secret_key, _) = Address::generate();
+        let parent = signed_transaction_from(&sender, &secret_key, 0, 2);
+        let child = signed_transaction_from(&sender, &secret_key, 1, 9);
+        for tx in [&cheap, &priced, &parent, &child] {
+            pool.add_transaction(tx.clone()).unwrap();
+        }
+
+        let mut evicted = pool.reprioritize(3);
+        evicted.sort_unstable();
+        let mut expected = vec![cheap.id.clone(), parent.id.clone(), child.id.clone()];
+        expected.sort_unstable();
+        assert_eq!(evicted, expected);
+        assert_eq!(pool.dynamic_min_fee(), 3);
+        assert!(pool.add_transaction(signed_transaction(10, 2)).is_err());
+
+        let remaining = pool.pull_transactions_for_block();
+        assert_eq!(remaining, vec![priced.clone()]);
+        assert!(pool.reprioritize(3).is_empty());
+    }
+
+    #[test]
+    fn test_selection_respects_block_weight() {
+        let weight = signed_transaction(10, 1).weight();
+        let network = NetworkConfig { max_block_weight: 3 * weight + BASE_WEIGHT, ..NetworkConfig::default() };
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16).with_network(network);
+        for fee in 1..=5 {
+            pool.add_transaction(signed_transaction(10, fee)).unwrap();
+        }
+
+        let pulled = pool.pull_transactions_for_block();
+        assert_eq!(pulled.iter().map(|tx| tx.fee).collect::<Vec<_>>(), vec![5, 4, 3]);
+        assert_eq!(pool.pending_count(), 2);
+    }
+
+    #[test]
+    fn test_restore_returns_pulled_transactions() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let txs: Vec<Transaction> = (1..=3).map(|fee| signed_transaction(10, fee)).collect();
+        for tx in &txs {
+            pool.add_transaction(tx.clone()).unwrap();
+        }
+
+        let checkpoint = pool.checkpoint();
+        assert_eq!(pool.pull_transactions_for_block().len(), 3);
+        assert_eq!(pool.pending_count(), 0);
+
+        pool.restore(checkpoint);
+        assert_eq!(pool.pending_count(), 3);
+        assert!(txs.iter().all(|tx| pool.contains(&tx.id)));
+        assert!(pool.is_consistent());
+    }
+
+    #[test]
+    fn test_mined_transactions_keep_descendants() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (sender, secret_key, _) = Address::generate();
+        let first = signed_transaction_from(&sender, &secret_key, 0, 1);
+        pool.add_transaction(first.clone()).unwrap();
+        pool.add_transaction(signed_transaction_from(&sender, &secret_key, 1, 1)).unwrap();
+
+        pool.remove_transaction(&first.id);
+        assert_eq!(pool.pending_count(), 1);
+    }
+
+    #[test]
+    fn test_amount_bounds() {
+        let network = NetworkConfig {
+            min_amount: Some(10),
+            max_amount: Some(1_000),
+            ..NetworkConfig::default()
+        };
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16).with_network(network);
+
+        assert!(pool.add_transaction(signed_transaction(9, 1)).is_err());
+        assert!(pool.add_transaction(signed_transaction(1_001, 1)).is_err());
+        assert!(pool.add_transaction(signed_transaction(10, 1)).is_ok());
+        assert!(pool.add_transaction(signed_transaction(1_000, 1)).is_ok());
+        assert_eq!(pool.pending_count(), 2);
+    }
+
+    #[test]
+    fn test_gas_limit_above_network_max_rejected() {
+        let network = NetworkConfig { max_gas_limit: 100, ..NetworkConfig::default() };
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16).with_network(network);
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10).with_gas(101, 1);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+
+        assert!(pool.add_transaction(tx).is_err());
+        assert!(pool.add_transaction(signed_transaction(10, 1)).is_ok());
+    }
+
+    fn high_priority_transaction(fee: u64) -> Transaction {
+        let (from, secret_key, _) = Address::generate();
+        let mut tx = Transaction::new(from, Address::generate().0, 10)
+            .with_gas(TRANSFER_GAS, fee)
+            .with_priority(Priority::High);
+        tx.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        tx
+    }
+
+    /// Pool whose blocks hold about four and a half transactions by size, half of it reserved
+    fn pool_reserving_half_of_four_transactions() -> TransactionPool {
+        let pool = TransactionPool::new(10, 0, 16);
+        let tx_size = pool.estimate_transaction_size(&signed_transaction(10, 100));
+        TransactionPool::new(10, 4 * tx_size + tx_size / 2, 16).with_high_priority_reserve(50)
+    }
+
+    #[test]
+    fn test_high_priority_included_ahead_of_higher_fees() {
+        let mut pool = pool_reserving_half_of_four_transactions();
+        let urgent = high_priority_transaction(1);
+        pool.add_transaction(urgent.clone()).unwrap();
+        for _ in 0..5 {
+            pool.add_transaction(signed_transaction(10, 100)).unwrap();
+        }
+
+        let selected = pool.pull_transactions_for_block();
+        assert_eq!(selected.len(), 4);
+        assert!(selected.iter().any(|tx| tx.id == urgent.id));
+        assert_eq!(pool.pending_count(), 2);
+    }
+
+    #[test]
+    fn test_high_priority_reserve_quota_respected() {
+        let mut pool = pool_reserving_half_of_four_transactions();
+        for _ in 0..3 {
+            pool.add_transaction(high_priority_transaction(1)).unwrap();
+        }
+        for _ in 0..4 {
+            pool.add_transaction(signed_transaction(10, 100)).unwrap();
+        }
+
+        let selected = pool.pull_transactions_for_block();
+        let high_priority = selected.iter().filter(|tx| tx.priority == Priority::High).count();
+        assert_eq!(selected.len(), 4);
+        assert_eq!(high_priority, 2);
+        assert_eq!(selected.iter().filter(|tx| tx.fee == 100).count(), 2);
+    }
+
+    #[test]
+    fn test_revalidate_drops_transactions_invalidated_by_block() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (alice, alice_key, _) = Address::generate();
+        let (bob, bob_key, _) = Address::generate();
+
+        let mut state = AccountState::new();
+        state.apply_transaction(&Transaction::coinbase(alice.clone(), 100)).unwrap();
+        state.apply_transaction(&Transaction::coinbase(bob.clone(), 100)).unwrap();
+
+        // Alice's nonce 0 gets mined through a competing transaction, Bob spends most of the balance
+        let conflicting = signed_transaction_from(&alice, &alice_key, 0, 1);
+        let alice_next = signed_transaction_from(&alice, &alice_key, 1, 1);
+        let mut bob_spend = Transaction::new(bob.clone(), Address::generate().0, 95).with_gas(TRANSFER_GAS, 1);
+        bob_spend.sign(&bob_key, DEFAULT_CHAIN_ID).unwrap();
+        let bob_pending = signed_transaction_from(&bob, &bob_key, 1, 1);
+        let bob_later = signed_transaction_from(&bob, &bob_key, 2, 1);
+
+        pool.add_transaction(conflicting.clone()).unwrap();
+        pool.add_transaction(alice_next.clone()).unwrap();
+        pool.add_transaction(bob_pending.clone()).unwrap();
+        pool.add_transaction(bob_later.clone()).unwrap();
+
+        state.apply_transaction(&signed_transaction_from(&alice, &alice_key, 0, 2)).unwrap();
+        state.apply_transaction(&bob_spend).unwrap();
+
+        let mut dropped = pool.revalidate_against_state(&state);
+        dropped.sort();
+        let mut expected = vec![conflicting.id.clone(), bob_pending.id.clone(), bob_later.id.clone()];
+        expected.sort();
+        assert_eq!(dropped, expected);
+        assert_eq!(pool.pending_count(), 1);
+        assert_eq!(pool.pull_transactions_for_block(), vec![alice_next]);
+    }
+
+    #[test]
+    fn test_repair_rebuilds_corrupted_fee_buckets() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let transactions: Vec<Transaction> = [5, 5, 8].iter().map(|fee| signed_transaction(10, *fee)).collect();
+        for tx in &transactions {
+            pool.add_transaction(tx.clone()).unwrap();
+        }
+        assert!(pool.is_consistent());
+
+        // Lose one transaction, file another under the wrong fee and leave an empty bucket behind
+        pool.by_fee.get_mut(&5).unwrap().pop();
+        pool.by_fee.entry(1).or_default().push(transactions[2].clone());
+        pool.by_fee.insert(3, Vec::new());
+        assert!(!pool.is_consistent());
+
+        pool.repair();
+        assert!(pool.is_consistent());
+        assert_eq!(pool.by_fee.keys().copied().collect::<Vec<_>>(), vec![5, 8]);
+        assert_eq!(pool.pull_transactions_for_block().len(), 3);
+    }
+
+    #[test]
+    fn test_operations_keep_fee_buckets_in_sync() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (from, secret_key, _) = Address::generate();
+        let first = signed_transaction_from(&from, &secret_key, 0, 2);
+        let second = signed_transaction_from(&from, &secret_key, 1, 2);
+        let other = signed_transaction(10, 4);
+
+        for tx in [first, second.clone(), other.clone()] {
+            pool.add_transaction(tx).unwrap();
+            assert!(pool.is_consistent());
+        }
+
+        // Replacing nonce 0 evicts the sender's later nonce too
+        pool.add_transaction(signed_transaction_from(&from, &secret_key, 0, 3)).unwrap();
+        assert!(pool.is_consistent());
+        assert_eq!(pool.pending_count(), 2);
+
+        pool.remove_transaction(&other.id);
+        assert!(pool.is_consistent());
+        pool.pull_transactions_for_block();
+        assert!(pool.is_consistent());
+        assert!(pool.by_fee.is_empty());
+    }
+
+    #[test]
+    fn test_address_validation_on_admission() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        let (_, secret_key, _) = Address::generate();
+
+        let mut from_zero = Transaction::new(Address::zero(), Address::generate().0, 10).with_gas(TRANSFER_GAS, 1);
+        from_zero.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(pool.add_transaction(from_zero).unwrap_err().contains("Coinbase"));
+
+        let (from, secret_key, _) = Address::generate();
+        let invalid = Address { value: "0x1234".to_string(), raw_bytes: None };
+        let mut to_invalid = Transaction::new(from, invalid, 10).with_gas(TRANSFER_GAS, 1);
+        to_invalid.sign(&secret_key, DEFAULT_CHAIN_ID).unwrap();
+        assert!(!to_invalid.is_valid());
+        assert!(pool.add_transaction(to_invalid).unwrap_err().contains("Recipient"));
+
+        assert!(pool.add_transaction(signed_transaction(10, 1)).is_ok());
+        assert_eq!(pool.pending_count(), 1);
+    }
+
+    #[test]
+    fn test_simulate_checks_state_without_adding() {
+        let mut chain = Chain::new_easy();
+        let (alice, alice_key, _) = Address::generate();
+        let genesis_hash = chain.genesis_block().current_block_hash.clone().unwrap();
+        let mut block = Block::new(1, vec![Transaction::coinbase(alice.clone(), 50)], genesis_hash, 1);
+        block.mine_block(1).unwrap();
+        chain.add_block(block).unwrap();
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+
+        let first = signed_transaction_from(&alice, &alice_key, 0, 1);
+        assert_eq!(pool.simulate(&first, &chain), Ok(()));
+        assert_eq!(pool.pending_count(), 0);
+
+        // The next nonce is only valid once the first one is pending, and pays out of what it leaves
+        let second = signed_transaction_from(&alice, &alice_key, 1, 1);
+        assert_eq!(pool.simulate(&second, &chain), Err(TxRejection::BadNonce { expected: 0, actual: 1 }));
+        pool.add_transaction(first).unwrap();
+        assert_eq!(pool.simulate(&second, &chain), Ok(()));
+
+        let mut overdraft = Transaction::new(alice.clone(), Address::generate().0, 40)
+            .with_nonce(1)
+            .with_gas(TRANSFER_GAS, 1);
+        overdraft.sign(&alice_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(
+            pool.simulate(&overdraft, &chain),
+            Err(TxRejection::InsufficientBalance { required: 41, available: 39 })
+        );
+
+        let (_, other_key, _) = Address::generate();
+        let mut forged = signed_transaction_from(&alice, &alice_key, 1, 1);
+        forged.sign(&other_key, DEFAULT_CHAIN_ID).unwrap();
+        assert_eq!(pool.simulate(&forged, &chain), Err(TxRejection::BadSignature));
+        assert_eq!(pool.pending_count(), 1);
+    }
+
+    #[test]
+    fn test_amount_unbounded_by_default() {
+        let mut pool = TransactionPool::new(10, 1024 * 1024, 16);
+        assert!(pool.add_transaction(signed_transaction(1, 1)).is_ok());
+        assert!(pool.add_transaction(signed_transaction(u64::MAX - 1, 1)).is_ok());
+        assert!(pool.add_transaction(signed_transaction(u64::MAX, 1)).is_err());
+    }
+}